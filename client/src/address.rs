@@ -0,0 +1,150 @@
+use std::net::{IpAddr, SocketAddr};
+
+use rustls_pki_types::ServerName;
+use tokio::net::lookup_host;
+
+use crate::error::{Error, Result};
+
+/// Port the server listens on when the address doesn't name one.
+pub const DEFAULT_PORT: u16 = 64400;
+
+/// Scheme for a plaintext connection, meant for local development.
+const PLAINTEXT_SCHEME: &str = "mcs://";
+
+/// Scheme for a TLS connection, the same as giving no scheme.
+const TLS_SCHEME: &str = "mcss://";
+
+/// A server address typed at login: `host`, `host:port`, or `[ipv6]:port`,
+/// optionally prefixed with `mcs://` (plaintext) or `mcss://` (TLS).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerAddress {
+    /// Hostname or IP literal, without IPv6 brackets.
+    pub host: String,
+    pub port: u16,
+    /// Skip TLS and speak the protocol over plain TCP.
+    pub plaintext: bool,
+}
+
+impl ServerAddress {
+    pub fn parse(input: &str) -> Result<Self> {
+        let input = input.trim();
+        let plaintext = input.starts_with(PLAINTEXT_SCHEME);
+        let rest = input
+            .strip_prefix(PLAINTEXT_SCHEME)
+            .or_else(|| input.strip_prefix(TLS_SCHEME))
+            .unwrap_or(input);
+        let (host, port) = parse_host_port(rest)
+            .ok_or_else(|| Error::Address(format!("'{input}', expected host or host:port")))?;
+        Ok(Self {
+            host,
+            port,
+            plaintext,
+        })
+    }
+
+    /// The TLS name to verify the server certificate against, either an IP or a DNS name.
+    pub fn server_name(&self) -> Result<ServerName<'static>> {
+        ServerName::try_from(self.host.clone())
+            .map_err(|e| Error::Address(format!("'{}' is not a valid host: {e}", self.host)))
+    }
+
+    /// Resolves the host, returning the first socket address found.
+    pub async fn resolve(&self) -> Result<SocketAddr> {
+        lookup_host((self.host.as_str(), self.port))
+            .await
+            .map_err(|_| Error::Resolve(self.host.clone()))?
+            .next()
+            .ok_or_else(|| Error::Resolve(self.host.clone()))
+    }
+}
+
+fn parse_host_port(input: &str) -> Option<(String, u16)> {
+    if let Ok(ip) = input.parse::<IpAddr>() {
+        return Some((ip.to_string(), DEFAULT_PORT));
+    }
+    if let Ok(addr) = input.parse::<SocketAddr>() {
+        return Some((addr.ip().to_string(), addr.port()));
+    }
+
+    if let Some(rest) = input.strip_prefix('[') {
+        // A bracketed literal that didn't parse above is either bare or malformed.
+        let (ip, port) = rest.split_once(']')?;
+        let ip: IpAddr = ip.parse().ok()?;
+        return port.is_empty().then(|| (ip.to_string(), DEFAULT_PORT));
+    }
+
+    let (host, port) = match input.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (input, DEFAULT_PORT),
+    };
+    if host.is_empty() || host.contains(':') {
+        return None;
+    }
+    Some((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DEFAULT_PORT, ServerAddress};
+    use rustls_pki_types::ServerName;
+
+    fn parse(input: &str) -> (String, u16) {
+        let addr = ServerAddress::parse(input).unwrap();
+        (addr.host, addr.port)
+    }
+
+    #[test]
+    fn parses_ipv4_with_and_without_port() {
+        assert_eq!(parse("127.0.0.1"), ("127.0.0.1".to_string(), DEFAULT_PORT));
+        assert_eq!(parse("10.0.0.2:7000"), ("10.0.0.2".to_string(), 7000));
+    }
+
+    #[test]
+    fn parses_ipv6_literals() {
+        assert_eq!(parse("[::1]:7000"), ("::1".to_string(), 7000));
+        assert_eq!(parse("[::1]"), ("::1".to_string(), DEFAULT_PORT));
+        assert_eq!(parse("::1"), ("::1".to_string(), DEFAULT_PORT));
+        assert!(matches!(
+            ServerAddress::parse("[::1]:7000").unwrap().server_name(),
+            Ok(ServerName::IpAddress(_))
+        ));
+    }
+
+    #[test]
+    fn parses_hostnames() {
+        assert_eq!(
+            parse("chat.local"),
+            ("chat.local".to_string(), DEFAULT_PORT)
+        );
+        assert_eq!(parse("chat.local:7000"), ("chat.local".to_string(), 7000));
+        assert!(matches!(
+            ServerAddress::parse("chat.local").unwrap().server_name(),
+            Ok(ServerName::DnsName(_))
+        ));
+    }
+
+    #[test]
+    fn scheme_picks_plaintext_or_tls() {
+        let plain = ServerAddress::parse("mcs://127.0.0.1:7000").unwrap();
+        assert_eq!((plain.host.as_str(), plain.port), ("127.0.0.1", 7000));
+        assert!(plain.plaintext);
+
+        assert!(!ServerAddress::parse("mcss://chat.local").unwrap().plaintext);
+        assert!(!ServerAddress::parse("chat.local").unwrap().plaintext);
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for input in [
+            "",
+            ":7000",
+            "chat.local:port",
+            "[::1",
+            "[::1]x",
+            "a:b:c",
+            "mcs://",
+        ] {
+            assert!(ServerAddress::parse(input).is_err(), "{input}");
+        }
+    }
+}
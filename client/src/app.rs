@@ -1,11 +1,47 @@
-use crate::{error::Error, event::AppEvent, network::NetworkClient};
-use crossterm::event::{KeyCode, KeyEvent};
-use protocol::{ChatPacket, JoinPacket, Message};
-use std::collections::VecDeque;
+use crate::{
+    clipboard::{ClipboardSink, CopyTarget},
+    command::{self, Command},
+    config::{self, ClientConfig},
+    emoji,
+    error::Error,
+    error_log::{ErrorLog, Level},
+    event::AppEvent,
+    export,
+    latency::{LatencyTracker, PING_INTERVAL},
+    network::NetworkClient,
+    outbox::Outbox,
+    typing::{TYPING_EXPIRY, TypingDebouncer},
+    ui::components::message_list,
+};
+use chrono::Utc;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use protocol::{
+    ChatError, ChatPacket, HistoryDirection, JoinPacket, Message, OnlineUser, RateUsage,
+};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
 use tokio::sync::mpsc;
 
 /// Maximum number of messages to keep in memory.
-const MAX_MESSAGES: usize = 500;
+/// Messages kept in the chat view unless the config sets `scrollback`.
+const DEFAULT_MAX_MESSAGES: usize = 500;
+
+/// Sender of locally drawn join and leave lines; no username can contain '*'.
+pub const PRESENCE_SENDER: &str = "*";
+
+/// Number of results requested by `/search`.
+const SEARCH_LIMIT: u32 = 50;
+
+/// How long without chatting or typing before `/who` marks a user idle.
+const IDLE_AFTER_MS: i64 = 60_000;
+
+/// How long the rate limit indicator stays visible after a warning.
+const RATE_WARNING_DURATION: Duration = Duration::from_secs(1);
+
+/// Shortest gap between rate usage requests while chatting.
+const RATE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Actions to be handled by the app.
 pub enum Action {
@@ -21,6 +57,18 @@ pub enum Action {
     ScrollUp,
     /// User scrolls down.
     ScrollDown,
+    /// User jumps to the oldest loaded message, fetching older history.
+    ScrollToTop,
+    /// User jumps back to the latest message.
+    ScrollToBottom,
+    /// User moves the message selection toward older messages.
+    SelectUp,
+    /// User moves the message selection toward newer messages.
+    SelectDown,
+    /// User copies the selected message, or the latest one.
+    Copy,
+    /// User opens or closes the error log panel.
+    ToggleErrorLog,
     None,
 }
 
@@ -46,15 +94,71 @@ pub struct GlobalState {
 pub struct UIState {
     pub input_buffer: String,
     pub error_message: Option<String>,
+    /// Recent errors, kept after `error_message` moves on to the next one.
+    pub error_log: ErrorLog,
+    pub show_error_log: bool,
 }
 
 pub struct ChatState {
     pub messages: VecDeque<ChatPacket>,
+    /// Cap on `messages`; the oldest are evicted beyond it.
+    pub max_messages: usize,
+    /// The server's cap on message length, from its `Hello`; the protocol
+    /// default until one arrives.
+    pub max_message_len: usize,
+    /// Ids of messages in `messages`, used to drop duplicates from history and live paths.
+    pub seen_ids: HashSet<u64>,
     pub network: Option<NetworkClient>,
     pub username: String,
+    /// Rows scrolled up from the bottom of the message list.
     pub scroll_offset: u16,
+    /// Messages received while scrolled up, reset on reaching the bottom.
+    pub unread_count: usize,
+    /// Inner width of the message list from the last render.
+    pub viewport_width: usize,
     pub should_request_history: bool,
-    pub history_request_timestamp: Option<i64>,
+    /// Timestamp and id of the oldest shown message when history was last wanted.
+    pub history_request_cursor: Option<(i64, u64)>,
+    /// Set once the server reports no older history, so scrolling up stops asking.
+    pub reached_history_start: bool,
+    /// Set once paging back pushed the newest messages out of the view. Newer
+    /// messages then come from forward pages until the view catches up.
+    pub detached: bool,
+    /// Live messages held back while `detached`, merged in on catching up.
+    pub held_live: VecDeque<ChatPacket>,
+    /// Cursor of the page of newer history on its way, if any.
+    pub pending_newer: Option<(i64, u64)>,
+    /// Set when the server reports usage close to the rate limit.
+    pub rate_warning_until: Option<Instant>,
+    /// When to next ask the server for rate usage; set by sending chat.
+    pub next_rate_check: Option<Instant>,
+    pub last_rate_check: Option<Instant>,
+    /// Shortcodes from the config, checked before the built-in ones.
+    pub emoji: HashMap<String, String>,
+    /// Users seen coming online since joining, less those seen leaving.
+    pub online_users: BTreeSet<String>,
+    /// Other users currently typing, keyed by username with the last notice time.
+    pub typing_users: HashMap<String, Instant>,
+    pub typing: TypingDebouncer,
+    /// Latest admin announcement, shown as a banner.
+    pub announcement: Option<String>,
+    pub search: Option<SearchView>,
+    /// Index into `messages` of the highlighted message, if any.
+    pub selected_index: Option<usize>,
+    pub clipboard: ClipboardSink,
+    /// The user's own messages not yet echoed back by the server.
+    pub outbox: Outbox,
+    /// Round-trip time from the most recent pong.
+    pub latency_ms: Option<u64>,
+    pub latency: LatencyTracker,
+    pub last_ping: Option<Instant>,
+}
+
+/// A `/search` shown in place of the chat history until closed with Esc.
+pub struct SearchView {
+    pub query: String,
+    /// Matches from the server, newest first, or `None` while waiting.
+    pub results: Option<Vec<ChatPacket>>,
 }
 
 pub struct LoginState {
@@ -62,6 +166,8 @@ pub struct LoginState {
     pub ip: String,
     pub user: String,
     pub pass: String,
+    /// Whether the last server connected to accepts guests.
+    pub guest_allowed: bool,
 }
 
 pub struct App {
@@ -82,26 +188,62 @@ impl App {
             ui: UIState {
                 input_buffer: String::new(),
                 error_message: None,
+                error_log: ErrorLog::default(),
+                show_error_log: false,
             },
             chat: ChatState {
-                messages: VecDeque::with_capacity(MAX_MESSAGES),
+                messages: VecDeque::with_capacity(DEFAULT_MAX_MESSAGES),
+                max_messages: DEFAULT_MAX_MESSAGES,
+                max_message_len: protocol::MAX_MESSAGE_LEN,
+                seen_ids: HashSet::with_capacity(DEFAULT_MAX_MESSAGES),
                 network: None,
                 username: String::new(),
                 scroll_offset: 0,
+                unread_count: 0,
+                viewport_width: 0,
                 should_request_history: false,
-                history_request_timestamp: None,
+                history_request_cursor: None,
+                reached_history_start: false,
+                detached: false,
+                held_live: VecDeque::new(),
+                pending_newer: None,
+                rate_warning_until: None,
+                next_rate_check: None,
+                last_rate_check: None,
+                emoji: HashMap::new(),
+                online_users: BTreeSet::new(),
+                typing_users: HashMap::new(),
+                typing: TypingDebouncer::default(),
+                announcement: None,
+                search: None,
+                selected_index: None,
+                clipboard: ClipboardSink::default(),
+                outbox: Outbox::default(),
+                latency_ms: None,
+                latency: LatencyTracker::default(),
+                last_ping: None,
             },
             login: LoginState {
                 step: LoginStep::Ip,
                 ip: String::new(),
                 user: String::new(),
                 pass: String::new(),
+                guest_allowed: false,
             },
         }
     }
 
-    /// Consumes an event and updates state.
-    pub fn handle_event(&mut self, event: AppEvent) {
+    /// Applies saved settings and fills the login form from a previous session.
+    pub fn apply_config(&mut self, config: ClientConfig) {
+        self.chat.max_messages = config.scrollback.unwrap_or(DEFAULT_MAX_MESSAGES).max(1);
+        self.chat.emoji = config.emoji;
+        self.login.ip = config.server;
+        self.login.user = config.username;
+        self.ui.input_buffer = self.login.ip.clone();
+    }
+
+    /// Consumes an event and updates state, returning whether the screen needs redrawing.
+    pub fn handle_event(&mut self, event: AppEvent) -> bool {
         match event {
             AppEvent::Input(key) => {
                 let action = Self::map_key_to_action(key);
@@ -113,27 +255,81 @@ impl App {
             AppEvent::Err(e) => {
                 self.handle_error(&e);
             }
-            AppEvent::Tick => {}
-            AppEvent::LoginSuccess(tx) => {
-                self.chat.network = Some(NetworkClient::new(tx));
-                self.chat.username = self.login.user.clone();
-                self.global.screen = CurrentScreen::Chat;
-                self.ui.error_message = None;
+            AppEvent::Tick => return self.on_tick(Instant::now()),
+            AppEvent::Resize => {}
+            AppEvent::LoginSuccess { tx, username } => {
+                self.on_login(tx, username);
+                self.ui.error_message = config::save(&ClientConfig {
+                    server: self.login.ip.clone(),
+                    username: self.login.user.clone(),
+                    ..config::load()
+                })
+                .err()
+                .map(|e| format!("Couldn't save login details: {e}"));
             }
             AppEvent::LoginFailed(e) => {
-                self.ui.error_message = Some(format!("Connection failed: {e}"));
+                self.ui.error_message = Some(e);
             }
         }
+        true
+    }
+
+    /// Starts a chat session for `username` over a freshly authenticated connection.
+    fn on_login(&mut self, tx: mpsc::UnboundedSender<Message>, username: String) {
+        self.chat.network = Some(NetworkClient::new(tx));
+        self.chat.pending_newer = None;
+        // Another user's unsent messages must not go out under this login.
+        if self.chat.username != username {
+            self.chat.outbox.clear();
+        }
+        self.chat.online_users = BTreeSet::from([username.clone()]);
+        self.chat.username = username;
+        self.retry_failed_messages();
+        self.global.screen = CurrentScreen::Chat;
+    }
+
+    /// Runs the periodic timers, returning whether anything on screen changed.
+    fn on_tick(&mut self, now: Instant) -> bool {
+        let typing = self.chat.typing_users.len();
+        self.chat
+            .typing_users
+            .retain(|_, seen| now.duration_since(*seen) < TYPING_EXPIRY);
+        let errors = self.ui.error_log.entries().len();
+        self.ui.error_log.expire(now);
+        let warning_ended = self
+            .chat
+            .rate_warning_until
+            .is_some_and(|until| until <= now);
+        if warning_ended {
+            self.chat.rate_warning_until = None;
+        }
+        self.ping_if_due(now);
+        self.check_rate_if_due(now);
+
+        // The open error log shows each entry's age, so it changes every tick.
+        self.chat.typing_users.len() != typing
+            || self.ui.error_log.entries().len() != errors
+            || warning_ended
+            || self.ui.show_error_log
     }
 
     const fn map_key_to_action(key: KeyEvent) -> Action {
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
         match key.code {
+            KeyCode::Up if shift => Action::SelectUp,
+            KeyCode::Down if shift => Action::SelectDown,
+            KeyCode::Char('y') if ctrl => Action::Copy,
+            KeyCode::Char('e') if ctrl => Action::ToggleErrorLog,
             KeyCode::Esc => Action::Quit,
             KeyCode::Enter => Action::Submit,
             KeyCode::Backspace => Action::DeleteChar,
             KeyCode::Char(c) => Action::EnterChar(c),
             KeyCode::Up | KeyCode::PageUp | KeyCode::BackTab => Action::ScrollUp,
             KeyCode::Down | KeyCode::PageDown | KeyCode::Tab => Action::ScrollDown,
+            // Not g/G: the input box always has focus, so letters are typed.
+            KeyCode::Home => Action::ScrollToTop,
+            KeyCode::End => Action::ScrollToBottom,
             _ => Action::None,
         }
     }
@@ -144,6 +340,7 @@ impl App {
         }
 
         match action {
+            Action::Quit if self.chat.search.is_some() => self.chat.search = None,
             Action::Quit => self.global.should_quit = true,
             Action::EnterChar(c) => self.ui.input_buffer.push(*c),
             Action::DeleteChar => {
@@ -162,24 +359,118 @@ impl App {
             Action::ScrollDown => match self.global.screen {
                 CurrentScreen::Login => self.next_login_field(),
                 CurrentScreen::Chat => {
-                    self.chat.scroll_offset = self.chat.scroll_offset.saturating_sub(1);
+                    self.scroll_down(1);
+                    self.get_newer_history();
                 }
             },
+            Action::ScrollToTop => {
+                if self.global.screen == CurrentScreen::Chat {
+                    self.scroll_to_top();
+                }
+            }
+            Action::ScrollToBottom => {
+                self.scroll_down(self.chat.scroll_offset);
+                self.get_newer_history();
+            }
+            Action::SelectUp => self.select_previous(),
+            Action::SelectDown => self.select_next(),
+            Action::Copy => self.copy_selected(),
+            Action::ToggleErrorLog => self.ui.show_error_log = !self.ui.show_error_log,
             Action::None => {}
         }
+
+        if self.global.screen == CurrentScreen::Chat
+            && matches!(
+                action,
+                Action::EnterChar(_) | Action::DeleteChar | Action::Submit
+            )
+        {
+            self.notify_typing();
+        }
+    }
+
+    fn notify_typing(&mut self) {
+        let buffer_empty = self.ui.input_buffer.is_empty();
+        if let Some(is_typing) = self.chat.typing.on_input(Instant::now(), buffer_empty)
+            && let Some(network) = &self.chat.network
+        {
+            let _ = network.send(Message::Typing {
+                username: self.chat.username.clone(),
+                is_typing,
+            });
+        }
+    }
+
+    fn ping_if_due(&mut self, now: Instant) {
+        let Some(network) = &self.chat.network else {
+            return;
+        };
+        if self
+            .chat
+            .last_ping
+            .is_some_and(|sent| now.duration_since(sent) < PING_INTERVAL)
+        {
+            return;
+        }
+        self.chat.last_ping = Some(now);
+        let _ = network.send(self.chat.latency.ping(Utc::now().timestamp_millis()));
+    }
+
+    /// Asks for rate usage once a check is due after sending chat.
+    fn check_rate_if_due(&mut self, now: Instant) {
+        let Some(network) = &self.chat.network else {
+            return;
+        };
+        if self.chat.next_rate_check.is_some_and(|due| due <= now) {
+            self.chat.next_rate_check = None;
+            self.chat.last_rate_check = Some(now);
+            let _ = network.send(Message::RateStatusRequest);
+        }
     }
 
     fn get_history(&mut self) {
-        if let Some(timestamp) = self.chat.history_request_timestamp
+        // A full view left at the bottom keeps its newest messages; paging back
+        // past the cap only happens while the user is scrolled up reading.
+        let full_at_bottom =
+            self.chat.messages.len() >= self.chat.max_messages && self.chat.scroll_offset == 0;
+        if !self.chat.reached_history_start
+            && !full_at_bottom
+            && let Some((cursor_ts, cursor_id)) = self.chat.history_request_cursor
             && let Some(client) = &self.chat.network
         {
-            let history_request = Message::HistoryRequest(timestamp);
+            let history_request = Message::HistoryRequest {
+                cursor_ts,
+                cursor_id,
+                sender: None,
+                direction: HistoryDirection::Before,
+            };
             if let Err(e) = client.send(history_request) {
                 self.handle_error(&e);
             }
         }
         self.chat.should_request_history = false;
-        self.chat.history_request_timestamp = None;
+        self.chat.history_request_cursor = None;
+    }
+
+    /// Asks for the page after the newest shown message once a detached view
+    /// is scrolled to its bottom.
+    fn get_newer_history(&mut self) {
+        if !self.chat.detached || self.chat.pending_newer.is_some() || self.chat.scroll_offset > 0 {
+            return;
+        }
+        let Some(newest) = self.chat.messages.iter().rev().find(|m| m.id != 0) else {
+            return;
+        };
+        let cursor = (newest.timestamp, newest.id);
+        let request = Message::HistoryRequest {
+            cursor_ts: cursor.0,
+            cursor_id: cursor.1,
+            sender: None,
+            direction: HistoryDirection::After,
+        };
+        if self.send_network(request) {
+            self.chat.pending_newer = Some(cursor);
+        }
     }
 
     fn next_login_field(&mut self) {
@@ -230,6 +521,10 @@ impl App {
             LoginStep::Ip | LoginStep::Username => self.next_login_field(),
             LoginStep::Password => {
                 self.login.pass = self.ui.input_buffer.clone();
+                if let Err(e) = protocol::validate_username(&self.login.user) {
+                    self.ui.error_message = Some(format!("Invalid username: {e}"));
+                    return;
+                }
                 let pass = self.login.pass.clone();
                 self.connect_to_server(pass);
             }
@@ -246,7 +541,19 @@ impl App {
 
         tokio::spawn(async move {
             match NetworkClient::connect(&ip, event_tx.clone()).await {
-                Ok(client) => {
+                Ok((client, guest_allowed)) => {
+                    let guest = password.is_empty();
+                    if guest && !guest_allowed {
+                        let _ = event_tx.send(AppEvent::LoginFailed(
+                            "This server needs a password".to_string(),
+                        ));
+                        return;
+                    }
+                    let username = if guest {
+                        protocol::guest_name(&user)
+                    } else {
+                        user.clone()
+                    };
                     let join_packet = Message::Join(JoinPacket {
                         username: user,
                         password,
@@ -258,7 +565,10 @@ impl App {
                         return;
                     }
 
-                    let _ = event_tx.send(AppEvent::LoginSuccess(client.into_inner()));
+                    let _ = event_tx.send(AppEvent::LoginSuccess {
+                        tx: client.into_inner(),
+                        username,
+                    });
                 }
                 Err(e) => {
                     let _ = event_tx.send(AppEvent::LoginFailed(e.to_string()));
@@ -267,39 +577,297 @@ impl App {
         });
     }
 
-    fn handle_chat_submit(&mut self, input: String) {
+    fn handle_chat_submit(&mut self, mut input: String) {
+        // Only the newline that submitted it; any inside are the user's.
+        input.truncate(trim_trailing_newline(&input).len());
         if input.trim().is_empty() {
             return;
         }
 
-        if let Some(network) = &self.chat.network {
-            let packet = ChatPacket::new_user_packet(self.chat.username.clone(), input);
-            let msg = Message::Chat(packet);
+        // Measured as sent, since shortcodes can expand past the limit. Keep
+        // the text so it can be trimmed instead of retyped.
+        if emoji::expand_shortcodes(&input, &self.chat.emoji).len() > self.chat.max_message_len {
+            self.ui.error_message = Some(
+                ChatError::MessageTooLong {
+                    max: self.chat.max_message_len,
+                }
+                .to_string(),
+            );
+            self.ui.input_buffer = input;
+            return;
+        }
+
+        if input.trim_start().starts_with('/') {
+            self.handle_command(command::parse_command(&input));
+            return;
+        }
 
-            if let Err(e) = network.send(msg) {
-                self.handle_error(&e);
-            } else {
-                self.chat.scroll_offset = 0;
+        self.send_chat(&input);
+    }
+
+    fn handle_command(&mut self, cmd: Command) {
+        match cmd {
+            Command::Quit => self.global.should_quit = true,
+            Command::Me(action) => self.send_chat(&format!("{}{action}", command::EMOTE_PREFIX)),
+            Command::Dm { user, .. } => {
+                self.ui.error_message = Some(format!(
+                    "Direct messages to {user} aren't supported by this server"
+                ));
             }
-        } else {
+            Command::Edit(content) => {
+                if let Some(id) = self.last_own_message_id() {
+                    self.send_network(Message::EditMessage { id, content });
+                }
+            }
+            Command::Delete => {
+                if let Some(id) = self.last_own_message_id() {
+                    self.send_network(Message::DeleteMessage { id });
+                }
+            }
+            Command::Announce(content) => {
+                self.send_network(Message::Announcement { content });
+            }
+            Command::Search(query) => {
+                if self.send_network(Message::SearchRequest {
+                    query: query.clone(),
+                    limit: SEARCH_LIMIT,
+                }) {
+                    self.chat.search = Some(SearchView {
+                        query,
+                        results: None,
+                    });
+                }
+            }
+            Command::From(sender) => {
+                if self.send_network(Message::HistoryRequest {
+                    cursor_ts: i64::MAX,
+                    cursor_id: u64::MAX,
+                    sender: Some(sender.clone()),
+                    direction: HistoryDirection::Before,
+                }) {
+                    self.chat.search = Some(SearchView {
+                        query: format!("from:{sender}"),
+                        results: None,
+                    });
+                }
+            }
+            Command::Export { path, format } => {
+                self.ui.error_message =
+                    Some(match export::export(&self.chat.messages, &path, format) {
+                        Ok(()) => {
+                            format!("Exported {} messages to {path}", self.chat.messages.len())
+                        }
+                        Err(e) => format!("Export failed: {e}"),
+                    });
+            }
+            Command::Kick { user, reason, ban } => {
+                self.send_network(Message::KickRequest {
+                    username: user,
+                    reason,
+                    ban,
+                });
+            }
+            Command::Stats => {
+                self.send_network(Message::StatsRequest);
+            }
+            Command::Who => {
+                self.send_network(Message::OnlineUsersRequest);
+            }
+            Command::Passwd { old, new } => match protocol::validate_password(&new) {
+                Ok(()) => {
+                    self.send_network(Message::ChangePassword { old, new });
+                }
+                Err(e) => self.ui.error_message = Some(e.to_string()),
+            },
+            Command::Clear => self.clear_messages(),
+            Command::Help => self.ui.error_message = Some(command::HELP_TEXT.to_string()),
+            Command::Usage(usage) => self.ui.error_message = Some(format!("Usage: {usage}")),
+            Command::Unknown(name) => {
+                self.ui.error_message = Some(format!("Unknown command {name}, try /help"));
+            }
+        }
+    }
+
+    /// Finds the id of the most recent live message sent by the current user.
+    fn last_own_message_id(&mut self) -> Option<u64> {
+        let id = self
+            .chat
+            .messages
+            .iter()
+            .rev()
+            .find(|m| m.sender == self.chat.username && m.id != 0 && !m.deleted)
+            .map(|m| m.id);
+
+        if id.is_none() {
+            self.ui.error_message = Some("You have no messages to change".to_string());
+        }
+        id
+    }
+
+    /// Sends a message to the server, returning whether it was queued.
+    fn send_network(&mut self, msg: Message) -> bool {
+        let Some(network) = &self.chat.network else {
             self.ui.error_message = Some("Disconnected from server".to_string());
+            return false;
+        };
+
+        if let Err(e) = network.send(msg) {
+            self.handle_error(&e);
+            return false;
+        }
+        true
+    }
+
+    fn send_chat(&mut self, content: &str) {
+        let content = emoji::expand_shortcodes(content, &self.chat.emoji);
+        let packet = ChatPacket::new_user_packet(self.chat.username.clone(), content);
+        let local_id = self.chat.outbox.push(packet.clone());
+        self.scroll_down(self.chat.scroll_offset);
+        if self.send_outgoing(local_id, packet) {
+            // Checks stay at most one per interval however fast chat is sent.
+            let last = self.chat.last_rate_check;
+            self.chat
+                .next_rate_check
+                .get_or_insert_with(|| last.map_or_else(Instant::now, |t| t + RATE_CHECK_INTERVAL));
+        }
+    }
+
+    /// Sends a queued message tagged with its local id, failing it if the send fails.
+    fn send_outgoing(&mut self, local_id: u64, mut packet: ChatPacket) -> bool {
+        packet.id = local_id;
+        let sent = self.send_network(Message::Chat(packet));
+        if !sent {
+            self.chat.outbox.mark_failed(local_id);
+        }
+        sent
+    }
+
+    fn retry_failed_messages(&mut self) {
+        for (local_id, packet) in self.chat.outbox.retry_failed() {
+            self.send_outgoing(local_id, packet);
         }
     }
 
     fn process_network_message(&mut self, msg: Message) {
         match msg {
-            Message::Chat(packet) => self.push_message(packet),
-            Message::HistoryResponse(history) => self.push_history_messages(history),
-            Message::Error(e) => {
-                self.ui.error_message = Some(format!("Server error: {e}"));
+            Message::Chat(packet) => {
+                self.chat.typing_users.remove(&packet.sender);
+                self.chat.outbox.confirm(packet.id);
+                self.push_message(packet);
+            }
+            Message::ChatAck { local_id, result } => match result {
+                // The broadcast may have beaten the ack here.
+                Ok(id) if self.chat.seen_ids.contains(&id) => self.chat.outbox.remove(local_id),
+                Ok(id) => self.chat.outbox.acknowledge(local_id, id),
+                Err(e) => {
+                    self.chat.outbox.reject(local_id);
+                    self.show_server_error(&e);
+                }
+            },
+            Message::HistoryResponse {
+                messages,
+                has_more,
+                sender,
+                direction,
+            } => {
+                self.ack_history(&messages);
+                self.on_history(messages, has_more, sender.is_some(), direction);
+            }
+            Message::EditMessage { id, content } => {
+                if let Some(packet) = self.chat.messages.iter_mut().find(|m| m.id == id) {
+                    packet.content = content;
+                }
+            }
+            Message::DeleteMessage { id } => {
+                if let Some(packet) = self.chat.messages.iter_mut().find(|m| m.id == id) {
+                    packet.content.clear();
+                    packet.deleted = true;
+                }
+            }
+            Message::RateStatusResponse {
+                messages_per_sec,
+                bytes_per_sec,
+                current_usage,
+            } => self.note_rate_usage(messages_per_sec, bytes_per_sec, current_usage),
+            Message::Announcement { content } => self.chat.announcement = Some(content),
+            Message::StatsResponse {
+                active_users,
+                messages_stored,
+                uptime_secs,
+                node_id,
+            } => {
+                self.ui.error_message = Some(format!(
+                    "{node_id}: {active_users} users • {messages_stored} messages • up {}h{:02}m",
+                    uptime_secs / 3600,
+                    uptime_secs % 3600 / 60
+                ));
+            }
+            Message::SearchResponse(results) => self.show_search_results(results),
+            Message::OnlineUsersResponse(users) => {
+                self.ui.error_message = Some(who_summary(&users, Utc::now().timestamp_millis()));
+                self.chat.online_users = users.into_iter().map(|u| u.username).collect();
+            }
+            Message::Pong { nonce, .. } => {
+                if let Some(rtt) = self
+                    .chat
+                    .latency
+                    .on_pong(nonce, Utc::now().timestamp_millis())
+                {
+                    self.chat.latency_ms = Some(rtt);
+                }
+            }
+            Message::UserJoined(username) => self.on_presence(username, true),
+            Message::UserLeft(username) => self.on_presence(username, false),
+            Message::Typing {
+                username,
+                is_typing,
+            } => {
+                if username == self.chat.username {
+                    return;
+                }
+                if is_typing {
+                    self.chat.typing_users.insert(username, Instant::now());
+                } else {
+                    self.chat.typing_users.remove(&username);
+                }
+            }
+            Message::Error(e @ ChatError::TooManyAttempts { .. }) => {
+                self.return_to_login(format!("Login locked: {e}"));
+            }
+            Message::Error(e @ (ChatError::Banned(_) | ChatError::ServerBusy)) => {
+                self.return_to_login(e.to_string());
+            }
+            Message::Error(e) => self.show_server_error(&e),
+            // The server hangs up right after, so the disconnect is already explained.
+            Message::Kicked { reason } => self.return_to_login(format!("Kicked: {reason}")),
+            Message::Hello {
+                guest_allowed,
+                max_message_len,
+            } => self.on_hello(guest_allowed, max_message_len),
+            Message::PasswordChanged => {
+                self.ui.error_message = Some("Password changed".to_string());
             }
             _ => {}
         }
     }
 
+    /// Takes in the server's login options and limits from its greeting.
+    const fn on_hello(&mut self, guest_allowed: bool, max_message_len: usize) {
+        self.login.guest_allowed = guest_allowed;
+        self.chat.max_message_len = max_message_len;
+    }
+
     fn handle_error(&mut self, err: &Error) {
+        if !matches!(err, Error::Disconnected if self.chat.network.is_none()) {
+            self.ui
+                .error_log
+                .push(Instant::now(), Level::Error, err.to_string());
+        }
         match err {
+            // A rejected login already explained itself before the server hung up.
+            Error::Disconnected if self.chat.network.is_none() => {}
             Error::Disconnected => {
+                self.chat.outbox.fail_pending();
                 self.ui.error_message = Some("Connection lost. Press Esc to quit".to_string());
                 self.chat.network = None;
                 self.global.screen = CurrentScreen::Login;
@@ -310,16 +878,1058 @@ impl App {
         }
     }
 
-    fn push_history_messages(&mut self, history: Vec<ChatPacket>) {
-        for packet in history.into_iter().rev() {
-            self.chat.messages.push_front(packet);
+    /// Shows a refused or failed request in the status line and the error log.
+    fn show_server_error(&mut self, e: &ChatError) {
+        self.ui
+            .error_log
+            .push(Instant::now(), Level::Warn, e.to_string());
+        self.ui.error_message = Some(format!("Server error: {e}"));
+    }
+
+    /// Drops the connection and shows `message` on the login screen.
+    fn return_to_login(&mut self, message: String) {
+        self.ui.error_message = Some(message);
+        self.chat.network = None;
+        self.global.screen = CurrentScreen::Login;
+    }
+
+    /// Warns once usage reaches 80% of either rate limit.
+    fn note_rate_usage(&mut self, messages_per_sec: u32, bytes_per_sec: u32, usage: RateUsage) {
+        let near_limit = u64::from(usage.messages) * 5 >= u64::from(messages_per_sec) * 4
+            || u64::from(usage.bytes) * 5 >= u64::from(bytes_per_sec) * 4;
+        if near_limit {
+            self.chat.rate_warning_until = Some(Instant::now() + RATE_WARNING_DURATION);
+        }
+    }
+
+    /// Tracks a user coming online or leaving and notes it in the timeline.
+    ///
+    /// The line has no id, so it never collides with the stored copy history
+    /// may bring later.
+    fn on_presence(&mut self, username: String, joined: bool) {
+        let content = if joined {
+            format!("→ {username} joined")
+        } else {
+            format!("← {username} left")
+        };
+        self.push_message(ChatPacket {
+            id: 0,
+            sender: PRESENCE_SENDER.to_string(),
+            content,
+            timestamp: Utc::now().timestamp_millis(),
+            deleted: false,
+        });
+        if joined {
+            self.chat.online_users.insert(username);
+        } else {
+            self.chat.online_users.remove(&username);
+            self.chat.typing_users.remove(&username);
+        }
+    }
+
+    /// Confirms a history batch arrived so the server won't resend it.
+    fn ack_history(&self, messages: &[ChatPacket]) {
+        if let Some((up_to_ts, up_to_id)) = messages.iter().map(|m| (m.timestamp, m.id)).max()
+            && let Some(network) = &self.chat.network
+        {
+            let _ = network.send(Message::HistoryAck { up_to_ts, up_to_id });
+        }
+    }
+
+    /// Handles a history page, which the server always sends oldest first.
+    fn on_history(
+        &mut self,
+        mut messages: Vec<ChatPacket>,
+        has_more: bool,
+        filtered: bool,
+        direction: HistoryDirection,
+    ) {
+        match direction {
+            // A `/from` page, shown newest first like search results rather
+            // than merged into the timeline.
+            _ if filtered => {
+                messages.reverse();
+                self.show_search_results(messages);
+            }
+            HistoryDirection::After => {
+                self.chat.pending_newer = None;
+                self.push_newer_history(messages, has_more);
+            }
+            HistoryDirection::Before => {
+                self.chat.reached_history_start = !has_more;
+                self.push_history_messages(messages);
+            }
+        }
+    }
+
+    /// Fills the open search view, ignoring results that arrive after it closed.
+    fn show_search_results(&mut self, results: Vec<ChatPacket>) {
+        if let Some(search) = &mut self.chat.search {
+            search.results = Some(results);
+        }
+    }
+
+    /// Merges a page of older history into the message list.
+    ///
+    /// Pages are usually older than everything shown, but a refetch after
+    /// dropped broadcasts can also fill gaps among recent messages. Past the
+    /// cap, a user scrolled up to read keeps the older page and the view lets
+    /// go of its newest messages, to be paged forward again later. Otherwise
+    /// the newest `max_messages` are kept.
+    fn push_history_messages(&mut self, mut history: Vec<ChatPacket>) {
+        // A detached view can't show messages newer than its last one without
+        // leaving a gap, so they wait with the held live messages.
+        if self.chat.detached
+            && let Some(newest) = self.chat.messages.back().map(|m| (m.timestamp, m.id))
+        {
+            let (newer, older) = history
+                .into_iter()
+                .partition(|m| (m.timestamp, m.id) > newest);
+            self.hold_live(newer);
+            history = older;
+        }
+
+        self.merge_messages(history);
+        if self.chat.messages.len() > self.chat.max_messages {
+            if self.chat.scroll_offset > 0 {
+                self.evict_newest();
+            } else {
+                self.evict_over_cap();
+            }
+        }
+    }
+
+    /// Appends a page of newer history to a detached view, rejoining the live
+    /// messages once the server has nothing newer.
+    ///
+    /// The viewport stays where it is, so the page is read by scrolling down.
+    fn push_newer_history(&mut self, page: Vec<ChatPacket>, has_more: bool) {
+        if !self.chat.detached {
+            return;
+        }
+        let before = self.chat.messages.len();
+        self.merge_messages(page);
+        if !has_more {
+            self.chat.detached = false;
+            // Live messages that arrived meanwhile overlap the page's tail;
+            // merging drops the ones already shown.
+            let held: Vec<ChatPacket> = self.chat.held_live.drain(..).collect();
+            self.merge_messages(held);
+        }
+
+        let added: u16 = self
+            .chat
+            .messages
+            .iter()
+            .skip(before)
+            .map(|packet| {
+                message_list::message_height(packet, &self.chat.username, self.chat.viewport_width)
+            })
+            .fold(0, u16::saturating_add);
+        self.chat.scroll_offset = self.chat.scroll_offset.saturating_add(added);
+        self.evict_over_cap();
+    }
+
+    /// Keeps live messages for a detached view until it catches up, up to the cap.
+    fn hold_live(&mut self, packets: impl IntoIterator<Item = ChatPacket>) {
+        for packet in packets {
+            if !self.chat.seen_ids.contains(&packet.id) || packet.id == 0 {
+                self.chat.unread_count += 1;
+                self.chat.held_live.push_back(packet);
+            }
+        }
+        while self.chat.held_live.len() > self.chat.max_messages {
+            self.chat.held_live.pop_front();
+        }
+    }
+
+    /// Inserts packets in order of timestamp, then id, skipping ones already shown.
+    fn merge_messages(&mut self, packets: Vec<ChatPacket>) {
+        for packet in packets.into_iter().rev() {
+            // A refetch after dropped broadcasts may be where our own echo turns up.
+            self.chat.outbox.confirm(packet.id);
+            if self.mark_seen(packet.id) {
+                let position = self
+                    .chat
+                    .messages
+                    .partition_point(|m| (m.timestamp, m.id) < (packet.timestamp, packet.id));
+                self.chat.messages.insert(position, packet);
+                if let Some(index) = &mut self.chat.selected_index
+                    && position <= *index
+                {
+                    *index += 1;
+                }
+            }
         }
     }
 
     fn push_message(&mut self, packet: ChatPacket) {
-        if self.chat.messages.len() >= MAX_MESSAGES {
-            self.chat.messages.pop_front();
+        if self.chat.detached {
+            self.hold_live([packet]);
+            return;
+        }
+        if !self.mark_seen(packet.id) {
+            return;
+        }
+
+        // Keep the viewport anchored while the user is reading older messages.
+        if self.chat.scroll_offset > 0 {
+            let height = message_list::message_height(
+                &packet,
+                &self.chat.username,
+                self.chat.viewport_width,
+            );
+            self.chat.scroll_offset = self.chat.scroll_offset.saturating_add(height);
+            self.chat.unread_count += 1;
         }
         self.chat.messages.push_back(packet);
+        self.evict_over_cap();
+    }
+
+    /// Drops the oldest messages until the view is back within its cap.
+    fn evict_over_cap(&mut self) {
+        while self.chat.messages.len() > self.chat.max_messages
+            && let Some(dropped) = self.chat.messages.pop_front()
+        {
+            self.chat.seen_ids.remove(&dropped.id);
+            self.chat.reached_history_start = false;
+            self.chat.selected_index = self.chat.selected_index.and_then(|i| i.checked_sub(1));
+        }
+    }
+
+    /// Drops the newest messages until the view is back within its cap,
+    /// detaching it from the live end.
+    fn evict_newest(&mut self) {
+        while self.chat.messages.len() > self.chat.max_messages
+            && let Some(dropped) = self.chat.messages.pop_back()
+        {
+            self.chat.seen_ids.remove(&dropped.id);
+            let height = message_list::message_height(
+                &dropped,
+                &self.chat.username,
+                self.chat.viewport_width,
+            );
+            self.chat.scroll_offset = self.chat.scroll_offset.saturating_sub(height);
+            self.chat.detached = true;
+        }
+        let len = self.chat.messages.len();
+        self.chat.selected_index = self.chat.selected_index.filter(|&i| i < len);
+    }
+
+    /// Empties the local view without touching server history.
+    fn clear_messages(&mut self) {
+        self.chat.messages.clear();
+        self.chat.seen_ids.clear();
+        self.chat.selected_index = None;
+        self.chat.scroll_offset = 0;
+        self.chat.unread_count = 0;
+        self.chat.reached_history_start = false;
+        self.chat.history_request_cursor = None;
+        self.chat.detached = false;
+        self.chat.held_live.clear();
+    }
+
+    /// Moves the selection one message older, starting from the newest.
+    fn select_previous(&mut self) {
+        let len = self.chat.messages.len();
+        self.chat.selected_index = match self.chat.selected_index {
+            _ if len == 0 => None,
+            None => Some(len - 1),
+            Some(i) => Some(i.saturating_sub(1)),
+        };
+    }
+
+    /// Moves the selection one message newer, stopping at the newest.
+    fn select_next(&mut self) {
+        let len = self.chat.messages.len();
+        self.chat.selected_index = match self.chat.selected_index {
+            _ if len == 0 => None,
+            None => None,
+            Some(i) => Some((i + 1).min(len - 1)),
+        };
+    }
+
+    fn copy_selected(&mut self) {
+        let index = self
+            .chat
+            .selected_index
+            .or_else(|| self.chat.messages.len().checked_sub(1));
+        let Some(packet) = index.and_then(|i| self.chat.messages.get(i)) else {
+            self.ui.error_message = Some("No message to copy".to_string());
+            return;
+        };
+
+        self.ui.error_message = Some(match self.chat.clipboard.copy(&packet.content) {
+            Ok(CopyTarget::Clipboard) => "Copied message to clipboard".to_string(),
+            Ok(CopyTarget::File(path)) => {
+                format!("No clipboard available, saved to {}", path.display())
+            }
+            Err(e) => format!("Copy failed: {e}"),
+        });
+    }
+
+    /// Jumps to the oldest loaded message and asks for the history before it.
+    fn scroll_to_top(&mut self) {
+        // The next render clamps this to the top of what's loaded.
+        self.chat.scroll_offset = u16::MAX;
+        self.chat.history_request_cursor = self
+            .chat
+            .messages
+            .front()
+            .map(|packet| (packet.timestamp, packet.id));
+        self.get_history();
+    }
+
+    /// Scrolls toward the newest messages, clearing the unread count at the bottom.
+    const fn scroll_down(&mut self, rows: u16) {
+        self.chat.scroll_offset = self.chat.scroll_offset.saturating_sub(rows);
+        if self.chat.scroll_offset == 0 {
+            self.chat.unread_count = 0;
+        }
+    }
+
+    /// Records a message id, returning `false` if it was already displayed.
+    ///
+    /// Unsaved packets carry id `0` and are never treated as duplicates.
+    fn mark_seen(&mut self, id: u64) -> bool {
+        id == 0 || self.chat.seen_ids.insert(id)
+    }
+}
+
+/// Lists who is online for `/who`, marking users who haven't chatted or typed
+/// for a while as idle.
+fn who_summary(users: &[OnlineUser], now_ms: i64) -> String {
+    let names: Vec<String> = users
+        .iter()
+        .map(|user| {
+            let silent_ms = now_ms - user.last_active;
+            if silent_ms < IDLE_AFTER_MS {
+                user.username.clone()
+            } else {
+                format!("{} (idle {}m)", user.username, silent_ms / 60_000)
+            }
+        })
+        .collect();
+    format!("Online: {}", names.join(", "))
+}
+
+/// Drops a single trailing `\n` or `\r\n` from `input`.
+fn trim_trailing_newline(input: &str) -> &str {
+    input
+        .strip_suffix('\n')
+        .map_or(input, |line| line.strip_suffix('\r').unwrap_or(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, App, CurrentScreen, DEFAULT_MAX_MESSAGES, RATE_CHECK_INTERVAL};
+    use crate::command::Command;
+    use crate::error::Error;
+    use crate::error_log::Level;
+    use crate::event::AppEvent;
+    use crate::network::NetworkClient;
+    use crate::outbox::DeliveryStatus;
+    use crate::typing::TYPING_EXPIRY;
+    use chrono::Utc;
+    use protocol::{ChatError, ChatPacket, HistoryDirection, Message, OnlineUser};
+    use std::time::Instant;
+    use tokio::sync::mpsc;
+
+    fn app() -> App {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        App::new(tx)
+    }
+
+    fn packet(id: u64, timestamp: i64) -> ChatPacket {
+        ChatPacket {
+            id,
+            sender: "alice".to_string(),
+            content: format!("message {id}"),
+            timestamp,
+            deleted: false,
+        }
+    }
+
+    fn history(messages: Vec<ChatPacket>) -> Message {
+        Message::HistoryResponse {
+            messages,
+            has_more: true,
+            sender: None,
+            direction: HistoryDirection::Before,
+        }
+    }
+
+    fn ids(app: &App) -> Vec<u64> {
+        app.chat.messages.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn overlapping_history_and_live_messages_are_deduplicated() {
+        let mut app = app();
+
+        app.process_network_message(Message::Chat(packet(4, 40)));
+        app.process_network_message(history(vec![packet(2, 20), packet(3, 30), packet(4, 40)]));
+        app.process_network_message(Message::Chat(packet(3, 30)));
+        app.process_network_message(Message::Chat(packet(5, 50)));
+        app.process_network_message(history(vec![packet(1, 10), packet(2, 20)]));
+
+        assert_eq!(ids(&app), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn refetched_history_fills_gaps_in_order() {
+        let mut app = app();
+
+        app.process_network_message(Message::Chat(packet(1, 10)));
+        app.process_network_message(Message::Chat(packet(4, 40)));
+        app.process_network_message(history(vec![packet(2, 20), packet(3, 30), packet(4, 40)]));
+
+        assert_eq!(ids(&app), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn unsaved_packets_are_never_deduplicated() {
+        let mut app = app();
+
+        app.process_network_message(Message::Chat(packet(0, 10)));
+        app.process_network_message(Message::Chat(packet(0, 10)));
+
+        assert_eq!(ids(&app), vec![0, 0]);
+    }
+
+    #[test]
+    fn messages_at_bottom_are_not_counted_as_unread() {
+        let mut app = app();
+
+        app.process_network_message(Message::Chat(packet(1, 10)));
+
+        assert_eq!(app.chat.unread_count, 0);
+        assert_eq!(app.chat.scroll_offset, 0);
+    }
+
+    #[test]
+    fn messages_while_scrolled_up_are_unread_and_keep_viewport() {
+        let mut app = app();
+        app.chat.viewport_width = 200;
+        app.chat.scroll_offset = 3;
+
+        app.process_network_message(Message::Chat(packet(1, 10)));
+        app.process_network_message(Message::Chat(packet(2, 20)));
+
+        assert_eq!(app.chat.unread_count, 2);
+        assert_eq!(app.chat.scroll_offset, 5);
+    }
+
+    #[test]
+    fn end_jumps_to_latest_and_clears_unread() {
+        let mut app = app();
+        app.global.screen = CurrentScreen::Chat;
+        app.chat.viewport_width = 200;
+        app.chat.scroll_offset = 7;
+        app.process_network_message(Message::Chat(packet(1, 10)));
+        assert_eq!(app.chat.unread_count, 1);
+
+        app.dispatch_action(&Action::ScrollToBottom);
+
+        assert_eq!(app.chat.scroll_offset, 0);
+        assert_eq!(app.chat.unread_count, 0);
+    }
+
+    #[test]
+    fn home_jumps_to_oldest_and_fetches_earlier_history() {
+        let mut app = app();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        app.chat.network = Some(NetworkClient::new(tx));
+        app.global.screen = CurrentScreen::Chat;
+        app.process_network_message(Message::Chat(packet(2, 20)));
+        app.process_network_message(Message::Chat(packet(3, 30)));
+
+        app.dispatch_action(&Action::ScrollToTop);
+
+        assert_eq!(app.chat.scroll_offset, u16::MAX);
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Message::HistoryRequest {
+                cursor_ts: 20,
+                cursor_id: 2,
+                sender: None,
+                direction: HistoryDirection::Before
+            })
+        ));
+
+        app.chat.reached_history_start = true;
+        app.dispatch_action(&Action::ScrollToTop);
+        assert!(rx.try_recv().is_err(), "nothing older to fetch");
+    }
+
+    #[test]
+    fn scrolling_to_bottom_resets_unread() {
+        let mut app = app();
+        app.chat.viewport_width = 200;
+        app.chat.scroll_offset = 1;
+        app.process_network_message(Message::Chat(packet(1, 10)));
+
+        app.scroll_down(1);
+        assert_eq!(app.chat.unread_count, 1);
+
+        app.scroll_down(1);
+        assert_eq!(app.chat.unread_count, 0);
+    }
+
+    #[test]
+    fn one_trailing_newline_is_dropped_from_submitted_messages() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut app = app();
+        app.global.screen = CurrentScreen::Chat;
+        app.chat.network = Some(crate::network::NetworkClient::new(tx));
+
+        for (input, sent) in [("hi\n", "hi"), ("hi\r\n", "hi"), ("a\nb\n\n", "a\nb\n")] {
+            app.handle_chat_submit(input.to_string());
+            assert!(matches!(
+                rx.try_recv(),
+                Ok(Message::Chat(packet)) if packet.content == sent
+            ));
+        }
+
+        // The pending copy shown until the server echoes it is trimmed too.
+        assert_eq!(app.chat.outbox.entries()[0].packet.content, "hi");
+
+        app.handle_chat_submit("\n".to_string());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn overlong_message_is_kept_in_the_input() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut app = app();
+        app.global.screen = CurrentScreen::Chat;
+        app.chat.network = Some(crate::network::NetworkClient::new(tx));
+
+        let too_long = "x".repeat(protocol::MAX_MESSAGE_LEN + 1);
+        app.handle_chat_submit(too_long.clone());
+        assert!(rx.try_recv().is_err());
+        assert_eq!(app.ui.input_buffer, too_long);
+        assert!(app.ui.error_message.is_some());
+
+        app.handle_chat_submit("x".repeat(protocol::MAX_MESSAGE_LEN));
+        assert!(matches!(rx.try_recv(), Ok(Message::Chat(_))));
+    }
+
+    #[test]
+    fn message_length_follows_the_servers_limit() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut app = app();
+        app.global.screen = CurrentScreen::Chat;
+        app.chat.network = Some(crate::network::NetworkClient::new(tx));
+        app.process_network_message(Message::Hello {
+            guest_allowed: false,
+            max_message_len: 10,
+        });
+
+        app.handle_chat_submit("x".repeat(11));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(app.ui.input_buffer, "x".repeat(11));
+
+        app.handle_chat_submit("x".repeat(10));
+        assert!(matches!(rx.try_recv(), Ok(Message::Chat(_))));
+    }
+
+    #[test]
+    fn message_length_is_checked_after_shortcodes_expand() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut app = app();
+        app.global.screen = CurrentScreen::Chat;
+        app.chat.network = Some(crate::network::NetworkClient::new(tx));
+        app.chat.max_message_len = 10;
+        app.chat
+            .emoji
+            .insert("shrug".to_string(), "¯\\_(ツ)_/¯".to_string());
+
+        app.handle_chat_submit("ok :shrug:".to_string());
+        assert!(rx.try_recv().is_err());
+        assert_eq!(app.ui.input_buffer, "ok :shrug:");
+
+        app.handle_chat_submit(":smile::smile:".to_string());
+        assert!(matches!(rx.try_recv(), Ok(Message::Chat(p)) if p.content == "😄😄"));
+    }
+
+    #[test]
+    fn search_results_show_until_escape() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut app = app();
+        app.global.screen = CurrentScreen::Chat;
+        app.chat.network = Some(crate::network::NetworkClient::new(tx));
+
+        app.handle_chat_submit("/search message".to_string());
+        assert!(app.chat.search.as_ref().unwrap().results.is_none());
+
+        app.process_network_message(Message::SearchResponse(vec![packet(2, 20), packet(1, 10)]));
+        let search = app.chat.search.as_ref().unwrap();
+        assert_eq!(search.query, "message");
+        assert_eq!(search.results.as_ref().unwrap().len(), 2);
+
+        app.dispatch_action(&Action::Quit);
+        assert!(app.chat.search.is_none());
+        assert!(!app.global.should_quit);
+    }
+
+    #[test]
+    fn from_shows_sender_history_apart_from_timeline() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut app = app();
+        app.global.screen = CurrentScreen::Chat;
+        app.chat.network = Some(crate::network::NetworkClient::new(tx));
+
+        app.handle_chat_submit("/from alice".to_string());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Message::HistoryRequest { cursor_ts: i64::MAX, cursor_id: u64::MAX, sender: Some(s), direction: HistoryDirection::Before }) if s == "alice"
+        ));
+
+        app.process_network_message(Message::HistoryResponse {
+            messages: vec![packet(1, 10), packet(2, 20)],
+            has_more: false,
+            sender: Some("alice".to_string()),
+            direction: HistoryDirection::Before,
+        });
+        let search = app.chat.search.as_ref().unwrap();
+        assert_eq!(search.query, "from:alice");
+        let ids: Vec<u64> = search
+            .results
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, vec![2, 1]);
+        assert!(app.chat.messages.is_empty());
+        assert!(!app.chat.reached_history_start);
+    }
+
+    #[test]
+    fn selection_starts_at_newest_and_stays_in_bounds() {
+        let mut app = app();
+        app.select_previous();
+        assert_eq!(app.chat.selected_index, None);
+
+        for id in 1..=3 {
+            app.process_network_message(Message::Chat(packet(id, 10)));
+        }
+
+        app.select_previous();
+        assert_eq!(app.chat.selected_index, Some(2));
+        for _ in 0..5 {
+            app.select_previous();
+        }
+        assert_eq!(app.chat.selected_index, Some(0));
+
+        for _ in 0..5 {
+            app.select_next();
+        }
+        assert_eq!(app.chat.selected_index, Some(2));
+    }
+
+    #[test]
+    fn selection_follows_message_when_history_is_prepended() {
+        let mut app = app();
+        app.process_network_message(Message::Chat(packet(3, 30)));
+        app.select_previous();
+
+        app.process_network_message(history(vec![packet(1, 10), packet(2, 20)]));
+
+        assert_eq!(app.chat.selected_index, Some(2));
+        assert_eq!(app.chat.messages[2].id, 3);
+    }
+
+    #[test]
+    fn history_stops_at_start_until_messages_are_evicted() {
+        let mut app = app();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        app.chat.network = Some(NetworkClient::new(tx));
+        let mut request_history = |app: &mut App| {
+            app.chat.history_request_cursor = Some((10, 1));
+            app.get_history();
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .find(|msg| !matches!(msg, Message::HistoryAck { .. }))
+        };
+
+        app.process_network_message(Message::HistoryResponse {
+            messages: vec![packet(1, 10)],
+            has_more: false,
+            sender: None,
+            direction: HistoryDirection::Before,
+        });
+        assert!(app.chat.reached_history_start);
+        assert!(request_history(&mut app).is_none());
+
+        for id in 2..=(DEFAULT_MAX_MESSAGES as u64 + 1) {
+            app.process_network_message(Message::Chat(packet(id, 20)));
+        }
+        assert!(!app.chat.reached_history_start);
+        // Scrolled up to the top of the now full view.
+        app.chat.scroll_offset = 1;
+        assert!(matches!(
+            request_history(&mut app),
+            Some(Message::HistoryRequest {
+                cursor_ts: 10,
+                cursor_id: 1,
+                sender: None,
+                direction: HistoryDirection::Before
+            })
+        ));
+    }
+
+    #[test]
+    fn paging_back_past_the_cap_detaches_until_paged_forward_again() {
+        let mut app = app();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        app.chat.network = Some(NetworkClient::new(tx));
+        app.global.screen = CurrentScreen::Chat;
+        app.chat.max_messages = 3;
+        app.process_network_message(history(vec![packet(3, 30), packet(4, 40)]));
+
+        app.chat.scroll_offset = 5;
+        app.process_network_message(history(vec![packet(1, 10), packet(2, 20)]));
+        assert_eq!(ids(&app), vec![1, 2, 3]);
+        assert!(app.chat.detached);
+
+        app.process_network_message(Message::Chat(packet(5, 50)));
+        assert_eq!(
+            ids(&app),
+            vec![1, 2, 3],
+            "live messages wait while detached"
+        );
+        while rx.try_recv().is_ok() {}
+
+        app.dispatch_action(&Action::ScrollToBottom);
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Message::HistoryRequest {
+                cursor_ts: 30,
+                cursor_id: 3,
+                sender: None,
+                direction: HistoryDirection::After
+            })
+        ));
+        app.dispatch_action(&Action::ScrollDown);
+        assert!(rx.try_recv().is_err(), "one forward page at a time");
+
+        app.process_network_message(Message::HistoryResponse {
+            messages: vec![packet(4, 40), packet(5, 50)],
+            has_more: false,
+            sender: None,
+            direction: HistoryDirection::After,
+        });
+        assert!(!app.chat.detached);
+        assert_eq!(ids(&app), vec![3, 4, 5]);
+
+        app.process_network_message(Message::Chat(packet(6, 60)));
+        assert_eq!(ids(&app), vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn own_message_leaves_outbox_whichever_of_ack_and_echo_comes_first() {
+        let mut app = app();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        app.chat.network = Some(NetworkClient::new(tx));
+        app.chat.username = "alice".to_string();
+
+        app.send_chat("first");
+        app.send_chat("second");
+        assert_eq!(app.chat.outbox.entries().len(), 2);
+
+        app.process_network_message(Message::ChatAck {
+            local_id: 1,
+            result: Ok(10),
+        });
+        app.process_network_message(Message::Chat(packet(10, 10)));
+        app.process_network_message(Message::Chat(packet(11, 11)));
+        app.process_network_message(Message::ChatAck {
+            local_id: 2,
+            result: Ok(11),
+        });
+
+        assert!(app.chat.outbox.entries().is_empty());
+        assert_eq!(ids(&app), vec![10, 11]);
+    }
+
+    #[test]
+    fn rejected_messages_are_marked_apart_from_unsent_ones() {
+        let mut app = app();
+        let (tx, rx) = mpsc::unbounded_channel();
+        app.chat.network = Some(NetworkClient::new(tx));
+
+        app.send_chat("spam");
+        app.process_network_message(Message::ChatAck {
+            local_id: 1,
+            result: Err(ChatError::RateLimited),
+        });
+        drop(rx);
+        app.send_chat("lost");
+
+        let statuses: Vec<DeliveryStatus> =
+            app.chat.outbox.entries().iter().map(|e| e.status).collect();
+        assert_eq!(
+            statuses,
+            vec![DeliveryStatus::Rejected, DeliveryStatus::Failed]
+        );
+    }
+
+    #[test]
+    fn login_as_another_user_drops_the_unsent_messages() {
+        let mut app = app();
+        app.chat.username = "alice".to_string();
+        app.send_chat("from alice");
+        assert_eq!(app.chat.outbox.entries().len(), 1);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        app.on_login(tx, "bob".to_string());
+
+        assert!(app.chat.outbox.entries().is_empty());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn history_beyond_cap_trims_oldest() {
+        let mut app = app();
+        app.chat.max_messages = 3;
+        app.process_network_message(history(vec![packet(3, 30), packet(4, 40)]));
+
+        app.process_network_message(history(vec![packet(1, 10), packet(2, 20)]));
+
+        assert_eq!(ids(&app), vec![2, 3, 4]);
+        assert!(!app.chat.seen_ids.contains(&1));
+    }
+
+    #[test]
+    fn clear_empties_local_messages() {
+        let mut app = app();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        app.chat.network = Some(NetworkClient::new(tx));
+        app.process_network_message(history(vec![packet(1, 10), packet(2, 20)]));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Message::HistoryAck {
+                up_to_ts: 20,
+                up_to_id: 2
+            })
+        ));
+        app.chat.scroll_offset = 4;
+
+        app.handle_command(Command::Clear);
+
+        assert!(app.chat.messages.is_empty());
+        assert_eq!(app.chat.scroll_offset, 0);
+        assert!(
+            rx.try_recv().is_err(),
+            "clearing shouldn't contact the server"
+        );
+        app.process_network_message(Message::Chat(packet(1, 10)));
+        assert_eq!(ids(&app), vec![1]);
+    }
+
+    #[test]
+    fn interleaved_sends_and_history_stay_within_cap() {
+        let mut app = app();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        app.chat.network = Some(NetworkClient::new(tx));
+        app.chat.max_messages = 10;
+
+        for round in 0..5_u64 {
+            let live = 1000 + round * 4;
+            for id in live..live + 4 {
+                app.process_network_message(Message::Chat(packet(id, id.cast_signed())));
+                assert!(app.chat.messages.len() <= 10);
+            }
+            let older = (round * 6..round * 6 + 6)
+                .map(|id| packet(id + 1, id.cast_signed()))
+                .collect();
+            app.process_network_message(history(older));
+            assert!(app.chat.messages.len() <= 10);
+        }
+
+        // The newest messages are the ones kept.
+        assert_eq!(app.chat.messages.back().map(|m| m.id), Some(1019));
+        assert_eq!(app.chat.messages.len(), 10);
+        while let Ok(msg) = rx.try_recv() {
+            assert!(matches!(msg, Message::HistoryAck { .. }));
+        }
+        app.chat.history_request_cursor = Some((1, 1));
+        app.get_history();
+        assert!(
+            rx.try_recv().is_err(),
+            "a full view shouldn't request more history"
+        );
+    }
+
+    #[test]
+    fn presence_events_update_online_users() {
+        let mut app = app();
+        app.chat.username = "me".to_string();
+
+        app.process_network_message(Message::UserJoined("alice".to_string()));
+        app.process_network_message(Message::UserJoined("bob".to_string()));
+        app.process_network_message(Message::UserLeft("alice".to_string()));
+
+        assert_eq!(
+            app.chat.online_users.iter().collect::<Vec<_>>(),
+            vec!["bob"]
+        );
+        let lines: Vec<_> = app
+            .chat
+            .messages
+            .iter()
+            .map(|m| (m.sender.as_str(), m.content.as_str()))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (super::PRESENCE_SENDER, "→ alice joined"),
+                (super::PRESENCE_SENDER, "→ bob joined"),
+                (super::PRESENCE_SENDER, "← alice left"),
+            ]
+        );
+    }
+
+    #[test]
+    fn who_lists_everyone_online_and_marks_idle_users() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut app = app();
+        app.chat.network = Some(NetworkClient::new(tx));
+
+        app.handle_command(Command::Who);
+        assert!(matches!(rx.try_recv(), Ok(Message::OnlineUsersRequest)));
+
+        let now = Utc::now().timestamp_millis();
+        app.process_network_message(Message::OnlineUsersResponse(vec![
+            OnlineUser {
+                username: "alice".to_string(),
+                last_active: now - 10_000,
+            },
+            // Still connected, but quiet for five and a half minutes.
+            OnlineUser {
+                username: "bob".to_string(),
+                last_active: now - 330_000,
+            },
+        ]));
+
+        assert_eq!(
+            app.ui.error_message.as_deref(),
+            Some("Online: alice, bob (idle 5m)")
+        );
+        assert_eq!(
+            app.chat.online_users.iter().collect::<Vec<_>>(),
+            vec!["alice", "bob"]
+        );
+    }
+
+    #[test]
+    fn kicked_returns_to_login_with_reason() {
+        let mut app = app();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        app.chat.network = Some(NetworkClient::new(tx));
+        app.global.screen = CurrentScreen::Chat;
+
+        app.process_network_message(Message::Kicked {
+            reason: "spamming".to_string(),
+        });
+        app.handle_error(&Error::Disconnected);
+
+        assert!(app.chat.network.is_none());
+        assert_eq!(app.global.screen, CurrentScreen::Login);
+        assert_eq!(app.ui.error_message.as_deref(), Some("Kicked: spamming"));
+    }
+
+    #[test]
+    fn own_message_is_shown_exactly_once() {
+        let mut app = app();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        app.chat.network = Some(NetworkClient::new(tx));
+        app.chat.username = "alice".to_string();
+        let shown = |app: &App| {
+            let outgoing = app.chat.outbox.entries().len();
+            outgoing + app.chat.messages.iter().filter(|m| m.id == 10).count()
+        };
+
+        app.send_chat("hi");
+        assert_eq!(shown(&app), 1);
+        app.process_network_message(Message::ChatAck {
+            local_id: 1,
+            result: Ok(10),
+        });
+        assert_eq!(shown(&app), 1);
+        // The echo was dropped while lagging, so it only comes back in history.
+        app.process_network_message(history(vec![packet(9, 9), packet(10, 10)]));
+        assert_eq!(shown(&app), 1);
+        app.process_network_message(Message::Chat(packet(10, 10)));
+        assert_eq!(shown(&app), 1);
+        assert!(app.chat.outbox.entries().is_empty());
+    }
+
+    #[test]
+    fn errors_accumulate_in_the_log() {
+        let mut app = app();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        app.chat.network = Some(NetworkClient::new(tx));
+
+        app.process_network_message(Message::Error(ChatError::RateLimited));
+        app.handle_error(&Error::Disconnected);
+        // Already disconnected, so this one isn't news.
+        app.handle_error(&Error::Disconnected);
+
+        let levels: Vec<Level> = app.ui.error_log.entries().iter().map(|e| e.1).collect();
+        assert_eq!(levels, vec![Level::Warn, Level::Error]);
+        assert_eq!(
+            app.ui.error_message.as_deref(),
+            Some("Connection lost. Press Esc to quit")
+        );
+    }
+
+    #[test]
+    fn only_ticks_that_change_the_screen_redraw() {
+        let mut app = app();
+        assert!(!app.handle_event(AppEvent::Tick));
+        assert!(!app.handle_event(AppEvent::Tick));
+
+        let now = Instant::now();
+        app.chat
+            .typing_users
+            .insert("bob".to_string(), now.checked_sub(TYPING_EXPIRY).unwrap());
+        assert!(app.on_tick(now), "expired typing indicator");
+        assert!(!app.on_tick(now));
+
+        assert!(app.handle_event(AppEvent::Resize));
+    }
+
+    #[test]
+    fn rate_usage_is_requested_at_most_once_per_interval() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut app = app();
+        app.global.screen = CurrentScreen::Chat;
+        app.chat.network = Some(NetworkClient::new(tx));
+        let mut rate_checks = || {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .filter(|msg| matches!(msg, Message::RateStatusRequest))
+                .count()
+        };
+
+        app.on_tick(Instant::now());
+        assert_eq!(rate_checks(), 0, "nothing sent yet");
+
+        for _ in 0..3 {
+            app.handle_chat_submit("hi".to_string());
+        }
+        assert_eq!(rate_checks(), 0);
+        let now = Instant::now();
+        app.on_tick(now);
+        assert_eq!(rate_checks(), 1);
+
+        app.handle_chat_submit("hi".to_string());
+        app.on_tick(now + RATE_CHECK_INTERVAL / 2);
+        assert_eq!(rate_checks(), 0);
+        app.on_tick(now + RATE_CHECK_INTERVAL);
+        assert_eq!(rate_checks(), 1);
+        app.on_tick(now + RATE_CHECK_INTERVAL * 3);
+        assert_eq!(rate_checks(), 0);
     }
 }
@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use crate::error::Result;
+
+/// File used when no system clipboard is available.
+const FALLBACK_FILE: &str = "mcs-clipboard.txt";
+
+/// Where copied text ended up.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CopyTarget {
+    Clipboard,
+    File(PathBuf),
+}
+
+/// Copies text to the system clipboard, falling back to a temp file.
+///
+/// The clipboard handle is kept alive because on X11 the owning process must
+/// keep serving the contents after a copy.
+#[derive(Default)]
+pub struct ClipboardSink {
+    clipboard: Option<arboard::Clipboard>,
+}
+
+impl ClipboardSink {
+    pub fn copy(&mut self, text: &str) -> Result<CopyTarget> {
+        if self.clipboard.is_none() {
+            self.clipboard = arboard::Clipboard::new().ok();
+        }
+
+        if let Some(clipboard) = &mut self.clipboard
+            && clipboard.set_text(text).is_ok()
+        {
+            return Ok(CopyTarget::Clipboard);
+        }
+
+        let path = std::env::temp_dir().join(FALLBACK_FILE);
+        std::fs::write(&path, text)?;
+        Ok(CopyTarget::File(path))
+    }
+}
@@ -0,0 +1,373 @@
+use crate::export::ExportFormat;
+
+/// Content prefix used to mark a chat message as an emote.
+pub const EMOTE_PREFIX: &str = "/me ";
+
+/// Usage text shown by `/help`.
+pub const HELP_TEXT: &str = "Commands: /quit • /me <action> • /dm <user> <message> • /edit <message> • /delete • /announce <message> • /search <term> • /from <user> • /export <path> [text|json] • /kick <user> [reason] • /ban <user> [reason] • /stats • /who • /passwd <old> <new> • /clear • /help • Home/End oldest/latest • Shift+↑/↓ select • Ctrl+Y copy • Ctrl+E errors";
+
+/// A slash command entered in the chat input.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Close the app.
+    Quit,
+    /// Send an emote-styled chat message.
+    Me(String),
+    /// Send a direct message to a single user.
+    Dm { user: String, message: String },
+    /// Replace the content of the user's last message.
+    Edit(String),
+    /// Delete the user's last message.
+    Delete,
+    /// Send an admin announcement to every user.
+    Announce(String),
+    /// Search past messages for a term.
+    Search(String),
+    /// Show recent messages from a single user.
+    From(String),
+    /// Save the transcript to a file.
+    Export { path: String, format: ExportFormat },
+    /// Disconnect a user (admins only), optionally banning them too.
+    Kick {
+        user: String,
+        reason: String,
+        ban: bool,
+    },
+    /// Ask the server for node statistics (admins only).
+    Stats,
+    /// List users seen online since joining.
+    Who,
+    /// Change the account password.
+    Passwd { old: String, new: String },
+    /// Empty the local message view; server history is untouched.
+    Clear,
+    /// Show command usage.
+    Help,
+    /// A known command with missing or malformed arguments.
+    Usage(&'static str),
+    /// A command that isn't recognized.
+    Unknown(String),
+}
+
+/// Parses a line of chat input starting with `/` into a `Command`.
+///
+/// Command names are case-insensitive. Arguments keep their inner whitespace,
+/// and the `/dm` recipient may be wrapped in double quotes.
+pub fn parse_command(input: &str) -> Command {
+    let input = input.trim();
+    let body = input.strip_prefix('/').unwrap_or(input);
+    let (name, args) = body
+        .split_once(char::is_whitespace)
+        .map_or((body, ""), |(name, args)| (name, args.trim()));
+
+    match name.to_lowercase().as_str() {
+        "quit" | "q" => Command::Quit,
+        "help" | "?" => Command::Help,
+        "me" if args.is_empty() => Command::Usage("/me <action>"),
+        "me" => Command::Me(args.to_string()),
+        "edit" if args.is_empty() => Command::Usage("/edit <message>"),
+        "edit" => Command::Edit(args.to_string()),
+        "delete" | "del" => Command::Delete,
+        "announce" if args.is_empty() => Command::Usage("/announce <message>"),
+        "announce" => Command::Announce(args.to_string()),
+        "search" if args.is_empty() => Command::Usage("/search <term>"),
+        "search" => Command::Search(args.to_string()),
+        "from" if args.is_empty() => Command::Usage("/from <user>"),
+        "from" => Command::From(args.to_string()),
+        "export" => parse_export(args),
+        "kick" => parse_kick(args, false).unwrap_or(Command::Usage("/kick <user> [reason]")),
+        "ban" => parse_kick(args, true).unwrap_or(Command::Usage("/ban <user> [reason]")),
+        "stats" => Command::Stats,
+        "who" => Command::Who,
+        "passwd" => parse_passwd(args).unwrap_or(Command::Usage("/passwd <old> <new>")),
+        "clear" => Command::Clear,
+        "dm" => split_recipient(args)
+            .map_or(Command::Usage("/dm <user> <message>"), |(user, message)| {
+                Command::Dm { user, message }
+            }),
+        _ => Command::Unknown(format!("/{name}")),
+    }
+}
+
+/// Parses `/export` arguments, inferring the format from the extension unless one is given.
+fn parse_export(args: &str) -> Command {
+    let (path, format) = match args.rsplit_once(char::is_whitespace) {
+        Some((path, "json")) => (path.trim(), Some(ExportFormat::Json)),
+        Some((path, "text" | "txt")) => (path.trim(), Some(ExportFormat::Text)),
+        _ => (args, None),
+    };
+
+    if path.is_empty() {
+        return Command::Usage("/export <path> [text|json]");
+    }
+
+    Command::Export {
+        path: path.to_string(),
+        format: format.unwrap_or_else(|| ExportFormat::from_path(path)),
+    }
+}
+
+/// Parses `/kick` and `/ban` arguments; the reason is optional.
+fn parse_kick(args: &str, ban: bool) -> Option<Command> {
+    let (user, reason) = args
+        .split_once(char::is_whitespace)
+        .map_or((args, ""), |(user, reason)| (user, reason.trim()));
+    if user.is_empty() {
+        return None;
+    }
+
+    Some(Command::Kick {
+        user: user.to_string(),
+        reason: reason.to_string(),
+        ban,
+    })
+}
+
+/// Parses `/passwd` arguments; neither password may contain whitespace.
+fn parse_passwd(args: &str) -> Option<Command> {
+    let mut parts = args.split_whitespace();
+    let (old, new) = (parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(Command::Passwd {
+        old: old.to_string(),
+        new: new.to_string(),
+    })
+}
+
+/// Splits `/dm` arguments into a recipient and a non-empty message.
+fn split_recipient(args: &str) -> Option<(String, String)> {
+    let (user, rest) = if let Some(quoted) = args.strip_prefix('"') {
+        let (user, rest) = quoted.split_once('"')?;
+        (user.trim(), rest)
+    } else {
+        args.split_once(char::is_whitespace)?
+    };
+
+    let message = rest.trim();
+    if user.is_empty() || message.is_empty() {
+        return None;
+    }
+
+    Some((user.to_string(), message.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, parse_command};
+    use crate::export::ExportFormat;
+
+    #[test]
+    fn parse_quit_succeeds() {
+        assert_eq!(parse_command("/quit"), Command::Quit);
+        assert_eq!(parse_command("  /QUIT  "), Command::Quit);
+        assert_eq!(parse_command("/q"), Command::Quit);
+    }
+
+    #[test]
+    fn parse_help_succeeds() {
+        assert_eq!(parse_command("/help"), Command::Help);
+        assert_eq!(parse_command("/help me please"), Command::Help);
+    }
+
+    #[test]
+    fn parse_me_keeps_inner_whitespace() {
+        assert_eq!(
+            parse_command("/me   waves   hello "),
+            Command::Me("waves   hello".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_me_without_action_is_usage() {
+        assert_eq!(parse_command("/me"), Command::Usage("/me <action>"));
+        assert_eq!(parse_command("/me    "), Command::Usage("/me <action>"));
+    }
+
+    #[test]
+    fn parse_dm_succeeds() {
+        assert_eq!(
+            parse_command("/dm alice hi there"),
+            Command::Dm {
+                user: "alice".to_string(),
+                message: "hi there".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_dm_with_tab_separator_succeeds() {
+        assert_eq!(
+            parse_command("/dm\talice\thi"),
+            Command::Dm {
+                user: "alice".to_string(),
+                message: "hi".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_dm_with_quoted_user_succeeds() {
+        assert_eq!(
+            parse_command("/dm \"alice smith\"   hi  there"),
+            Command::Dm {
+                user: "alice smith".to_string(),
+                message: "hi  there".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_dm_with_missing_parts_is_usage() {
+        let usage = Command::Usage("/dm <user> <message>");
+        assert_eq!(parse_command("/dm"), usage);
+        assert_eq!(parse_command("/dm alice"), usage);
+        assert_eq!(parse_command("/dm alice    "), usage);
+        assert_eq!(parse_command("/dm \"alice hi"), usage);
+        assert_eq!(parse_command("/dm \"\" hi"), usage);
+        assert_eq!(parse_command("/dm \"alice\""), usage);
+    }
+
+    #[test]
+    fn parse_unknown_command() {
+        assert_eq!(
+            parse_command("/dance now"),
+            Command::Unknown("/dance".to_string())
+        );
+        assert_eq!(parse_command("/"), Command::Unknown("/".to_string()));
+    }
+
+    #[test]
+    fn parse_edit_and_delete_succeeds() {
+        assert_eq!(
+            parse_command("/edit  fixed typo "),
+            Command::Edit("fixed typo".to_string())
+        );
+        assert_eq!(parse_command("/edit"), Command::Usage("/edit <message>"));
+        assert_eq!(parse_command("/delete"), Command::Delete);
+        assert_eq!(parse_command("/DEL"), Command::Delete);
+    }
+
+    #[test]
+    fn parse_announce_succeeds() {
+        assert_eq!(
+            parse_command("/announce  server restart at 5 "),
+            Command::Announce("server restart at 5".to_string())
+        );
+        assert_eq!(
+            parse_command("/announce"),
+            Command::Usage("/announce <message>")
+        );
+    }
+
+    #[test]
+    fn parse_search_succeeds() {
+        assert_eq!(
+            parse_command("/search  50% off "),
+            Command::Search("50% off".to_string())
+        );
+        assert_eq!(parse_command("/search"), Command::Usage("/search <term>"));
+    }
+
+    #[test]
+    fn parse_from_succeeds() {
+        assert_eq!(
+            parse_command("/from  alice "),
+            Command::From("alice".to_string())
+        );
+        assert_eq!(parse_command("/from"), Command::Usage("/from <user>"));
+    }
+
+    #[test]
+    fn parse_stats_succeeds() {
+        assert_eq!(parse_command("/stats"), Command::Stats);
+        assert_eq!(parse_command("/STATS extra"), Command::Stats);
+    }
+
+    #[test]
+    fn parse_who_succeeds() {
+        assert_eq!(parse_command("/who"), Command::Who);
+    }
+
+    #[test]
+    fn parse_passwd_needs_exactly_two_arguments() {
+        assert_eq!(
+            parse_command("/passwd  hunter22   correct-horse "),
+            Command::Passwd {
+                old: "hunter22".to_string(),
+                new: "correct-horse".to_string(),
+            }
+        );
+        for input in ["/passwd", "/passwd old", "/passwd old new extra"] {
+            assert_eq!(
+                parse_command(input),
+                Command::Usage("/passwd <old> <new>"),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_clear_succeeds() {
+        assert_eq!(parse_command("/clear"), Command::Clear);
+    }
+
+    #[test]
+    fn parse_export_succeeds() {
+        assert_eq!(
+            parse_command("/export chat.json"),
+            Command::Export {
+                path: "chat.json".to_string(),
+                format: ExportFormat::Json,
+            }
+        );
+        assert_eq!(
+            parse_command("/export my chat.log json"),
+            Command::Export {
+                path: "my chat.log".to_string(),
+                format: ExportFormat::Json,
+            }
+        );
+        assert_eq!(
+            parse_command("/export chat.txt"),
+            Command::Export {
+                path: "chat.txt".to_string(),
+                format: ExportFormat::Text,
+            }
+        );
+        assert_eq!(
+            parse_command("/export"),
+            Command::Usage("/export <path> [text|json]")
+        );
+    }
+
+    #[test]
+    fn parse_kick_and_ban_succeeds() {
+        assert_eq!(
+            parse_command("/kick alice stop  spamming"),
+            Command::Kick {
+                user: "alice".to_string(),
+                reason: "stop  spamming".to_string(),
+                ban: false,
+            }
+        );
+        assert_eq!(
+            parse_command("/ban bob"),
+            Command::Kick {
+                user: "bob".to_string(),
+                reason: String::new(),
+                ban: true,
+            }
+        );
+        assert_eq!(
+            parse_command("/kick"),
+            Command::Usage("/kick <user> [reason]")
+        );
+        assert_eq!(
+            parse_command("/ban "),
+            Command::Usage("/ban <user> [reason]")
+        );
+    }
+}
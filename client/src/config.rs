@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// Environment variable overriding the CA certificate used to verify the server.
+pub const CA_CERT_ENV: &str = "MCS_CA_CERT";
+
+/// CA certificate used when neither the environment nor the config names one.
+const DEFAULT_CA_CERT: &str = "tls/ca.cert";
+
+/// Login details remembered between launches. The password is never stored.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    pub server: String,
+    pub username: String,
+    /// Path to a PEM CA certificate; unset trusts the system roots.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<PathBuf>,
+    /// Messages kept in the chat view; unset uses the built-in default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scrollback: Option<usize>,
+    /// Extra `:name:` shortcodes, keyed by name without colons.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub emoji: HashMap<String, String>,
+}
+
+/// Returns `~/.config/mcs/config.toml`, honouring `XDG_CONFIG_HOME`.
+fn config_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(base.join("mcs").join("config.toml"))
+}
+
+/// Loads the saved config, falling back to empty defaults if it's missing or unreadable.
+pub fn load() -> ClientConfig {
+    config_path()
+        .map(|path| load_from(&path))
+        .unwrap_or_default()
+}
+
+/// Picks the CA certificate to trust: `MCS_CA_CERT`, then the config file, then
+/// `tls/ca.cert` if present. `None` means the system root store is used.
+pub fn ca_cert(config: &ClientConfig) -> Option<PathBuf> {
+    std::env::var_os(CA_CERT_ENV)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| config.ca_cert.clone())
+        .or_else(|| {
+            let default = PathBuf::from(DEFAULT_CA_CERT);
+            default.exists().then_some(default)
+        })
+}
+
+pub fn save(config: &ClientConfig) -> Result<()> {
+    let path = config_path()
+        .ok_or_else(|| Error::Config("no home directory to save config in".to_string()))?;
+    save_to(config, &path)
+}
+
+fn load_from(path: &Path) -> ClientConfig {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn save_to(config: &ClientConfig, path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let contents = toml::to_string(config).map_err(|e| Error::Config(e.to_string()))?;
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientConfig, load_from, save_to};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    /// A scratch directory removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("mcs-config-{name}-{}", std::process::id())))
+        }
+
+        /// Where the config file goes; the directory is left for `save_to` to create.
+        fn config_path(&self) -> PathBuf {
+            self.0.join("config.toml")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn config_round_trips() {
+        let dir = TempDir::new("round-trip");
+        let path = dir.config_path();
+        let config = ClientConfig {
+            server: "chat.example.com".to_string(),
+            username: "alice".to_string(),
+            ca_cert: Some(PathBuf::from("/etc/mcs/ca.cert")),
+            scrollback: Some(200),
+            emoji: HashMap::from([("shrug".to_string(), "¯\\_(ツ)_/¯".to_string())]),
+        };
+
+        save_to(&config, &path).unwrap();
+
+        assert_eq!(load_from(&path), config);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("password"));
+    }
+
+    #[test]
+    fn missing_or_corrupt_config_falls_back_to_defaults() {
+        let dir = TempDir::new("corrupt");
+        let path = dir.config_path();
+        assert_eq!(load_from(&path), ClientConfig::default());
+
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "server = [not toml").unwrap();
+        assert_eq!(load_from(&path), ClientConfig::default());
+    }
+
+    #[test]
+    fn partial_config_keeps_known_fields() {
+        let dir = TempDir::new("partial");
+        let path = dir.config_path();
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "username = \"bob\"\n").unwrap();
+
+        let config = load_from(&path);
+        assert_eq!(config.username, "bob");
+        assert!(config.server.is_empty());
+        assert!(config.ca_cert.is_none());
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+/// Shortcodes every client knows, without their colons.
+const BUILTIN: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("clap", "👏"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("ok_hand", "👌"),
+    ("pray", "🙏"),
+    ("rocket", "🚀"),
+    ("smile", "😄"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("wave", "👋"),
+    ("wink", "😉"),
+];
+
+/// Replaces `:name:` shortcodes in `text` with their emoji, checking `custom`
+/// before the built-in ones. Unknown shortcodes are left as typed.
+///
+/// A colon that doesn't close a known shortcode may still open the next
+/// one, so `:a::b:` and `re:smile:` both expand.
+pub fn expand_shortcodes(text: &str, custom: &HashMap<String, String>) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after
+            .find(':')
+            .map(|end| &after[..end])
+            .filter(|name| is_shortcode_name(name))
+            .and_then(|name| lookup(name, custom).map(|emoji| (name, emoji)));
+
+        if let Some((name, emoji)) = emoji {
+            expanded.push_str(emoji);
+            rest = &after[name.len() + 1..];
+        } else {
+            expanded.push(':');
+            rest = after;
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn lookup<'a>(name: &str, custom: &'a HashMap<String, String>) -> Option<&'a str> {
+    custom.get(name).map(String::as_str).or_else(|| {
+        BUILTIN
+            .iter()
+            .find(|(code, _)| *code == name)
+            .map(|(_, emoji)| *emoji)
+    })
+}
+
+fn is_shortcode_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expand_shortcodes;
+    use std::collections::HashMap;
+
+    fn expand(text: &str) -> String {
+        expand_shortcodes(text, &HashMap::new())
+    }
+
+    #[test]
+    fn known_shortcodes_expand_and_unknown_ones_stay() {
+        assert_eq!(expand("hi :smile:"), "hi 😄");
+        assert_eq!(expand(":+1: nice :heart:"), "👍 nice ❤️");
+        assert_eq!(expand("a :nope: b"), "a :nope: b");
+        assert_eq!(expand("at 10:30: done"), "at 10:30: done");
+        assert_eq!(expand(":: : :smile"), ":: : :smile");
+    }
+
+    #[test]
+    fn adjacent_shortcodes_each_expand() {
+        assert_eq!(expand(":fire::rocket:"), "🔥🚀");
+        assert_eq!(expand(":nope::smile:"), ":nope:😄");
+        assert_eq!(expand("re:smile:"), "re😄");
+    }
+
+    #[test]
+    fn custom_shortcodes_add_to_and_override_the_builtins() {
+        let custom = HashMap::from([
+            ("shrug".to_string(), "¯\\_(ツ)_/¯".to_string()),
+            ("smile".to_string(), "🙂".to_string()),
+        ]);
+
+        assert_eq!(
+            expand_shortcodes(":shrug: :smile: :wink:", &custom),
+            "¯\\_(ツ)_/¯ 🙂 😉"
+        );
+    }
+}
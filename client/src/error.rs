@@ -18,6 +18,32 @@ pub enum Error {
     #[error("Connection failed: {0}")]
     Connect(String),
 
+    #[error("Connection refused by {0}, is the server running?")]
+    ConnectionRefused(String),
+
+    #[error("Could not resolve {0}, check the hostname")]
+    Resolve(String),
+
+    #[error(
+        "Server certificate not trusted, check the ca_cert setting or MCS_CA_CERT points to the server's CA ({0})"
+    )]
+    Untrusted(String),
+
+    #[error("Timed out connecting to {0}")]
+    Timeout(String),
+
+    #[error("Invalid server address {0}")]
+    Address(String),
+
+    #[error("Proxy error: {0}")]
+    Proxy(String),
+
+    #[error("Config error: {0}")]
+    Config(String),
+
+    #[error("Export error: {0}")]
+    Export(String),
+
     #[error("Network channel closed")]
     ChannelClosed,
 
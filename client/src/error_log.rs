@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Most entries kept; older ones are dropped first.
+pub const ERROR_LOG_CAPACITY: usize = 50;
+
+/// How long an entry stays in the log.
+pub const ERROR_LOG_EXPIRY: Duration = Duration::from_mins(5);
+
+/// How serious a logged problem is, which picks its color in the panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// The server refused or failed a request; the session carries on.
+    Warn,
+    /// The client itself hit a problem, such as a lost connection.
+    Error,
+}
+
+/// Recent errors, newest last, for the log panel.
+#[derive(Debug)]
+pub struct ErrorLog {
+    entries: VecDeque<(Instant, Level, String)>,
+    capacity: usize,
+}
+
+impl Default for ErrorLog {
+    fn default() -> Self {
+        Self::with_capacity(ERROR_LOG_CAPACITY)
+    }
+}
+
+impl ErrorLog {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Appends an entry, dropping the oldest once the log is full.
+    pub fn push(&mut self, now: Instant, level: Level, message: String) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((now, level, message));
+    }
+
+    /// Drops entries older than `ERROR_LOG_EXPIRY`.
+    pub fn expire(&mut self, now: Instant) {
+        while self
+            .entries
+            .front()
+            .is_some_and(|(at, ..)| now.duration_since(*at) >= ERROR_LOG_EXPIRY)
+        {
+            self.entries.pop_front();
+        }
+    }
+
+    pub const fn entries(&self) -> &VecDeque<(Instant, Level, String)> {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ERROR_LOG_EXPIRY, ErrorLog, Level};
+    use std::time::{Duration, Instant};
+
+    fn messages(log: &ErrorLog) -> Vec<&str> {
+        log.entries().iter().map(|(.., m)| m.as_str()).collect()
+    }
+
+    #[test]
+    fn full_log_drops_oldest_entries() {
+        let mut log = ErrorLog::with_capacity(3);
+        let now = Instant::now();
+
+        for i in 0..5 {
+            log.push(now, Level::Error, format!("error {i}"));
+        }
+
+        assert_eq!(messages(&log), vec!["error 2", "error 3", "error 4"]);
+    }
+
+    #[test]
+    fn old_entries_expire() {
+        let mut log = ErrorLog::default();
+        let start = Instant::now();
+        log.push(start, Level::Error, "old".to_string());
+        log.push(
+            start + Duration::from_mins(1),
+            Level::Warn,
+            "new".to_string(),
+        );
+
+        log.expire(start + ERROR_LOG_EXPIRY.saturating_sub(Duration::from_secs(1)));
+        assert_eq!(messages(&log), vec!["old", "new"]);
+
+        log.expire(start + ERROR_LOG_EXPIRY);
+        assert_eq!(messages(&log), vec!["new"]);
+
+        log.expire(start + ERROR_LOG_EXPIRY + Duration::from_mins(1));
+        assert!(log.entries().is_empty());
+    }
+}
@@ -16,9 +16,15 @@ pub enum AppEvent {
     Network(Message),
     /// Internal errors
     Err(Error),
-    /// Periodic UI redraw ticks
+    /// Periodic timer ticks; they only redraw if something on screen changed.
     Tick,
-    LoginSuccess(mpsc::UnboundedSender<Message>),
+    /// The terminal was resized.
+    Resize,
+    /// Joined the server, whose session goes by `username`.
+    LoginSuccess {
+        tx: mpsc::UnboundedSender<Message>,
+        username: String,
+    },
     LoginFailed(String),
 }
 
@@ -52,11 +58,13 @@ impl EventHandler {
         let input_sender = sender.clone();
         let input_task = tokio::spawn(async move {
             let mut reader = EventStream::new();
-            while let Some(Ok(event)) = reader.next().await
-                && let Event::Key(key) = event
-                && key.kind == KeyEventKind::Press
-            {
-                if input_sender.send(AppEvent::Input(key)).is_err() {
+            while let Some(Ok(event)) = reader.next().await {
+                let event = match event {
+                    Event::Key(key) if key.kind == KeyEventKind::Press => AppEvent::Input(key),
+                    Event::Resize(..) => AppEvent::Resize,
+                    _ => continue,
+                };
+                if input_sender.send(event).is_err() {
                     break;
                 }
             }
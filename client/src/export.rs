@@ -0,0 +1,137 @@
+use std::{collections::VecDeque, path::Path};
+
+use protocol::ChatPacket;
+use serde::Serialize;
+
+use crate::{
+    error::{Error, Result},
+    ui::components::message_list,
+};
+
+/// Output format for `/export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Text,
+    Json,
+}
+
+impl ExportFormat {
+    /// Picks JSON for `.json` paths and plain text otherwise.
+    pub fn from_path(path: &str) -> Self {
+        if Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+        {
+            Self::Json
+        } else {
+            Self::Text
+        }
+    }
+}
+
+/// A packet with its timestamp rendered the same way the chat view shows it.
+#[derive(Serialize)]
+struct ExportedMessage<'a> {
+    #[serde(flatten)]
+    packet: &'a ChatPacket,
+    time: String,
+}
+
+/// Renders a transcript of `messages` in the given format.
+pub fn render(messages: &VecDeque<ChatPacket>, format: ExportFormat) -> Result<String> {
+    match format {
+        ExportFormat::Text => Ok(messages
+            .iter()
+            .map(|msg| {
+                let mut line = message_list::format_line(msg, "").to_string();
+                line.truncate(line.trim_end().len());
+                line.push('\n');
+                line
+            })
+            .collect()),
+        ExportFormat::Json => {
+            let exported: Vec<ExportedMessage> = messages
+                .iter()
+                .map(|packet| ExportedMessage {
+                    packet,
+                    time: message_list::format_timestamp(packet.timestamp),
+                })
+                .collect();
+            serde_json::to_string_pretty(&exported).map_err(|e| Error::Export(e.to_string()))
+        }
+    }
+}
+
+/// Writes a transcript of `messages` to `path`.
+pub fn export(messages: &VecDeque<ChatPacket>, path: &str, format: ExportFormat) -> Result<()> {
+    std::fs::write(path, render(messages, format)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExportFormat, render};
+    use crate::ui::components::message_list::format_timestamp;
+    use protocol::ChatPacket;
+    use std::collections::VecDeque;
+
+    fn transcript() -> VecDeque<ChatPacket> {
+        VecDeque::from([
+            ChatPacket {
+                id: 1,
+                sender: "server".to_string(),
+                content: "alice joined.\n".to_string(),
+                timestamp: 1_700_000_000_000,
+                deleted: false,
+            },
+            ChatPacket {
+                id: 2,
+                sender: "alice".to_string(),
+                content: "hello".to_string(),
+                timestamp: 1_700_000_060_000,
+                deleted: false,
+            },
+            ChatPacket {
+                id: 3,
+                sender: "alice".to_string(),
+                content: String::new(),
+                timestamp: 1_700_000_120_000,
+                deleted: true,
+            },
+        ])
+    }
+
+    #[test]
+    fn text_export_matches_chat_view() {
+        let text = render(&transcript(), ExportFormat::Text).unwrap();
+
+        let expected = format!(
+            "[{}] alice joined.\n[{}] alice: hello\n[{}] alice: [deleted]\n",
+            format_timestamp(1_700_000_000_000),
+            format_timestamp(1_700_000_060_000),
+            format_timestamp(1_700_000_120_000),
+        );
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    fn json_export_includes_packets_and_local_time() {
+        let json = render(&transcript(), ExportFormat::Json).unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let messages = value.as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["sender"], "alice");
+        assert_eq!(messages[1]["content"], "hello");
+        assert_eq!(messages[1]["timestamp"], 1_700_000_060_000_i64);
+        assert_eq!(messages[1]["time"], format_timestamp(1_700_000_060_000));
+        assert_eq!(messages[2]["deleted"], true);
+    }
+
+    #[test]
+    fn format_is_inferred_from_extension() {
+        assert_eq!(ExportFormat::from_path("chat.JSON"), ExportFormat::Json);
+        assert_eq!(ExportFormat::from_path("chat.txt"), ExportFormat::Text);
+        assert_eq!(ExportFormat::from_path("chat"), ExportFormat::Text);
+    }
+}
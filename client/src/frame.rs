@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+
+/// Shortest gap between two renders, about 60 frames a second.
+pub const FRAME_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Coalesces redraws: state changes only mark the screen dirty, and a dirty
+/// screen is drawn at most once per frame, however many events arrive.
+#[derive(Debug)]
+pub struct FrameLimiter {
+    interval: Duration,
+    dirty: bool,
+    last_render: Option<Instant>,
+}
+
+impl FrameLimiter {
+    /// Starts dirty so the first frame is drawn straight away.
+    pub const fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            dirty: true,
+            last_render: None,
+        }
+    }
+
+    pub const fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Whether to draw now. Claims the frame if so, so pending changes that
+    /// arrive before the next one wait for it.
+    pub fn should_render(&mut self, now: Instant) -> bool {
+        let frame_due = self
+            .last_render
+            .is_none_or(|last| now.duration_since(last) >= self.interval);
+        if !self.dirty || !frame_due {
+            return false;
+        }
+
+        self.dirty = false;
+        self.last_render = Some(now);
+        true
+    }
+
+    /// When the next frame should be drawn, if anything is waiting for one.
+    pub fn next_frame(&self) -> Option<Instant> {
+        if !self.dirty {
+            return None;
+        }
+        Some(
+            self.last_render
+                .map_or_else(Instant::now, |last| last + self.interval),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameLimiter;
+    use std::time::{Duration, Instant};
+
+    const INTERVAL: Duration = Duration::from_millis(16);
+
+    #[test]
+    fn burst_of_changes_renders_once_per_frame() {
+        let start = Instant::now();
+        let mut frames = FrameLimiter::new(INTERVAL);
+        assert!(frames.should_render(start));
+
+        let mut renders = 0;
+        for ms in 1..16 {
+            frames.mark_dirty();
+            if frames.should_render(start + Duration::from_millis(ms)) {
+                renders += 1;
+            }
+        }
+        assert_eq!(renders, 0);
+        assert_eq!(frames.next_frame(), Some(start + INTERVAL));
+
+        assert!(frames.should_render(start + INTERVAL));
+        assert!(!frames.should_render(start + INTERVAL * 2));
+    }
+
+    #[test]
+    fn clean_screen_is_not_redrawn() {
+        let start = Instant::now();
+        let mut frames = FrameLimiter::new(INTERVAL);
+        assert!(frames.should_render(start));
+
+        assert_eq!(frames.next_frame(), None);
+        assert!(!frames.should_render(start + INTERVAL * 10));
+    }
+}
@@ -0,0 +1,83 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use protocol::Message;
+
+/// How often the client pings the server to measure round-trip time.
+pub const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pings still awaiting a pong; older ones are dropped beyond this.
+const MAX_PENDING: usize = 8;
+
+/// Issues pings and turns matching pongs into round-trip times.
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    next_nonce: u64,
+    /// Send time in unix milliseconds of each unanswered ping, by nonce.
+    pending: BTreeMap<u64, i64>,
+}
+
+impl LatencyTracker {
+    /// Builds the next ping, remembering when it was sent.
+    pub fn ping(&mut self, now_ms: i64) -> Message {
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        self.pending.insert(nonce, now_ms);
+        while self.pending.len() > MAX_PENDING {
+            self.pending.pop_first();
+        }
+
+        Message::Ping {
+            nonce,
+            sent_ms: now_ms,
+        }
+    }
+
+    /// Returns the round-trip time for a pong, or `None` if it is unknown, duplicated or stale.
+    pub fn on_pong(&mut self, nonce: u64, now_ms: i64) -> Option<u64> {
+        let sent_ms = self.pending.remove(&nonce)?;
+        // Anything older than this pong would only report an outdated latency.
+        self.pending.retain(|&pending, _| pending > nonce);
+        Some(u64::try_from(now_ms.saturating_sub(sent_ms)).unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LatencyTracker;
+    use protocol::Message;
+
+    fn nonce(msg: &Message) -> u64 {
+        match msg {
+            Message::Ping { nonce, .. } => *nonce,
+            other => panic!("expected a ping, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn latency_is_time_since_matching_ping() {
+        let mut tracker = LatencyTracker::default();
+        let first = nonce(&tracker.ping(1_000));
+        let second = nonce(&tracker.ping(2_000));
+
+        assert_eq!(tracker.on_pong(second, 2_045), Some(45));
+        assert_eq!(tracker.on_pong(first, 2_050), None, "stale pong is ignored");
+    }
+
+    #[test]
+    fn duplicate_and_unknown_pongs_are_ignored() {
+        let mut tracker = LatencyTracker::default();
+        let n = nonce(&tracker.ping(1_000));
+
+        assert_eq!(tracker.on_pong(n + 1, 1_010), None);
+        assert_eq!(tracker.on_pong(n, 1_020), Some(20));
+        assert_eq!(tracker.on_pong(n, 1_030), None);
+    }
+
+    #[test]
+    fn clock_going_backwards_reports_zero() {
+        let mut tracker = LatencyTracker::default();
+        let n = nonce(&tracker.ping(1_000));
+
+        assert_eq!(tracker.on_pong(n, 900), Some(0));
+    }
+}
@@ -1,33 +1,64 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery, unused_extern_crates)]
 
+mod address;
 mod app;
+mod clipboard;
+mod command;
+mod config;
+mod emoji;
 mod error;
+mod error_log;
 mod event;
+mod export;
+mod frame;
+mod latency;
 mod network;
+mod outbox;
+mod proxy;
 mod tui;
+mod typing;
 mod ui;
 
+use std::time::Instant;
+
 use rustls::crypto::ring;
 
-use crate::{app::App, error::Result};
+use crate::{app::App, error::Result, frame::FrameLimiter};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let _ = ring::default_provider().install_default();
 
+    tui::install_panic_hook();
+    let _restore = tui::RestoreGuard::new();
     let mut terminal = tui::init().map_err(error::Error::Io)?;
     let mut events = event::EventHandler::new(250);
     let mut app = App::new(events.sender());
+    app.apply_config(config::load());
+
+    let mut frames = FrameLimiter::new(frame::FRAME_INTERVAL);
 
     while !app.global.should_quit {
-        terminal
-            .draw(|f| ui::render(f, &mut app))
-            .map_err(|e| error::Error::Render(e.to_string()))?;
-        if let Some(event) = events.next().await {
-            app.handle_event(event);
+        if frames.should_render(Instant::now()) {
+            terminal
+                .draw(|f| ui::render(f, &mut app))
+                .map_err(|e| error::Error::Render(e.to_string()))?;
+        }
+
+        // A pending frame cuts the wait short; otherwise sleep until the next event.
+        let event = match frames.next_frame() {
+            Some(due) => tokio::select! {
+                event = events.next() => event,
+                () = tokio::time::sleep_until(due.into()) => continue,
+            },
+            None => events.next().await,
+        };
+        if let Some(event) = event
+            && app.handle_event(event)
+        {
+            frames.mark_dirty();
         }
     }
 
-    tui::restore().map_err(error::Error::Io)?;
     Ok(())
 }
@@ -1,18 +1,35 @@
-use std::{fs::File, io::BufReader, sync::Arc};
+use std::{io, path::Path, sync::Arc, time::Duration};
 
 use futures::{SinkExt, StreamExt};
 use protocol::{McsCodec, Message};
 use rustls::{ClientConfig, RootCertStore};
-use rustls_pki_types::ServerName;
-use tokio::{net::TcpStream, sync::mpsc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::mpsc,
+};
 use tokio_rustls::TlsConnector;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 use crate::{
+    address::ServerAddress,
+    config::{self, CA_CERT_ENV},
     error::{Error, Result},
     event::AppEvent,
+    proxy,
 };
 
+/// How often the client pings the server so idle sessions aren't dropped.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long connecting, including the TLS handshake, may take before giving up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The connection to the server, over TLS or, for `mcs://` addresses, plain TCP.
+trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
 /// A client to handle network events.
 pub struct NetworkClient {
     /// Channel to send messages to the server.
@@ -28,43 +45,64 @@ impl NetworkClient {
         self.tx.send(msg).map_err(|_| Error::ChannelClosed)
     }
 
-    pub async fn connect(ip: &str, event_tx: mpsc::UnboundedSender<AppEvent>) -> Result<Self> {
-        let mut root_store = RootCertStore::empty();
-        let file = File::open("tls/ca.cert")?;
-        let mut reader = BufReader::new(file);
-
-        for cert in rustls_pemfile::certs(&mut reader) {
-            let cert = cert.map_err(|e| Error::Cert(e.to_string()))?;
-            root_store
-                .add(cert)
-                .map_err(|e| Error::Tls(e.to_string()))?;
-        }
-
-        let config = ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
-        let connector = TlsConnector::from(Arc::new(config));
+    /// Connects and waits for the server's hello, returning the client along
+    /// with whether the server accepts guests.
+    pub async fn connect(
+        address: &str,
+        event_tx: mpsc::UnboundedSender<AppEvent>,
+    ) -> Result<(Self, bool)> {
+        let address = ServerAddress::parse(address)?;
+        let ca_cert = config::ca_cert(&config::load());
+        Self::connect_to(&address, ca_cert.as_deref(), event_tx).await
+    }
 
-        let stream = TcpStream::connect(format!("{ip}:64400"))
+    async fn connect_to(
+        address: &ServerAddress,
+        ca_cert: Option<&Path>,
+        event_tx: mpsc::UnboundedSender<AppEvent>,
+    ) -> Result<(Self, bool)> {
+        let target = format!("{}:{}", address.host, address.port);
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, open(address, ca_cert, &target))
             .await
-            .map_err(|e| Error::Connect(e.to_string()))?;
+            .map_err(|_| Error::Timeout(target.clone()))??;
 
-        let domain = ServerName::try_from(ip.to_string())
-            .map_err(|e| Error::Tls(format!("Invalid DNS name: {e}")))?;
+        let (reader, writer) = tokio::io::split(stream);
+        let mut framed_reader = FramedRead::new(reader, McsCodec::new());
+        let mut framed_writer = FramedWrite::new(writer, McsCodec::new());
 
-        let tls_stream = connector
-            .connect(domain, stream)
+        let hello = tokio::time::timeout(CONNECT_TIMEOUT, framed_reader.next())
             .await
-            .map_err(|e| Error::Tls(e.to_string()))?;
-
-        let (reader, writer) = tokio::io::split(tls_stream);
-        let mut framed_reader = FramedRead::new(reader, McsCodec);
-        let mut framed_writer = FramedWrite::new(writer, McsCodec);
+            .map_err(|_| Error::Timeout(target.clone()))?;
+        let (guest_allowed, max_message_len) = match hello {
+            Some(Ok(Message::Hello {
+                guest_allowed,
+                max_message_len,
+            })) => (guest_allowed, max_message_len),
+            // The load balancer says why it's turning us away before hanging up.
+            Some(Ok(Message::Error(e))) => return Err(Error::Connect(e.to_string())),
+            Some(Ok(other)) => {
+                return Err(Error::Connect(format!("unexpected handshake {other:?}")));
+            }
+            Some(Err(e)) => return Err(Error::Connect(e.to_string())),
+            None => return Err(Error::Disconnected),
+        };
+        let _ = event_tx.send(AppEvent::Network(Message::Hello {
+            guest_allowed,
+            max_message_len,
+        }));
 
         let (outbound_tx, mut outbound_rx) = mpsc::unbounded_channel::<Message>();
 
         tokio::spawn(async move {
-            while let Some(msg) = outbound_rx.recv().await {
+            let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                let msg = tokio::select! {
+                    msg = outbound_rx.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    _ = heartbeat.tick() => Message::Heartbeat,
+                };
                 if framed_writer.send(msg).await.is_err() {
                     break;
                 }
@@ -80,10 +118,189 @@ impl NetworkClient {
             let _ = event_tx.send(AppEvent::Err(Error::Disconnected));
         });
 
-        Ok(Self::new(outbound_tx))
+        Ok((Self::new(outbound_tx), guest_allowed))
     }
 
     pub fn into_inner(self) -> mpsc::UnboundedSender<Message> {
         self.tx
     }
 }
+
+/// Opens the connection to `address`, through the proxy if one is set, and
+/// runs the TLS handshake unless the address asks for plaintext.
+async fn open(
+    address: &ServerAddress,
+    ca_cert: Option<&Path>,
+    target: &str,
+) -> Result<Box<dyn Transport>> {
+    // Built before connecting so a bad CA is reported without touching the network.
+    let connector = if address.plaintext {
+        None
+    } else {
+        let tls_config = ClientConfig::builder()
+            .with_root_certificates(root_store(ca_cert)?)
+            .with_no_client_auth();
+        Some((
+            TlsConnector::from(Arc::new(tls_config)),
+            address.server_name()?,
+        ))
+    };
+
+    let stream = match proxy::from_env()? {
+        Some(proxy_addr) => proxy::connect(&proxy_addr, &address.host, address.port).await?,
+        None => TcpStream::connect(address.resolve().await?)
+            .await
+            .map_err(|e| connect_error(&e, target))?,
+    };
+
+    let Some((connector, domain)) = connector else {
+        return Ok(Box::new(stream));
+    };
+    let tls_stream = connector
+        .connect(domain, stream)
+        .await
+        .map_err(|e| tls_error(&e))?;
+    Ok(Box::new(tls_stream))
+}
+
+/// Loads the trusted roots from the CA certificate at `ca_cert`, or the system store.
+fn root_store(ca_cert: Option<&Path>) -> Result<RootCertStore> {
+    let mut root_store = RootCertStore::empty();
+
+    let Some(path) = ca_cert else {
+        let native = rustls_native_certs::load_native_certs();
+        if native.certs.is_empty() {
+            return Err(Error::Cert(format!(
+                "no system root certificates found, set {CA_CERT_ENV} to a CA certificate"
+            )));
+        }
+        root_store.add_parsable_certificates(native.certs);
+        return Ok(root_store);
+    };
+
+    let certs = tls::load_certs(path).map_err(|e| match e {
+        tls::Error::Read { .. } => Error::Cert(format!("{e}, set {CA_CERT_ENV} to its path")),
+        e => Error::Cert(e.to_string()),
+    })?;
+    for cert in certs {
+        root_store
+            .add(cert)
+            .map_err(|e| Error::Tls(e.to_string()))?;
+    }
+
+    Ok(root_store)
+}
+
+/// Maps a failed TCP connect to an error that tells the user what to check.
+fn connect_error(e: &io::Error, target: &str) -> Error {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => Error::ConnectionRefused(target.to_string()),
+        io::ErrorKind::TimedOut => Error::Timeout(target.to_string()),
+        _ => Error::Connect(e.to_string()),
+    }
+}
+
+/// Separates certificate trust failures from other handshake errors.
+fn tls_error(e: &io::Error) -> Error {
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+    {
+        Some(rustls::Error::InvalidCertificate(reason)) => Error::Untrusted(format!("{reason:?}")),
+        _ => Error::Tls(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NetworkClient, connect_error, root_store, tls_error};
+    use crate::{address::ServerAddress, error::Error};
+    use futures::{SinkExt, StreamExt};
+    use protocol::{McsCodec, Message};
+    use rustls::CertificateError;
+    use std::io;
+    use std::path::Path;
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tokio_util::codec::Framed;
+
+    #[tokio::test]
+    async fn plaintext_connects_without_a_ca_cert() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(socket, McsCodec::new());
+            framed
+                .send(Message::Hello {
+                    guest_allowed: true,
+                    max_message_len: protocol::MAX_MESSAGE_LEN,
+                })
+                .await
+                .unwrap();
+            // Hold the connection open until the client is done with it.
+            let _ = framed.next().await;
+        });
+
+        let address = ServerAddress::parse(&format!("mcs://127.0.0.1:{port}")).unwrap();
+        let (event_tx, _event_rx) = mpsc::unbounded_channel();
+        let missing_ca = Path::new("/nonexistent/mcs/ca.cert");
+
+        let (_, guest_allowed) = NetworkClient::connect_to(&address, Some(missing_ca), event_tx)
+            .await
+            .unwrap();
+        assert!(guest_allowed);
+    }
+
+    #[test]
+    fn missing_ca_cert_names_the_path() {
+        let path = Path::new("/nonexistent/mcs/ca.cert");
+
+        match root_store(Some(path)) {
+            Err(Error::Cert(msg)) => {
+                assert!(msg.contains("/nonexistent/mcs/ca.cert"), "{msg}");
+                assert!(msg.contains("MCS_CA_CERT"), "{msg}");
+            }
+            other => panic!("expected a certificate error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn connect_errors_map_to_actionable_messages() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let timed_out = io::Error::from(io::ErrorKind::TimedOut);
+        let other = io::Error::from(io::ErrorKind::PermissionDenied);
+
+        assert!(matches!(
+            connect_error(&refused, "chat.local:64400"),
+            Error::ConnectionRefused(target) if target == "chat.local:64400"
+        ));
+        assert!(
+            connect_error(&refused, "chat.local:64400")
+                .to_string()
+                .contains("is the server running?")
+        );
+        assert!(matches!(
+            connect_error(&timed_out, "chat.local:64400"),
+            Error::Timeout(_)
+        ));
+        assert!(matches!(
+            connect_error(&other, "chat.local:64400"),
+            Error::Connect(_)
+        ));
+    }
+
+    #[test]
+    fn untrusted_certificates_are_reported_separately() {
+        let untrusted = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer),
+        );
+        let other = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::Error::HandshakeNotComplete,
+        );
+
+        assert!(matches!(tls_error(&untrusted), Error::Untrusted(_)));
+        assert!(matches!(tls_error(&other), Error::Tls(_)));
+    }
+}
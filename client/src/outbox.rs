@@ -0,0 +1,201 @@
+use protocol::ChatPacket;
+
+/// Delivery state of a chat message sent from this client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// Handed to the network, waiting for the server to accept it.
+    Sending,
+    /// Accepted by the server, waiting for the broadcast echo.
+    Sent,
+    /// Not delivered; retried on the next login.
+    Failed,
+    /// Refused by the server; never retried.
+    Rejected,
+}
+
+/// A message shown optimistically until the server echoes it back.
+///
+/// Its timestamp is the local clock's and only provisional; the echoed packet
+/// carries the server's.
+#[derive(Debug)]
+pub struct OutgoingMessage {
+    pub local_id: u64,
+    pub packet: ChatPacket,
+    pub status: DeliveryStatus,
+    server_id: Option<u64>,
+}
+
+/// Tracks the user's own messages from send until their broadcast arrives.
+///
+/// The server's copy is the one kept: an entry is dropped as soon as the
+/// packet with its server id reaches the message list, whether by echo or by
+/// history, so the user sees each message exactly once.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    next_local_id: u64,
+    entries: Vec<OutgoingMessage>,
+}
+
+impl Outbox {
+    /// Queues a message as `Sending` and returns its local id.
+    pub fn push(&mut self, packet: ChatPacket) -> u64 {
+        self.next_local_id += 1;
+        self.entries.push(OutgoingMessage {
+            local_id: self.next_local_id,
+            packet,
+            status: DeliveryStatus::Sending,
+            server_id: None,
+        });
+        self.next_local_id
+    }
+
+    pub fn entries(&self) -> &[OutgoingMessage] {
+        &self.entries
+    }
+
+    pub fn mark_failed(&mut self, local_id: u64) {
+        if let Some(entry) = self.get_mut(local_id) {
+            entry.status = DeliveryStatus::Failed;
+        }
+    }
+
+    /// Marks a message the server refused, so it is never resent.
+    pub fn reject(&mut self, local_id: u64) {
+        if let Some(entry) = self.get_mut(local_id) {
+            entry.status = DeliveryStatus::Rejected;
+        }
+    }
+
+    /// Forgets every message, e.g. when another user logs in.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Fails every message still waiting on the server, e.g. after a disconnect.
+    pub fn fail_pending(&mut self) {
+        for entry in &mut self.entries {
+            if entry.status == DeliveryStatus::Sending {
+                entry.status = DeliveryStatus::Failed;
+            }
+        }
+    }
+
+    /// Records the server's id for an accepted message.
+    pub fn acknowledge(&mut self, local_id: u64, server_id: u64) {
+        if let Some(entry) = self.get_mut(local_id)
+            && entry.status == DeliveryStatus::Sending
+        {
+            entry.status = DeliveryStatus::Sent;
+            entry.server_id = Some(server_id);
+        }
+    }
+
+    /// Drops a message once its broadcast has arrived in the history.
+    pub fn remove(&mut self, local_id: u64) {
+        self.entries.retain(|e| e.local_id != local_id);
+    }
+
+    /// Drops the sent message the server assigned `server_id`, if any.
+    pub fn confirm(&mut self, server_id: u64) {
+        self.entries.retain(|e| e.server_id != Some(server_id));
+    }
+
+    /// Marks failed messages as `Sending` again and returns them for resending.
+    pub fn retry_failed(&mut self) -> Vec<(u64, ChatPacket)> {
+        self.entries
+            .iter_mut()
+            .filter(|e| e.status == DeliveryStatus::Failed)
+            .map(|e| {
+                e.status = DeliveryStatus::Sending;
+                (e.local_id, e.packet.clone())
+            })
+            .collect()
+    }
+
+    fn get_mut(&mut self, local_id: u64) -> Option<&mut OutgoingMessage> {
+        self.entries.iter_mut().find(|e| e.local_id == local_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeliveryStatus, Outbox};
+    use protocol::ChatPacket;
+
+    fn outbox_with(content: &str) -> (Outbox, u64) {
+        let mut outbox = Outbox::default();
+        let id = outbox.push(ChatPacket::new_user_packet(
+            "alice".to_string(),
+            content.to_string(),
+        ));
+        (outbox, id)
+    }
+
+    fn status(outbox: &Outbox, local_id: u64) -> Option<DeliveryStatus> {
+        outbox
+            .entries()
+            .iter()
+            .find(|e| e.local_id == local_id)
+            .map(|e| e.status)
+    }
+
+    #[test]
+    fn sending_is_acknowledged_then_confirmed() {
+        let (mut outbox, id) = outbox_with("hi");
+        assert_eq!(status(&outbox, id), Some(DeliveryStatus::Sending));
+
+        outbox.acknowledge(id, 42);
+        assert_eq!(status(&outbox, id), Some(DeliveryStatus::Sent));
+
+        outbox.confirm(7);
+        assert_eq!(status(&outbox, id), Some(DeliveryStatus::Sent));
+        outbox.confirm(42);
+        assert_eq!(status(&outbox, id), None);
+    }
+
+    #[test]
+    fn failed_messages_are_retried_as_sending() {
+        let (mut outbox, id) = outbox_with("hi");
+        outbox.mark_failed(id);
+        assert_eq!(status(&outbox, id), Some(DeliveryStatus::Failed));
+
+        outbox.acknowledge(id, 42);
+        assert_eq!(
+            status(&outbox, id),
+            Some(DeliveryStatus::Failed),
+            "a failed message can't be acknowledged before it is retried"
+        );
+
+        let retried = outbox.retry_failed();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].0, id);
+        assert_eq!(status(&outbox, id), Some(DeliveryStatus::Sending));
+        assert!(outbox.retry_failed().is_empty());
+    }
+
+    #[test]
+    fn disconnect_fails_only_unacknowledged_messages() {
+        let (mut outbox, sent) = outbox_with("one");
+        let pending = outbox.push(ChatPacket::new_user_packet(
+            "alice".to_string(),
+            "two".to_string(),
+        ));
+        outbox.acknowledge(sent, 1);
+
+        outbox.fail_pending();
+
+        assert_eq!(status(&outbox, sent), Some(DeliveryStatus::Sent));
+        assert_eq!(status(&outbox, pending), Some(DeliveryStatus::Failed));
+    }
+
+    #[test]
+    fn rejected_messages_are_never_retried() {
+        let (mut outbox, id) = outbox_with("spam");
+        outbox.reject(id);
+
+        outbox.fail_pending();
+
+        assert!(outbox.retry_failed().is_empty());
+        assert_eq!(status(&outbox, id), Some(DeliveryStatus::Rejected));
+    }
+}
@@ -0,0 +1,211 @@
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::error::{Error, Result};
+
+/// Environment variable holding the optional `host:port` of a SOCKS5 proxy.
+pub const PROXY_ENV: &str = "MCS_SOCKS5_PROXY";
+
+const SOCKS_VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Returns the configured proxy address, if any, after validating it.
+pub fn from_env() -> Result<Option<String>> {
+    match std::env::var(PROXY_ENV) {
+        Ok(addr) if !addr.trim().is_empty() => validate(addr.trim()).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Checks that a proxy address is a `host:port` pair with a valid port.
+fn validate(addr: &str) -> Result<String> {
+    if addr.parse::<SocketAddr>().is_ok() {
+        return Ok(addr.to_string());
+    }
+
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(addr.to_string())
+        }
+        _ => Err(Error::Proxy(format!(
+            "invalid proxy address '{addr}', expected host:port"
+        ))),
+    }
+}
+
+/// Opens a TCP stream to `host:port` tunnelled through the SOCKS5 proxy at `proxy`.
+pub async fn connect(proxy: &str, host: &str, port: u16) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy)
+        .await
+        .map_err(|e| Error::Proxy(format!("could not reach proxy {proxy}: {e}")))?;
+
+    handshake(&mut stream, host, port)
+        .await
+        .map_err(|e| match e {
+            Error::Io(e) => Error::Proxy(format!("proxy handshake failed: {e}")),
+            e => e,
+        })?;
+
+    Ok(stream)
+}
+
+async fn handshake(stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+    stream.write_all(&[SOCKS_VERSION, 1, NO_AUTH]).await?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [SOCKS_VERSION, NO_AUTH] {
+        return Err(Error::Proxy(
+            "proxy requires an unsupported authentication method".to_string(),
+        ));
+    }
+
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+    match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len())
+                .map_err(|_| Error::Proxy(format!("hostname '{host}' is too long")))?;
+            request.push(ATYP_DOMAIN);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(Error::Proxy(format!(
+            "proxy refused connection to {host}:{port} ({})",
+            reply_message(reply[1])
+        )));
+    }
+
+    let bound_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => usize::from(stream.read_u8().await?),
+        other => {
+            return Err(Error::Proxy(format!(
+                "proxy replied with unknown address type {other}"
+            )));
+        }
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(())
+}
+
+const fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, validate};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    /// Accepts one SOCKS5 client, connects it to the requested IPv4 target, and relays bytes.
+    async fn spawn_mock_proxy() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+
+            let mut greeting = [0u8; 3];
+            client.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            client.write_all(&[5, 0]).await.unwrap();
+
+            let mut header = [0u8; 4];
+            client.read_exact(&mut header).await.unwrap();
+            assert_eq!(header, [5, 1, 0, 1]);
+            let mut target = [0u8; 6];
+            client.read_exact(&mut target).await.unwrap();
+            let port = u16::from_be_bytes([target[4], target[5]]);
+            let ip = format!("{}.{}.{}.{}", target[0], target[1], target[2], target[3]);
+
+            let mut upstream = TcpStream::connect((ip.as_str(), port)).await.unwrap();
+            client
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+                .await
+                .unwrap();
+            tokio::io::copy_bidirectional(&mut client, &mut upstream)
+                .await
+                .ok();
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn connect_through_proxy_succeeds() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = target.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            socket.write_all(&buf).await.unwrap();
+        });
+
+        let proxy = spawn_mock_proxy().await;
+        let mut stream = connect(&proxy, "127.0.0.1", target_port).await.unwrap();
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn connect_to_unreachable_proxy_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        assert!(connect(&addr, "127.0.0.1", 64400).await.is_err());
+    }
+
+    #[test]
+    fn validate_proxy_address() {
+        assert!(validate("127.0.0.1:1080").is_ok());
+        assert!(validate("[::1]:1080").is_ok());
+        assert!(validate("proxy.local:1080").is_ok());
+        assert!(validate("proxy.local").is_err());
+        assert!(validate(":1080").is_err());
+        assert!(validate("proxy.local:socks").is_err());
+    }
+}
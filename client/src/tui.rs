@@ -21,3 +21,56 @@ pub fn restore() -> std::io::Result<()> {
     execute!(std::io::stdout(), LeaveAlternateScreen)?;
     Ok(())
 }
+
+/// Restores the terminal before the default panic message is printed,
+/// so the message lands on a usable screen.
+pub fn install_panic_hook() {
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = restore();
+        hook(info);
+    }));
+}
+
+/// Restores the terminal when dropped, covering early returns from the main loop.
+pub struct RestoreGuard {
+    teardown: fn() -> std::io::Result<()>,
+}
+
+impl RestoreGuard {
+    pub const fn new() -> Self {
+        Self { teardown: restore }
+    }
+}
+
+impl Drop for RestoreGuard {
+    fn drop(&mut self) {
+        let _ = (self.teardown)();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RestoreGuard;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static RESTORED: AtomicBool = AtomicBool::new(false);
+
+    #[allow(clippy::unnecessary_wraps)]
+    fn fake_restore() -> std::io::Result<()> {
+        RESTORED.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    #[test]
+    fn dropping_guard_restores_terminal() {
+        let guard = RestoreGuard {
+            teardown: fake_restore,
+        };
+        assert!(!RESTORED.load(Ordering::SeqCst));
+
+        drop(guard);
+
+        assert!(RESTORED.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+/// How often a "still typing" notice is re-sent while the user keeps typing.
+pub const TYPING_REFRESH: Duration = Duration::from_secs(2);
+
+/// How long a remote user is shown as typing without a fresh notice.
+pub const TYPING_EXPIRY: Duration = Duration::from_secs(3);
+
+/// Debounces typing notifications so keystrokes don't flood the network.
+#[derive(Debug, Default)]
+pub struct TypingDebouncer {
+    last_sent: Option<Instant>,
+    is_typing: bool,
+}
+
+impl TypingDebouncer {
+    /// Records an input change and returns the typing state to send, if any.
+    pub fn on_input(&mut self, now: Instant, buffer_empty: bool) -> Option<bool> {
+        if buffer_empty {
+            if !self.is_typing {
+                return None;
+            }
+            self.is_typing = false;
+            self.last_sent = Some(now);
+            return Some(false);
+        }
+
+        let stale = self
+            .last_sent
+            .is_none_or(|sent| now.duration_since(sent) >= TYPING_REFRESH);
+        if self.is_typing && !stale {
+            return None;
+        }
+
+        self.is_typing = true;
+        self.last_sent = Some(now);
+        Some(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TYPING_REFRESH, TypingDebouncer};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn keystrokes_are_debounced() {
+        let mut debouncer = TypingDebouncer::default();
+        let start = Instant::now();
+
+        let sent: Vec<bool> = (0..50)
+            .filter_map(|i| debouncer.on_input(start + Duration::from_millis(i * 20), false))
+            .collect();
+
+        assert_eq!(sent, vec![true]);
+    }
+
+    #[test]
+    fn typing_is_refreshed_after_interval() {
+        let mut debouncer = TypingDebouncer::default();
+        let start = Instant::now();
+
+        assert_eq!(debouncer.on_input(start, false), Some(true));
+        assert_eq!(debouncer.on_input(start + TYPING_REFRESH / 2, false), None);
+        assert_eq!(
+            debouncer.on_input(start + TYPING_REFRESH, false),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn clearing_input_stops_typing_once() {
+        let mut debouncer = TypingDebouncer::default();
+        let start = Instant::now();
+
+        assert_eq!(debouncer.on_input(start, true), None);
+        assert_eq!(debouncer.on_input(start, false), Some(true));
+        assert_eq!(debouncer.on_input(start, true), Some(false));
+        assert_eq!(debouncer.on_input(start, true), None);
+        assert_eq!(debouncer.on_input(start, false), Some(true));
+    }
+}
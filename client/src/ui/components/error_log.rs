@@ -0,0 +1,56 @@
+use std::time::Instant;
+
+use ratatui::{
+    Frame,
+    style::{Color, Style},
+    text::{Line, Span, Text},
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
+};
+
+use crate::{
+    error_log::{ErrorLog, Level},
+    ui::centered_rect,
+};
+
+/// Renders recent errors over the current screen, newest first.
+pub fn draw(f: &mut Frame, log: &ErrorLog) {
+    let area = centered_rect(f.area(), 70, 50);
+    let now = Instant::now();
+
+    let lines: Vec<Line> = if log.entries().is_empty() {
+        vec![Line::styled(
+            "No recent errors.",
+            Style::default().fg(Color::DarkGray),
+        )]
+    } else {
+        log.entries()
+            .iter()
+            .rev()
+            .map(|(at, level, message)| {
+                let color = match level {
+                    Level::Warn => Color::Yellow,
+                    Level::Error => Color::Red,
+                };
+                Line::from(vec![
+                    Span::styled(
+                        format!("{:>4}s ago ", now.duration_since(*at).as_secs()),
+                        Style::default().fg(Color::DarkGray),
+                    ),
+                    Span::styled(message.as_str(), Style::default().fg(color)),
+                ])
+            })
+            .collect()
+    };
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(" Errors (Ctrl+E to close) ")
+        .border_style(Style::default().fg(Color::Red))
+        .style(Style::default().bg(Color::Black));
+    let paragraph = Paragraph::new(Text::from(lines))
+        .block(block)
+        .wrap(Wrap { trim: false });
+
+    f.render_widget(Clear, area);
+    f.render_widget(paragraph, area);
+}
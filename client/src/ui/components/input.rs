@@ -1,29 +1,64 @@
 use ratatui::{
     Frame,
-    layout::Rect,
+    layout::{Position, Rect},
     style::{Color, Style},
-    widgets::{Block, Borders, Paragraph, Wrap},
+    widgets::{Block, Borders, Paragraph},
 };
 
-/// Renders a bordered input box.
+/// Renders a bordered, single-line input box and returns where the cursor
+/// goes after its content.
 ///
 /// * `title` - The text displayed in the top border.
-/// * `content` - The text inside the box.
+/// * `content` - The text inside the box. Content wider than the box scrolls
+///   so its end, where the cursor is, stays in view.
 /// * `is_focused` - If true, the border turns yellow; otherwise, white.
-pub fn draw(f: &mut Frame, area: Rect, title: &str, content: &str, is_focused: bool) {
+pub fn draw(f: &mut Frame, area: Rect, title: &str, content: &str, is_focused: bool) -> Position {
     let border_style = if is_focused {
         Style::default().fg(Color::Yellow)
     } else {
         Style::default().fg(Color::White)
     };
 
+    let len = content.chars().count();
+    let offset = scroll_offset(len, usize::from(area.width.saturating_sub(2)));
+    let visible: String = content.chars().skip(offset).collect();
+
     let block = Block::default()
         .borders(Borders::ALL)
         .title(format!(" {title} "))
         .border_style(border_style);
-    let p = Paragraph::new(content)
-        .block(block)
-        .wrap(Wrap { trim: false });
+    f.render_widget(Paragraph::new(visible).block(block), area);
+
+    // The offset keeps this within the box, so it always fits.
+    let column = u16::try_from(len - offset).unwrap_or(u16::MAX);
+    Position::new(area.x + 1 + column, area.y + 1)
+}
+
+/// How many leading chars to hide so a cursor after char `cursor` still fits
+/// in a box `width` columns wide, with a column left for the cursor itself.
+pub const fn scroll_offset(cursor: usize, width: usize) -> usize {
+    cursor.saturating_sub(width.saturating_sub(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::scroll_offset;
+
+    #[test]
+    fn short_content_is_not_scrolled() {
+        assert_eq!(scroll_offset(0, 20), 0);
+        assert_eq!(scroll_offset(19, 20), 0);
+    }
+
+    #[test]
+    fn long_content_scrolls_to_keep_the_cursor_in_the_box() {
+        // 19 chars of content and the cursor fill a 20 column box.
+        assert_eq!(scroll_offset(20, 20), 1);
+        assert_eq!(scroll_offset(100, 20), 81);
+        assert_eq!(100 - scroll_offset(100, 20), 19);
 
-    f.render_widget(p, area);
+        // A box too narrow to show anything only has room for the cursor.
+        assert_eq!(scroll_offset(5, 1), 5);
+        assert_eq!(scroll_offset(5, 0), 5);
+    }
 }
@@ -1,77 +1,60 @@
-use std::collections::VecDeque;
-
-use chrono::{DateTime, Local, TimeZone, Utc};
+use chrono::{DateTime, Local, Utc};
 use protocol::ChatPacket;
 use ratatui::{
     Frame,
     layout::Rect,
-    style::{Color, Style},
+    style::{Color, Modifier, Style},
     text::{Line, Span, Text},
     widgets::{Block, Borders, Paragraph, Wrap},
 };
 
-#[allow(clippy::cast_possible_truncation)]
-pub fn draw(
-    f: &mut Frame,
-    area: Rect,
-    messages: &VecDeque<ChatPacket>,
-    current_user: &str,
-    scroll_offset: &mut u16,
-    should_request_history: &mut bool,
-    history_request_timestamp: &mut Option<i64>,
-) {
-    let block = Block::default()
-        .borders(Borders::ALL)
-        .title(" Chat History ");
+use crate::{
+    app::{ChatState, PRESENCE_SENDER},
+    command::EMOTE_PREFIX,
+    outbox::{DeliveryStatus, OutgoingMessage},
+};
+
+pub fn draw(f: &mut Frame, area: Rect, chat: &mut ChatState) {
+    let title = if chat.unread_count > 0 {
+        format!(" Chat History ({} new) ", chat.unread_count)
+    } else {
+        " Chat History ".to_string()
+    };
+    let block = Block::default().borders(Borders::ALL).title(title);
     let inner_width = area.width.saturating_sub(2) as usize;
     let inner_height = area.height.saturating_sub(2);
-    let mut total_visual_lines = 0;
+    chat.viewport_width = inner_width;
+    let mut total_visual_lines: u16 = 0;
 
-    let lines: Vec<Line> = messages
+    let mut lines: Vec<Line> = chat
+        .messages
         .iter()
-        .map(|msg| {
-            let time_str = format_timestamp(msg.timestamp);
-            let line = if msg.sender == "server" {
-                Line::from(Span::styled(
-                    format!("[{}] {}", time_str, msg.content),
-                    Style::default().fg(Color::DarkGray),
-                ))
-            } else {
-                let color = if msg.sender == current_user {
-                    Color::Green
-                } else {
-                    Color::Blue
-                };
-
-                Line::from(vec![
-                    Span::styled(
-                        format!("[{}] {}: ", time_str, msg.sender),
-                        Style::default().fg(color),
-                    ),
-                    Span::raw(&msg.content),
-                ])
-            };
-            let line_len = line.iter().len();
-            let lines_taken = if inner_width > 0 {
-                line_len.div_ceil(inner_width) as u16
-            } else {
-                1
-            };
-
-            let lines_taken = lines_taken.max(1);
-            total_visual_lines += lines_taken;
-
+        .enumerate()
+        .map(|(i, msg)| {
+            let mut line = format_line(msg, &chat.username);
+            if chat.selected_index == Some(i) {
+                line = line.patch_style(Style::default().add_modifier(Modifier::REVERSED));
+            }
+            let height = line_height(&line, inner_width);
+            total_visual_lines = total_visual_lines.saturating_add(height);
             line
         })
         .collect();
+    for entry in chat.outbox.entries() {
+        let line = format_outgoing(entry, &chat.username);
+        total_visual_lines = total_visual_lines.saturating_add(line_height(&line, inner_width));
+        lines.push(line);
+    }
 
     let max_scroll = total_visual_lines.saturating_sub(inner_height);
-    *scroll_offset = (*scroll_offset).min(max_scroll);
-    *should_request_history = max_scroll == *scroll_offset;
-    if *should_request_history && let Some(packet) = messages.iter().peekable().peek() {
-        *history_request_timestamp = Some(packet.timestamp);
+    chat.scroll_offset = chat.scroll_offset.min(max_scroll);
+    chat.should_request_history = max_scroll == chat.scroll_offset;
+    if chat.should_request_history
+        && let Some(packet) = chat.messages.front()
+    {
+        chat.history_request_cursor = Some((packet.timestamp, packet.id));
     }
-    let scroll_from_top = max_scroll.saturating_sub(*scroll_offset);
+    let scroll_from_top = max_scroll.saturating_sub(chat.scroll_offset);
 
     let paragraph = Paragraph::new(Text::from(lines))
         .block(block)
@@ -81,8 +64,136 @@ pub fn draw(
     f.render_widget(paragraph, area);
 }
 
-fn format_timestamp(ts: i64) -> String {
-    let dt: DateTime<Utc> = Utc.timestamp_opt(ts, 0).earliest().unwrap_or_else(Utc::now);
+/// Returns how many rows `msg` occupies when wrapped to `width` columns.
+pub fn message_height(msg: &ChatPacket, current_user: &str, width: usize) -> u16 {
+    line_height(&format_line(msg, current_user), width)
+}
+
+/// Counts rows the way the rendered `Paragraph` wraps them: by display width
+/// (so wide CJK glyphs take two columns) and at word boundaries.
+fn line_height(line: &Line, width: usize) -> u16 {
+    if width == 0 {
+        return 1;
+    }
+    let width = u16::try_from(width).unwrap_or(u16::MAX);
+    let rows = Paragraph::new(line.clone())
+        .wrap(Wrap { trim: false })
+        .line_count(width);
+    u16::try_from(rows).unwrap_or(u16::MAX).max(1)
+}
+
+/// Formats a message as a single styled line for the chat views.
+pub fn format_line<'a>(msg: &'a ChatPacket, current_user: &str) -> Line<'a> {
+    let time_str = format_timestamp(msg.timestamp);
+    if msg.deleted {
+        Line::from(Span::styled(
+            format!("[{}] {}: [deleted]", time_str, msg.sender),
+            Style::default()
+                .fg(Color::DarkGray)
+                .add_modifier(Modifier::ITALIC),
+        ))
+    } else if msg.sender == PRESENCE_SENDER {
+        Line::from(Span::styled(
+            format!("[{}] {}", time_str, msg.content),
+            Style::default()
+                .fg(Color::Cyan)
+                .add_modifier(Modifier::ITALIC),
+        ))
+    } else if msg.sender == "server" {
+        Line::from(Span::styled(
+            format!("[{}] {}", time_str, msg.content),
+            Style::default().fg(Color::DarkGray),
+        ))
+    } else if let Some(action) = msg.content.strip_prefix(EMOTE_PREFIX) {
+        Line::from(Span::styled(
+            format!("[{}] * {} {}", time_str, msg.sender, action),
+            Style::default()
+                .fg(Color::Magenta)
+                .add_modifier(Modifier::ITALIC),
+        ))
+    } else {
+        let color = if msg.sender == current_user {
+            Color::Green
+        } else {
+            Color::Blue
+        };
+
+        Line::from(vec![
+            Span::styled(
+                format!("[{}] {}: ", time_str, msg.sender),
+                Style::default().fg(color),
+            ),
+            Span::raw(&msg.content),
+        ])
+    }
+}
+
+/// Formats a message still in the outbox, marked with its delivery status.
+fn format_outgoing<'a>(entry: &'a OutgoingMessage, current_user: &str) -> Line<'a> {
+    let (glyph, color) = match entry.status {
+        DeliveryStatus::Sending => (" ◷", Color::DarkGray),
+        DeliveryStatus::Sent => (" ✓", Color::DarkGray),
+        DeliveryStatus::Failed => (" ✗ not sent", Color::Red),
+        DeliveryStatus::Rejected => (" ✗ rejected", Color::Red),
+    };
+    let mut line = format_line(&entry.packet, current_user);
+    line.push_span(Span::styled(glyph, Style::default().fg(color)));
+    line
+}
+
+/// Formats a unix timestamp in milliseconds in local time, as shown next to each message.
+pub fn format_timestamp(ts: i64) -> String {
+    let dt: DateTime<Utc> = DateTime::from_timestamp_millis(ts).unwrap_or_else(Utc::now);
     let local: DateTime<Local> = DateTime::from(dt);
     local.format("%Y-%m-%d %H:%M").to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{format_timestamp, line_height};
+    use chrono::{Local, TimeZone};
+    use ratatui::text::Line;
+
+    #[test]
+    fn wide_characters_count_two_columns() {
+        let line = Line::from("你好世界你好");
+        assert_eq!(line_height(&line, 12), 1);
+        assert_eq!(line_height(&line, 6), 2);
+        assert_eq!(line_height(&line, 4), 3);
+    }
+
+    #[test]
+    fn mixed_width_text_wraps_by_columns() {
+        // Twelve columns in total: at ten, the last word moves to a second row.
+        let line = Line::from("ok 你好 世界");
+        assert_eq!(line_height(&line, 10), 2);
+        assert_eq!(line_height(&line, 12), 1);
+    }
+
+    #[test]
+    fn words_wrap_at_boundaries() {
+        // Nine columns fit two rows of five by raw width, but the long word is
+        // moved off the first row before it is split.
+        let line = Line::from("ab cdefgh");
+        assert_eq!(line_height(&line, 5), 3);
+    }
+
+    #[test]
+    fn empty_line_takes_one_row() {
+        assert_eq!(line_height(&Line::from(""), 10), 1);
+        assert_eq!(line_height(&Line::from("abc"), 0), 1);
+    }
+
+    #[test]
+    fn timestamps_are_milliseconds() {
+        let expected = Local
+            .timestamp_opt(1_700_000_000, 0)
+            .unwrap()
+            .format("%Y-%m-%d %H:%M")
+            .to_string();
+
+        assert_eq!(format_timestamp(1_700_000_000_000), expected);
+        assert_eq!(format_timestamp(1_700_000_000_999), expected);
+        assert_ne!(format_timestamp(1_700_000_060_000), expected);
+    }
+}
@@ -1,2 +1,4 @@
+pub mod error_log;
 pub mod input;
 pub mod message_list;
+pub mod search_results;
@@ -0,0 +1,44 @@
+use ratatui::{
+    Frame,
+    layout::Rect,
+    style::{Color, Style},
+    text::{Line, Text},
+    widgets::{Block, Borders, Paragraph, Wrap},
+};
+
+use crate::{app::SearchView, ui::components::message_list};
+
+/// Renders the results of a `/search` in place of the chat history.
+pub fn draw(f: &mut Frame, area: Rect, search: &SearchView, current_user: &str) {
+    let (title, lines) = match &search.results {
+        None => (format!(" Searching for \"{}\"… ", search.query), Vec::new()),
+        Some(results) if results.is_empty() => (
+            format!(" Search: \"{}\" (Esc to close) ", search.query),
+            vec![Line::styled(
+                "No messages found.",
+                Style::default().fg(Color::DarkGray),
+            )],
+        ),
+        Some(results) => (
+            format!(
+                " Search: \"{}\" ({} results, Esc to close) ",
+                search.query,
+                results.len()
+            ),
+            results
+                .iter()
+                .map(|msg| message_list::format_line(msg, current_user))
+                .collect(),
+        ),
+    };
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .border_style(Style::default().fg(Color::Cyan));
+    let paragraph = Paragraph::new(Text::from(lines))
+        .block(block)
+        .wrap(Wrap { trim: false });
+
+    f.render_widget(paragraph, area);
+}
@@ -13,6 +13,9 @@ pub fn render(f: &mut Frame, app: &mut App) {
         CurrentScreen::Login => screens::login::draw(f, app),
         CurrentScreen::Chat => screens::chat::draw(f, app),
     }
+    if app.ui.show_error_log {
+        components::error_log::draw(f, &app.ui.error_log);
+    }
 }
 
 pub fn centered_rect(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
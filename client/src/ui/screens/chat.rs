@@ -1,40 +1,78 @@
+use std::time::Instant;
+
 use ratatui::{
     Frame,
-    layout::{Constraint, Layout},
+    layout::{Alignment, Constraint, Layout},
+    style::{Color, Modifier, Style},
+    widgets::Paragraph,
 };
 
 use crate::{
     app::App,
-    ui::components::{input, message_list},
+    ui::components::{input, message_list, search_results},
 };
 
-#[allow(clippy::cast_possible_truncation)]
 pub fn draw(f: &mut Frame, app: &mut App) {
     let area = f.area();
     let chunks = Layout::default()
         .direction(ratatui::layout::Direction::Vertical)
-        .constraints([Constraint::Min(1), Constraint::Length(3)])
+        .constraints([
+            Constraint::Length(u16::from(app.chat.announcement.is_some())),
+            Constraint::Min(1),
+            Constraint::Length(1),
+            Constraint::Length(3),
+        ])
         .split(area);
 
-    message_list::draw(
-        f,
-        chunks[0],
-        &app.chat.messages,
-        &app.chat.username,
-        &mut app.chat.scroll_offset,
-        &mut app.chat.should_request_history,
-        &mut app.chat.history_request_timestamp,
-    );
-
-    input::draw(
-        f,
-        chunks[1],
-        "Message (Esc to quit)",
-        &app.ui.input_buffer,
-        true,
-    );
-    f.set_cursor_position((
-        chunks[1].x + 1 + app.ui.input_buffer.len() as u16,
-        chunks[1].y + 1,
-    ));
+    if let Some(announcement) = &app.chat.announcement {
+        let banner = Paragraph::new(format!(" Announcement: {announcement}")).style(
+            Style::default()
+                .fg(Color::Black)
+                .bg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        );
+        f.render_widget(banner, chunks[0]);
+    }
+
+    if let Some(search) = &app.chat.search {
+        search_results::draw(f, chunks[1], search, &app.chat.username);
+    } else {
+        message_list::draw(f, chunks[1], &mut app.chat);
+    }
+
+    let status_row = Layout::default()
+        .direction(ratatui::layout::Direction::Horizontal)
+        .constraints([Constraint::Min(1), Constraint::Length(12)])
+        .split(chunks[2]);
+    if let Some(latency) = app.chat.latency_ms {
+        let p = Paragraph::new(format!("{latency} ms"))
+            .alignment(Alignment::Right)
+            .style(Style::default().fg(Color::DarkGray));
+        f.render_widget(p, status_row[1]);
+    }
+
+    if let Some(status) = &app.ui.error_message {
+        let p = Paragraph::new(status.as_str()).style(Style::default().fg(Color::Yellow));
+        f.render_widget(p, status_row[0]);
+    } else if !app.chat.typing_users.is_empty() {
+        let mut names: Vec<&str> = app.chat.typing_users.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        let verb = if names.len() == 1 { "is" } else { "are" };
+        let p = Paragraph::new(format!("{} {verb} typing…", names.join(", ")))
+            .style(Style::default().fg(Color::DarkGray));
+        f.render_widget(p, status_row[0]);
+    }
+
+    let near_rate_limit = app
+        .chat
+        .rate_warning_until
+        .is_some_and(|until| until > Instant::now());
+    let title = match (app.chat.search.is_some(), near_rate_limit) {
+        (true, _) => "Message (Esc to close search)",
+        (false, true) => "Message (Esc to quit) • approaching rate limit",
+        (false, false) => "Message (Esc to quit)",
+    };
+
+    let cursor = input::draw(f, chunks[3], title, &app.ui.input_buffer, true);
+    f.set_cursor_position(cursor);
 }
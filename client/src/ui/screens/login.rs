@@ -10,7 +10,6 @@ use crate::{
     ui::{centered_rect, components::input},
 };
 
-#[allow(clippy::cast_possible_truncation)]
 pub fn draw(f: &mut Frame, app: &mut App) {
     let area = f.area();
 
@@ -44,10 +43,10 @@ pub fn draw(f: &mut Frame, app: &mut App) {
     } else {
         &app.login.ip
     };
-    input::draw(
+    let ip_cursor = input::draw(
         f,
         layout[0],
-        "Server IP",
+        "Server Address",
         ip_content,
         app.login.step == LoginStep::Ip,
     );
@@ -57,7 +56,7 @@ pub fn draw(f: &mut Frame, app: &mut App) {
     } else {
         &app.login.user
     };
-    input::draw(
+    let user_cursor = input::draw(
         f,
         layout[1],
         "Username",
@@ -65,16 +64,22 @@ pub fn draw(f: &mut Frame, app: &mut App) {
         app.login.step == LoginStep::Username,
     );
 
-    let pass_display = if app.login.step == LoginStep::Password {
-        app.ui.input_buffer.chars().map(|_| '*').collect::<String>()
+    // Masked either way, so the length entered still shows once focus moves on.
+    let pass_content = if app.login.step == LoginStep::Password {
+        &app.ui.input_buffer
     } else {
-        String::new()
+        &app.login.pass
     };
+    let pass_display: String = pass_content.chars().map(|_| '*').collect();
 
-    input::draw(
+    let pass_cursor = input::draw(
         f,
         layout[2],
-        "Password",
+        if app.login.guest_allowed {
+            "Password (blank for guest)"
+        } else {
+            "Password"
+        },
         &pass_display,
         app.login.step == LoginStep::Password,
     );
@@ -92,13 +97,9 @@ pub fn draw(f: &mut Frame, app: &mut App) {
 
     f.render_widget(bottom_paragraph, layout[3]);
 
-    let active_chunk = match app.login.step {
-        LoginStep::Ip => layout[0],
-        LoginStep::Username => layout[1],
-        LoginStep::Password => layout[2],
-    };
-    f.set_cursor_position((
-        active_chunk.x + 1 + app.ui.input_buffer.len() as u16,
-        active_chunk.y + 1,
-    ));
+    f.set_cursor_position(match app.login.step {
+        LoginStep::Ip => ip_cursor,
+        LoginStep::Username => user_cursor,
+        LoginStep::Password => pass_cursor,
+    });
 }
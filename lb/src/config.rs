@@ -1,4 +1,75 @@
-use std::env;
+use std::{env, time::Duration};
+
+use crate::{
+    core::DEFAULT_IDLE_TIMEOUT,
+    rate_limiter::{BandwidthLimit, DEFAULT_BYTES_PER_SEC},
+    state::lb::DEFAULT_MAX_CONNECTIONS_PER_IP,
+};
+
+/// How the lb picks a backend for a new client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    /// Backend with the fewest active connections.
+    LeastConn,
+    /// Backend owning the client IP on a consistent-hash ring.
+    ConsistentHash,
+}
+
+/// Which PROXY protocol header the lb starts backend connections with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// A text line with the client's address, which any PROXY-aware server reads.
+    V1,
+    /// A binary header that also carries the connection's id, so the servers
+    /// can log under the same id as the lb.
+    V2,
+}
+
+/// Slack on top of one heartbeat before a node counts as gone: heartbeat
+/// scores are whole seconds, and beats jitter by a scheduling tick or two.
+const STALENESS_SLACK: Duration = Duration::from_secs(2);
+
+/// How often the lb polls for backends and probes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub discovery_interval: Duration,
+    pub health_interval: Duration,
+    pub health_timeout: Duration,
+    /// How often chat servers refresh their registration; must match theirs.
+    pub node_heartbeat: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            discovery_interval: Duration::from_secs(5),
+            health_interval: Duration::from_secs(3),
+            health_timeout: Duration::from_millis(500),
+            node_heartbeat: Duration::from_secs(3),
+        }
+    }
+}
+
+impl Timing {
+    /// Seconds since its last heartbeat after which a node is dropped.
+    ///
+    /// Derived from the heartbeat so the window can never be shorter than the
+    /// gap between beats, which would drop healthy nodes.
+    pub fn staleness_window(&self) -> u64 {
+        let window = self.node_heartbeat + STALENESS_SLACK;
+        window.as_secs() + u64::from(window.subsec_nanos() > 0)
+    }
+}
+
+/// Reads a millisecond duration from `key`, falling back to `default` when unset,
+/// invalid, or zero.
+fn millis_or(key: &str, default: Duration) -> Duration {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&ms| ms > 0)
+        .map_or(default, Duration::from_millis)
+}
 
 #[derive(Debug)]
 pub struct Config {
@@ -8,6 +79,25 @@ pub struct Config {
     pub redis_url: String,
     pub tls_cert_path: String,
     pub tls_key_path: String,
+    pub require_client_cert: bool,
+    pub client_ca_path: String,
+    /// Oldest TLS version clients may negotiate, `1.2` or `1.3`.
+    pub tls_min_version: String,
+    pub strategy: BalanceStrategy,
+    pub max_connections_per_ip: usize,
+    pub bandwidth: BandwidthLimit,
+    pub timing: Timing,
+    /// Closes proxied connections that pass no bytes for this long.
+    pub idle_timeout: Duration,
+    /// Re-encrypt traffic to backends instead of forwarding it in plaintext.
+    pub backend_tls: bool,
+    /// CA bundle trusted for backend certificates when `backend_tls` is set.
+    pub backend_ca_path: String,
+    /// Send clients a `NoBackends` error before closing when no backend is up.
+    pub notify_no_backends: bool,
+    /// Announce each client's address to backends with a PROXY protocol
+    /// header of this version; v2 also carries the connection id.
+    pub proxy_protocol: Option<ProxyProtocol>,
 }
 
 impl Config {
@@ -26,6 +116,55 @@ impl Config {
             env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
         let tls_cert_path = env::var("TLS_CERT").unwrap_or_else(|_| "tls/server.cert".to_string());
         let tls_key_path = env::var("TLS_KEY").unwrap_or_else(|_| "tls/server.key".to_string());
+        let require_client_cert = env::var("REQUIRE_CLIENT_CERT")
+            .map(|v| matches!(v.as_str(), "1" | "true"))
+            .unwrap_or(false);
+        let tls_min_version = env::var(tls::MIN_VERSION_ENV).unwrap_or_else(|_| "1.2".to_string());
+        let strategy = match env::var("LB_STRATEGY").as_deref() {
+            Ok("consistent_hash") => BalanceStrategy::ConsistentHash,
+            _ => BalanceStrategy::LeastConn,
+        };
+        let max_connections_per_ip = env::var("LB_MAX_CONNECTIONS_PER_IP")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP);
+        let bandwidth = BandwidthLimit::new(
+            env::var("LB_BANDWIDTH_BYTES_PER_SEC")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_BYTES_PER_SEC),
+            env::var("LB_BANDWIDTH_BURST_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok()),
+        );
+        let defaults = Timing::default();
+        let timing = Timing {
+            discovery_interval: millis_or("DISCOVERY_INTERVAL_MS", defaults.discovery_interval),
+            health_interval: millis_or("HEALTH_INTERVAL_MS", defaults.health_interval),
+            health_timeout: millis_or("HEALTH_TIMEOUT_MS", defaults.health_timeout),
+            node_heartbeat: millis_or("NODE_HEARTBEAT_MS", defaults.node_heartbeat),
+        };
+        let idle_timeout = env::var("LB_IDLE_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&secs| secs > 0)
+            .map_or(DEFAULT_IDLE_TIMEOUT, Duration::from_secs);
+        let client_ca_path =
+            env::var("TLS_CLIENT_CA").unwrap_or_else(|_| "tls/client-ca.cert".to_string());
+        let backend_tls = env::var("LB_BACKEND_TLS")
+            .map(|v| matches!(v.as_str(), "1" | "true"))
+            .unwrap_or(false);
+        let backend_ca_path =
+            env::var("LB_BACKEND_CA").unwrap_or_else(|_| "tls/ca.cert".to_string());
+        let notify_no_backends = env::var("LB_NOTIFY_NO_BACKENDS")
+            .map(|v| matches!(v.as_str(), "1" | "true"))
+            .unwrap_or(true);
+        // `true` predates v2, so it still means v1.
+        let proxy_protocol = match env::var("LB_PROXY_PROTOCOL").as_deref() {
+            Ok("1" | "true" | "v1") => Some(ProxyProtocol::V1),
+            Ok("v2") => Some(ProxyProtocol::V2),
+            _ => None,
+        };
 
         Self {
             host,
@@ -34,6 +173,61 @@ impl Config {
             redis_url,
             tls_cert_path,
             tls_key_path,
+            require_client_cert,
+            client_ca_path,
+            tls_min_version,
+            strategy,
+            max_connections_per_ip,
+            bandwidth,
+            timing,
+            idle_timeout,
+            backend_tls,
+            backend_ca_path,
+            notify_no_backends,
+            proxy_protocol,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timing;
+    use std::time::Duration;
+
+    fn with_heartbeat(node_heartbeat: Duration) -> Timing {
+        Timing {
+            node_heartbeat,
+            ..Timing::default()
+        }
+    }
+
+    #[test]
+    fn staleness_window_follows_heartbeat() {
+        assert_eq!(Timing::default().staleness_window(), 5);
+        assert_eq!(
+            with_heartbeat(Duration::from_secs(10)).staleness_window(),
+            12
+        );
+    }
+
+    #[test]
+    fn staleness_window_rounds_up_partial_seconds() {
+        assert_eq!(
+            with_heartbeat(Duration::from_millis(500)).staleness_window(),
+            3
+        );
+        assert_eq!(
+            with_heartbeat(Duration::from_millis(2100)).staleness_window(),
+            5
+        );
+    }
+
+    #[test]
+    fn staleness_window_outlasts_heartbeat() {
+        for ms in [1, 999, 1000, 2500, 30_000] {
+            let heartbeat = Duration::from_millis(ms);
+            let window = Duration::from_secs(with_heartbeat(heartbeat).staleness_window());
+            assert!(window > heartbeat, "{ms}ms heartbeat");
         }
     }
 }
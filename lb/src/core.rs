@@ -1,64 +1,192 @@
-use crate::rate_limiter::RateLimitedStream;
+use crate::config::{BalanceStrategy, ProxyProtocol, Timing};
+use crate::idle::{Activity, IdleStream};
+use crate::outcome::{Outcome, OutcomeStream};
+use crate::rate_limiter::{BandwidthLimit, RateLimitedStream};
 use crate::state::lb::LoadBalancerState;
 use anyhow::{Context, Result};
 use metrics::counter;
+use protocol::{ChatError, McsCodec, Message, NodeAddr};
 use redis::AsyncCommands;
-use rustls::ServerConfig;
-use rustls_pemfile::{Item, certs, read_one};
-use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{
+    ClientConfig, RootCertStore, ServerConfig, SupportedProtocolVersion,
+    server::WebPkiClientVerifier,
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName};
 use std::{
-    fs::File,
-    io::BufReader,
+    collections::HashSet,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::Path,
     sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
 use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     time::{self, Duration},
 };
-use tokio_rustls::{TlsAcceptor, server::TlsStream};
-use tracing::{error, info, warn};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_util::{bytes::BytesMut, codec::Encoder};
+use tracing::{Instrument, debug, error, info, info_span, warn};
+use uuid::Uuid;
+
+/// Opens every PROXY protocol v2 header.
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The PROXY v2 TLV type for an id the proxy gave the connection.
+const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+
+/// How long closing a finished connection may take before it is dropped anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a connection may pass no bytes either way unless configured otherwise.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How each proxied connection is handled, cloned into every connection's task.
+#[derive(Clone)]
+struct ConnectionSettings {
+    strategy: BalanceStrategy,
+    /// Re-encrypts traffic to backends when set; plaintext otherwise.
+    backend_tls: Option<TlsConnector>,
+    /// Tells backends each client's address in a PROXY protocol header of
+    /// this version, along with the connection's id for v2.
+    proxy_protocol: Option<ProxyProtocol>,
+    idle_timeout: Duration,
+    /// Whether clients hear why they were turned away when no backend is up.
+    notify_no_backends: bool,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            strategy: BalanceStrategy::LeastConn,
+            backend_tls: None,
+            proxy_protocol: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            notify_no_backends: true,
+        }
+    }
+}
+
+/// Where the client-facing TLS identity lives and which clients it accepts.
+pub struct TlsOptions {
+    pub cert_path: String,
+    pub key_path: String,
+    /// Requires client certificates signed by this CA bundle when set.
+    pub client_ca_path: Option<String>,
+    /// Oldest TLS version clients may negotiate, `1.2` or `1.3`.
+    pub min_version: String,
+}
 
 pub struct LoadBalancer {
     state: LoadBalancerState,
     redis_url: String,
     bind_addr: String,
     tls_acceptor: TlsAcceptor,
+    settings: ConnectionSettings,
+    timing: Timing,
 }
 
 impl LoadBalancer {
     pub fn new(
         bind_addr: String,
         redis_url: String,
-        tls_cert_path: String,
-        tls_key_path: String,
+        tls: TlsOptions,
+        strategy: BalanceStrategy,
+        max_connections_per_ip: usize,
     ) -> Self {
-        let certs = Self::load_certs(&tls_cert_path).expect("failed to load certs");
-        let key = Self::load_key(&tls_key_path).expect("failed to load private key");
-        let tls_config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .expect("bad certificate/key combination");
+        let identity = tls::load_identity(
+            Path::new(&tls.cert_path),
+            Path::new(&tls.key_path),
+            tls::key_passphrase().as_deref(),
+        )
+        .unwrap_or_else(|e| panic!("failed to load TLS identity: {e}"));
+        let client_roots = tls.client_ca_path.map(|path| {
+            let mut roots = RootCertStore::empty();
+            let certs = tls::load_certs(Path::new(&path))
+                .unwrap_or_else(|e| panic!("failed to load client CA: {e}"));
+            for cert in certs {
+                roots.add(cert).expect("invalid client CA certificate");
+            }
+            info!(%path, "requiring client certificates");
+            roots
+        });
+        let versions = tls::protocol_versions(&tls.min_version)
+            .unwrap_or_else(|e| panic!("bad TLS configuration: {e}"));
+        info!(min_version = %tls.min_version, "accepting TLS clients");
+        let tls_config =
+            Self::build_tls_config(identity.chain, identity.key, client_roots, versions)
+                .expect("bad TLS configuration");
         let tls_acceptor = TlsAcceptor::from(Arc::new(tls_config));
 
         Self {
-            state: LoadBalancerState::new(),
+            state: LoadBalancerState::new().with_max_connections_per_ip(max_connections_per_ip),
             redis_url,
             bind_addr,
             tls_acceptor,
+            settings: ConnectionSettings {
+                strategy,
+                ..ConnectionSettings::default()
+            },
+            timing: Timing::default(),
+        }
+    }
+
+    pub const fn with_timing(mut self, timing: Timing) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    pub const fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.settings.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub const fn with_no_backends_notice(mut self, notify: bool) -> Self {
+        self.settings.notify_no_backends = notify;
+        self
+    }
+
+    /// Caps how fast each client IP may send.
+    pub fn with_bandwidth_limit(mut self, bandwidth: BandwidthLimit) -> Self {
+        self.state = self.state.with_bandwidth_limit(bandwidth);
+        self
+    }
+
+    /// Prefixes each backend connection with a PROXY protocol header of `version`.
+    pub const fn with_proxy_protocol(mut self, version: Option<ProxyProtocol>) -> Self {
+        self.settings.proxy_protocol = version;
+        self
+    }
+
+    /// Connects to backends over TLS, trusting the CA bundle at `ca_path`.
+    pub fn with_backend_tls(mut self, ca_path: &str) -> Self {
+        let mut roots = RootCertStore::empty();
+        let certs = tls::load_certs(Path::new(ca_path))
+            .unwrap_or_else(|e| panic!("failed to load backend CA: {e}"));
+        for cert in certs {
+            roots.add(cert).expect("invalid backend CA certificate");
         }
+        info!(%ca_path, "encrypting backend connections");
+        self.settings.backend_tls = Some(Self::backend_connector(roots));
+        self
+    }
+
+    fn backend_connector(roots: RootCertStore) -> TlsConnector {
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
     }
 
     pub async fn run(&self) -> Result<()> {
         let state_discovery = self.state.clone();
         let redis_url = self.redis_url.clone();
+        let timing = self.timing;
         tokio::spawn(async move {
-            Self::discovery_task(state_discovery, redis_url).await;
+            Self::discovery_task(state_discovery, redis_url, timing).await;
         });
 
         let state_health = self.state.clone();
         tokio::spawn(async move {
-            Self::health_check_task(state_health).await;
+            Self::health_check_task(state_health, timing).await;
         });
 
         let listener = TcpListener::bind(&self.bind_addr).await?;
@@ -76,22 +204,34 @@ impl LoadBalancer {
                 warn!(%ip, "connection rate limit exceeded");
                 continue;
             }
+            let Some(permit) = client_state.try_acquire_connection() else {
+                warn!(%ip, "concurrent connection limit exceeded");
+                counter!("lb_connections_rejected_concurrency").increment(1);
+                continue;
+            };
 
             let acceptor = self.tls_acceptor.clone();
+            let settings = self.settings.clone();
 
             tokio::spawn(async move {
+                // Released when the connection ends, however it ends.
+                let _permit = permit;
                 match acceptor.accept(client_socket).await {
                     Ok(tls_stream) => {
                         let limited_client_socket = RateLimitedStream::new(
                             tls_stream,
                             client_state.bandwidth_limiter.clone(),
+                            Some(ip.to_string()),
                         );
 
-                        if let Err(e) =
-                            Self::handle_connection(lb_state, limited_client_socket).await
-                        {
-                            warn!(%client_addr, err=?e, "failed to establish connection")
-                        }
+                        // Logged under the connection's span by `handle_connection`.
+                        let _ = Self::handle_connection(
+                            lb_state,
+                            limited_client_socket,
+                            client_addr,
+                            settings,
+                        )
+                        .await;
                     }
                     Err(e) => warn!(%client_addr, err=?e, "TLS handshake failed"),
                 }
@@ -99,31 +239,189 @@ impl LoadBalancer {
         }
     }
 
-    async fn handle_connection(
+    /// Routes and proxies one client connection inside a `connection` span
+    /// carrying a fresh `conn_id`, which backends also get when the PROXY
+    /// protocol is on, so both sides' logs can be matched up.
+    async fn handle_connection<C>(
         state: LoadBalancerState,
-        mut limited_client_socket: RateLimitedStream<TlsStream<TcpStream>>,
-    ) -> Result<()> {
+        client: C,
+        client_addr: SocketAddr,
+        settings: ConnectionSettings,
+    ) -> Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let conn_id = Uuid::new_v4();
+        let span = info_span!("connection", %conn_id, %client_addr);
+        async {
+            let result = Self::route(state, client, client_addr, conn_id, settings).await;
+            if let Err(e) = &result {
+                warn!(err=?e, "failed to establish connection");
+            }
+            result
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn route<C>(
+        state: LoadBalancerState,
+        mut client: C,
+        client_addr: SocketAddr,
+        conn_id: Uuid,
+        settings: ConnectionSettings,
+    ) -> Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
         counter!("lb_total_connections").increment(1);
 
-        let backend_addr = match state.next_backend().await {
-            Some(addr) => addr,
-            None => {
-                return Ok(());
+        let backend = match settings.strategy {
+            BalanceStrategy::LeastConn => state.next_backend().await,
+            BalanceStrategy::ConsistentHash => state.next_backend_for(client_addr.ip()).await,
+        };
+        let Some(backend_addr) = backend else {
+            warn!("no backends available");
+            counter!("lb_connections_rejected_no_backends").increment(1);
+            if settings.notify_no_backends {
+                Self::reject_no_backends(&mut client).await?;
             }
+            return Ok(());
         };
 
-        let mut server_socket = TcpStream::connect(&backend_addr).await?;
-        state.inc_backend_connection(&backend_addr).await;
+        debug!(backend=%backend_addr, "routing connection");
+        Self::proxy(
+            &state,
+            &mut client,
+            &backend_addr,
+            client_addr,
+            conn_id,
+            &settings,
+        )
+        .await
+    }
+
+    /// Writes a framed `NoBackends` error to `client` and closes it, so the
+    /// client can explain the hang-up instead of seeing a bare close.
+    async fn reject_no_backends<C>(client: &mut C) -> Result<()>
+    where
+        C: AsyncWrite + Unpin,
+    {
+        let mut frame = BytesMut::new();
+        McsCodec::new().encode(Message::Error(ChatError::NoBackends), &mut frame)?;
+
+        let _ = time::timeout(SHUTDOWN_TIMEOUT, async {
+            client.write_all(&frame).await?;
+            client.shutdown().await
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Connects to the backend, announcing `client_addr` and `conn_id` first if
+    /// the PROXY protocol is on and switching to TLS if `backend_tls` is set,
+    /// then pipes `client` to it.
+    async fn proxy<C>(
+        state: &LoadBalancerState,
+        client: &mut C,
+        backend_addr: &NodeAddr,
+        client_addr: SocketAddr,
+        conn_id: Uuid,
+        settings: &ConnectionSettings,
+    ) -> Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let idle_timeout = settings.idle_timeout;
+        let socket = match Self::connect_backend(backend_addr, client_addr, conn_id, settings).await
+        {
+            Ok(socket) => socket,
+            Err(e) => {
+                state.record_backend_failure(backend_addr).await;
+                return Err(e.into());
+            }
+        };
+        let Some(connector) = &settings.backend_tls else {
+            return Self::pipe(state, client, socket, backend_addr, idle_timeout).await;
+        };
+
+        let server_name = backend_server_name(backend_addr)?;
+        match connector.connect(server_name, socket).await {
+            Ok(tls_socket) => {
+                Self::pipe(state, client, tls_socket, backend_addr, idle_timeout).await
+            }
+            Err(e) => {
+                state.record_backend_failure(backend_addr).await;
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn connect_backend(
+        backend_addr: &NodeAddr,
+        client_addr: SocketAddr,
+        conn_id: Uuid,
+        settings: &ConnectionSettings,
+    ) -> std::io::Result<TcpStream> {
+        let mut socket = TcpStream::connect(backend_addr.as_str()).await?;
+        if let Some(version) = settings.proxy_protocol {
+            let backend = socket.peer_addr()?;
+            let header = match version {
+                ProxyProtocol::V1 => proxy_header_v1(client_addr, backend).into_bytes(),
+                ProxyProtocol::V2 => proxy_header_v2(client_addr, backend, conn_id),
+            };
+            socket.write_all(&header).await?;
+        }
+        Ok(socket)
+    }
 
-        let result =
-            tokio::io::copy_bidirectional(&mut limited_client_socket, &mut server_socket).await;
-        state.dec_backend_connection(&backend_addr).await;
+    /// Pipes `client` to `server_socket` until either side closes or no bytes
+    /// move for `idle_timeout`, then shuts both down.
+    ///
+    /// Only errors on the backend's side count against its circuit, and a
+    /// session it answered without one closes the circuit.
+    async fn pipe<C, S>(
+        state: &LoadBalancerState,
+        client: &mut C,
+        server_socket: S,
+        backend_addr: &NodeAddr,
+        idle_timeout: Duration,
+    ) -> Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let _connection = state.track_backend_connection(backend_addr);
+        let activity = Activity::new();
+        let mut client = IdleStream::new(client, activity.clone());
+        let outcome = Outcome::new();
+        let mut server_socket = OutcomeStream::new(server_socket, outcome.clone());
+        let result = tokio::select! {
+            result = tokio::io::copy_bidirectional(&mut client, &mut server_socket) => result,
+            () = activity.idle_for(idle_timeout) => {
+                info!(backend=%backend_addr, timeout=?idle_timeout, "closing idle connection");
+                counter!("lb_idle_timeouts_total").increment(1);
+                Ok((0, 0))
+            }
+        };
+        if outcome.failed() {
+            state.record_backend_failure(backend_addr).await;
+        } else if outcome.answered() {
+            state.record_backend_success(backend_addr).await;
+        }
+
+        // Sends the client a TLS close_notify rather than just dropping the socket.
+        let _ = time::timeout(SHUTDOWN_TIMEOUT, async {
+            let _ = client.shutdown().await;
+            let _ = server_socket.shutdown().await;
+        })
+        .await;
 
         let _ = result?;
         Ok(())
     }
 
-    async fn discovery_task(state: LoadBalancerState, redis_url: String) {
+    async fn discovery_task(state: LoadBalancerState, redis_url: String, timing: Timing) {
         let client = match redis::Client::open(redis_url) {
             Ok(c) => c,
             Err(e) => {
@@ -132,13 +430,13 @@ impl LoadBalancer {
             }
         };
 
-        let mut interval = time::interval(Duration::from_secs(5));
+        let mut interval = time::interval(timing.discovery_interval);
         loop {
             interval.tick().await;
             let mut conn = match client.get_multiplexed_async_connection().await {
                 Ok(c) => c,
                 Err(e) => {
-                    warn!(err=?e, "redis connection failed, retrying in 5s");
+                    warn!(err=?e, retry_in=?timing.discovery_interval, "redis connection failed");
                     continue;
                 }
             };
@@ -147,80 +445,689 @@ impl LoadBalancer {
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs();
-            let min_score = now.saturating_sub(5);
+            let min_score = now.saturating_sub(timing.staleness_window());
 
-            let redis_backends: Vec<String> =
-                match conn.zrangebyscore("mcs:node", min_score, "+inf").await {
-                    Ok(s) => s,
+            let redis_backends = match conn.zrangebyscore("mcs:node", min_score, "+inf").await {
+                Ok(members) => parse_members(members),
+                Err(e) => {
+                    warn!(err=?e, "failed to fetch servers from redis");
+                    continue;
+                }
+            };
+
+            // Each node's drain flag, then its connection cap, if it set one.
+            let flags: Vec<Option<String>> = if redis_backends.is_empty() {
+                Vec::new()
+            } else {
+                let keys: Vec<String> = redis_backends
+                    .iter()
+                    .flat_map(|addr| {
+                        [
+                            format!("mcs:node:drain:{addr}"),
+                            format!("mcs:node:max_connections:{addr}"),
+                        ]
+                    })
+                    .collect();
+                match conn.mget(&keys).await {
+                    Ok(f) => f,
                     Err(e) => {
-                        warn!(err=?e, "failed to fetch servers from redis");
+                        warn!(err=?e, "failed to fetch node flags from redis");
                         continue;
                     }
-                };
-
-            let current_backends = state.get_backend_addrs().await;
-            for addr in &redis_backends {
-                if !current_backends.contains(addr) {
-                    info!(%addr, "adding backend to registry");
-                    state.add_backend(addr.clone(), 0).await;
                 }
-            }
+            };
+            let draining: HashSet<NodeAddr> = redis_backends
+                .iter()
+                .zip(flags.chunks(2))
+                .filter(|(_, flags)| flags[0].is_some())
+                .map(|(addr, _)| addr.clone())
+                .collect();
 
-            for addr in &current_backends {
-                if !redis_backends.contains(addr) {
-                    warn!(%addr, "removing backend from registery");
-                    state.remove_backend(addr).await;
-                }
+            state.sync_backends(&redis_backends, &draining).await;
+            for (addr, flags) in redis_backends.iter().zip(flags.chunks(2)) {
+                let max_connections = flags.get(1).and_then(|cap| cap.as_deref()?.parse().ok());
+                state.set_max_connections(addr, max_connections).await;
             }
         }
     }
 
-    async fn health_check_task(state: LoadBalancerState) {
-        let mut interval = time::interval(Duration::from_secs(3));
+    async fn health_check_task(state: LoadBalancerState, timing: Timing) {
+        let mut interval = time::interval(timing.health_interval);
 
         loop {
             interval.tick().await;
             let backend_addrs = state.get_backend_addrs().await;
             for addr in backend_addrs {
-                let timeout_duration = Duration::from_millis(500);
                 let connect_result =
-                    time::timeout(timeout_duration, TcpStream::connect(&addr)).await;
+                    time::timeout(timing.health_timeout, TcpStream::connect(addr.as_str())).await;
 
                 let is_healthy = match connect_result {
                     Ok(Ok(_)) => true,
                     Ok(Err(_)) | Err(_) => false,
                 };
                 state.set_health(&addr, is_healthy).await;
-                if !is_healthy {
+                if is_healthy {
+                    state.record_probe_success(&addr).await;
+                } else {
                     warn!(%addr, "backend failed health check");
-                    counter!("lb_backend_health_check_failures", "backend" => addr.clone())
+                    counter!("lb_backend_health_check_failures", "backend" => addr.to_string())
                         .increment(1);
                 }
             }
         }
     }
 
-    fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
-        let file = File::open(path).context(format!("failed to open {}", path))?;
-        let mut reader = BufReader::new(file);
-        let certs = certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
-        Ok(certs)
+    /// Builds the TLS server config, verifying client certificates when `client_roots` is set.
+    fn build_tls_config(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        client_roots: Option<RootCertStore>,
+        versions: &[&'static SupportedProtocolVersion],
+    ) -> Result<ServerConfig> {
+        let builder = ServerConfig::builder_with_protocol_versions(versions);
+        let builder = match client_roots {
+            Some(roots) => {
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(builder.with_single_cert(certs, key)?)
+    }
+}
+
+/// A PROXY protocol v1 header for a connection from `src`. The destination is
+/// the backend itself; servers only use the source. Mixed address families are
+/// sent as IPv4-mapped IPv6.
+fn proxy_header_v1(src: SocketAddr, dst: SocketAddr) -> String {
+    let (sport, dport) = (src.port(), dst.port());
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => format!("PROXY TCP4 {s} {d} {sport} {dport}\r\n"),
+        (s, d) => format!(
+            "PROXY TCP6 {} {} {sport} {dport}\r\n",
+            to_ipv6(s),
+            to_ipv6(d)
+        ),
     }
+}
 
-    fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
-        let file = File::open(path).context(format!("failed to open {}", path))?;
-        let mut reader = BufReader::new(file);
+/// A PROXY protocol v2 header like [`proxy_header_v1`]'s, also carrying
+/// `conn_id` as its unique id.
+fn proxy_header_v2(src: SocketAddr, dst: SocketAddr, conn_id: Uuid) -> Vec<u8> {
+    let mut addrs = Vec::with_capacity(36);
+    let family = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            addrs.extend(s.octets());
+            addrs.extend(d.octets());
+            0x11
+        }
+        (s, d) => {
+            addrs.extend(to_ipv6(s).octets());
+            addrs.extend(to_ipv6(d).octets());
+            0x21
+        }
+    };
+    addrs.extend(src.port().to_be_bytes());
+    addrs.extend(dst.port().to_be_bytes());
 
-        loop {
-            match read_one(&mut reader)? {
-                Some(Item::Pkcs1Key(key)) => return Ok(key.into()),
-                Some(Item::Pkcs8Key(key)) => return Ok(key.into()),
-                Some(Item::Sec1Key(key)) => return Ok(key.into()),
-                None => break,
-                _ => {}
+    let id = conn_id.to_string();
+    let mut header = PROXY_V2_SIGNATURE.to_vec();
+    // Version 2, PROXY command, then TCP over the address family.
+    header.extend([0x21, family]);
+    header.extend(((addrs.len() + 3 + id.len()) as u16).to_be_bytes());
+    header.extend(addrs);
+    header.push(PP2_TYPE_UNIQUE_ID);
+    header.extend((id.len() as u16).to_be_bytes());
+    header.extend(id.as_bytes());
+    header
+}
+
+const fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+/// Parses the node addresses registered in redis, skipping any that couldn't
+/// be dialed, so a bad registration never reaches backend selection.
+fn parse_members(members: Vec<String>) -> Vec<NodeAddr> {
+    members
+        .into_iter()
+        .filter_map(|member| match member.parse() {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                warn!(err=%e, "ignoring malformed node registration");
+                None
             }
+        })
+        .collect()
+}
+
+/// The name a backend's certificate must carry: the host it registered under.
+fn backend_server_name(addr: &NodeAddr) -> Result<ServerName<'static>> {
+    ServerName::try_from(addr.host().to_string())
+        .with_context(|| format!("backend address {addr} has no valid TLS server name"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ConnectionSettings, LoadBalancer, PROXY_V2_SIGNATURE, backend_server_name, parse_members,
+        proxy_header_v1, proxy_header_v2,
+    };
+    use crate::config::ProxyProtocol;
+    use crate::state::lb::LoadBalancerState;
+    use futures::StreamExt;
+    use logging::testing::{LogBuffer, record_metrics};
+    use metrics_util::debugging::DebugValue;
+    use protocol::{ChatError, McsCodec, Message, NodeAddr};
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use rustls::{
+        ClientConfig, RootCertStore,
+        crypto::ring,
+        version::{TLS12, TLS13},
+    };
+    use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+    use std::{
+        io,
+        net::SocketAddr,
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+        time::{Duration, Instant},
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+        net::TcpListener,
+    };
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+    use tokio_util::codec::FramedRead;
+    use uuid::Uuid;
+
+    fn client_addr() -> SocketAddr {
+        "203.0.113.7:5555".parse().unwrap()
+    }
+
+    /// A client whose connection fails on the first read.
+    struct BrokenClient;
+
+    impl AsyncRead for BrokenClient {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    impl AsyncWrite for BrokenClient {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
         }
 
-        Err(anyhow::anyhow!("no valid private key found in {}", path))
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn proxy_silent_client(timeout: Duration) {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: NodeAddr = backend.local_addr().unwrap().to_string().parse().unwrap();
+        let backend = tokio::spawn(async move {
+            let (mut socket, _) = backend.accept().await.unwrap();
+            let mut buf = Vec::new();
+            socket.read_to_end(&mut buf).await.unwrap();
+        });
+        let state = LoadBalancerState::new();
+        state.add_backend(addr.clone(), 0).await;
+        let (mut client, mut lb_side) = tokio::io::duplex(64);
+
+        let start = Instant::now();
+        let settings = ConnectionSettings {
+            idle_timeout: timeout,
+            ..ConnectionSettings::default()
+        };
+        LoadBalancer::proxy(
+            &state,
+            &mut lb_side,
+            &addr,
+            client_addr(),
+            Uuid::nil(),
+            &settings,
+        )
+        .await
+        .unwrap();
+
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(state.backend_connections(&addr), Some(0));
+        backend.await.unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(
+            client.read(&mut buf).await.unwrap(),
+            0,
+            "client should see EOF"
+        );
+    }
+
+    #[test]
+    fn silent_client_is_closed_after_idle_timeout() {
+        let ((), recorded) = record_metrics(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(proxy_silent_client(Duration::from_millis(200)));
+        });
+
+        assert_eq!(
+            recorded.get("lb_idle_timeouts_total"),
+            Some(&DebugValue::Counter(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn client_is_told_when_there_are_no_backends() {
+        let (client, lb_side) = tokio::io::duplex(1024);
+
+        LoadBalancer::handle_connection(
+            LoadBalancerState::new(),
+            lb_side,
+            client_addr(),
+            ConnectionSettings::default(),
+        )
+        .await
+        .unwrap();
+
+        let mut framed = FramedRead::new(client, McsCodec::new());
+        assert!(matches!(
+            framed.next().await,
+            Some(Ok(Message::Error(ChatError::NoBackends)))
+        ));
+        assert!(framed.next().await.is_none(), "client should see EOF");
+    }
+
+    #[tokio::test]
+    async fn copy_error_releases_backend_connection() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: NodeAddr = backend.local_addr().unwrap().to_string().parse().unwrap();
+        let backend = tokio::spawn(async move {
+            let (mut socket, _) = backend.accept().await.unwrap();
+            let mut buf = Vec::new();
+            socket.read_to_end(&mut buf).await.unwrap();
+        });
+        let state = LoadBalancerState::new();
+        state.add_backend(addr.clone(), 0).await;
+
+        let result = LoadBalancer::proxy(
+            &state,
+            &mut BrokenClient,
+            &addr,
+            client_addr(),
+            Uuid::nil(),
+            &ConnectionSettings::default(),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(state.backend_connections(&addr), Some(0));
+        // The backend sees a clean close rather than waiting on a dead client.
+        backend.await.unwrap();
+    }
+
+    struct TestPki {
+        ca: CertifiedIssuer<'static, KeyPair>,
+    }
+
+    impl TestPki {
+        fn new() -> Self {
+            let _ = ring::default_provider().install_default();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+            Self { ca }
+        }
+
+        /// Issues a leaf certificate for `name` signed by this CA.
+        fn issue(&self, name: &str) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+            let key = KeyPair::generate().unwrap();
+            let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            let cert = params.signed_by(&key, &self.ca).unwrap();
+            let key = PrivatePkcs8KeyDer::from(key.serialize_der()).into();
+            (vec![cert.der().clone()], key)
+        }
+
+        fn roots(&self) -> RootCertStore {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.der().clone()).unwrap();
+            roots
+        }
+    }
+
+    /// Runs one handshake and returns whether the server accepted the client.
+    async fn handshake(pki: &TestPki, client_cert: bool) -> bool {
+        let (certs, key) = pki.issue("localhost");
+        let server_config =
+            LoadBalancer::build_tls_config(certs, key, Some(pki.roots()), rustls::ALL_VERSIONS)
+                .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let builder = ClientConfig::builder().with_root_certificates(pki.roots());
+        let client_config = if client_cert {
+            let (certs, key) = pki.issue("client");
+            builder.with_client_auth_cert(certs, key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        let connector = TlsConnector::from(Arc::new(client_config));
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server_io).await?;
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            Ok::<_, std::io::Error>(buf)
+        });
+
+        let domain = ServerName::try_from("localhost").unwrap();
+        // Keep the client open until the server finishes so its writes don't hit a closed pipe.
+        let _client = match connector.connect(domain, client_io).await {
+            Ok(mut stream) => {
+                let _ = stream.write_all(b"ping").await;
+                let _ = stream.flush().await;
+                Some(stream)
+            }
+            Err(_) => None,
+        };
+
+        matches!(server.await.unwrap(), Ok(buf) if &buf == b"ping")
+    }
+
+    #[tokio::test]
+    async fn client_without_cert_is_rejected() {
+        let pki = TestPki::new();
+        assert!(!handshake(&pki, false).await);
+    }
+
+    #[tokio::test]
+    async fn client_with_valid_cert_is_accepted() {
+        let pki = TestPki::new();
+        assert!(handshake(&pki, true).await);
+    }
+
+    #[tokio::test]
+    async fn client_with_cert_from_other_ca_is_rejected() {
+        let pki = TestPki::new();
+        let other = TestPki::new();
+        let (certs, key) = pki.issue("localhost");
+        let server_config =
+            LoadBalancer::build_tls_config(certs, key, Some(other.roots()), rustls::ALL_VERSIONS)
+                .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let (client_certs, client_key) = pki.issue("client");
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(pki.roots())
+            .with_client_auth_cert(client_certs, client_key)
+            .unwrap();
+        let connector = TlsConnector::from(Arc::new(client_config));
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move { acceptor.accept(server_io).await.map(|_| ()) });
+        let domain = ServerName::try_from("localhost").unwrap();
+        let _client = connector.connect(domain, client_io).await;
+
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn tls13_minimum_turns_away_tls12_clients() {
+        let pki = TestPki::new();
+        let versions = tls::protocol_versions("1.3").unwrap();
+        let (certs, key) = pki.issue("localhost");
+        let server_config = LoadBalancer::build_tls_config(certs, key, None, versions).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        for (client_versions, accepted) in [(&[&TLS12][..], false), (&[&TLS13][..], true)] {
+            let client_config = ClientConfig::builder_with_protocol_versions(client_versions)
+                .with_root_certificates(pki.roots())
+                .with_no_client_auth();
+            let connector = TlsConnector::from(Arc::new(client_config));
+
+            let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+            let acceptor = acceptor.clone();
+            let server = tokio::spawn(async move { acceptor.accept(server_io).await.map(|_| ()) });
+            let domain = ServerName::try_from("localhost").unwrap();
+            let _client = connector.connect(domain, client_io).await;
+
+            assert_eq!(
+                server.await.unwrap().is_ok(),
+                accepted,
+                "{client_versions:?}"
+            );
+        }
+    }
+
+    /// Proxies "ping" to the backend at `addr` with `settings`.
+    async fn send_ping(addr: NodeAddr, settings: ConnectionSettings) {
+        let state = LoadBalancerState::new();
+        state.add_backend(addr.clone(), 0).await;
+        let (mut client, mut lb_side) = tokio::io::duplex(64);
+        client.write_all(b"ping").await.unwrap();
+        tokio::spawn(async move {
+            let _client = client;
+            let _ = LoadBalancer::proxy(
+                &state,
+                &mut lb_side,
+                &addr,
+                client_addr(),
+                Uuid::new_v4(),
+                &settings,
+            )
+            .await;
+        });
+    }
+
+    #[tokio::test]
+    async fn backend_tls_off_proxies_plaintext() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: NodeAddr = backend.local_addr().unwrap().to_string().parse().unwrap();
+
+        send_ping(addr, ConnectionSettings::default()).await;
+
+        let (mut socket, _) = backend.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn backend_tls_on_handshakes_with_registered_name() {
+        let pki = TestPki::new();
+        let (certs, key) = pki.issue("localhost");
+        let server_config =
+            LoadBalancer::build_tls_config(certs, key, None, rustls::ALL_VERSIONS).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+
+        let connector = LoadBalancer::backend_connector(pki.roots());
+        let settings = ConnectionSettings {
+            backend_tls: Some(connector),
+            ..ConnectionSettings::default()
+        };
+        send_ping(format!("localhost:{port}").parse().unwrap(), settings).await;
+
+        let (socket, _) = backend.accept().await.unwrap();
+        let mut tls_stream = acceptor.accept(socket).await.unwrap();
+        let mut buf = [0u8; 4];
+        tls_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_v1_header_precedes_client_bytes() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let settings = ConnectionSettings {
+            proxy_protocol: Some(ProxyProtocol::V1),
+            ..ConnectionSettings::default()
+        };
+
+        send_ping(format!("127.0.0.1:{port}").parse().unwrap(), settings).await;
+
+        let (mut socket, _) = backend.accept().await.unwrap();
+        let expected = format!("PROXY TCP4 203.0.113.7 127.0.0.1 5555 {port}\r\nping");
+        let mut buf = vec![0u8; expected.len()];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_v2_header_precedes_client_bytes() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+        let settings = ConnectionSettings {
+            proxy_protocol: Some(ProxyProtocol::V2),
+            ..ConnectionSettings::default()
+        };
+
+        send_ping(format!("127.0.0.1:{port}").parse().unwrap(), settings).await;
+
+        let (mut socket, _) = backend.accept().await.unwrap();
+        let (addrs, conn_id) = read_proxy_header(&mut socket).await;
+        let mut expected = vec![203, 0, 113, 7, 127, 0, 0, 1];
+        expected.extend(5555_u16.to_be_bytes());
+        expected.extend(port.to_be_bytes());
+        assert_eq!(addrs, expected);
+        assert_ne!(conn_id, Uuid::nil());
+        let mut buf = [0u8; 4];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    /// Reads a v2 PROXY header the lb sent, returning its address block and
+    /// the connection id from its unique id TLV.
+    async fn read_proxy_header<S: AsyncRead + Unpin>(socket: &mut S) -> (Vec<u8>, Uuid) {
+        let mut fixed = [0u8; 16];
+        socket.read_exact(&mut fixed).await.unwrap();
+        assert_eq!(fixed[..12], PROXY_V2_SIGNATURE);
+        assert_eq!(fixed[12], 0x21);
+        let mut body = vec![0u8; usize::from(u16::from_be_bytes([fixed[14], fixed[15]]))];
+        socket.read_exact(&mut body).await.unwrap();
+
+        let addr_len = if fixed[13] == 0x11 { 12 } else { 36 };
+        let (addrs, tlv) = body.split_at(addr_len);
+        assert_eq!(tlv[..3], [0x05, 0x00, 36]);
+        let conn_id = std::str::from_utf8(&tlv[3..]).unwrap().parse().unwrap();
+        (addrs.to_vec(), conn_id)
+    }
+
+    #[test]
+    fn proxy_header_maps_mixed_families_to_ipv6() {
+        let backend = "[::1]:64400".parse().unwrap();
+        assert_eq!(
+            proxy_header_v1(client_addr(), backend),
+            "PROXY TCP6 ::ffff:203.0.113.7 ::1 5555 64400\r\n"
+        );
+
+        let header = proxy_header_v2(client_addr(), backend, Uuid::nil());
+
+        assert_eq!(header[13], 0x21);
+        let mapped: std::net::Ipv6Addr = "::ffff:203.0.113.7".parse().unwrap();
+        assert_eq!(header[16..32], mapped.octets());
+        assert_eq!(header[32..48], std::net::Ipv6Addr::LOCALHOST.octets());
+        assert_eq!(header[48..52], [0x15, 0xb3, 0xfb, 0x90]);
+    }
+
+    /// The `conn_id` each logged line was written under.
+    fn logged_conn_ids(logs: &LogBuffer) -> Vec<String> {
+        logs.contents()
+            .lines()
+            .map(|line| {
+                let (_, rest) = line.split_once("conn_id=").expect("line without a conn_id");
+                rest[..36].to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn each_connection_logs_its_own_id_and_sends_it_to_the_backend() {
+        let logs = LogBuffer::default();
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_writer(logs.clone())
+                .with_max_level(tracing::Level::DEBUG)
+                .with_ansi(false)
+                .finish(),
+        );
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: NodeAddr = backend.local_addr().unwrap().to_string().parse().unwrap();
+        let state = LoadBalancerState::new();
+        state.add_backend(addr, 0).await;
+        let settings = ConnectionSettings {
+            proxy_protocol: Some(ProxyProtocol::V2),
+            ..ConnectionSettings::default()
+        };
+
+        let (client, lb_side) = tokio::io::duplex(64);
+        let proxied = tokio::spawn(LoadBalancer::handle_connection(
+            state,
+            lb_side,
+            client_addr(),
+            settings,
+        ));
+        let (mut socket, _) = backend.accept().await.unwrap();
+        let (_, sent_id) = read_proxy_header(&mut socket).await;
+        drop((client, socket));
+        proxied.await.unwrap().unwrap();
+
+        let proxied_ids = logged_conn_ids(&logs);
+        assert!(!proxied_ids.is_empty());
+        assert!(proxied_ids.iter().all(|id| *id == sent_id.to_string()));
+
+        let (_client, lb_side) = tokio::io::duplex(1024);
+        LoadBalancer::handle_connection(
+            LoadBalancerState::new(),
+            lb_side,
+            client_addr(),
+            ConnectionSettings::default(),
+        )
+        .await
+        .unwrap();
+
+        let rejected_id = logged_conn_ids(&logs).pop().unwrap();
+        assert_ne!(rejected_id, sent_id.to_string());
+    }
+
+    #[test]
+    fn backend_server_name_drops_the_port() {
+        assert_eq!(
+            backend_server_name(&"chat-1:64400".parse().unwrap()).unwrap(),
+            ServerName::try_from("chat-1").unwrap()
+        );
+        assert_eq!(
+            backend_server_name(&"[::1]:64400".parse().unwrap()).unwrap(),
+            ServerName::try_from("::1").unwrap()
+        );
+    }
+
+    #[test]
+    fn malformed_registrations_are_skipped() {
+        let members = ["chat-1:64400", "chat-2", "0.0.0.0:64400", "[::1]:64401"];
+
+        let backends = parse_members(members.map(String::from).to_vec());
+
+        let backends: Vec<String> = backends.iter().map(ToString::to_string).collect();
+        assert_eq!(backends, ["chat-1:64400", "[::1]:64401"]);
     }
 }
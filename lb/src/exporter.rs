@@ -0,0 +1,90 @@
+use std::net::SocketAddr;
+
+use anyhow::{Context, Result};
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusRecorder};
+use tracing::warn;
+
+/// Builds a Prometheus recorder and starts serving it on `addr` at `/metrics`.
+///
+/// The recorder is returned rather than installed so callers decide whether it
+/// becomes the global recorder. Must be called from within a tokio runtime.
+pub fn serve(addr: SocketAddr) -> Result<PrometheusRecorder> {
+    let (recorder, exporter) = PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .build()
+        .map_err(|e| match e {
+            BuildError::FailedToCreateHTTPListener(reason) => {
+                anyhow::anyhow!("could not bind metrics endpoint on {addr}: {reason}")
+            }
+            e => e.into(),
+        })?;
+
+    tokio::spawn(async move {
+        if let Err(e) = exporter.await {
+            warn!("metrics endpoint stopped: {:?}", e);
+        }
+    });
+
+    Ok(recorder)
+}
+
+/// Serves metrics on `addr` and installs the recorder globally, so every
+/// `counter!`/`gauge!` in the lb is scrapeable.
+pub fn install(addr: SocketAddr) -> Result<()> {
+    let recorder = serve(addr)?;
+    metrics::set_global_recorder(recorder).context("a metrics recorder is already installed")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::serve;
+    use std::net::{SocketAddr, TcpListener};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    async fn scrape(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn endpoint_serves_recorded_metrics() {
+        let addr = free_addr();
+        let recorder = serve(addr).unwrap();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("lb_total_connections").increment(3);
+        });
+
+        let response = scrape(addr).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.contains("lb_total_connections 3"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn port_in_use_is_reported() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+
+        let err = serve(addr).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("could not bind metrics endpoint on {addr}")),
+            "{err}"
+        );
+    }
+}
@@ -1,41 +1,50 @@
-use crate::{config::Config, core::LoadBalancer};
+use crate::{
+    config::Config,
+    core::{LoadBalancer, TlsOptions},
+};
 use anyhow::Result;
-use metrics_exporter_prometheus::PrometheusBuilder;
 use rustls::crypto::ring;
 use tracing::info;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod config;
 mod core;
+mod exporter;
+mod idle;
+mod outcome;
 mod rate_limiter;
 mod state;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "lb=info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    logging::init("lb=info");
 
     let _ = ring::default_provider().install_default();
     let config = Config::load();
-    let builder = PrometheusBuilder::new();
-    builder
-        .with_http_listener(([0, 0, 0, 0], config.prometheus_port))
-        .install()?;
+    exporter::install(([0, 0, 0, 0], config.prometheus_port).into())?;
     info!("metrics initialized on port {}", config.prometheus_port);
 
     let bind_addr = format!("{}:{}", config.host, config.host_port);
 
-    let lb = LoadBalancer::new(
+    let mut lb = LoadBalancer::new(
         bind_addr,
         config.redis_url,
-        config.tls_cert_path,
-        config.tls_key_path,
-    );
+        TlsOptions {
+            cert_path: config.tls_cert_path,
+            key_path: config.tls_key_path,
+            client_ca_path: config.require_client_cert.then_some(config.client_ca_path),
+            min_version: config.tls_min_version,
+        },
+        config.strategy,
+        config.max_connections_per_ip,
+    )
+    .with_timing(config.timing)
+    .with_bandwidth_limit(config.bandwidth)
+    .with_idle_timeout(config.idle_timeout)
+    .with_no_backends_notice(config.notify_no_backends)
+    .with_proxy_protocol(config.proxy_protocol);
+    if config.backend_tls {
+        lb = lb.with_backend_tls(&config.backend_ca_path);
+    }
     let _ = lb.run().await;
     Ok(())
 }
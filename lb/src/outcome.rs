@@ -0,0 +1,119 @@
+use std::{
+    io::Result,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// How a backend connection went, shared between the stream and its watcher,
+/// so the circuit breaker can tell a backend's faults from its client's.
+#[derive(Clone, Debug, Default)]
+pub struct Outcome(Arc<Flags>);
+
+#[derive(Debug, Default)]
+struct Flags {
+    answered: AtomicBool,
+    failed: AtomicBool,
+}
+
+impl Outcome {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the backend sent anything.
+    pub fn answered(&self) -> bool {
+        self.0.answered.load(Ordering::Relaxed)
+    }
+
+    /// Whether reading from or writing to the backend failed.
+    pub fn failed(&self) -> bool {
+        self.0.failed.load(Ordering::Relaxed)
+    }
+
+    fn note<T>(&self, poll: &Poll<Result<T>>) {
+        if matches!(poll, Poll::Ready(Err(_))) {
+            self.0.failed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A wrapper around a backend stream that records its I/O in an `Outcome`.
+pub struct OutcomeStream<T> {
+    inner: T,
+    outcome: Outcome,
+}
+
+impl<T> OutcomeStream<T> {
+    pub const fn new(inner: T, outcome: Outcome) -> Self {
+        Self { inner, outcome }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for OutcomeStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(()))) && buf.filled().len() > before {
+            self.outcome.0.answered.store(true, Ordering::Relaxed);
+        }
+        self.outcome.note(&poll);
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for OutcomeStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.outcome.note(&poll);
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.outcome.note(&poll);
+        poll
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_shutdown(cx);
+        self.outcome.note(&poll);
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Outcome, OutcomeStream};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn records_replies_and_failures() {
+        let outcome = Outcome::new();
+        let (mut backend, inner) = tokio::io::duplex(64);
+        let mut stream = OutcomeStream::new(inner, outcome.clone());
+
+        backend.write_all(b"hi").await.unwrap();
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert!(outcome.answered());
+        assert!(!outcome.failed());
+
+        drop(backend);
+        assert!(stream.write_all(b"bye").await.is_err());
+        assert!(outcome.failed());
+    }
+}
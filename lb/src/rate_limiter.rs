@@ -7,14 +7,66 @@ use std::{
 };
 
 use governor::{
-    RateLimiter,
+    InsufficientCapacity, Quota, RateLimiter,
     clock::{Clock, DefaultClock},
     state::{InMemoryState, NotKeyed},
 };
+use metrics::{Label, counter, gauge};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
-    time::{Instant, Sleep},
+    time::{Duration, Instant, Sleep},
 };
+use tracing::warn;
+
+/// How often the per-client throughput gauge is refreshed.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Bytes per second each client IP may send unless configured otherwise.
+pub const DEFAULT_BYTES_PER_SEC: u32 = 100 * 1024;
+/// Smallest burst accepted: one TLS record, so a single read never has to be
+/// let through a bucket at a time.
+pub const MIN_BURST_BYTES: u32 = 16 * 1024;
+
+/// How fast each client IP may send and how much it may send at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    pub bytes_per_sec: NonZeroU32,
+    pub burst_bytes: NonZeroU32,
+}
+
+impl BandwidthLimit {
+    /// Builds a limit of `bytes_per_sec`, falling back to the default when
+    /// zero. The burst defaults to one second's worth and is raised to at
+    /// least `MIN_BURST_BYTES`.
+    pub fn new(bytes_per_sec: u32, burst_bytes: Option<u32>) -> Self {
+        let bytes_per_sec = NonZeroU32::new(bytes_per_sec)
+            .unwrap_or(NonZeroU32::new(DEFAULT_BYTES_PER_SEC).unwrap());
+        let burst = burst_bytes.unwrap_or(bytes_per_sec.get());
+        if burst < MIN_BURST_BYTES {
+            warn!(
+                burst,
+                min = MIN_BURST_BYTES,
+                "bandwidth burst too small, raising it"
+            );
+        }
+        Self {
+            bytes_per_sec,
+            burst_bytes: NonZeroU32::new(burst.max(MIN_BURST_BYTES)).unwrap(),
+        }
+    }
+
+    /// The governor quota: refills at `bytes_per_sec` and holds up to
+    /// `burst_bytes`.
+    pub fn quota(self) -> Quota {
+        Quota::per_second(self.bytes_per_sec).allow_burst(self.burst_bytes)
+    }
+}
+
+impl Default for BandwidthLimit {
+    fn default() -> Self {
+        Self::new(DEFAULT_BYTES_PER_SEC, None)
+    }
+}
 
 /// A wrapper around a generic IO stream that enforces bandwidth limits.
 pub struct RateLimitedStream<T> {
@@ -22,46 +74,73 @@ pub struct RateLimitedStream<T> {
     limiter: Arc<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
     sleep: Option<Pin<Box<Sleep>>>,
     pending_bytes: u32,
+    /// Most bytes checked against the limiter at once; lowered to the
+    /// bucket's capacity once a read outgrows it.
+    max_batch: u32,
+    /// Whether `pending_bytes` has already been counted as throttled.
+    throttled: bool,
+    /// Metric labels, e.g. the client IP.
+    labels: Vec<Label>,
+    window_start: Instant,
+    window_bytes: u64,
 }
 
 impl<T> RateLimitedStream<T> {
-    pub fn new(inner: T, limiter: Arc<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>) -> Self {
+    pub fn new(
+        inner: T,
+        limiter: Arc<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
+        client: Option<String>,
+    ) -> Self {
         Self {
             inner,
             limiter,
             sleep: None,
             pending_bytes: 0,
+            max_batch: u32::MAX,
+            throttled: false,
+            labels: client
+                .map(|client| vec![Label::new("client", client)])
+                .unwrap_or_default(),
+            window_start: Instant::now(),
+            window_bytes: 0,
         }
     }
 
-    fn poll_pending_bytes(&mut self, cx: &mut Context<'_>) -> Poll<()> {
-        if self.pending_bytes == 0 {
-            return Poll::Ready(());
+    fn record_read(&mut self, bytes: usize) {
+        self.window_bytes += bytes as u64;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= THROUGHPUT_WINDOW {
+            gauge!("lb_client_throughput_bytes_per_sec", self.labels.clone())
+                .set(self.window_bytes as f64 / elapsed.as_secs_f64());
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
         }
+    }
 
-        let nonzero = match NonZeroU32::new(self.pending_bytes) {
-            Some(n) => n,
-            None => {
-                self.pending_bytes = 0;
-                return Poll::Ready(());
-            }
-        };
-
-        match self.limiter.check_n(nonzero) {
-            Ok(Ok(_)) | Err(_) => {
-                self.pending_bytes = 0;
-                Poll::Ready(())
-            }
-            Ok(Err(not_until)) => {
-                let wait_time = not_until.wait_time_from(DefaultClock::default().now());
-                let mut sleep = Box::pin(tokio::time::sleep_until(Instant::now() + wait_time));
-                if sleep.as_mut().poll(cx).is_ready() {
-                    return Poll::Ready(());
+    fn poll_pending_bytes(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        while let Some(batch) = NonZeroU32::new(self.pending_bytes.min(self.max_batch)) {
+            match self.limiter.check_n(batch) {
+                Ok(Ok(())) => self.pending_bytes -= batch.get(),
+                // More than the bucket can ever hold, so it has to be paid for
+                // a bucket at a time rather than waved through.
+                Err(InsufficientCapacity(capacity)) => self.max_batch = capacity.max(1),
+                Ok(Err(not_until)) => {
+                    if !self.throttled {
+                        self.throttled = true;
+                        counter!("lb_throttled_bytes_total", self.labels.clone())
+                            .increment(u64::from(self.pending_bytes));
+                    }
+                    let wait_time = not_until.wait_time_from(DefaultClock::default().now());
+                    let mut sleep = Box::pin(tokio::time::sleep_until(Instant::now() + wait_time));
+                    if sleep.as_mut().poll(cx).is_pending() {
+                        self.sleep = Some(sleep);
+                        return Poll::Pending;
+                    }
                 }
-                self.sleep = Some(sleep);
-                Poll::Pending
             }
         }
+        self.throttled = false;
+        Poll::Ready(())
     }
 }
 
@@ -86,6 +165,7 @@ impl<T: AsyncRead + Unpin> AsyncRead for RateLimitedStream<T> {
 
         if diff > 0 {
             mut_rl.pending_bytes = mut_rl.pending_bytes.saturating_add(diff as u32);
+            mut_rl.record_read(diff);
         }
 
         poll
@@ -115,3 +195,102 @@ impl<T: AsyncWrite + Unpin> AsyncWrite for RateLimitedStream<T> {
         Pin::new(&mut self.inner).poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BandwidthLimit, DEFAULT_BYTES_PER_SEC, MIN_BURST_BYTES, RateLimitedStream};
+    use governor::{Quota, RateLimiter};
+    use logging::testing::record_metrics;
+    use metrics_util::debugging::DebugValue;
+    use std::{num::NonZeroU32, sync::Arc, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Reads `bytes` through a limiter of `bytes_per_sec` holding `burst`,
+    /// `read_size` at a time, and returns how long that took.
+    async fn time_transfer(
+        bytes_per_sec: u32,
+        burst: u32,
+        read_size: usize,
+        bytes: usize,
+    ) -> Duration {
+        let quota = Quota::per_second(NonZeroU32::new(bytes_per_sec).unwrap())
+            .allow_burst(NonZeroU32::new(burst).unwrap());
+        let limiter = Arc::new(RateLimiter::direct(quota));
+        let data = vec![0u8; bytes];
+        let mut stream = RateLimitedStream::new(&data[..], limiter, None);
+
+        let start = std::time::Instant::now();
+        let mut buf = vec![0u8; read_size];
+        while stream.read(&mut buf).await.unwrap() > 0 {}
+        start.elapsed()
+    }
+
+    async fn read_through_limiter(bytes: usize) {
+        let quota = Quota::per_second(NonZeroU32::new(1024).unwrap());
+        let limiter = Arc::new(RateLimiter::direct(quota));
+        let (mut client, server) = tokio::io::duplex(512);
+        let mut stream = RateLimitedStream::new(server, limiter, Some("10.0.0.1".to_string()));
+
+        let writer = tokio::spawn(async move {
+            client.write_all(&vec![0u8; bytes]).await.unwrap();
+        });
+        let mut buf = vec![0u8; bytes];
+        stream.read_exact(&mut buf).await.unwrap();
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sustained_throughput_matches_the_configured_rate() {
+        const RATE: u32 = 256 * 1024;
+        // After the first full bucket, the rest arrives at the refill rate;
+        // the last read isn't charged until the next one.
+        for (burst, read_size) in [(16 * 1024, 8 * 1024), (4 * 1024, 16 * 1024)] {
+            let bytes = burst as usize + 128 * 1024;
+            let expected = Duration::from_secs_f64(
+                (bytes - burst as usize - read_size) as f64 / f64::from(RATE),
+            );
+
+            let elapsed = time_transfer(RATE, burst, read_size, bytes).await;
+            assert!(
+                elapsed >= expected.mul_f64(0.8) && elapsed <= expected.mul_f64(1.6),
+                "{burst} byte burst, {read_size} byte reads: took {elapsed:?}, expected {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn bandwidth_bursts_hold_at_least_a_tls_record() {
+        let limit = BandwidthLimit::default();
+        assert_eq!(limit.bytes_per_sec.get(), DEFAULT_BYTES_PER_SEC);
+        assert_eq!(limit.burst_bytes.get(), DEFAULT_BYTES_PER_SEC);
+
+        let limit = BandwidthLimit::new(4096, None);
+        assert_eq!(limit.bytes_per_sec.get(), 4096);
+        assert_eq!(limit.burst_bytes.get(), MIN_BURST_BYTES);
+
+        let limit = BandwidthLimit::new(0, Some(1 << 20));
+        assert_eq!(limit.bytes_per_sec.get(), DEFAULT_BYTES_PER_SEC);
+        assert_eq!(limit.burst_bytes.get(), 1 << 20);
+    }
+
+    #[test]
+    fn throttled_reads_are_counted() {
+        let ((), recorded) = record_metrics(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .unwrap()
+                .block_on(read_through_limiter(2 * 1024));
+        });
+
+        let (key, value) = recorded
+            .all("lb_throttled_bytes_total")
+            .next()
+            .expect("throttling should be recorded");
+        assert!(matches!(value, DebugValue::Counter(n) if *n > 0));
+        assert!(
+            key.labels()
+                .any(|l| l.key() == "client" && l.value() == "10.0.0.1")
+        );
+    }
+}
@@ -6,7 +6,7 @@ use governor::{
 use std::{
     sync::{
         Arc,
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
     },
     time::SystemTime,
 };
@@ -16,17 +16,52 @@ pub struct ClientState {
     pub connection_limiter: RateLimiter<NotKeyed, InMemoryState, DefaultClock>,
     pub bandwidth_limiter: Arc<RateLimiter<NotKeyed, InMemoryState, DefaultClock>>,
     pub last_seen_ms: AtomicU64,
+    /// Connections from this IP currently being proxied.
+    active_connections: AtomicUsize,
+    max_connections: usize,
+}
+
+/// Holds one of a client's concurrent connection slots until dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    client: Arc<ClientState>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.client
+            .active_connections
+            .fetch_sub(1, Ordering::AcqRel);
+    }
 }
 
 impl ClientState {
-    pub fn new(connection_quota: Quota, bandwidth_quota: Quota) -> Self {
+    pub fn new(connection_quota: Quota, bandwidth_quota: Quota, max_connections: usize) -> Self {
         Self {
             connection_limiter: RateLimiter::direct(connection_quota),
             bandwidth_limiter: Arc::new(RateLimiter::direct(bandwidth_quota)),
             last_seen_ms: AtomicU64::new(Self::now_ms()),
+            active_connections: AtomicUsize::new(0),
+            max_connections,
         }
     }
 
+    /// Claims a connection slot, or `None` if the client already has its maximum open.
+    pub fn try_acquire_connection(self: &Arc<Self>) -> Option<ConnectionPermit> {
+        self.active_connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < self.max_connections).then_some(active + 1)
+            })
+            .ok()?;
+        Some(ConnectionPermit {
+            client: self.clone(),
+        })
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Acquire)
+    }
+
     pub fn update_seen(&self) {
         self.last_seen_ms.store(Self::now_ms(), Ordering::Relaxed);
     }
@@ -38,3 +73,27 @@ impl ClientState {
             .as_millis() as u64
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ClientState;
+    use governor::Quota;
+    use std::num::NonZeroU32;
+    use std::sync::Arc;
+
+    #[test]
+    fn connections_beyond_cap_are_refused_until_one_closes() {
+        let quota = Quota::per_second(NonZeroU32::new(100).unwrap());
+        let client = Arc::new(ClientState::new(quota, quota, 3));
+
+        let permits: Vec<_> = (0..3)
+            .map(|_| client.try_acquire_connection().expect("within cap"))
+            .collect();
+        assert!(client.try_acquire_connection().is_none());
+        assert_eq!(client.active_connections(), 3);
+
+        drop(permits);
+        assert_eq!(client.active_connections(), 0);
+        assert!(client.try_acquire_connection().is_some());
+    }
+}
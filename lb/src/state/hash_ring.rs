@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+
+/// Points each backend gets on the ring, smoothing out the key distribution.
+const VIRTUAL_NODES: u32 = 128;
+
+/// Consistent-hash ring mapping keys to backend addresses.
+#[derive(Debug, Default)]
+pub struct HashRing {
+    ring: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    pub fn new<'a>(backends: impl IntoIterator<Item = &'a String>) -> Self {
+        let mut ring = BTreeMap::new();
+        for addr in backends {
+            for i in 0..VIRTUAL_NODES {
+                ring.insert(hash(format!("{addr}#{i}").as_bytes()), addr.clone());
+            }
+        }
+        Self { ring }
+    }
+
+    /// Returns backends in ring order starting from `key`'s position, each listed once.
+    pub fn candidates(&self, key: &[u8]) -> Vec<&str> {
+        let point = hash(key);
+        let mut seen = Vec::new();
+        for addr in self
+            .ring
+            .range(point..)
+            .chain(self.ring.range(..point))
+            .map(|(_, addr)| addr.as_str())
+        {
+            if !seen.contains(&addr) {
+                seen.push(addr);
+            }
+        }
+        seen
+    }
+}
+
+/// FNV-1a followed by a 64-bit finalizer, stable across processes so every lb agrees.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HashRing;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn keys() -> Vec<Vec<u8>> {
+        (0..2000u32)
+            .map(|i| {
+                IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i))
+                    .to_string()
+                    .into_bytes()
+            })
+            .collect()
+    }
+
+    fn owners(ring: &HashRing) -> Vec<String> {
+        keys()
+            .iter()
+            .map(|k| ring.candidates(k)[0].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn removing_backend_only_remaps_its_keys() {
+        let all: Vec<String> = ["a:1", "b:1", "c:1"].map(String::from).to_vec();
+        let before = owners(&HashRing::new(&all));
+        let after = owners(&HashRing::new(&all[..2]));
+
+        let mut moved = 0;
+        for (old, new) in before.iter().zip(&after) {
+            if old == "c:1" {
+                assert_ne!(new, "c:1");
+                moved += 1;
+            } else {
+                assert_eq!(old, new, "keys on surviving backends must not move");
+            }
+        }
+        assert!(moved > 0, "removed backend should have owned some keys");
+    }
+
+    #[test]
+    fn keys_spread_across_backends() {
+        let all: Vec<String> = ["a:1", "b:1", "c:1"].map(String::from).to_vec();
+        let owners = owners(&HashRing::new(&all));
+
+        for addr in &all {
+            let share = owners.iter().filter(|o| *o == addr).count();
+            assert!(share > 400, "{addr} only owns {share} of 2000 keys");
+        }
+    }
+
+    #[test]
+    fn candidates_list_each_backend_once() {
+        let all: Vec<String> = ["a:1", "b:1"].map(String::from).to_vec();
+        let ring = HashRing::new(&all);
+
+        let mut candidates = ring.candidates(b"10.0.0.1");
+        candidates.sort_unstable();
+        assert_eq!(candidates, vec!["a:1", "b:1"]);
+        assert!(HashRing::default().candidates(b"10.0.0.1").is_empty());
+    }
+}
@@ -1,24 +1,86 @@
+use crate::rate_limiter::BandwidthLimit;
 use crate::state::ClientState;
-use dashmap::DashMap;
+use crate::state::hash_ring::HashRing;
+use dashmap::{DashMap, mapref::entry::Entry};
 use governor::Quota;
-use metrics::gauge;
+use metrics::{counter, gauge};
+use protocol::NodeAddr;
+use std::collections::HashSet;
 use std::net::IpAddr;
 use std::num::NonZeroU32;
-use std::sync::Arc;
 use std::sync::atomic::Ordering;
-use std::time::Duration;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Failures within `FAILURE_WINDOW` that open a backend's circuit.
+const FAILURE_THRESHOLD: u32 = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(10);
+/// How long an open circuit keeps a backend out of rotation before a probe is let through.
+const CIRCUIT_COOLDOWN: Duration = Duration::from_secs(30);
+/// Concurrent connections allowed per client IP unless configured otherwise.
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 10;
 
 #[derive(Debug)]
 struct BackendState {
-    pub addr: String,
+    pub addr: NodeAddr,
     pub active_connections: usize,
     pub is_healthy: bool,
+    /// Set while the node is shutting down; it keeps existing clients but gets no new ones.
+    pub draining: bool,
+    pub consecutive_failures: u32,
+    pub last_failure: Option<Instant>,
+    /// While set, the circuit is open; once it passes, one probe connection is allowed.
+    pub open_until: Option<Instant>,
+    /// Most connections the node registered to take; `None` if it's uncapped.
+    pub max_connections: Option<usize>,
+}
+
+impl BackendState {
+    fn new(addr: NodeAddr, active_connections: usize) -> Self {
+        Self {
+            addr,
+            active_connections,
+            is_healthy: true,
+            draining: false,
+            consecutive_failures: 0,
+            last_failure: None,
+            open_until: None,
+            max_connections: None,
+        }
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        self.is_healthy && !self.draining && self.open_until.is_none_or(|until| until <= now)
+    }
+
+    /// Whether the backend is at its cap, so another connection would be refused.
+    fn is_full(&self) -> bool {
+        self.max_connections
+            .is_some_and(|max| self.active_connections >= max)
+    }
+}
+
+/// A connection counted against a backend; released when dropped.
+#[derive(Debug)]
+pub struct BackendConnection {
+    state: LoadBalancerState,
+    addr: NodeAddr,
+}
+
+impl Drop for BackendConnection {
+    fn drop(&mut self) {
+        self.state.dec_backend_connection(&self.addr);
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct LoadBalancerState {
-    backends: Arc<DashMap<String, BackendState>>,
+    backends: Arc<DashMap<NodeAddr, BackendState>>,
     clients: Arc<DashMap<IpAddr, Arc<ClientState>>>,
+    ring: Arc<RwLock<HashRing>>,
+    max_connections_per_ip: usize,
+    bandwidth: BandwidthLimit,
 }
 
 impl LoadBalancerState {
@@ -26,46 +88,210 @@ impl LoadBalancerState {
         Self {
             backends: Arc::new(DashMap::new()),
             clients: Arc::new(DashMap::new()),
+            ring: Arc::new(RwLock::new(HashRing::default())),
+            max_connections_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP,
+            bandwidth: BandwidthLimit::default(),
         }
     }
 
-    pub async fn next_backend(&self) -> Option<String> {
-        self.backends
+    pub fn with_max_connections_per_ip(mut self, max: usize) -> Self {
+        self.max_connections_per_ip = max;
+        self
+    }
+
+    pub fn with_bandwidth_limit(mut self, bandwidth: BandwidthLimit) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// Picks the least loaded available backend with room for another
+    /// connection, or `None` if every one is unavailable or full.
+    pub async fn next_backend(&self) -> Option<NodeAddr> {
+        let now = Instant::now();
+        let (addr, load) = self
+            .backends
             .iter()
-            .filter(|b| b.is_healthy)
+            .filter(|b| b.is_available(now) && !b.is_full())
             .min_by_key(|b| b.active_connections)
-            .map(|b| b.addr.clone())
+            .map(|b| (b.addr.clone(), b.active_connections))?;
+        self.claim(&addr, now, load);
+        Some(addr)
     }
 
-    pub async fn add_backend(&self, addr: String, active_connections: usize) {
-        self.backends.insert(
-            addr.clone(),
-            BackendState {
-                addr,
-                active_connections,
-                is_healthy: true,
-            },
-        );
+    /// Picks the first available backend with room clockwise from `ip` on the hash ring.
+    pub async fn next_backend_for(&self, ip: IpAddr) -> Option<NodeAddr> {
+        let now = Instant::now();
+        let (skipped, addr) = self
+            .ring
+            .read()
+            .unwrap()
+            .candidates(ip.to_string().as_bytes())
+            .into_iter()
+            .enumerate()
+            .find_map(|(skipped, addr)| {
+                self.backends
+                    .get(addr)
+                    .filter(|b| b.is_available(now) && !b.is_full())
+                    .map(|b| (skipped, b.addr.clone()))
+            })?;
+        self.claim(&addr, now, skipped);
+        Some(addr)
+    }
+
+    /// Records the pick and re-arms a half-open circuit so only the probe
+    /// connection reaches the backend.
+    ///
+    /// `score` is what the strategy chose by: the backend's active connections
+    /// for least-conn, or how many ring candidates were skipped for consistent hashing.
+    fn claim(&self, addr: &NodeAddr, now: Instant, score: usize) {
+        counter!("lb_backend_routed_total", "backend" => addr.to_string()).increment(1);
+        gauge!("lb_backend_selection_score", "backend" => addr.to_string()).set(score as f64);
+        if let Some(mut b) = self.backends.get_mut(addr)
+            && b.open_until.is_some()
+        {
+            b.open_until = Some(now + CIRCUIT_COOLDOWN);
+        }
+    }
+
+    /// Records a failed connection, opening the circuit once failures pile up.
+    pub async fn record_backend_failure(&self, addr: &NodeAddr) {
+        self.record_failure_at(addr, Instant::now());
+    }
+
+    fn record_failure_at(&self, addr: &NodeAddr, now: Instant) {
+        let Some(mut b) = self.backends.get_mut(addr) else {
+            return;
+        };
+        if b.last_failure
+            .is_some_and(|last| now.duration_since(last) > FAILURE_WINDOW)
+        {
+            b.consecutive_failures = 0;
+        }
+        b.consecutive_failures += 1;
+        b.last_failure = Some(now);
+
+        // A failed probe reopens straight away.
+        if b.consecutive_failures >= FAILURE_THRESHOLD || b.open_until.is_some() {
+            if b.open_until.is_none() {
+                warn!(%addr, failures = b.consecutive_failures, "opening backend circuit");
+                counter!("lb_backend_circuit_opened", "backend" => addr.to_string()).increment(1);
+            }
+            b.open_until = Some(now + CIRCUIT_COOLDOWN);
+        }
+    }
+
+    /// Records a session the backend answered, closing its circuit.
+    pub async fn record_backend_success(&self, addr: &NodeAddr) {
+        self.record_success_at(addr, Instant::now(), false);
+    }
+
+    /// Records a passed health probe. It only closes a circuit whose cooldown
+    /// is over, standing in for the half-open probe connection.
+    pub async fn record_probe_success(&self, addr: &NodeAddr) {
+        self.record_success_at(addr, Instant::now(), true);
+    }
+
+    fn record_success_at(&self, addr: &NodeAddr, now: Instant, probe: bool) {
+        let Some(mut b) = self.backends.get_mut(addr) else {
+            return;
+        };
+        if probe && b.open_until.is_some_and(|until| until > now) {
+            return;
+        }
+        if b.open_until.take().is_some() {
+            info!(%addr, "closing backend circuit");
+        }
+        b.consecutive_failures = 0;
+        b.last_failure = None;
+    }
+
+    fn rebuild_ring(&self) {
+        let addrs: Vec<String> = self.backends.iter().map(|b| b.key().to_string()).collect();
+        *self.ring.write().unwrap() = HashRing::new(&addrs);
+    }
+
+    /// Registers a new backend; one already known keeps its health and connection state.
+    pub async fn add_backend(&self, addr: NodeAddr, active_connections: usize) {
+        match self.backends.entry(addr.clone()) {
+            Entry::Occupied(_) => return,
+            Entry::Vacant(entry) => {
+                entry.insert(BackendState::new(addr, active_connections));
+            }
+        }
+        self.rebuild_ring();
 
         gauge!("lb_healthy_backends").set(self.backends.len() as f64)
     }
 
-    pub async fn remove_backend(&self, addr: &str) {
+    pub async fn remove_backend(&self, addr: &NodeAddr) {
         gauge!("lb_healthy_backends").set(self.backends.len() as f64);
         self.backends.remove(addr);
+        self.rebuild_ring();
+    }
+
+    /// Reconciles the registry with the nodes currently advertised in redis.
+    pub async fn sync_backends(&self, live: &[NodeAddr], draining: &HashSet<NodeAddr>) {
+        let current_backends = self.get_backend_addrs().await;
+        for addr in live {
+            if !current_backends.contains(addr) {
+                info!(%addr, "adding backend to registry");
+                self.add_backend(addr.clone(), 0).await;
+            }
+            self.set_draining(addr, draining.contains(addr)).await;
+        }
+
+        for addr in &current_backends {
+            if !live.contains(addr) {
+                warn!(%addr, "removing backend from registery");
+                self.remove_backend(addr).await;
+            }
+        }
     }
 
-    pub async fn get_backend_addrs(&self) -> Vec<String> {
+    pub async fn set_draining(&self, addr: &NodeAddr, draining: bool) {
+        if let Some(mut b) = self.backends.get_mut(addr)
+            && b.draining != draining
+        {
+            info!(%addr, draining, "backend drain state changed");
+            b.draining = draining;
+        }
+    }
+
+    pub async fn set_max_connections(&self, addr: &NodeAddr, max_connections: Option<usize>) {
+        if let Some(mut b) = self.backends.get_mut(addr)
+            && b.max_connections != max_connections
+        {
+            info!(%addr, ?max_connections, "backend connection cap changed");
+            b.max_connections = max_connections;
+        }
+    }
+
+    pub async fn get_backend_addrs(&self) -> Vec<NodeAddr> {
         self.backends.iter().map(|r| (*r.key()).clone()).collect()
     }
 
-    pub async fn set_health(&self, addr: &str, is_healthy: bool) {
+    pub async fn set_health(&self, addr: &NodeAddr, is_healthy: bool) {
         if let Some(mut b) = self.backends.get_mut(addr) {
             b.is_healthy = is_healthy;
         }
     }
 
-    pub async fn inc_backend_connection(&self, addr: &str) {
+    /// Counts a connection on `addr` until the returned guard is dropped, so the
+    /// count is released on every exit path, including panics and cancellation.
+    pub fn track_backend_connection(&self, addr: &NodeAddr) -> BackendConnection {
+        self.inc_backend_connection(addr);
+        BackendConnection {
+            state: self.clone(),
+            addr: addr.clone(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn backend_connections(&self, addr: &NodeAddr) -> Option<usize> {
+        self.backends.get(addr).map(|b| b.active_connections)
+    }
+
+    fn inc_backend_connection(&self, addr: &NodeAddr) {
         if let Some(mut b) = self.backends.get_mut(addr) {
             b.active_connections += 1;
             gauge!("lb_backend_active_connections", "backend" => addr.to_string())
@@ -74,8 +300,13 @@ impl LoadBalancerState {
         }
     }
 
-    pub async fn dec_backend_connection(&self, addr: &str) {
+    /// Releases a connection; a backend re-added since the matching increment is already at zero.
+    fn dec_backend_connection(&self, addr: &NodeAddr) {
         if let Some(mut b) = self.backends.get_mut(addr) {
+            if b.active_connections == 0 {
+                warn!(%addr, "connection count already zero on release");
+                return;
+            }
             b.active_connections -= 1;
             gauge!("lb_backend_active_connections", "backend" => addr.to_string())
                 .set(b.active_connections as f64);
@@ -92,24 +323,315 @@ impl LoadBalancerState {
                 let now = ClientState::now_ms();
                 // 5min ttl
                 let expiration = 1000 * 60 * 5;
+                // Clients with open connections are kept so their cap isn't reset.
                 clients.retain(|_, state| {
                     let last_seen = state.last_seen_ms.load(Ordering::Relaxed);
-                    now - last_seen < expiration
+                    now - last_seen < expiration || state.active_connections() > 0
                 });
             }
         });
     }
 
     pub fn add_client(&self, ip: IpAddr) -> Arc<ClientState> {
+        let max_connections = self.max_connections_per_ip;
+        let bandwidth_quota = self.bandwidth.quota();
         self.clients
             .entry(ip)
             .or_insert_with(|| {
                 let connection_quota = Quota::per_second(NonZeroU32::new(5).unwrap());
-                let bandwidth_quota = Quota::per_second(NonZeroU32::new(100 * 1024).unwrap())
-                    .allow_burst(NonZeroU32::new(16 * 1024).unwrap());
 
-                Arc::new(ClientState::new(connection_quota, bandwidth_quota))
+                Arc::new(ClientState::new(
+                    connection_quota,
+                    bandwidth_quota,
+                    max_connections,
+                ))
             })
             .clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CIRCUIT_COOLDOWN, FAILURE_THRESHOLD, FAILURE_WINDOW, LoadBalancerState};
+    use logging::testing::record_metrics;
+    use metrics_util::debugging::DebugValue;
+    use protocol::NodeAddr;
+    use std::collections::HashSet;
+    use std::time::Instant;
+
+    fn addr(addr: &str) -> NodeAddr {
+        addr.parse().unwrap()
+    }
+
+    fn addrs(list: &[&str]) -> Vec<NodeAddr> {
+        list.iter().map(|a| addr(a)).collect()
+    }
+
+    #[tokio::test]
+    async fn sync_adds_and_removes_backends() {
+        let state = LoadBalancerState::new();
+        state
+            .sync_backends(&addrs(&["a:1", "b:1"]), &HashSet::new())
+            .await;
+        state
+            .sync_backends(&addrs(&["b:1", "c:1"]), &HashSet::new())
+            .await;
+
+        let mut current = state.get_backend_addrs().await;
+        current.sort();
+        assert_eq!(current, addrs(&["b:1", "c:1"]));
+    }
+
+    #[tokio::test]
+    async fn unhealthy_backend_stays_unhealthy_across_discovery() {
+        let state = LoadBalancerState::new();
+        let live = addrs(&["a:1"]);
+        state.sync_backends(&live, &HashSet::new()).await;
+        state.inc_backend_connection(&addr("a:1"));
+        state.set_health(&addr("a:1"), false).await;
+
+        state.sync_backends(&live, &HashSet::new()).await;
+        state.add_backend(addr("a:1"), 0).await;
+
+        let backend = state.backends.get("a:1").unwrap();
+        assert!(!backend.is_healthy);
+        assert_eq!(backend.active_connections, 1);
+        drop(backend);
+        assert_eq!(state.next_backend().await, None);
+    }
+
+    #[tokio::test]
+    async fn dec_on_idle_backend_stays_at_zero() {
+        let state = LoadBalancerState::new();
+        state.add_backend(addr("a:1"), 0).await;
+
+        state.dec_backend_connection(&addr("a:1"));
+        state.inc_backend_connection(&addr("a:1"));
+        state.dec_backend_connection(&addr("a:1"));
+        state.dec_backend_connection(&addr("a:1"));
+
+        assert_eq!(state.backends.get("a:1").unwrap().active_connections, 0);
+    }
+
+    #[tokio::test]
+    async fn tracked_connection_is_released_on_drop() {
+        let state = LoadBalancerState::new();
+        state.add_backend(addr("a:1"), 0).await;
+
+        let first = state.track_backend_connection(&addr("a:1"));
+        let second = state.track_backend_connection(&addr("a:1"));
+        assert_eq!(state.backend_connections(&addr("a:1")), Some(2));
+
+        drop(first);
+        assert_eq!(state.backend_connections(&addr("a:1")), Some(1));
+        drop(second);
+        assert_eq!(state.backend_connections(&addr("a:1")), Some(0));
+    }
+
+    #[tokio::test]
+    async fn draining_backend_gets_no_new_connections() {
+        let state = LoadBalancerState::new();
+        let live = addrs(&["a:1", "b:1"]);
+        state.sync_backends(&live, &HashSet::new()).await;
+        state.inc_backend_connection(&addr("b:1"));
+        state.inc_backend_connection(&addr("b:1"));
+        assert_eq!(state.next_backend().await, Some(addr("a:1")));
+
+        let draining = HashSet::from([addr("a:1")]);
+        state.sync_backends(&live, &draining).await;
+        assert_eq!(state.next_backend().await, Some(addr("b:1")));
+        assert_eq!(state.get_backend_addrs().await.len(), 2);
+
+        state.sync_backends(&live, &HashSet::new()).await;
+        assert_eq!(state.next_backend().await, Some(addr("a:1")));
+    }
+
+    #[tokio::test]
+    async fn full_backends_are_skipped_for_the_least_loaded_with_room() {
+        let state = LoadBalancerState::new();
+        state
+            .sync_backends(&addrs(&["a:1", "b:1", "c:1"]), &HashSet::new())
+            .await;
+        for (backend, cap, load) in [("a:1", 1, 1), ("b:1", 2, 2), ("c:1", 10, 3)] {
+            let backend = &addr(backend);
+            state.set_max_connections(backend, Some(cap)).await;
+            for _ in 0..load {
+                state.inc_backend_connection(backend);
+            }
+        }
+
+        assert_eq!(state.next_backend().await, Some(addr("c:1")));
+        let ip = "10.1.2.3".parse().unwrap();
+        assert_eq!(state.next_backend_for(ip).await, Some(addr("c:1")));
+
+        state.dec_backend_connection(&addr("b:1"));
+        assert_eq!(state.next_backend().await, Some(addr("b:1")));
+    }
+
+    #[tokio::test]
+    async fn all_backends_full_yields_none() {
+        let state = LoadBalancerState::new();
+        state
+            .sync_backends(&addrs(&["a:1", "b:1"]), &HashSet::new())
+            .await;
+        state.set_max_connections(&addr("a:1"), Some(1)).await;
+        state.set_max_connections(&addr("b:1"), Some(0)).await;
+        state.inc_backend_connection(&addr("a:1"));
+
+        assert_eq!(state.next_backend().await, None);
+        assert_eq!(
+            state.next_backend_for("10.1.2.3".parse().unwrap()).await,
+            None
+        );
+
+        state.set_max_connections(&addr("b:1"), None).await;
+        assert_eq!(state.next_backend().await, Some(addr("b:1")));
+    }
+
+    #[tokio::test]
+    async fn all_backends_draining_yields_none() {
+        let state = LoadBalancerState::new();
+        let live = addrs(&["a:1"]);
+        state
+            .sync_backends(&live, &HashSet::from([addr("a:1")]))
+            .await;
+
+        assert_eq!(state.next_backend().await, None);
+    }
+
+    #[tokio::test]
+    async fn consistent_hash_is_stable_and_skips_unavailable_backends() {
+        let state = LoadBalancerState::new();
+        state
+            .sync_backends(&addrs(&["a:1", "b:1", "c:1"]), &HashSet::new())
+            .await;
+        let ip = "10.1.2.3".parse().unwrap();
+
+        let first = state.next_backend_for(ip).await.unwrap();
+        assert_eq!(state.next_backend_for(ip).await.unwrap(), first);
+
+        state.set_health(&first, false).await;
+        let fallback = state.next_backend_for(ip).await.unwrap();
+        assert_ne!(fallback, first);
+
+        state.set_health(&first, true).await;
+        assert_eq!(state.next_backend_for(ip).await.unwrap(), first);
+    }
+
+    #[tokio::test]
+    async fn failure_burst_opens_circuit() {
+        let state = LoadBalancerState::new();
+        state
+            .sync_backends(&addrs(&["a:1", "b:1"]), &HashSet::new())
+            .await;
+        state.inc_backend_connection(&addr("b:1"));
+
+        let now = Instant::now();
+        for _ in 0..FAILURE_THRESHOLD - 1 {
+            state.record_failure_at(&addr("a:1"), now);
+        }
+        assert_eq!(state.next_backend().await, Some(addr("a:1")));
+
+        state.record_failure_at(&addr("a:1"), now);
+        assert_eq!(state.next_backend().await, Some(addr("b:1")));
+    }
+
+    #[tokio::test]
+    async fn failures_outside_window_do_not_accumulate() {
+        let state = LoadBalancerState::new();
+        state.add_backend(addr("a:1"), 0).await;
+
+        let start = Instant::now();
+        for i in 0..FAILURE_THRESHOLD {
+            state.record_failure_at(&addr("a:1"), start + FAILURE_WINDOW * (i + 1) * 2);
+        }
+        assert_eq!(state.backends.get("a:1").unwrap().open_until, None);
+    }
+
+    #[tokio::test]
+    async fn half_open_probe_success_closes_circuit() {
+        let state = LoadBalancerState::new();
+        state.add_backend(addr("a:1"), 0).await;
+
+        let now = Instant::now();
+        for _ in 0..FAILURE_THRESHOLD {
+            state.record_failure_at(&addr("a:1"), now);
+        }
+        assert_eq!(state.next_backend().await, None);
+
+        // Cooldown elapsed: exactly one probe gets through.
+        state.backends.get_mut("a:1").unwrap().open_until = Some(Instant::now());
+        assert_eq!(state.next_backend().await, Some(addr("a:1")));
+        assert_eq!(state.next_backend().await, None);
+
+        // Connecting alone proves nothing; the probe has to be answered.
+        state.inc_backend_connection(&addr("a:1"));
+        assert!(state.backends.get("a:1").unwrap().open_until.is_some());
+
+        state.record_backend_success(&addr("a:1")).await;
+        let backend = state.backends.get("a:1").unwrap();
+        assert_eq!(backend.open_until, None);
+        assert_eq!(backend.consecutive_failures, 0);
+        drop(backend);
+        assert_eq!(state.next_backend().await, Some(addr("a:1")));
+    }
+
+    #[tokio::test]
+    async fn health_probe_closes_circuit_only_after_cooldown() {
+        let state = LoadBalancerState::new();
+        state.add_backend(addr("a:1"), 0).await;
+
+        let now = Instant::now();
+        for _ in 0..FAILURE_THRESHOLD {
+            state.record_failure_at(&addr("a:1"), now);
+        }
+        state.record_success_at(&addr("a:1"), now, true);
+        assert!(state.backends.get("a:1").unwrap().open_until.is_some());
+
+        state.record_success_at(&addr("a:1"), now + CIRCUIT_COOLDOWN, true);
+        let backend = state.backends.get("a:1").unwrap();
+        assert_eq!(backend.open_until, None);
+        assert_eq!(backend.consecutive_failures, 0);
+    }
+
+    async fn route(state: &LoadBalancerState, n: usize) {
+        let mut held = Vec::new();
+        for _ in 0..n {
+            let backend = state.next_backend().await.unwrap();
+            held.push(state.track_backend_connection(&backend));
+        }
+    }
+
+    #[test]
+    fn routing_counts_every_pick_per_backend() {
+        let ((), recorded) = record_metrics(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    let state = LoadBalancerState::new();
+                    state
+                        .sync_backends(&addrs(&["a:1", "b:1"]), &HashSet::new())
+                        .await;
+                    route(&state, 10).await;
+                });
+        });
+
+        let mut routed: Vec<_> = recorded
+            .all("lb_backend_routed_total")
+            .map(|(key, value)| {
+                let backend = key.labels().next().unwrap().value().to_string();
+                (backend, value)
+            })
+            .collect();
+        routed.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            routed,
+            vec![
+                ("a:1".to_string(), &DebugValue::Counter(5)),
+                ("b:1".to_string(), &DebugValue::Counter(5)),
+            ]
+        );
+    }
+}
@@ -1,4 +1,5 @@
 pub mod client;
+pub mod hash_ring;
 pub mod lb;
 
 pub use client::ClientState;
@@ -0,0 +1,109 @@
+#![warn(clippy::all, clippy::pedantic, clippy::nursery, unused_extern_crates)]
+
+//! Shared `tracing` subscriber setup for the server and lb.
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+use tracing::Subscriber;
+use tracing_subscriber::{
+    EnvFilter, Layer, fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+
+/// Environment variable selecting the log output format.
+pub const LOG_FORMAT_ENV: &str = "LOG_FORMAT";
+
+/// How log lines are written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines from the default formatter.
+    Pretty,
+    /// One JSON object per line, for log aggregation.
+    Json,
+}
+
+impl LogFormat {
+    /// Reads `LOG_FORMAT`, defaulting to `Pretty` for anything but `json`.
+    #[must_use]
+    pub fn from_env() -> Self {
+        Self::parse(std::env::var(LOG_FORMAT_ENV).ok().as_deref())
+    }
+
+    fn parse(value: Option<&str>) -> Self {
+        match value.map(str::trim) {
+            Some(v) if v.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Pretty,
+        }
+    }
+}
+
+/// Installs the global subscriber, filtered by `RUST_LOG` or else `default_filter`.
+pub fn init(default_filter: &str) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| default_filter.into());
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer(LogFormat::from_env(), std::io::stdout))
+        .init();
+}
+
+/// Builds the formatting layer for `format`, writing to `writer`.
+fn layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let fmt = tracing_subscriber::fmt::layer().with_writer(writer);
+    match format {
+        // Span fields such as `node_id` are flattened onto each line.
+        LogFormat::Json => fmt
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .boxed(),
+        LogFormat::Pretty => fmt.boxed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogFormat, layer};
+    use crate::testing::LogBuffer;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn capture(format: LogFormat) -> String {
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::registry().with(layer(format, logs.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(backend = "10.0.0.2:64400", "backend added");
+        });
+
+        logs.contents()
+    }
+
+    #[test]
+    fn format_is_read_from_value() {
+        assert_eq!(LogFormat::parse(Some("json")), LogFormat::Json);
+        assert_eq!(LogFormat::parse(Some(" JSON ")), LogFormat::Json);
+        assert_eq!(LogFormat::parse(Some("pretty")), LogFormat::Pretty);
+        assert_eq!(LogFormat::parse(None), LogFormat::Pretty);
+    }
+
+    #[test]
+    fn json_format_writes_fields_as_json() {
+        let output = capture(LogFormat::Json);
+        let line: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+
+        assert_eq!(line["backend"], "10.0.0.2:64400");
+        assert_eq!(line["message"], "backend added");
+    }
+
+    #[test]
+    fn pretty_format_is_not_json() {
+        let output = capture(LogFormat::Pretty);
+
+        assert!(output.contains("backend added"));
+        assert!(serde_json::from_str::<serde_json::Value>(output.trim()).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+//! Helpers for the server's and lb's tests of what they log and record.
+
+use metrics::Key;
+use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+use std::io::Write;
+use std::sync::{Arc, Mutex, PoisonError};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Collects what a test subscriber logs. Clones share the buffer, so a test
+/// can hand one to the subscriber and read back through another.
+#[derive(Clone, Default)]
+pub struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl LogBuffer {
+    /// Everything logged so far.
+    #[must_use]
+    pub fn contents(&self) -> String {
+        let logged = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        String::from_utf8_lossy(&logged).into_owned()
+    }
+}
+
+impl Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MakeWriter<'_> for LogBuffer {
+    type Writer = Self;
+
+    fn make_writer(&self) -> Self {
+        self.clone()
+    }
+}
+
+/// Everything recorded while running a closure under [`record_metrics`].
+pub struct Recorded(Vec<(Key, DebugValue)>);
+
+impl Recorded {
+    /// The value of the first metric named `name`, if it was recorded.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&DebugValue> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.name() == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Every metric named `name`, one per distinct set of labels.
+    pub fn all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = (&'a Key, &'a DebugValue)> {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key.name() == name)
+            .map(|(key, value)| (key, value))
+    }
+}
+
+/// Runs `f` with a recorder of its own, returning its result and what it recorded.
+pub fn record_metrics<T>(f: impl FnOnce() -> T) -> (T, Recorded) {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    let result = metrics::with_local_recorder(&recorder, f);
+    let values = snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .map(|(key, .., value)| (key.key().clone(), value))
+        .collect();
+    (result, Recorded(values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::record_metrics;
+    use metrics_util::debugging::DebugValue;
+
+    #[test]
+    fn recorded_metrics_are_found_by_name() {
+        let ((), recorded) = record_metrics(|| {
+            metrics::counter!("hits_total", "path" => "a").increment(2);
+            metrics::counter!("hits_total", "path" => "b").increment(1);
+        });
+
+        assert_eq!(recorded.all("hits_total").count(), 2);
+        assert!(matches!(
+            recorded.get("hits_total"),
+            Some(DebugValue::Counter(_))
+        ));
+        assert!(recorded.get("misses_total").is_none());
+    }
+}
@@ -2,6 +2,9 @@
 
 use std::io::Error;
 
+mod node;
+pub use node::{NodeAddr, NodeAddrError};
+
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -10,13 +13,94 @@ use tokio_util::{
     codec::{Decoder, Encoder},
 };
 
-pub struct McsCodec;
+/// Frame flag marking a zstd-compressed payload.
+const FLAG_COMPRESSED: u8 = 0x01;
+
+/// Largest payload a compressed frame may expand to.
+const MAX_DECOMPRESSED_LEN: usize = 16 * 1024 * 1024;
+
+/// Largest payload a frame header may declare; anything bigger is rejected before buffering.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Default cap on a chat message's content, in bytes.
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+// A message at the cap must always fit in a frame, sender and framing included.
+const _: () = assert!(MAX_MESSAGE_LEN < MAX_FRAME_LEN / 2);
+
+/// Length-prefixed postcard framing for `Message`s.
+///
+/// With `with_checksum`, each frame also carries a trailing CRC32 of the payload.
+/// With `with_compression`, each frame gains a flag byte after the length, and
+/// payloads larger than the threshold are zstd-compressed.
+/// Both ends must agree on these options, since they change the frame layout.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct McsCodec {
+    checksum: bool,
+    compress_above: Option<usize>,
+}
+
+impl McsCodec {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            checksum: false,
+            compress_above: None,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_checksum(mut self) -> Self {
+        self.checksum = true;
+        self
+    }
+
+    /// Compresses payloads longer than `threshold` bytes.
+    #[must_use]
+    pub const fn with_compression(mut self, threshold: usize) -> Self {
+        self.compress_above = Some(threshold);
+        self
+    }
+
+    const fn header_len(self) -> usize {
+        if self.compress_above.is_some() { 5 } else { 4 }
+    }
+
+    const fn trailer_len(self) -> usize {
+        if self.checksum { 4 } else { 0 }
+    }
+}
+
+/// A frame whose payload didn't match its CRC32 trailer.
+#[derive(Debug, Error)]
+#[error("frame checksum mismatch: expected {expected:#010x}, got {actual:#010x}")]
+pub struct ChecksumMismatch {
+    pub expected: u32,
+    pub actual: u32,
+}
+
+pub const USERNAME_MIN_LEN: usize = 3;
+pub const USERNAME_MAX_LEN: usize = 32;
+
+/// Shortest password a user may change their password to.
+pub const PASSWORD_MIN_LEN: usize = 8;
+
+/// Marks the names of guest sessions. `validate_username` rejects it, so a
+/// guest can never pass for a registered user.
+pub const GUEST_PREFIX: char = '~';
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatPacket {
+    /// Server-assigned id, `0` until the message has been persisted.
+    pub id: u64,
     pub sender: String,
+    /// What the sender typed, after any filtering. It never carries the
+    /// sender's name or other formatting; how a message looks is up to clients.
     pub content: String,
+    /// Unix time in milliseconds. Servers before millisecond support sent
+    /// seconds, so mixed-version deployments aren't supported.
     pub timestamp: i64,
+    pub deleted: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Error)]
@@ -27,11 +111,63 @@ pub enum ChatError {
     #[error("username already taken")]
     UsernameTaken,
 
-    #[error("username too short")]
+    #[error("username must be at least {USERNAME_MIN_LEN} characters")]
     UsernameTooShort,
 
+    // Postcard encodes variants by position, so new ones go at the end.
     #[error("internal error")]
     Internal,
+
+    #[error("username must be at most {USERNAME_MAX_LEN} characters")]
+    UsernameTooLong,
+
+    #[error("username can't start or end with whitespace")]
+    UsernameWhitespace,
+
+    #[error("username can't contain '{0}', use letters, digits, '_' or '-'")]
+    UsernameInvalidChar(char),
+
+    #[error("message rejected: {0}")]
+    MessageRejected(String),
+
+    #[error("message not found or not sent by you")]
+    NotMessageAuthor,
+
+    #[error("rate limit exceeded, slow down")]
+    RateLimited,
+
+    #[error("only admins can do that")]
+    Unauthorized,
+
+    #[error("wrong password for this username")]
+    InvalidCredentials,
+
+    #[error("too many login attempts, try again in {retry_after_secs}s")]
+    TooManyAttempts { retry_after_secs: u64 },
+
+    #[error("disconnected after being idle too long")]
+    IdleTimeout,
+
+    #[error("missed {count} messages while lagging behind, reloading recent history")]
+    MessagesDropped { count: u64 },
+
+    #[error("banned from this server: {0}")]
+    Banned(String),
+
+    #[error("message is longer than {max} bytes")]
+    MessageTooLong { max: usize },
+
+    #[error("server is busy, try again shortly")]
+    ServerBusy,
+
+    #[error("password must be at least {PASSWORD_MIN_LEN} characters")]
+    PasswordTooShort,
+
+    #[error("no servers available, try again later")]
+    NoBackends,
+
+    #[error("already logged in from another session")]
+    AlreadyOnline,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,14 +176,159 @@ pub struct JoinPacket {
     pub password: String,
 }
 
+/// A user with a session on any node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OnlineUser {
+    pub username: String,
+    /// Unix time in milliseconds of the user's last chat message or typing, or of their login.
+    pub last_active: i64,
+}
+
+/// A session on any node, as listed for an admin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub username: String,
+    /// The node serving the session.
+    pub node: String,
+}
+
+/// Operator commands; only admins may send them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminCmd {
+    /// Lists every session across the cluster, answered with `Sessions`.
+    ListSessions,
+    /// Closes `username`'s session, wherever it is.
+    Disconnect(String),
+}
+
+/// Usage counted against a client's rate limits in the current window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateUsage {
+    pub messages: u32,
+    pub bytes: u32,
+}
+
+/// Which side of its cursor a history page is taken from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryDirection {
+    /// Older messages, paging back from the newest.
+    #[default]
+    Before,
+    /// Newer messages, paging forward toward the live end.
+    After,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
     Chat(ChatPacket),
     Join(JoinPacket),
     Heartbeat,
     Error(ChatError),
-    HistoryRequest(i64),
-    HistoryResponse(Vec<ChatPacket>),
+    /// Asks for the page of history ordered before or after
+    /// `(cursor_ts, cursor_id)`, optionally only messages from `sender`.
+    ///
+    /// The id breaks ties between messages sent within the same second.
+    /// Pages filtered by sender only go backward.
+    HistoryRequest {
+        cursor_ts: i64,
+        cursor_id: u64,
+        sender: Option<String>,
+        direction: HistoryDirection,
+    },
+    HistoryResponse {
+        /// Always oldest first, whichever way the page was requested.
+        messages: Vec<ChatPacket>,
+        /// Whether more messages exist beyond this page in its direction.
+        has_more: bool,
+        /// The sender filter of the request this page answers, if any.
+        sender: Option<String>,
+        direction: HistoryDirection,
+    },
+    EditMessage {
+        id: u64,
+        content: String,
+    },
+    DeleteMessage {
+        id: u64,
+    },
+    RateStatusRequest,
+    RateStatusResponse {
+        messages_per_sec: u32,
+        bytes_per_sec: u32,
+        current_usage: RateUsage,
+    },
+    Typing {
+        username: String,
+        is_typing: bool,
+    },
+    Announcement {
+        content: String,
+    },
+    SearchRequest {
+        query: String,
+        limit: u32,
+    },
+    SearchResponse(Vec<ChatPacket>),
+    Ping {
+        nonce: u64,
+        sent_ms: i64,
+    },
+    Pong {
+        nonce: u64,
+        sent_ms: i64,
+    },
+    /// Sent only to the author of a `Chat`, keyed by the id the client put in it.
+    ChatAck {
+        local_id: u64,
+        result: Result<u64, ChatError>,
+    },
+    StatsRequest,
+    StatsResponse {
+        active_users: u32,
+        messages_stored: u64,
+        uptime_secs: u64,
+        node_id: String,
+    },
+    /// Sent to a user's session just before the server closes it.
+    Kicked {
+        reason: String,
+    },
+    /// Asks the server to disconnect `username`, also banning them if `ban` is set (admins only).
+    KickRequest {
+        username: String,
+        reason: String,
+        ban: bool,
+    },
+    /// Sent by the server as soon as a connection opens, before the client joins.
+    Hello {
+        /// Whether a join with an empty password is accepted as a guest.
+        guest_allowed: bool,
+        /// Longest message content the server accepts, in bytes.
+        max_message_len: usize,
+    },
+    /// Confirms a `HistoryResponse` arrived, by its newest message's `(timestamp, id)`.
+    HistoryAck {
+        up_to_ts: i64,
+        up_to_id: u64,
+    },
+    /// Broadcast when a user comes online, apart from any chat history.
+    UserJoined(String),
+    /// Broadcast when a user has gone offline for good.
+    UserLeft(String),
+    /// Replaces the account password, provided `old` is the current one.
+    ChangePassword {
+        old: String,
+        new: String,
+    },
+    /// Confirms a `ChangePassword`; the session carries on as before.
+    PasswordChanged,
+    /// Asks who is online across every node.
+    OnlineUsersRequest,
+    /// Everyone online, by username.
+    OnlineUsersResponse(Vec<OnlineUser>),
+    AdminCommand(AdminCmd),
+    /// Answers `AdminCmd::ListSessions`, by username.
+    Sessions(Vec<SessionInfo>),
 }
 
 impl Decoder for McsCodec {
@@ -62,15 +343,45 @@ impl Decoder for McsCodec {
         let mut length_bytes = [0u8; 4];
         length_bytes.copy_from_slice(&src[0..4]);
         let length = u32::from_be_bytes(length_bytes) as usize;
+        if length > MAX_FRAME_LEN {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("frame of {length} bytes exceeds the {MAX_FRAME_LEN} byte limit"),
+            ));
+        }
 
-        if src.len() < 4 + length {
-            src.reserve(4 + length - src.len());
+        let frame_len = self.header_len() + length + self.trailer_len();
+
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
             return Ok(None);
         }
 
         src.advance(4);
+        let flags = if self.compress_above.is_some() {
+            src.get_u8()
+        } else {
+            0
+        };
         let payload = src.split_to(length);
 
+        if self.checksum {
+            let expected = src.get_u32();
+            let actual = crc32fast::hash(&payload);
+            if expected != actual {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    ChecksumMismatch { expected, actual },
+                ));
+            }
+        }
+
+        let payload = if flags & FLAG_COMPRESSED != 0 {
+            zstd::bulk::decompress(&payload, MAX_DECOMPRESSED_LEN)?
+        } else {
+            payload.to_vec()
+        };
+
         let message = postcard::from_bytes(&payload)
             .map_err(|_| Error::new(std::io::ErrorKind::InvalidData, "deserialzation failed"))?;
 
@@ -81,33 +392,96 @@ impl Decoder for McsCodec {
 impl Encoder<Message> for McsCodec {
     type Error = Error;
 
-    #[allow(clippy::cast_possible_truncation)]
     fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let payload = postcard::to_stdvec(&item)
             .map_err(|_| Error::new(std::io::ErrorKind::InvalidData, "serialization failed"))?;
-        dst.put_u32(payload.len() as u32);
+        let (flags, payload) = match self.compress_above {
+            Some(threshold) if payload.len() > threshold => {
+                (FLAG_COMPRESSED, zstd::bulk::compress(&payload, 0)?)
+            }
+            _ => (0, payload),
+        };
+
+        // The peer rejects frames over the limit, so never write one it would desync on.
+        let length = u32::try_from(payload.len())
+            .ok()
+            .filter(|&len| len as usize <= MAX_FRAME_LEN)
+            .ok_or_else(|| Error::new(std::io::ErrorKind::InvalidData, "message too large"))?;
+
+        dst.put_u32(length);
+        if self.compress_above.is_some() {
+            dst.put_u8(flags);
+        }
         dst.extend_from_slice(&payload);
+        if self.checksum {
+            dst.put_u32(crc32fast::hash(&payload));
+        }
 
         Ok(())
     }
 }
 
+/// Checks a username against the length and character rules shared by client and server.
+///
+/// # Errors
+///
+/// Returns the `ChatError` describing the first rule the name breaks.
+pub fn validate_username(name: &str) -> Result<(), ChatError> {
+    if name.trim() != name {
+        return Err(ChatError::UsernameWhitespace);
+    }
+
+    let len = name.chars().count();
+    if len < USERNAME_MIN_LEN {
+        return Err(ChatError::UsernameTooShort);
+    }
+    if len > USERNAME_MAX_LEN {
+        return Err(ChatError::UsernameTooLong);
+    }
+
+    name.chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-'))
+        .map_or(Ok(()), |c| Err(ChatError::UsernameInvalidChar(c)))
+}
+
+/// Checks a new password against the strength rules shared by client and server.
+///
+/// # Errors
+///
+/// Returns `ChatError::PasswordTooShort` if it has fewer than `PASSWORD_MIN_LEN` characters.
+pub fn validate_password(password: &str) -> Result<(), ChatError> {
+    if password.chars().count() < PASSWORD_MIN_LEN {
+        return Err(ChatError::PasswordTooShort);
+    }
+    Ok(())
+}
+
+/// Returns the session name a guest joining as `username` is shown under.
+#[must_use]
+pub fn guest_name(username: &str) -> String {
+    format!("{GUEST_PREFIX}{username}")
+}
+
 impl ChatPacket {
     #[must_use]
     pub fn new_server_packet(content: String) -> Self {
         Self {
+            id: 0,
             sender: "server".to_string(),
             content,
-            timestamp: Utc::now().timestamp(),
+            timestamp: Utc::now().timestamp_millis(),
+            deleted: false,
         }
     }
 
     #[must_use]
     pub fn new_user_packet(sender: String, content: String) -> Self {
         Self {
+            id: 0,
             sender,
             content,
-            timestamp: Utc::now().timestamp(),
+            timestamp: Utc::now().timestamp_millis(),
+            deleted: false,
         }
     }
 }
@@ -116,6 +490,11 @@ impl ChatPacket {
 mod tests {
     use crate::ChatError;
     use crate::ChatPacket;
+    use crate::ChecksumMismatch;
+    use crate::GUEST_PREFIX;
+    use crate::HistoryDirection;
+    use crate::guest_name;
+    use crate::validate_username;
 
     use super::McsCodec;
     use super::Message;
@@ -130,8 +509,8 @@ mod tests {
         let original_msg =
             Message::Chat(ChatPacket::new_user_packet(sender.clone(), content.clone()));
 
-        McsCodec.encode(original_msg, &mut buf).unwrap();
-        let decode_msg = McsCodec
+        McsCodec::new().encode(original_msg, &mut buf).unwrap();
+        let decode_msg = McsCodec::new()
             .decode(&mut buf)
             .unwrap()
             .expect("should return a message");
@@ -149,8 +528,8 @@ mod tests {
         let mut buf = BytesMut::new();
         let original_error = Message::Error(ChatError::UsernameTaken);
 
-        McsCodec.encode(original_error, &mut buf).unwrap();
-        let decode_msg = McsCodec
+        McsCodec::new().encode(original_error, &mut buf).unwrap();
+        let decode_msg = McsCodec::new()
             .decode(&mut buf)
             .unwrap()
             .expect("should return an error");
@@ -162,31 +541,84 @@ mod tests {
         }
     }
 
+    #[test]
+    fn original_errors_keep_their_wire_index() {
+        let index = |err: &ChatError| postcard::to_stdvec(err).unwrap()[0];
+
+        assert_eq!(index(&ChatError::Network), 0);
+        assert_eq!(index(&ChatError::UsernameTaken), 1);
+        assert_eq!(index(&ChatError::UsernameTooShort), 2);
+        assert_eq!(index(&ChatError::Internal), 3);
+    }
+
+    #[test]
+    fn string_length_past_payload_is_rejected() {
+        // Chat { id: 1, sender: <claims 200 bytes>, ... } with only two bytes present.
+        let payload = [0x00, 0x01, 0xC8, 0x01, b'a', b'b'];
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&u32::try_from(payload.len()).unwrap().to_be_bytes());
+        buf.extend_from_slice(&payload);
+
+        let err = McsCodec::new().decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_frame_header_is_rejected() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&u32::MAX.to_be_bytes());
+
+        let err = McsCodec::new().decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(
+            buf.capacity() < 1024,
+            "nothing should be reserved for the frame"
+        );
+    }
+
+    #[test]
+    fn oversized_message_is_rejected_at_encode() {
+        let mut buf = BytesMut::new();
+        let content = "x".repeat(super::MAX_FRAME_LEN + 1);
+        let msg = Message::Chat(ChatPacket::new_user_packet("alice".to_string(), content));
+
+        let err = McsCodec::new().encode(msg, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(
+            buf.is_empty(),
+            "nothing should be written for a rejected message"
+        );
+    }
+
     #[test]
     fn partial_packet_decoding_succeeds() {
         let mut buf = BytesMut::new();
 
         let msg1 = Message::Chat(ChatPacket {
+            id: 1,
             sender: "Alice".to_string(),
             content: "Part 1".to_string(),
             timestamp: 100,
+            deleted: false,
         });
 
         let msg2 = Message::Chat(ChatPacket {
+            id: 2,
             sender: "Bob".to_string(),
             content: "Part 2".to_string(),
             timestamp: 200,
+            deleted: false,
         });
 
         let mut full_stream = BytesMut::new();
-        McsCodec.encode(msg1, &mut full_stream).unwrap();
-        McsCodec.encode(msg2, &mut full_stream).unwrap();
+        McsCodec::new().encode(msg1, &mut full_stream).unwrap();
+        McsCodec::new().encode(msg2, &mut full_stream).unwrap();
 
         let split_point = 10;
         buf.extend_from_slice(&full_stream[..split_point]);
 
         {
-            let result = McsCodec.decode(&mut buf).unwrap();
+            let result = McsCodec::new().decode(&mut buf).unwrap();
             assert!(
                 result.is_none(),
                 "Should return None when data is incomplete"
@@ -196,7 +628,7 @@ mod tests {
         buf.extend_from_slice(&full_stream[split_point..]);
 
         {
-            let result = McsCodec
+            let result = McsCodec::new()
                 .decode(&mut buf)
                 .unwrap()
                 .expect("Should decode message 1");
@@ -210,7 +642,7 @@ mod tests {
         }
 
         {
-            let result = McsCodec
+            let result = McsCodec::new()
                 .decode(&mut buf)
                 .unwrap()
                 .expect("Should decode message 2");
@@ -225,4 +657,276 @@ mod tests {
 
         assert!(buf.is_empty());
     }
+
+    #[test]
+    fn encode_decode_edit_and_delete_succeeds() {
+        let mut buf = BytesMut::new();
+        McsCodec::new()
+            .encode(
+                Message::EditMessage {
+                    id: 42,
+                    content: "fixed".to_string(),
+                },
+                &mut buf,
+            )
+            .unwrap();
+        McsCodec::new()
+            .encode(Message::DeleteMessage { id: 7 }, &mut buf)
+            .unwrap();
+
+        match McsCodec::new().decode(&mut buf).unwrap() {
+            Some(Message::EditMessage { id, content }) => {
+                assert_eq!(id, 42);
+                assert_eq!(content, "fixed");
+            }
+            other => panic!("decoded wrong message type: {other:?}"),
+        }
+        match McsCodec::new().decode(&mut buf).unwrap() {
+            Some(Message::DeleteMessage { id }) => assert_eq!(id, 7),
+            other => panic!("decoded wrong message type: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn encode_decode_ping_pong_succeeds() {
+        let mut buf = BytesMut::new();
+        McsCodec::new()
+            .encode(
+                Message::Ping {
+                    nonce: 7,
+                    sent_ms: 1_700_000_000_123,
+                },
+                &mut buf,
+            )
+            .unwrap();
+        McsCodec::new()
+            .encode(
+                Message::Pong {
+                    nonce: u64::MAX,
+                    sent_ms: -1,
+                },
+                &mut buf,
+            )
+            .unwrap();
+
+        match McsCodec::new().decode(&mut buf).unwrap() {
+            Some(Message::Ping { nonce, sent_ms }) => {
+                assert_eq!(nonce, 7);
+                assert_eq!(sent_ms, 1_700_000_000_123);
+            }
+            other => panic!("expected a ping, got {other:?}"),
+        }
+        match McsCodec::new().decode(&mut buf).unwrap() {
+            Some(Message::Pong { nonce, sent_ms }) => {
+                assert_eq!(nonce, u64::MAX);
+                assert_eq!(sent_ms, -1);
+            }
+            other => panic!("expected a pong, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn encode_decode_history_ack_succeeds() {
+        let mut buf = BytesMut::new();
+        McsCodec::new()
+            .encode(
+                Message::HistoryAck {
+                    up_to_ts: 1_700_000_000_123,
+                    up_to_id: 42,
+                },
+                &mut buf,
+            )
+            .unwrap();
+
+        match McsCodec::new().decode(&mut buf).unwrap() {
+            Some(Message::HistoryAck { up_to_ts, up_to_id }) => {
+                assert_eq!((up_to_ts, up_to_id), (1_700_000_000_123, 42));
+            }
+            other => panic!("expected a history ack, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn encode_decode_presence_succeeds() {
+        let mut buf = BytesMut::new();
+        let mut codec = McsCodec::new();
+        codec
+            .encode(Message::UserJoined("alice".to_string()), &mut buf)
+            .unwrap();
+        codec
+            .encode(Message::UserLeft("~bob".to_string()), &mut buf)
+            .unwrap();
+
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Message::UserJoined(name)) if name == "alice"
+        ));
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Message::UserLeft(name)) if name == "~bob"
+        ));
+    }
+
+    #[test]
+    fn validate_username_cases() {
+        let cases = [
+            ("bob", Ok(())),
+            ("alice_smith-99", Ok(())),
+            (&"a".repeat(32), Ok(())),
+            ("ab", Err(ChatError::UsernameTooShort)),
+            ("", Err(ChatError::UsernameTooShort)),
+            (&"a".repeat(33), Err(ChatError::UsernameTooLong)),
+            (" bob", Err(ChatError::UsernameWhitespace)),
+            ("bob\t", Err(ChatError::UsernameWhitespace)),
+            ("bo b", Err(ChatError::UsernameInvalidChar(' '))),
+            ("bob:1", Err(ChatError::UsernameInvalidChar(':'))),
+            ("bob\u{7}", Err(ChatError::UsernameInvalidChar('\u{7}'))),
+            ("jos\u{e9}", Err(ChatError::UsernameInvalidChar('\u{e9}'))),
+        ];
+
+        for (name, expected) in cases {
+            assert_eq!(validate_username(name), expected, "username {name:?}");
+        }
+        assert_eq!(
+            validate_username(&guest_name("bob")),
+            Err(ChatError::UsernameInvalidChar(GUEST_PREFIX))
+        );
+    }
+
+    fn chat_message() -> Message {
+        Message::Chat(ChatPacket::new_user_packet(
+            "alice".to_string(),
+            "hello there".to_string(),
+        ))
+    }
+
+    #[test]
+    fn checksummed_frame_round_trips() {
+        let mut buf = BytesMut::new();
+        let mut codec = McsCodec::new().with_checksum();
+
+        codec.encode(chat_message(), &mut buf).unwrap();
+        match codec.decode(&mut buf).unwrap() {
+            Some(Message::Chat(packet)) => assert_eq!(packet.content, "hello there"),
+            other => panic!("expected a chat message, got {other:?}"),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn flipped_payload_byte_reports_checksum_mismatch() {
+        let mut buf = BytesMut::new();
+        let mut codec = McsCodec::new().with_checksum();
+        codec.encode(chat_message(), &mut buf).unwrap();
+
+        // Corrupt a byte of the content without touching the length prefix.
+        let last_payload_byte = buf.len() - 5;
+        buf[last_payload_byte] ^= 0xff;
+
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(
+            err.get_ref()
+                .is_some_and(|e| e.downcast_ref::<ChecksumMismatch>().is_some()),
+            "expected a checksum mismatch, got {err:?}"
+        );
+    }
+
+    #[test]
+    fn checksummed_frame_waits_for_trailer() {
+        let mut full = BytesMut::new();
+        let mut codec = McsCodec::new().with_checksum();
+        codec.encode(chat_message(), &mut full).unwrap();
+
+        let mut buf = BytesMut::from(&full[..full.len() - 2]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&full[full.len() - 2..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+    }
+
+    fn history(count: usize) -> Message {
+        Message::HistoryResponse {
+            messages: (0..count as u64)
+                .map(|i| ChatPacket {
+                    id: i,
+                    sender: "alice".to_string(),
+                    content: format!("message number {i} in the backlog"),
+                    timestamp: 1_700_000_000 + i.cast_signed(),
+                    deleted: false,
+                })
+                .collect(),
+            has_more: true,
+            sender: None,
+            direction: HistoryDirection::Before,
+        }
+    }
+
+    fn encoded_len(mut codec: McsCodec, msg: Message) -> usize {
+        let mut buf = BytesMut::new();
+        codec.encode(msg, &mut buf).unwrap();
+        buf.len()
+    }
+
+    #[test]
+    fn compressed_frames_round_trip_at_various_sizes() {
+        let mut codec = McsCodec::new().with_compression(256).with_checksum();
+
+        for len in [0, 1, 200, 256, 257, 4096, 256 * 1024] {
+            let content = "ab".repeat(len / 2);
+            let mut buf = BytesMut::new();
+            codec
+                .encode(
+                    Message::Chat(ChatPacket::new_user_packet(
+                        "alice".to_string(),
+                        content.clone(),
+                    )),
+                    &mut buf,
+                )
+                .unwrap();
+
+            match codec.decode(&mut buf).unwrap() {
+                Some(Message::Chat(packet)) => assert_eq!(packet.content, content, "len {len}"),
+                other => panic!("expected a chat message, got {other:?}"),
+            }
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn tiny_messages_skip_compression() {
+        let mut buf = BytesMut::new();
+        let mut codec = McsCodec::new().with_compression(256);
+        codec.encode(chat_message(), &mut buf).unwrap();
+
+        assert_eq!(buf[4], 0, "flag byte should mark the frame uncompressed");
+        assert_eq!(buf.len(), encoded_len(McsCodec::new(), chat_message()) + 1);
+    }
+
+    #[test]
+    fn history_response_is_compressed() {
+        let plain = encoded_len(McsCodec::new(), history(50));
+        let compressed = encoded_len(McsCodec::new().with_compression(256), history(50));
+        assert!(
+            compressed * 2 < plain,
+            "expected at least 2x savings, got {compressed} vs {plain}"
+        );
+
+        let mut buf = BytesMut::new();
+        let mut codec = McsCodec::new().with_compression(256);
+        codec.encode(history(50), &mut buf).unwrap();
+        assert_eq!(buf[4], 1, "flag byte should mark the frame compressed");
+        match codec.decode(&mut buf).unwrap() {
+            Some(Message::HistoryResponse {
+                messages: packets,
+                has_more,
+                ..
+            }) => {
+                assert!(has_more);
+                assert_eq!(packets.len(), 50);
+                assert_eq!(packets[49].content, "message number 49 in the backlog");
+            }
+            other => panic!("expected a history response, got {other:?}"),
+        }
+    }
 }
@@ -0,0 +1,142 @@
+//! Addresses chat nodes advertise to load balancers.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// A `host:port` a load balancer can dial, doubling as the node's id.
+///
+/// The host may be a name (the container hostname by default) or an IP, so this
+/// isn't a `SocketAddr`. IPs are normalised and IPv6 ones bracketed, so
+/// `Display` gives one canonical string for redis members and keys.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeAddr(String);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum NodeAddrError {
+    #[error("node address {0:?} needs a port")]
+    MissingPort(String),
+    #[error("invalid port in node address {0:?}")]
+    InvalidPort(String),
+    #[error("node address {0:?} isn't dialable")]
+    Undialable(String),
+    #[error("node address {0:?} is a wildcard, not a reachable host")]
+    Wildcard(String),
+}
+
+impl NodeAddr {
+    #[must_use]
+    pub fn host(&self) -> &str {
+        let (host, _) = self.0.rsplit_once(':').unwrap_or_default();
+        host.trim_start_matches('[').trim_end_matches(']')
+    }
+
+    #[must_use]
+    pub fn port(&self) -> u16 {
+        self.0
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+            .unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for NodeAddr {
+    type Err = NodeAddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| NodeAddrError::MissingPort(s.to_string()))?;
+        let port: u16 = port
+            .parse()
+            .map_err(|_| NodeAddrError::InvalidPort(s.to_string()))?;
+        let bracketed = host.starts_with('[') && host.ends_with(']');
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || port == 0 || host.contains(char::is_whitespace) {
+            return Err(NodeAddrError::Undialable(s.to_string()));
+        }
+
+        // Brackets must be there exactly when the host is IPv6, otherwise it's
+        // ambiguous where the port starts.
+        match host.parse::<IpAddr>() {
+            Ok(ip) if ip.is_unspecified() => Err(NodeAddrError::Wildcard(s.to_string())),
+            Ok(IpAddr::V6(ip)) if bracketed => Ok(Self(format!("[{ip}]:{port}"))),
+            Ok(IpAddr::V4(ip)) if !bracketed => Ok(Self(format!("{ip}:{port}"))),
+            Err(_) if !bracketed && !host.contains(':') => Ok(Self(format!("{host}:{port}"))),
+            _ => Err(NodeAddrError::Undialable(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for NodeAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Hashes and compares like the canonical string, so maps keyed by `NodeAddr`
+// can be looked up with a `&str`.
+impl Borrow<str> for NodeAddr {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for NodeAddr {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NodeAddr, NodeAddrError};
+
+    #[test]
+    fn dialable_addresses_parse_to_their_canonical_form() {
+        for (input, canonical) in [
+            ("node-1:64400", "node-1:64400"),
+            ("10.0.0.5:64400", "10.0.0.5:64400"),
+            ("[fd00::5]:64400", "[fd00::5]:64400"),
+            ("[FD00:0::05]:64400", "[fd00::5]:64400"),
+        ] {
+            let addr: NodeAddr = input.parse().unwrap();
+            assert_eq!(addr.to_string(), canonical);
+            assert_eq!(canonical.parse::<NodeAddr>().unwrap(), addr);
+        }
+
+        let addr: NodeAddr = "[fd00::5]:64400".parse().unwrap();
+        assert_eq!((addr.host(), addr.port()), ("fd00::5", 64400));
+    }
+
+    #[test]
+    fn malformed_addresses_are_rejected() {
+        for input in [
+            "node-1:http",
+            ":64400",
+            "node-1:0",
+            "fd00::5:64400",
+            "[node-1]:64400",
+            "[10.0.0.5]:64400",
+            "[::]:64400",
+        ] {
+            assert!(input.parse::<NodeAddr>().is_err(), "{input}");
+        }
+        assert_eq!(
+            "node-1".parse::<NodeAddr>(),
+            Err(NodeAddrError::MissingPort("node-1".into()))
+        );
+        assert_eq!(
+            "0.0.0.0:64400".parse::<NodeAddr>(),
+            Err(NodeAddrError::Wildcard("0.0.0.0:64400".into()))
+        );
+    }
+}
@@ -0,0 +1,3 @@
+ALTER TABLE messages ALTER COLUMN id TYPE BIGINT;
+
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS deleted BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS announcements (
+    id BIGSERIAL PRIMARY KEY,
+    sender TEXT NOT NULL,
+    content TEXT NOT NULL,
+    timestamp BIGINT NOT NULL
+);
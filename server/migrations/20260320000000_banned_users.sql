@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS banned_users (
+    username TEXT PRIMARY KEY,
+    reason TEXT NOT NULL,
+    banned_at BIGINT NOT NULL
+);
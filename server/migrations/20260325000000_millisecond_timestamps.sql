@@ -0,0 +1,5 @@
+-- Message and announcement timestamps move from unix seconds to milliseconds.
+-- Anything below 10^11 can only be seconds: as milliseconds it would predate 1974.
+UPDATE messages SET timestamp = timestamp * 1000 WHERE timestamp < 100000000000;
+UPDATE announcements SET timestamp = timestamp * 1000 WHERE timestamp < 100000000000;
+COMMENT ON COLUMN messages.timestamp IS 'Unix time in milliseconds';
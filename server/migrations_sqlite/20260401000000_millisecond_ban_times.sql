@@ -0,0 +1,3 @@
+-- Ban times move from unix seconds to milliseconds, like message timestamps.
+-- Anything below 10^11 can only be seconds: as milliseconds it would predate 1974.
+UPDATE banned_users SET banned_at = banned_at * 1000 WHERE banned_at < 100000000000;
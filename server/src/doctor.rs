@@ -0,0 +1,390 @@
+//! `server --doctor`: checks the node's dependencies and prints a report.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::StreamExt;
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::{Connection, PgConnection, SqliteConnection};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::config::Config;
+
+/// How long any one check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Channel the redis round trip publishes on; nothing else listens to it.
+const REDIS_PROBE_CHANNEL: &str = "mcs:doctor";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Fail,
+    /// The check doesn't apply to this configuration.
+    Skip,
+}
+
+/// The outcome of one check, with what was found or what went wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            detail: detail.into(),
+        }
+    }
+
+    fn skip(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Skip,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Every check's outcome, in the order they ran.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn count(&self, status: Status) -> usize {
+        self.checks.iter().filter(|c| c.status == status).count()
+    }
+
+    /// Whether nothing failed; skipped checks don't count against the node.
+    pub fn passed(&self) -> bool {
+        self.count(Status::Fail) == 0
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        if self.passed() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let label = match check.status {
+                Status::Pass => "PASS",
+                Status::Fail => "FAIL",
+                Status::Skip => "SKIP",
+            };
+            writeln!(f, "[{label}] {}: {}", check.name, check.detail)?;
+        }
+        writeln!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.count(Status::Pass),
+            self.count(Status::Fail),
+            self.count(Status::Skip)
+        )
+    }
+}
+
+/// Runs every check against `config`.
+pub async fn run(config: &Config) -> Report {
+    let advertised = match config.listen_addr() {
+        Ok((bind_addr, node_addr)) => check_advertised(bind_addr, node_addr.as_str()).await,
+        Err(e) => Check::fail("advertised address", e),
+    };
+    let tls = if config.plaintext {
+        Check::skip("tls", "MCS_PLAINTEXT is set")
+    } else {
+        check_tls(
+            Path::new(&config.tls_cert_path),
+            Path::new(&config.tls_key_path),
+            SystemTime::now(),
+        )
+    };
+
+    let database = if config.persistence {
+        check_database(&config.db_url).await
+    } else {
+        Check::skip("database", "MCS_PERSISTENCE is off")
+    };
+
+    Report {
+        checks: vec![
+            database,
+            check_redis(&config.redis_url).await,
+            tls,
+            advertised,
+        ],
+    }
+}
+
+/// Connects to the database and compares its applied migrations with the
+/// ones built into this binary, without applying any.
+pub async fn check_database(db_url: &str) -> Check {
+    const NAME: &str = "database";
+    let status = if db_url.starts_with("sqlite:") {
+        let migrator = sqlx::migrate!("./migrations_sqlite");
+        timeout(
+            CHECK_TIMEOUT,
+            migration_status::<SqliteConnection>(db_url, &migrator),
+        )
+        .await
+    } else {
+        let migrator = sqlx::migrate!("./migrations");
+        timeout(
+            CHECK_TIMEOUT,
+            migration_status::<PgConnection>(db_url, &migrator),
+        )
+        .await
+    };
+
+    match status {
+        Ok(Ok(Ok(detail))) => Check::pass(NAME, detail),
+        Ok(Ok(Err(problem))) => Check::fail(NAME, problem),
+        Ok(Err(e)) => Check::fail(NAME, format!("could not connect: {e}")),
+        Err(_) => Check::fail(NAME, "timed out connecting"),
+    }
+}
+
+/// Describes how the database's migrations line up with `migrator`'s, or what
+/// would stop startup from applying the rest.
+async fn migration_status<C>(
+    db_url: &str,
+    migrator: &Migrator,
+) -> Result<Result<String, String>, sqlx::Error>
+where
+    C: Connection + Migrate,
+{
+    let mut conn = C::connect(db_url).await?;
+    // Startup creates sqlx's bookkeeping table the same way, so this is harmless.
+    conn.ensure_migrations_table().await?;
+    if let Some(version) = conn.dirty_version().await? {
+        return Ok(Err(format!("migration {version} failed part way")));
+    }
+    let applied = conn.list_applied_migrations().await?;
+
+    for migration in &applied {
+        match migrator.iter().find(|m| m.version == migration.version) {
+            None => {
+                return Ok(Err(format!(
+                    "migration {} is applied but unknown to this build",
+                    migration.version
+                )));
+            }
+            Some(known) if known.checksum != migration.checksum => {
+                return Ok(Err(format!(
+                    "migration {} was changed after being applied",
+                    migration.version
+                )));
+            }
+            Some(_) => {}
+        }
+    }
+
+    let pending = migrator
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .filter(|m| !applied.iter().any(|a| a.version == m.version))
+        .count();
+    Ok(Ok(format!(
+        "connected, {} migrations applied, {pending} pending (applied at startup)",
+        applied.len()
+    )))
+}
+
+/// Connects to redis and checks a published message comes back through pub/sub.
+pub async fn check_redis(redis_url: &str) -> Check {
+    const NAME: &str = "redis";
+    match timeout(CHECK_TIMEOUT, redis_round_trip(redis_url)).await {
+        Ok(Ok(())) => Check::pass(NAME, "connected, pub/sub round trip ok"),
+        Ok(Err(e)) => Check::fail(NAME, e.to_string()),
+        Err(_) => Check::fail(NAME, "timed out waiting for the pub/sub round trip"),
+    }
+}
+
+async fn redis_round_trip(redis_url: &str) -> redis::RedisResult<()> {
+    let client = redis::Client::open(redis_url)?;
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(REDIS_PROBE_CHANNEL).await?;
+
+    let probe = format!("probe-{}", std::process::id());
+    redis::cmd("PUBLISH")
+        .arg(REDIS_PROBE_CHANNEL)
+        .arg(&probe)
+        .query_async::<()>(&mut conn)
+        .await?;
+
+    let mut messages = pubsub.on_message();
+    while let Some(msg) = messages.next().await {
+        if msg.get_payload::<String>()? == probe {
+            return Ok(());
+        }
+    }
+    Err(redis::RedisError::from((
+        redis::ErrorKind::Io,
+        "pub/sub connection closed",
+    )))
+}
+
+/// Loads the certificate chain and key the way startup does and checks the
+/// leaf certificate is valid at `now`.
+pub fn check_tls(cert_path: &Path, key_path: &Path, now: SystemTime) -> Check {
+    const NAME: &str = "tls";
+    let identity =
+        match ::tls::load_identity(cert_path, key_path, ::tls::key_passphrase().as_deref()) {
+            Ok(identity) => identity,
+            Err(e) => return Check::fail(NAME, e.to_string()),
+        };
+    let Some(leaf) = identity.chain.first() else {
+        return Check::fail(NAME, "certificate chain is empty");
+    };
+    let cert = match X509Certificate::from_der(leaf) {
+        Ok((_, cert)) => cert,
+        Err(e) => return Check::fail(NAME, format!("unreadable certificate: {e}")),
+    };
+
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| i64::try_from(d.as_secs()).unwrap_or(i64::MAX));
+    let validity = cert.validity();
+    if now < validity.not_before.timestamp() {
+        return Check::fail(
+            NAME,
+            format!("certificate not valid until {}", validity.not_before),
+        );
+    }
+    let remaining = validity.not_after.timestamp() - now;
+    if remaining < 0 {
+        return Check::fail(NAME, format!("certificate expired {}", validity.not_after));
+    }
+    Check::pass(
+        NAME,
+        format!(
+            "certificate and key match, expires in {} days",
+            remaining / 86_400
+        ),
+    )
+}
+
+/// Dials the advertised address the way the lb would. When the server isn't
+/// running, a listener stands in for it on the bind address.
+pub async fn check_advertised(bind_addr: SocketAddr, advertise_addr: &str) -> Check {
+    const NAME: &str = "advertised address";
+    // Fails when the server itself is listening, which is just as good.
+    let _stand_in = TcpListener::bind(bind_addr).await.ok();
+
+    match timeout(CHECK_TIMEOUT, TcpStream::connect(advertise_addr)).await {
+        Ok(Ok(_)) => Check::pass(NAME, format!("{advertise_addr} accepts connections")),
+        Ok(Err(e)) => Check::fail(NAME, format!("could not reach {advertise_addr}: {e}")),
+        Err(_) => Check::fail(NAME, format!("timed out dialing {advertise_addr}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Check, Report, Status, check_advertised, check_tls};
+    use rcgen::{CertificateParams, KeyPair, date_time_ymd};
+    use std::process::ExitCode;
+    use std::time::SystemTime;
+    use tokio::net::TcpListener;
+
+    fn report(statuses: &[Status]) -> Report {
+        Report {
+            checks: statuses
+                .iter()
+                .map(|&status| Check {
+                    name: "check",
+                    status,
+                    detail: String::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn any_failure_fails_the_report() {
+        let healthy = report(&[Status::Pass, Status::Skip, Status::Pass]);
+        assert!(healthy.passed());
+        assert_eq!(healthy.exit_code(), ExitCode::SUCCESS);
+
+        let broken = report(&[Status::Pass, Status::Fail, Status::Skip]);
+        assert!(!broken.passed());
+        assert_eq!(broken.exit_code(), ExitCode::FAILURE);
+    }
+
+    #[test]
+    fn report_lists_each_check_and_a_summary() {
+        let report = Report {
+            checks: vec![
+                Check::pass("database", "connected"),
+                Check::fail("redis", "refused"),
+                Check::skip("tls", "MCS_PLAINTEXT is set"),
+            ],
+        };
+
+        assert_eq!(
+            report.to_string(),
+            "[PASS] database: connected\n\
+             [FAIL] redis: refused\n\
+             [SKIP] tls: MCS_PLAINTEXT is set\n\
+             1 passed, 1 failed, 1 skipped\n"
+        );
+    }
+
+    #[test]
+    fn expired_certificate_fails() {
+        let dir = std::env::temp_dir().join(format!("mcs-doctor-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.not_before = date_time_ymd(2020, 1, 1);
+        params.not_after = date_time_ymd(2021, 1, 1);
+        let cert_path = dir.join("server.cert");
+        let key_path = dir.join("server.key");
+        std::fs::write(&cert_path, params.self_signed(&key).unwrap().pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+
+        let check = check_tls(&cert_path, &key_path, SystemTime::now());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(check.status, Status::Fail);
+        assert!(check.detail.contains("expired"), "{}", check.detail);
+    }
+
+    #[tokio::test]
+    async fn advertised_address_must_accept_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let check = check_advertised(addr, &addr.to_string()).await;
+        assert_eq!(check.status, Status::Pass, "{}", check.detail);
+
+        drop(listener);
+        let elsewhere = "127.0.0.1:0".parse().unwrap();
+        let check = check_advertised(elsewhere, &addr.to_string()).await;
+        assert_eq!(check.status, Status::Fail);
+    }
+}
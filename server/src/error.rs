@@ -21,31 +21,67 @@ pub enum Error {
     #[error("redis error: {0}")]
     Redis(#[from] redis::RedisError),
 
-    #[error("username '{0}' is already taken")]
-    UsernameTaken(String),
+    #[error("user '{0}' is already online")]
+    AlreadyOnline(String),
 
-    #[error("username '{0}' is too short")]
-    UsernameTooShort(String),
+    #[error("invalid username: {0}")]
+    InvalidUsername(ChatError),
+
+    #[error("invalid password: {0}")]
+    InvalidPassword(ChatError),
 
     #[error("invalid user credentials")]
     InvalidCredentials,
 
+    #[error("message rejected: {0}")]
+    MessageRejected(String),
+
+    #[error("message exceeds the {0} byte limit")]
+    MessageTooLong(usize),
+
+    #[error("message {0} not found or not owned by the sender")]
+    NotMessageAuthor(u64),
+
+    #[error("user '{0}' is not an admin")]
+    NotAdmin(String),
+
+    #[error("too many failed logins, locked for {0}s")]
+    TooManyAttempts(u64),
+
+    #[error("user is banned: {0}")]
+    Banned(String),
+
     #[error("encryption error: {0}")]
     Encryption(#[from] argon2::Error),
 
     #[error("hashing error: {0}")]
     Hashing(#[from] argon2::password_hash::Error),
 
+    #[error("TLS error: {0}")]
+    Tls(#[from] rustls::Error),
+
+    #[error("{0}")]
+    Certificate(#[from] tls::Error),
+
     #[error("migration error: {0}")]
     Migration(#[from] sqlx::migrate::MigrateError),
 }
 
 impl Error {
-    pub const fn to_chat_error(&self) -> ChatError {
+    pub fn to_chat_error(&self) -> ChatError {
         match self {
             Self::Network(_) => ChatError::Network,
-            Self::UsernameTaken(_) => ChatError::UsernameTaken,
-            Self::UsernameTooShort(_) => ChatError::UsernameTooShort,
+            Self::AlreadyOnline(_) => ChatError::AlreadyOnline,
+            Self::InvalidUsername(e) | Self::InvalidPassword(e) => e.clone(),
+            Self::MessageRejected(reason) => ChatError::MessageRejected(reason.clone()),
+            Self::MessageTooLong(max) => ChatError::MessageTooLong { max: *max },
+            Self::NotMessageAuthor(_) => ChatError::NotMessageAuthor,
+            Self::NotAdmin(_) => ChatError::Unauthorized,
+            Self::InvalidCredentials => ChatError::InvalidCredentials,
+            Self::TooManyAttempts(secs) => ChatError::TooManyAttempts {
+                retry_after_secs: *secs,
+            },
+            Self::Banned(reason) => ChatError::Banned(reason.clone()),
             _ => ChatError::Internal,
         }
     }
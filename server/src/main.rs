@@ -1,13 +1,13 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery, unused_extern_crates)]
 
-use futures::{SinkExt, StreamExt};
-use protocol::{ChatPacket, JoinPacket, McsCodec, Message};
-use tokio::{io::split, net::TcpListener};
-use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{error, info, warn};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use std::process::ExitCode;
+
+use metrics_exporter_prometheus::PrometheusBuilder;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
 
 mod config;
+mod doctor;
 mod error;
 mod repository;
 mod service;
@@ -15,97 +15,102 @@ mod transport;
 
 use config::Config;
 use service::AppState;
-use transport::session::ClientSession;
+use transport::{connection::accept, tls::build_acceptor};
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "server=info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    logging::init("server=info");
 
     let config = Config::load();
-    let addr = format!("{}:{}", config.hostname, config.port);
-    let state: AppState = AppState::new(&config.db_url, &config.redis_url, addr.clone()).await?;
+    if std::env::args().skip(1).any(|arg| arg == "--doctor") {
+        let report = doctor::run(&config).await;
+        print!("{report}");
+        return Ok(report.exit_code());
+    }
+
+    PrometheusBuilder::new()
+        .with_http_listener(([0, 0, 0, 0], config.prometheus_port))
+        .install()?;
+    info!("metrics initialized on port {}", config.prometheus_port);
+
+    // Behind a TLS-terminating lb the server speaks plaintext.
+    let acceptor = if config.plaintext {
+        None
+    } else {
+        Some(build_acceptor(
+            &config.tls_cert_path,
+            &config.tls_key_path,
+            &config.tls_min_version,
+        )?)
+    };
+
+    let (bind_addr, node_addr) = config.listen_addr()?;
+    let state: AppState = AppState::new(&config, node_addr).await?;
     state.node.register().await?;
     state.node.start_heartbeat();
+    state.spawn_load_monitor();
+    if let Some(retention) = config.message_retention {
+        state.chat.spawn_pruner(retention, config.prune_interval);
+    }
 
-    let listener = TcpListener::bind(&addr).await?;
-    info!(%addr, "server running");
+    let listener = TcpListener::bind(bind_addr).await?;
+    info!(%bind_addr, advertised = %state.node.id(), tls = acceptor.is_some(), "server running");
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     loop {
-        let (socket, addr) = match listener.accept().await {
-            Ok(v) => v,
-            Err(e) => {
-                warn!(err=?e, "failed to accept new connection");
-                continue;
-            }
+        let (socket, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!(err=?e, "failed to accept new connection");
+                    continue;
+                }
+            },
+            () = &mut shutdown => break,
         };
 
-        let state = state.clone();
-
-        tokio::spawn(async move {
-            let (reader, writer) = split(socket);
-            let mut framed_reader = FramedRead::new(reader, McsCodec);
-            let mut framed_writer = FramedWrite::new(writer, McsCodec);
-
-            match framed_reader.next().await {
-                // 1. Success: User sent a Join Packet
-                Some(Ok(Message::Join(JoinPacket { username, password }))) => {
-                    match state.auth.register_and_login(&username, &password).await {
-                        Ok(()) => {
-                            info!(user=%username, "user authenticated");
-
-                            let join_msg = match state
-                                .chat
-                                .broadcast_system_message(format!("{username} joined.\n"))
-                                .await
-                            {
-                                Ok(p) => p,
-                                Err(e) => {
-                                    warn!(err=?e, "failed to broadcast join message");
-                                    ChatPacket::new_server_packet(String::new())
-                                }
-                            };
-
-                            match state.chat.get_history(join_msg.timestamp + 1).await {
-                                Ok(history) => {
-                                    let _ =
-                                        framed_writer.send(Message::HistoryResponse(history)).await;
-                                }
-                                Err(e) => {
-                                    error!(err=?e, "failed to fetch history during join");
-                                }
-                            }
-
-                            let mut session =
-                                ClientSession::new(username, state, framed_reader, framed_writer);
-                            session.run().await;
-                        }
-                        Err(e) => {
-                            warn!(user=%username, err=?e, "failed to authenticate user");
-                            let _ = framed_writer.send(Message::Error(e.to_chat_error())).await;
-                        }
-                    }
-                }
-                // 2. Health Check: Connection closed immediately (0 bytes)
-                None => {
-                    // This is normal behavior for the Load Balancer's health check.
-                    // We use 'debug!' so it doesn't spam your console logs.
-                    tracing::debug!(ip = %addr.ip(), "health check probe (connection closed)");
-                }
-                // 3. Actual Protocol Violation: User sent Chat/Heartbeat BEFORE Joining
-                Some(Ok(msg)) => {
-                    warn!(ip = %addr.ip(), ?msg, "protocol violation: expected JoinPacket, got {:?}", msg);
-                }
-                // 4. Decode Error
-                Some(Err(e)) => {
-                    warn!(ip = %addr.ip(), err = ?e, "failed to decode packet");
-                }
+        tokio::spawn(accept(
+            state.clone(),
+            socket,
+            addr,
+            acceptor.clone(),
+            config.proxy_protocol,
+        ));
+    }
+
+    info!("shutdown signal received");
+    if let Err(e) = state.node.drain().await {
+        warn!(err=?e, "failed to drain node, deregistering now");
+        state.node.deregister().await?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
             }
-        });
+            Err(e) => {
+                warn!(err=?e, "failed to install SIGTERM handler");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {},
+        () = terminate => {},
     }
 }
@@ -0,0 +1,286 @@
+use super::{HistoryPage, HistoryQuery, MessageRepository};
+use crate::error::Result;
+use async_trait::async_trait;
+use protocol::ChatPacket;
+
+/// Counter handing out message ids across every node.
+const LAST_ID_KEY: &str = "mcs:messages:last_id";
+/// Prefix of the keys holding each cached message; the id follows it.
+const MESSAGE_KEY_PREFIX: &str = "mcs:message:";
+const ANNOUNCEMENT_KEY: &str = "mcs:announcement";
+
+/// How many of the newest messages are kept; older ones are dropped as new
+/// ones arrive.
+const CACHED_MESSAGES: u64 = 1000;
+
+/// Keeps only a window of recent messages in redis, for running without a
+/// database when chat doesn't need to outlive the cluster.
+///
+/// Ids still come from one shared counter, so every node agrees on them and
+/// clients can dedupe and page as they would against a database.
+#[derive(Clone)]
+pub struct EphemeralRepository {
+    conn: redis::aio::MultiplexedConnection,
+}
+
+impl EphemeralRepository {
+    pub async fn new(redis_url: &str) -> Result<Self> {
+        let client = redis::Client::open(redis_url)?;
+        let conn = client.get_multiplexed_async_connection().await?;
+        Ok(Self { conn })
+    }
+
+    /// Returns every message still cached, in no particular order.
+    async fn cached(&self) -> Result<Vec<ChatPacket>> {
+        let mut conn = self.conn.clone();
+        let last_id: Option<u64> = redis::cmd("GET")
+            .arg(LAST_ID_KEY)
+            .query_async(&mut conn)
+            .await?;
+        let Some(last_id) = last_id.filter(|&id| id > 0) else {
+            return Ok(Vec::new());
+        };
+
+        let first_id = last_id.saturating_sub(CACHED_MESSAGES - 1).max(1);
+        let keys: Vec<String> = (first_id..=last_id).map(message_key).collect();
+        let stored: Vec<Option<Vec<u8>>> =
+            redis::cmd("MGET").arg(keys).query_async(&mut conn).await?;
+
+        Ok(stored
+            .into_iter()
+            .flatten()
+            .filter_map(|bytes| postcard::from_bytes(&bytes).ok())
+            .collect())
+    }
+
+    /// Applies `change` to message `id` if `sender` owns it and it's live.
+    async fn update(
+        &self,
+        id: u64,
+        sender: &str,
+        change: impl FnOnce(&mut ChatPacket),
+    ) -> Result<bool> {
+        let key = message_key(id);
+        let mut conn = self.conn.clone();
+        let stored: Option<Vec<u8>> = redis::cmd("GET").arg(&key).query_async(&mut conn).await?;
+        let Some(mut msg) =
+            stored.and_then(|bytes| postcard::from_bytes::<ChatPacket>(&bytes).ok())
+        else {
+            return Ok(false);
+        };
+        if msg.sender != sender || msg.deleted {
+            return Ok(false);
+        }
+
+        change(&mut msg);
+        // XX so a message dropped from the window meanwhile stays dropped.
+        redis::cmd("SET")
+            .arg(key)
+            .arg(postcard::to_stdvec(&msg)?)
+            .arg("XX")
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(true)
+    }
+}
+
+fn message_key(id: u64) -> String {
+    format!("{MESSAGE_KEY_PREFIX}{id}")
+}
+
+/// Picks the page of up to `limit` messages ordered just before
+/// `(before_ts, before_id)`, from `sender` when one is given.
+fn page_before(
+    mut messages: Vec<ChatPacket>,
+    sender: Option<&str>,
+    query: HistoryQuery,
+) -> HistoryPage {
+    messages.retain(|m| {
+        sender.is_none_or(|s| m.sender == s)
+            && (m.timestamp, m.id) < (query.before_ts, query.before_id)
+    });
+    messages.sort_by_key(|m| std::cmp::Reverse((m.timestamp, m.id)));
+    messages.truncate(query.limit as usize + 1);
+    HistoryPage::from_newest_first(messages, query.limit)
+}
+
+/// Picks the page of up to `limit` messages ordered just after
+/// `(after_ts, after_id)`, oldest first.
+fn page_after(
+    mut messages: Vec<ChatPacket>,
+    after_ts: i64,
+    after_id: u64,
+    limit: u32,
+) -> HistoryPage {
+    messages.retain(|m| (m.timestamp, m.id) > (after_ts, after_id));
+    messages.sort_by_key(|m| (m.timestamp, m.id));
+    messages.truncate(limit as usize + 1);
+    HistoryPage::from_oldest_first(messages, limit)
+}
+
+#[async_trait]
+impl MessageRepository for EphemeralRepository {
+    async fn save_message(&self, msg: &ChatPacket) -> Result<u64> {
+        let mut conn = self.conn.clone();
+        let id: u64 = redis::cmd("INCR")
+            .arg(LAST_ID_KEY)
+            .query_async(&mut conn)
+            .await?;
+        let saved = ChatPacket { id, ..msg.clone() };
+
+        let mut pipe = redis::pipe();
+        pipe.cmd("SET")
+            .arg(message_key(id))
+            .arg(postcard::to_stdvec(&saved)?)
+            .ignore();
+        if id > CACHED_MESSAGES {
+            pipe.cmd("DEL")
+                .arg(message_key(id - CACHED_MESSAGES))
+                .ignore();
+        }
+        pipe.query_async::<()>(&mut conn).await?;
+
+        Ok(id)
+    }
+
+    async fn get_recent_messages(&self, query: HistoryQuery) -> Result<HistoryPage> {
+        Ok(page_before(self.cached().await?, None, query))
+    }
+
+    async fn get_messages_by_sender(
+        &self,
+        sender: &str,
+        before_ts: i64,
+        before_id: u64,
+        limit: u32,
+    ) -> Result<HistoryPage> {
+        let query = HistoryQuery {
+            before_ts,
+            before_id,
+            limit,
+        };
+        Ok(page_before(self.cached().await?, Some(sender), query))
+    }
+
+    async fn get_messages_after(
+        &self,
+        after_ts: i64,
+        after_id: u64,
+        limit: u32,
+    ) -> Result<HistoryPage> {
+        Ok(page_after(self.cached().await?, after_ts, after_id, limit))
+    }
+
+    async fn edit_message(&self, id: u64, sender: &str, content: &str) -> Result<bool> {
+        self.update(id, sender, |msg| msg.content = content.to_string())
+            .await
+    }
+
+    async fn delete_message(&self, id: u64, sender: &str) -> Result<bool> {
+        self.update(id, sender, |msg| {
+            msg.content.clear();
+            msg.deleted = true;
+        })
+        .await
+    }
+
+    async fn save_announcement(&self, _sender: &str, content: &str, _timestamp: i64) -> Result<()> {
+        let mut conn = self.conn.clone();
+        redis::cmd("SET")
+            .arg(ANNOUNCEMENT_KEY)
+            .arg(content)
+            .query_async::<()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_latest_announcement(&self) -> Result<Option<String>> {
+        let mut conn = self.conn.clone();
+        Ok(redis::cmd("GET")
+            .arg(ANNOUNCEMENT_KEY)
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    async fn search_messages(&self, query: &str, limit: u32) -> Result<Vec<ChatPacket>> {
+        let query = query.to_lowercase();
+        let mut matches = self.cached().await?;
+        matches.retain(|m| !m.deleted && m.content.to_lowercase().contains(&query));
+        matches.sort_by_key(|m| std::cmp::Reverse((m.timestamp, m.id)));
+        matches.truncate(limit as usize);
+        Ok(matches)
+    }
+
+    // The window already bounds what's kept, so retention has nothing to do.
+    async fn prune_older_than(&self, _before_ts: i64) -> Result<u64> {
+        Ok(0)
+    }
+
+    async fn count_messages(&self) -> Result<u64> {
+        Ok(self.cached().await?.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{page_after, page_before};
+    use crate::repository::HistoryQuery;
+    use protocol::ChatPacket;
+
+    fn cached() -> Vec<ChatPacket> {
+        // Out of order, as MGET results aren't sorted by timestamp.
+        [
+            (3, "alice", 30),
+            (1, "alice", 10),
+            (4, "bob", 40),
+            (2, "bob", 20),
+        ]
+        .into_iter()
+        .map(|(id, sender, timestamp)| ChatPacket {
+            id,
+            timestamp,
+            ..ChatPacket::new_user_packet(sender.to_string(), format!("msg {id}"))
+        })
+        .collect()
+    }
+
+    fn ids(messages: &[ChatPacket]) -> Vec<u64> {
+        messages.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn pages_before_the_cursor_come_from_the_cached_window() {
+        let query = HistoryQuery {
+            before_ts: i64::MAX,
+            before_id: u64::MAX,
+            limit: 2,
+        };
+
+        let page = page_before(cached(), None, query);
+        assert_eq!(ids(&page.messages), [3, 4]);
+        assert!(page.has_more);
+
+        let older = HistoryQuery {
+            before_ts: 30,
+            before_id: 3,
+            ..query
+        };
+        let page = page_before(cached(), None, older);
+        assert_eq!(ids(&page.messages), [1, 2]);
+        assert!(!page.has_more);
+
+        let page = page_before(cached(), Some("bob"), query);
+        assert_eq!(ids(&page.messages), [2, 4]);
+        assert!(!page.has_more);
+
+        assert!(page_before(Vec::new(), None, query).messages.is_empty());
+    }
+
+    #[test]
+    fn pages_after_the_cursor_are_oldest_first() {
+        let page = page_after(cached(), 10, 1, 2);
+
+        assert_eq!(ids(&page.messages), [2, 3]);
+        assert!(page.has_more);
+    }
+}
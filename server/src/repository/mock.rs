@@ -0,0 +1,413 @@
+//! In-memory repositories for service and session tests.
+
+use super::{
+    HistoryPage, HistoryQuery, MessageRepository, PresenceRepository, SessionClaim, SessionOwner,
+    UserRepository,
+};
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use protocol::{ChatPacket, Message, NodeAddr};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::broadcast::Sender;
+
+/// Stores users, messages, and presence in memory and records broadcasts.
+#[derive(Default)]
+pub struct MockRepository {
+    pub users: Mutex<HashMap<String, String>>,
+    pub saved: Mutex<Vec<ChatPacket>>,
+    pub announcements: Mutex<Vec<String>>,
+    pub broadcasts: Mutex<Vec<Message>>,
+    /// Local sessions, which get broadcasts to subscribed rooms even while
+    /// presence is down, as with redis.
+    pub local_sender: Mutex<Option<Sender<Message>>>,
+    /// Rooms whose broadcasts this node is subscribed to.
+    pub rooms: Mutex<HashSet<String>>,
+    /// Messages sent to single users, in order.
+    pub user_messages: Mutex<Vec<(String, Message)>>,
+    /// Ban reasons by username.
+    pub bans: Mutex<HashMap<String, String>>,
+    /// Owner of each online user's session.
+    pub online: Mutex<HashMap<String, SessionOwner>>,
+    /// Last activity per user in unix ms, left behind when a session expires.
+    pub last_active: Mutex<HashMap<String, i64>>,
+    pub nodes: Mutex<HashSet<String>>,
+    pub node_max_connections: Mutex<HashMap<String, usize>>,
+    pub draining_nodes: Mutex<HashSet<String>>,
+    /// Failed login count and cooldown per username.
+    pub login_failures: Mutex<HashMap<String, (u64, u64)>>,
+    /// Makes every presence call fail, as if redis were down.
+    pub presence_down: AtomicBool,
+}
+
+impl MockRepository {
+    fn presence_up(&self) -> Result<()> {
+        if self.presence_down.load(Ordering::Relaxed) {
+            return Err(Error::Redis(redis::RedisError::from((
+                redis::ErrorKind::Io,
+                "presence store down",
+            ))));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserRepository for MockRepository {
+    async fn user_exists(&self, username: &str) -> Result<bool> {
+        Ok(self.users.lock().unwrap().contains_key(username))
+    }
+
+    async fn create_user(&self, username: &str, password: &str) -> Result<()> {
+        self.users
+            .lock()
+            .unwrap()
+            .entry(username.to_string())
+            .or_insert_with(|| password.to_string());
+        Ok(())
+    }
+
+    async fn verify_credentials(&self, username: &str, password: &str) -> Result<bool> {
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .get(username)
+            .is_some_and(|p| p == password))
+    }
+
+    async fn update_password(
+        &self,
+        username: &str,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<bool> {
+        let mut users = self.users.lock().unwrap();
+        match users.get_mut(username) {
+            Some(password) if password == old_password => {
+                *password = new_password.to_string();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn ban_user(&self, username: &str, reason: &str) -> Result<()> {
+        self.bans
+            .lock()
+            .unwrap()
+            .insert(username.to_string(), reason.to_string());
+        Ok(())
+    }
+
+    async fn get_ban(&self, username: &str) -> Result<Option<String>> {
+        Ok(self.bans.lock().unwrap().get(username).cloned())
+    }
+}
+
+#[async_trait]
+impl MessageRepository for MockRepository {
+    async fn save_message(&self, msg: &ChatPacket) -> Result<u64> {
+        let mut saved = self.saved.lock().unwrap();
+        let mut msg = msg.clone();
+        msg.id = saved.len() as u64 + 1;
+        saved.push(msg);
+        Ok(saved.len() as u64)
+    }
+
+    async fn get_recent_messages(&self, query: HistoryQuery) -> Result<HistoryPage> {
+        let mut rows: Vec<ChatPacket> = self
+            .saved
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| (m.timestamp, m.id) < (query.before_ts, query.before_id))
+            .cloned()
+            .collect();
+        rows.sort_by_key(|m| std::cmp::Reverse((m.timestamp, m.id)));
+        rows.truncate(query.limit as usize + 1);
+        Ok(HistoryPage::from_newest_first(rows, query.limit))
+    }
+
+    async fn get_messages_by_sender(
+        &self,
+        sender: &str,
+        before_ts: i64,
+        before_id: u64,
+        limit: u32,
+    ) -> Result<HistoryPage> {
+        let mut rows: Vec<ChatPacket> = self
+            .saved
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.sender == sender && (m.timestamp, m.id) < (before_ts, before_id))
+            .cloned()
+            .collect();
+        rows.sort_by_key(|m| std::cmp::Reverse((m.timestamp, m.id)));
+        rows.truncate(limit as usize + 1);
+        Ok(HistoryPage::from_newest_first(rows, limit))
+    }
+
+    async fn get_messages_after(
+        &self,
+        after_ts: i64,
+        after_id: u64,
+        limit: u32,
+    ) -> Result<HistoryPage> {
+        let mut rows: Vec<ChatPacket> = self
+            .saved
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| (m.timestamp, m.id) > (after_ts, after_id))
+            .cloned()
+            .collect();
+        rows.sort_by_key(|m| (m.timestamp, m.id));
+        rows.truncate(limit as usize + 1);
+        Ok(HistoryPage::from_oldest_first(rows, limit))
+    }
+
+    async fn edit_message(&self, id: u64, sender: &str, content: &str) -> Result<bool> {
+        let mut saved = self.saved.lock().unwrap();
+        match saved
+            .iter_mut()
+            .find(|m| m.id == id && m.sender == sender && !m.deleted)
+        {
+            Some(msg) => {
+                msg.content = content.to_string();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn delete_message(&self, id: u64, sender: &str) -> Result<bool> {
+        let mut saved = self.saved.lock().unwrap();
+        match saved
+            .iter_mut()
+            .find(|m| m.id == id && m.sender == sender && !m.deleted)
+        {
+            Some(msg) => {
+                msg.content.clear();
+                msg.deleted = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn save_announcement(&self, _sender: &str, content: &str, _timestamp: i64) -> Result<()> {
+        self.announcements.lock().unwrap().push(content.to_string());
+        Ok(())
+    }
+
+    async fn get_latest_announcement(&self) -> Result<Option<String>> {
+        Ok(self.announcements.lock().unwrap().last().cloned())
+    }
+
+    async fn search_messages(&self, query: &str, limit: u32) -> Result<Vec<ChatPacket>> {
+        let query = query.to_lowercase();
+        let mut matches: Vec<ChatPacket> = self
+            .saved
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| !m.deleted && m.content.to_lowercase().contains(&query))
+            .cloned()
+            .collect();
+        matches.sort_by_key(|m| std::cmp::Reverse(m.timestamp));
+        matches.truncate(limit as usize);
+        Ok(matches)
+    }
+
+    async fn prune_older_than(&self, before_ts: i64) -> Result<u64> {
+        let mut saved = self.saved.lock().unwrap();
+        let before = saved.len();
+        saved.retain(|m| m.timestamp >= before_ts);
+        Ok((before - saved.len()) as u64)
+    }
+
+    async fn count_messages(&self) -> Result<u64> {
+        Ok(self.saved.lock().unwrap().len() as u64)
+    }
+}
+
+#[async_trait]
+impl PresenceRepository for MockRepository {
+    async fn set_online(&self, username: &str, owner: &SessionOwner) -> Result<SessionClaim> {
+        self.presence_up()?;
+        match self.online.lock().unwrap().entry(username.to_string()) {
+            Entry::Occupied(held) => Ok(SessionClaim::Held(Some(held.get().clone()))),
+            Entry::Vacant(slot) => {
+                slot.insert(owner.clone());
+                self.last_active
+                    .lock()
+                    .unwrap()
+                    .insert(username.to_string(), Utc::now().timestamp_millis());
+                Ok(SessionClaim::Claimed)
+            }
+        }
+    }
+
+    async fn take_over_session(
+        &self,
+        username: &str,
+        stale: &SessionOwner,
+        owner: &SessionOwner,
+    ) -> Result<bool> {
+        self.presence_up()?;
+        Ok(match self.online.lock().unwrap().get_mut(username) {
+            Some(held) if held == stale => {
+                *held = owner.clone();
+                self.last_active
+                    .lock()
+                    .unwrap()
+                    .insert(username.to_string(), Utc::now().timestamp_millis());
+                true
+            }
+            _ => false,
+        })
+    }
+
+    async fn set_offline(&self, username: &str, owner: &SessionOwner) -> Result<()> {
+        self.presence_up()?;
+        let mut online = self.online.lock().unwrap();
+        if online.get(username) == Some(owner) {
+            online.remove(username);
+            self.last_active.lock().unwrap().remove(username);
+        }
+        drop(online);
+        Ok(())
+    }
+
+    async fn refresh_heartbeat(&self, username: &str, last_active: i64) -> Result<()> {
+        self.presence_up()?;
+        self.last_active
+            .lock()
+            .unwrap()
+            .insert(username.to_string(), last_active);
+        Ok(())
+    }
+
+    async fn get_online_users(&self) -> Result<Vec<(String, i64)>> {
+        self.presence_up()?;
+        let online = self.online.lock().unwrap();
+        Ok(self
+            .last_active
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(username, _)| online.contains_key(*username))
+            .map(|(username, at)| (username.clone(), *at))
+            .collect())
+    }
+
+    async fn get_sessions(&self) -> Result<Vec<(String, SessionOwner)>> {
+        self.presence_up()?;
+        Ok(self
+            .online
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(username, owner)| (username.clone(), owner.clone()))
+            .collect())
+    }
+
+    async fn register_node(
+        &self,
+        address: &NodeAddr,
+        max_connections: Option<usize>,
+    ) -> Result<()> {
+        self.presence_up()?;
+        self.nodes.lock().unwrap().insert(address.to_string());
+        let mut caps = self.node_max_connections.lock().unwrap();
+        match max_connections {
+            Some(max) => caps.insert(address.to_string(), max),
+            None => caps.remove(address.as_str()),
+        };
+        drop(caps);
+        Ok(())
+    }
+
+    async fn set_node_draining(&self, address: &NodeAddr, _ttl_secs: u64) -> Result<()> {
+        self.presence_up()?;
+        self.draining_nodes
+            .lock()
+            .unwrap()
+            .insert(address.to_string());
+        Ok(())
+    }
+
+    async fn deregister_node(&self, address: &NodeAddr) -> Result<()> {
+        self.presence_up()?;
+        self.nodes.lock().unwrap().remove(address.as_str());
+        self.node_max_connections
+            .lock()
+            .unwrap()
+            .remove(address.as_str());
+        Ok(())
+    }
+
+    async fn broadcast(&self, room: &str, msg: Message) -> Result<()> {
+        if let Some(sender) = self.local_sender.lock().unwrap().as_ref()
+            && self.rooms.lock().unwrap().contains(room)
+        {
+            let _ = sender.send(msg.clone());
+        }
+        self.presence_up()?;
+        self.broadcasts.lock().unwrap().push(msg);
+        Ok(())
+    }
+
+    async fn subscribe_room(&self, room: &str) -> Result<()> {
+        self.presence_up()?;
+        self.rooms.lock().unwrap().insert(room.to_string());
+        Ok(())
+    }
+
+    async fn unsubscribe_room(&self, room: &str) -> Result<()> {
+        self.presence_up()?;
+        self.rooms.lock().unwrap().remove(room);
+        Ok(())
+    }
+
+    async fn send_to_user(&self, username: &str, msg: Message) -> Result<()> {
+        self.presence_up()?;
+        self.user_messages
+            .lock()
+            .unwrap()
+            .push((username.to_string(), msg));
+        Ok(())
+    }
+
+    async fn record_login_failure(&self, username: &str, cooldown_secs: u64) -> Result<u64> {
+        self.presence_up()?;
+        let mut failures = self.login_failures.lock().unwrap();
+        let entry = failures.entry(username.to_string()).or_default();
+        *entry = (entry.0 + 1, cooldown_secs);
+        let count = entry.0;
+        drop(failures);
+        Ok(count)
+    }
+
+    async fn get_login_failures(&self, username: &str) -> Result<(u64, u64)> {
+        self.presence_up()?;
+        Ok(self
+            .login_failures
+            .lock()
+            .unwrap()
+            .get(username)
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn clear_login_failures(&self, username: &str) -> Result<()> {
+        self.presence_up()?;
+        self.login_failures.lock().unwrap().remove(username);
+        Ok(())
+    }
+}
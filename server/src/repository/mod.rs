@@ -1,30 +1,246 @@
 use crate::error::Result;
 use async_trait::async_trait;
-use protocol::{ChatPacket, Message};
+use protocol::{ChatPacket, HistoryDirection, Message, NodeAddr};
+use std::fmt;
+use std::str::FromStr;
 
+pub mod ephemeral;
+#[cfg(test)]
+pub mod mock;
+mod password;
 pub mod postgres;
 pub mod redis;
+pub mod sqlite;
+
+/// Number of messages returned per history request unless configured otherwise.
+pub const HISTORY_PAGE_SIZE: u32 = 50;
+
+/// Selects the page of up to `limit` messages ordered just before
+/// `(before_ts, before_id)`.
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryQuery {
+    pub before_ts: i64,
+    pub before_id: u64,
+    pub limit: u32,
+}
+
+/// A page of history, always oldest first as clients render it.
+#[derive(Debug, Default)]
+pub struct HistoryPage {
+    pub messages: Vec<ChatPacket>,
+    /// Whether more messages exist past the page: older than the first for a
+    /// page before a cursor, newer than the last for one after it.
+    pub has_more: bool,
+}
+
+impl HistoryPage {
+    /// Builds a page from up to `page_size + 1` rows, oldest first.
+    fn from_oldest_first(mut rows: Vec<ChatPacket>, page_size: u32) -> Self {
+        let page_size = page_size as usize;
+        let has_more = rows.len() > page_size;
+        rows.truncate(page_size);
+        Self {
+            messages: rows,
+            has_more,
+        }
+    }
+
+    /// Builds a page from up to `page_size + 1` rows, newest first.
+    fn from_newest_first(mut rows: Vec<ChatPacket>, page_size: u32) -> Self {
+        let page_size = page_size as usize;
+        let has_more = rows.len() > page_size;
+        rows.truncate(page_size);
+        rows.reverse();
+        Self {
+            messages: rows,
+            has_more,
+        }
+    }
+}
+
+impl From<HistoryPage> for Message {
+    fn from(page: HistoryPage) -> Self {
+        Self::HistoryResponse {
+            messages: page.messages,
+            has_more: page.has_more,
+            sender: None,
+            direction: HistoryDirection::Before,
+        }
+    }
+}
+
+/// Who holds a user's session: the node serving it and a nonce unique to it.
+///
+/// Stored as `{node}/{nonce}`; the node goes first since it contains ':'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionOwner {
+    pub node: NodeAddr,
+    pub nonce: u64,
+}
+
+impl fmt::Display for SessionOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.node, self.nonce)
+    }
+}
+
+impl FromStr for SessionOwner {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (node, nonce) = s.rsplit_once('/').ok_or(())?;
+        Ok(Self {
+            node: node.parse().map_err(drop)?,
+            nonce: nonce.parse().map_err(drop)?,
+        })
+    }
+}
+
+/// The outcome of marking a user online.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionClaim {
+    Claimed,
+    /// Another session holds the name; `None` if its owner can't be read,
+    /// as with keys written before owners were recorded.
+    Held(Option<SessionOwner>),
+}
+
+/// A message for one user, delivered to whichever node holds their session.
+#[derive(Debug, Clone)]
+pub struct UserMessage {
+    pub username: String,
+    pub message: Message,
+}
 
 /// Manages persistent user data.
 #[async_trait]
 pub trait UserRepository: Send + Sync {
+    async fn user_exists(&self, username: &str) -> Result<bool>;
     async fn create_user(&self, username: &str, password: &str) -> Result<()>;
     async fn verify_credentials(&self, username: &str, password: &str) -> Result<bool>;
+    /// Replaces the password, returning `false` without writing anything
+    /// unless `old_password` is the current one.
+    async fn update_password(
+        &self,
+        username: &str,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<bool>;
+    /// Bans a user, replacing the reason if they were already banned.
+    async fn ban_user(&self, username: &str, reason: &str) -> Result<()>;
+    /// Returns the ban reason if the user is banned.
+    async fn get_ban(&self, username: &str) -> Result<Option<String>>;
 }
 
 /// Manages persistent message history.
 #[async_trait]
 pub trait MessageRepository: Send + Sync {
-    async fn save_message(&self, msg: &ChatPacket) -> Result<()>;
-    async fn get_recent_messages(&self, before_ts: i64) -> Result<Vec<ChatPacket>>;
+    /// Persists a message and returns its assigned id.
+    async fn save_message(&self, msg: &ChatPacket) -> Result<u64>;
+    /// Returns the page `query` selects.
+    async fn get_recent_messages(&self, query: HistoryQuery) -> Result<HistoryPage>;
+    /// Returns the page of up to `limit` messages from `sender` ordered just
+    /// before `(before_ts, before_id)`.
+    async fn get_messages_by_sender(
+        &self,
+        sender: &str,
+        before_ts: i64,
+        before_id: u64,
+        limit: u32,
+    ) -> Result<HistoryPage>;
+    /// Returns the page of up to `limit` messages ordered just after
+    /// `(after_ts, after_id)`, oldest first.
+    async fn get_messages_after(
+        &self,
+        after_ts: i64,
+        after_id: u64,
+        limit: u32,
+    ) -> Result<HistoryPage>;
+    /// Replaces the content of a message, returning `false` if `sender` doesn't own it.
+    async fn edit_message(&self, id: u64, sender: &str, content: &str) -> Result<bool>;
+    /// Tombstones a message, returning `false` if `sender` doesn't own it.
+    async fn delete_message(&self, id: u64, sender: &str) -> Result<bool>;
+    async fn save_announcement(&self, sender: &str, content: &str, timestamp: i64) -> Result<()>;
+    async fn get_latest_announcement(&self) -> Result<Option<String>>;
+    /// Returns up to `limit` live messages containing `query`, newest first.
+    async fn search_messages(&self, query: &str, limit: u32) -> Result<Vec<ChatPacket>>;
+    /// Deletes messages sent before `before_ts`, returning how many were removed.
+    async fn prune_older_than(&self, before_ts: i64) -> Result<u64>;
+    /// Returns the number of stored messages, including tombstoned ones.
+    async fn count_messages(&self) -> Result<u64>;
 }
 
 /// Manages ephemeral states.
 #[async_trait]
 pub trait PresenceRepository: Send + Sync {
-    async fn set_online(&self, username: &str) -> Result<bool>;
-    async fn set_offline(&self, username: &str) -> Result<()>;
-    async fn refresh_heartbeat(&self, username: &str) -> Result<()>;
-    async fn register_node(&self, address: &str) -> Result<()>;
-    async fn broadcast(&self, msg: Message) -> Result<()>;
+    /// Marks `username` online under `owner` unless another session holds it.
+    async fn set_online(&self, username: &str, owner: &SessionOwner) -> Result<SessionClaim>;
+    /// Hands `username`'s session from `stale` to `owner`, returning `false`
+    /// if `stale` no longer holds it.
+    async fn take_over_session(
+        &self,
+        username: &str,
+        stale: &SessionOwner,
+        owner: &SessionOwner,
+    ) -> Result<bool>;
+    /// Marks `username` offline, unless a session other than `owner` holds it.
+    async fn set_offline(&self, username: &str, owner: &SessionOwner) -> Result<()>;
+    /// Keeps `username` marked online, recording `last_active`, in unix ms,
+    /// as when they last chatted or typed.
+    async fn refresh_heartbeat(&self, username: &str, last_active: i64) -> Result<()>;
+    /// Returns everyone online with when they were last active, in unix ms.
+    async fn get_online_users(&self) -> Result<Vec<(String, i64)>>;
+    /// Returns every live session and its owner, across all nodes.
+    async fn get_sessions(&self) -> Result<Vec<(String, SessionOwner)>>;
+    /// Lists a node for load balancers, along with the most connections they
+    /// should send it, if it's capped.
+    async fn register_node(&self, address: &NodeAddr, max_connections: Option<usize>)
+    -> Result<()>;
+    /// Flags a node so load balancers stop routing new clients to it.
+    async fn set_node_draining(&self, address: &NodeAddr, ttl_secs: u64) -> Result<()>;
+    async fn deregister_node(&self, address: &NodeAddr) -> Result<()>;
+    /// Publishes `msg` to every node with a session in `room`. This node's own
+    /// sessions get it first, so they still do if publishing fails.
+    async fn broadcast(&self, room: &str, msg: Message) -> Result<()>;
+    /// Starts delivering `room`'s broadcasts to this node.
+    async fn subscribe_room(&self, room: &str) -> Result<()>;
+    /// Stops delivering `room`'s broadcasts to this node.
+    async fn unsubscribe_room(&self, room: &str) -> Result<()>;
+    /// Publishes `msg` to `username`'s sessions on every node.
+    async fn send_to_user(&self, username: &str, msg: Message) -> Result<()>;
+    /// Counts a failed login and restarts its cooldown, returning the failure count.
+    async fn record_login_failure(&self, username: &str, cooldown_secs: u64) -> Result<u64>;
+    /// Returns the failure count and seconds until it expires.
+    async fn get_login_failures(&self, username: &str) -> Result<(u64, u64)>;
+    async fn clear_login_failures(&self, username: &str) -> Result<()>;
+}
+
+/// Clamps a cursor id into the signed range message ids are stored in.
+fn id_bound(id: u64) -> i64 {
+    i64::try_from(id).unwrap_or(i64::MAX)
+}
+
+/// Escapes `LIKE` wildcards so user input only ever matches literally.
+fn escape_like(query: &str) -> String {
+    let mut escaped = String::with_capacity(query.len());
+    for c in query.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_like;
+
+    #[test]
+    fn escape_like_escapes_wildcards() {
+        assert_eq!(escape_like("hello"), "hello");
+        assert_eq!(escape_like("100%"), "100\\%");
+        assert_eq!(escape_like("snake_case"), "snake\\_case");
+        assert_eq!(escape_like(r"C:\dir"), r"C:\\dir");
+    }
 }
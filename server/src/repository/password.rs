@@ -0,0 +1,73 @@
+//! Argon2id password hashing shared by the SQL repositories.
+
+use crate::config::Argon2Config;
+use crate::error::Result;
+use argon2::{
+    Algorithm, Argon2, Params, Version,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+};
+
+pub fn build_hasher(config: &Argon2Config) -> Result<Argon2<'static>> {
+    let params = Params::new(
+        config.memory_kib,
+        config.iterations,
+        config.parallelism,
+        None,
+    )?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+pub fn hash_password(hasher: &Argon2, password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(hasher
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Verifies against the parameters encoded in the PHC string, not the hasher's own.
+pub fn verify_password(hasher: &Argon2, password: &str, hash: &str) -> Result<bool> {
+    let parsed_hash = PasswordHash::new(hash)?;
+    Ok(hasher
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_hasher, hash_password, verify_password};
+    use crate::config::Argon2Config;
+    use argon2::Argon2;
+
+    const TUNED: Argon2Config = Argon2Config {
+        memory_kib: 8 * 1024,
+        iterations: 3,
+        parallelism: 2,
+    };
+
+    #[test]
+    fn tuned_hash_verifies() {
+        let hasher = build_hasher(&TUNED).unwrap();
+        let hash = hash_password(&hasher, "hunter22").unwrap();
+
+        assert!(hash.contains("m=8192,t=3,p=2"));
+        assert!(verify_password(&hasher, "hunter22", &hash).unwrap());
+        assert!(!verify_password(&hasher, "hunter23", &hash).unwrap());
+    }
+
+    #[test]
+    fn hash_verifies_with_different_params() {
+        let tuned = build_hasher(&TUNED).unwrap();
+        let hash = hash_password(&Argon2::default(), "hunter22").unwrap();
+
+        assert!(verify_password(&tuned, "hunter22", &hash).unwrap());
+    }
+
+    #[test]
+    fn invalid_params_are_rejected() {
+        let config = Argon2Config {
+            memory_kib: 1,
+            ..TUNED
+        };
+        assert!(build_hasher(&config).is_err());
+    }
+}
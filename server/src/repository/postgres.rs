@@ -1,9 +1,8 @@
-use super::{MessageRepository, UserRepository};
+use super::password::{build_hasher, hash_password, verify_password};
+use super::{HistoryPage, HistoryQuery, MessageRepository, UserRepository, escape_like, id_bound};
+use crate::config::Argon2Config;
 use crate::error::Result;
-use argon2::{
-    Argon2,
-    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
-};
+use argon2::Argon2;
 use async_trait::async_trait;
 use protocol::ChatPacket;
 use sqlx::{PgPool, postgres::PgPoolOptions};
@@ -11,23 +10,33 @@ use sqlx::{PgPool, postgres::PgPoolOptions};
 #[derive(Clone)]
 pub struct PostgresRepository {
     pool: PgPool,
+    hasher: Argon2<'static>,
 }
 
 impl PostgresRepository {
-    pub async fn new(url: &str) -> Result<Self> {
+    pub async fn new(url: &str, argon2: &Argon2Config) -> Result<Self> {
+        let hasher = build_hasher(argon2)?;
         let pool = PgPoolOptions::new().max_connections(5).connect(url).await?;
         sqlx::migrate!("./migrations").run(&pool).await?;
-        Ok(Self { pool })
+        Ok(Self { pool, hasher })
     }
 }
 
 #[async_trait]
 impl UserRepository for PostgresRepository {
+    async fn user_exists(&self, username: &str) -> Result<bool> {
+        let row = sqlx::query!(
+            r#"SELECT EXISTS(SELECT 1 FROM users WHERE username = $1) AS "exists!""#,
+            username
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.exists)
+    }
+
     async fn create_user(&self, username: &str, password: &str) -> Result<()> {
-        let salt = SaltString::generate(&mut OsRng);
-        let password_hash = Argon2::default()
-            .hash_password(password.as_bytes(), &salt)?
-            .to_string();
+        let password_hash = hash_password(&self.hasher, password)?;
 
         sqlx::query!(
             "INSERT INTO users (username, password_hash) VALUES ($1, $2) ON CONFLICT (username) DO NOTHING",
@@ -46,37 +55,230 @@ impl UserRepository for PostgresRepository {
         .await?;
 
         if let Some(record) = row {
-            let parsed_hash = PasswordHash::new(&record.password_hash)?;
-            return Ok(Argon2::default()
-                .verify_password(password.as_bytes(), &parsed_hash)
-                .is_ok());
+            return verify_password(&self.hasher, password, &record.password_hash);
         }
 
         Ok(false)
     }
+
+    async fn update_password(
+        &self,
+        username: &str,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<bool> {
+        let Some(record) = sqlx::query!(
+            "SELECT password_hash FROM users WHERE username = $1",
+            username
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        else {
+            return Ok(false);
+        };
+        if !verify_password(&self.hasher, old_password, &record.password_hash)? {
+            return Ok(false);
+        }
+
+        // Matching on the old hash loses the race to a concurrent change rather than overwriting it.
+        let new_hash = hash_password(&self.hasher, new_password)?;
+        let result = sqlx::query!(
+            "UPDATE users SET password_hash = $1 WHERE username = $2 AND password_hash = $3",
+            new_hash,
+            username,
+            record.password_hash
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn ban_user(&self, username: &str, reason: &str) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO banned_users (username, reason, banned_at) VALUES ($1, $2, $3)
+            ON CONFLICT (username) DO UPDATE SET reason = EXCLUDED.reason, banned_at = EXCLUDED.banned_at",
+            username,
+            reason,
+            chrono::Utc::now().timestamp_millis()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_ban(&self, username: &str) -> Result<Option<String>> {
+        let row = sqlx::query!(
+            "SELECT reason FROM banned_users WHERE username = $1",
+            username
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|r| r.reason))
+    }
 }
 
 #[async_trait]
 impl MessageRepository for PostgresRepository {
-    async fn save_message(&self, msg: &ChatPacket) -> Result<()> {
-        sqlx::query!(
-            "INSERT INTO messages (sender, content, timestamp) VALUES ($1, $2, $3)",
+    async fn save_message(&self, msg: &ChatPacket) -> Result<u64> {
+        let row = sqlx::query!(
+            "INSERT INTO messages (sender, content, timestamp) VALUES ($1, $2, $3) RETURNING id",
             msg.sender,
             msg.content,
             msg.timestamp
         )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.id.cast_unsigned())
+    }
+
+    async fn get_recent_messages(&self, query: HistoryQuery) -> Result<HistoryPage> {
+        // One extra row tells us whether anything older remains.
+        let rows = sqlx::query!(
+            "SELECT id, sender, content, timestamp, deleted FROM messages
+            WHERE (timestamp, id) < ($1::BIGINT, $2::BIGINT)
+            ORDER BY timestamp DESC, id DESC LIMIT $3",
+            query.before_ts,
+            id_bound(query.before_id),
+            i64::from(query.limit) + 1
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(HistoryPage::from_newest_first(
+            rows.into_iter()
+                .map(|r| ChatPacket {
+                    id: r.id.cast_unsigned(),
+                    sender: r.sender,
+                    content: r.content,
+                    timestamp: r.timestamp,
+                    deleted: r.deleted,
+                })
+                .collect(),
+            query.limit,
+        ))
+    }
+
+    async fn get_messages_by_sender(
+        &self,
+        sender: &str,
+        before_ts: i64,
+        before_id: u64,
+        limit: u32,
+    ) -> Result<HistoryPage> {
+        let rows = sqlx::query!(
+            "SELECT id, sender, content, timestamp, deleted FROM messages
+            WHERE sender = $1 AND (timestamp, id) < ($2::BIGINT, $3::BIGINT)
+            ORDER BY timestamp DESC, id DESC LIMIT $4",
+            sender,
+            before_ts,
+            id_bound(before_id),
+            i64::from(limit) + 1
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(HistoryPage::from_newest_first(
+            rows.into_iter()
+                .map(|r| ChatPacket {
+                    id: r.id.cast_unsigned(),
+                    sender: r.sender,
+                    content: r.content,
+                    timestamp: r.timestamp,
+                    deleted: r.deleted,
+                })
+                .collect(),
+            limit,
+        ))
+    }
+
+    async fn get_messages_after(
+        &self,
+        after_ts: i64,
+        after_id: u64,
+        limit: u32,
+    ) -> Result<HistoryPage> {
+        let rows = sqlx::query!(
+            "SELECT id, sender, content, timestamp, deleted FROM messages
+            WHERE (timestamp, id) > ($1::BIGINT, $2::BIGINT)
+            ORDER BY timestamp, id LIMIT $3",
+            after_ts,
+            id_bound(after_id),
+            i64::from(limit) + 1
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(HistoryPage::from_oldest_first(
+            rows.into_iter()
+                .map(|r| ChatPacket {
+                    id: r.id.cast_unsigned(),
+                    sender: r.sender,
+                    content: r.content,
+                    timestamp: r.timestamp,
+                    deleted: r.deleted,
+                })
+                .collect(),
+            limit,
+        ))
+    }
+
+    async fn edit_message(&self, id: u64, sender: &str, content: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE messages SET content = $1 WHERE id = $2 AND sender = $3 AND NOT deleted",
+            content,
+            id.cast_signed(),
+            sender
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete_message(&self, id: u64, sender: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE messages SET content = '', deleted = TRUE WHERE id = $1 AND sender = $2 AND NOT deleted",
+            id.cast_signed(),
+            sender
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn save_announcement(&self, sender: &str, content: &str, timestamp: i64) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO announcements (sender, content, timestamp) VALUES ($1, $2, $3)",
+            sender,
+            content,
+            timestamp
+        )
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
-    async fn get_recent_messages(&self, before_ts: i64) -> Result<Vec<ChatPacket>> {
+    async fn get_latest_announcement(&self) -> Result<Option<String>> {
+        let row = sqlx::query!("SELECT content FROM announcements ORDER BY id DESC LIMIT 1")
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|r| r.content))
+    }
+
+    async fn search_messages(&self, query: &str, limit: u32) -> Result<Vec<ChatPacket>> {
         let rows = sqlx::query!(
-            "SELECT sender, content, timestamp FROM messages
-            WHERE timestamp < $1::BIGINT
-            ORDER BY timestamp DESC LIMIT 50",
-            before_ts
+            r#"SELECT id, sender, content, timestamp, deleted FROM messages
+            WHERE NOT deleted AND content ILIKE '%' || $1 || '%' ESCAPE '\'
+            ORDER BY timestamp DESC LIMIT $2"#,
+            escape_like(query),
+            i64::from(limit)
         )
         .fetch_all(&self.pool)
         .await?;
@@ -84,11 +286,28 @@ impl MessageRepository for PostgresRepository {
         Ok(rows
             .into_iter()
             .map(|r| ChatPacket {
+                id: r.id.cast_unsigned(),
                 sender: r.sender,
                 content: r.content,
                 timestamp: r.timestamp,
+                deleted: r.deleted,
             })
-            .rev()
             .collect())
     }
+
+    async fn prune_older_than(&self, before_ts: i64) -> Result<u64> {
+        let result = sqlx::query!("DELETE FROM messages WHERE timestamp < $1", before_ts)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn count_messages(&self) -> Result<u64> {
+        let row = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM messages"#)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.count.cast_unsigned())
+    }
 }
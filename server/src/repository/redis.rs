@@ -1,122 +1,701 @@
-use super::PresenceRepository;
+use super::{PresenceRepository, SessionClaim, SessionOwner, UserMessage};
 use crate::error::Result;
 use async_trait::async_trait;
 use chrono::Utc;
-use futures::StreamExt;
-use protocol::Message;
-use redis::Client;
-use tokio::sync::broadcast::Sender;
-use tracing::error;
+use futures::{Stream, StreamExt};
+use metrics::counter;
+use protocol::{Message, NodeAddr};
+use redis::{
+    Client,
+    aio::{PubSubSink, PubSubStream},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, broadcast::Sender};
+use tokio::time;
+use tracing::{error, info, warn};
+
+/// Prefix of the per-user channels; the username follows it.
+const USER_CHANNEL_PREFIX: &str = "mcs:user:";
+/// Prefix of the per-room chat channels; the room name follows it.
+const ROOM_CHANNEL_PREFIX: &str = "mcs:chat:";
+
+/// Seconds a session stays marked online without a heartbeat.
+const SESSION_TTL_SECS: u64 = 30;
+
+/// Sorted set of online usernames, scored by when they last chatted or typed, in unix ms.
+const ACTIVITY_KEY: &str = "user:last_active";
+
+/// Replaces a session key's owner only if it still holds the expected one,
+/// recording the new session's login as its first activity.
+const TAKE_OVER_SESSION: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+    redis.call('ZADD', KEYS[2], ARGV[4], ARGV[5])
+    return 1
+end
+return 0
+";
+
+/// Deletes a session key and its activity only if the expected owner holds it.
+const RELEASE_SESSION: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('ZREM', KEYS[2], ARGV[2])
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// Wait before the first attempt to replace a dropped pubsub connection.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+/// Longest wait between attempts to replace a dropped pubsub connection.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// The node's pubsub connection and the rooms subscribed on it, kept so a
+/// replacement connection can pick the rooms back up.
+#[derive(Default)]
+struct Subscriptions {
+    sink: Option<PubSubSink>,
+    rooms: HashSet<String>,
+}
+
+/// What goes over redis: a message and the node that published it.
+#[derive(Serialize, Deserialize)]
+struct Envelope<'a> {
+    origin: &'a str,
+    message: Message,
+}
+
+fn encode(origin: &str, message: Message) -> Result<Vec<u8>> {
+    Ok(postcard::to_stdvec(&Envelope { origin, message })?)
+}
 
 #[derive(Clone)]
 pub struct RedisRepository {
     conn: redis::aio::MultiplexedConnection,
+    /// Subscribes the node to room channels as local sessions come and go.
+    pubsub: Arc<Mutex<Subscriptions>>,
+    /// Tags everything this node publishes, so it can skip its own echoes.
+    node_id: String,
+    /// Local sessions, which get this node's room broadcasts without waiting
+    /// for redis to echo them back.
+    local_sender: Sender<Message>,
 }
 
 impl RedisRepository {
-    pub async fn new(url: &str, app_sender: Sender<Message>) -> Result<Self> {
+    pub async fn new(
+        url: &str,
+        node_id: String,
+        app_sender: Sender<Message>,
+        user_sender: Sender<UserMessage>,
+    ) -> Result<Self> {
         let client = Client::open(url)?;
         let conn = client.get_multiplexed_async_connection().await?;
 
-        Self::spawn_subscriber(client.clone(), app_sender);
+        let pubsub = Arc::new(Mutex::new(Subscriptions::default()));
+        let stream = connect_pubsub(client.clone(), pubsub.clone()).await?;
+        let subscriptions = pubsub.clone();
+        tokio::spawn(run_subscriber(
+            stream,
+            move || connect_pubsub(client.clone(), subscriptions.clone()),
+            node_id.clone(),
+            app_sender.clone(),
+            user_sender,
+        ));
 
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            pubsub,
+            node_id,
+            local_sender: app_sender,
+        })
     }
+}
 
-    fn spawn_subscriber(client: Client, sender: Sender<Message>) {
-        tokio::spawn(async move {
-            let mut conn = match client.get_async_pubsub().await {
-                Ok(c) => c,
-                Err(e) => {
-                    error!(err = ?e, "redis pubsub connect failed");
-                    return;
-                }
-            };
+/// Opens a pubsub connection listening to the user channels and every room
+/// the node is in, and makes it the one rooms are (un)subscribed on.
+///
+/// Connecting happens without the lock, so sessions entering rooms meanwhile
+/// aren't held up by a slow or unreachable redis.
+#[allow(clippy::significant_drop_tightening)]
+async fn connect_pubsub(
+    client: Client,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+) -> Result<PubSubStream> {
+    let (mut sink, stream) = client.get_async_pubsub().await?.split();
+    sink.psubscribe(format!("{USER_CHANNEL_PREFIX}*")).await?;
+    let rooms = subscriptions.lock().await.rooms.clone();
+    for room in &rooms {
+        sink.subscribe(room_channel(room)).await?;
+    }
 
-            if let Err(e) = conn.subscribe("mcs:chat").await {
-                error!(err = ?e, "failed to subscribe to 'mcs:chat'");
-                return;
-            }
+    // Rooms entered or left while connecting went to the old sink, if any,
+    // so they're caught up before the swap.
+    let mut subscriptions = subscriptions.lock().await;
+    for room in subscriptions.rooms.difference(&rooms) {
+        sink.subscribe(room_channel(room)).await?;
+    }
+    for room in rooms.difference(&subscriptions.rooms) {
+        sink.unsubscribe(room_channel(room)).await?;
+    }
+    subscriptions.sink = Some(sink);
+    Ok(stream)
+}
 
-            let mut stream = conn.on_message();
-            while let Some(msg) = stream.next().await {
-                let payload: Vec<u8> = match msg.get_payload() {
-                    Ok(p) => p,
-                    Err(_) => continue,
-                };
+/// Routes pubsub messages to local sessions. Whenever the stream ends it is
+/// replaced through `reconnect`, with backoff, so a redis blip doesn't leave
+/// the node deaf to other nodes.
+async fn run_subscriber<S, F, Fut>(
+    mut stream: S,
+    mut reconnect: F,
+    node_id: String,
+    sender: Sender<Message>,
+    user_sender: Sender<UserMessage>,
+) where
+    S: Stream<Item = redis::Msg> + Unpin,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<S>>,
+{
+    loop {
+        while let Some(msg) = stream.next().await {
+            route(
+                msg.get_channel_name(),
+                msg.get_payload_bytes(),
+                &node_id,
+                &sender,
+                &user_sender,
+            );
+        }
+        warn!("redis pubsub stream ended, reconnecting");
 
-                if let Ok(chat_msg) = postcard::from_bytes::<Message>(&payload) {
-                    let _ = sender.send(chat_msg);
+        let mut delay = RECONNECT_MIN_DELAY;
+        stream = loop {
+            time::sleep(delay).await;
+            match reconnect().await {
+                Ok(stream) => break stream,
+                Err(e) => {
+                    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                    error!(err=?e, retry_in=?delay, "failed to reconnect redis pubsub");
                 }
             }
+        };
+        counter!("mcs_redis_pubsub_reconnects_total").increment(1);
+        info!("redis pubsub reconnected");
+    }
+}
+
+/// Pairs each username with its session's owner, skipping sessions that
+/// expired since they were listed or whose owner can't be read.
+fn owned_sessions(
+    usernames: Vec<String>,
+    owners: Vec<Option<String>>,
+) -> Vec<(String, SessionOwner)> {
+    usernames
+        .into_iter()
+        .zip(owners)
+        .filter_map(|(username, owner)| Some((username, owner?.parse().ok()?)))
+        .collect()
+}
+
+fn room_channel(room: &str) -> String {
+    format!("{ROOM_CHANNEL_PREFIX}{room}")
+}
+
+/// Hands a message from `channel` to the local sessions it's meant for.
+/// Room broadcasts this node published itself were delivered locally when
+/// published, so their echo is dropped.
+fn route(
+    channel: &str,
+    payload: &[u8],
+    node_id: &str,
+    sender: &Sender<Message>,
+    user_sender: &Sender<UserMessage>,
+) {
+    let Ok(Envelope { origin, message }) = postcard::from_bytes(payload) else {
+        return;
+    };
+    if let Some(username) = channel.strip_prefix(USER_CHANNEL_PREFIX) {
+        let _ = user_sender.send(UserMessage {
+            username: username.to_string(),
+            message,
         });
+    } else if channel.starts_with(ROOM_CHANNEL_PREFIX) && origin != node_id {
+        let _ = sender.send(message);
     }
 }
 
 #[async_trait]
 impl PresenceRepository for RedisRepository {
-    async fn set_online(&self, username: &str) -> Result<bool> {
+    async fn set_online(&self, username: &str, owner: &SessionOwner) -> Result<SessionClaim> {
         let key = format!("user:session:{username}");
         let mut conn = self.conn.clone();
 
-        let res: Option<String> = redis::cmd("SET")
+        // With GET, a refused SET NX hands back the holder in the same round trip.
+        let held: Option<String> = redis::cmd("SET")
             .arg(&key)
-            .arg("online")
+            .arg(owner.to_string())
             .arg("NX")
+            .arg("GET")
             .arg("EX")
-            .arg(30)
+            .arg(SESSION_TTL_SECS)
             .query_async(&mut conn)
             .await?;
 
-        Ok(res.is_some())
+        if let Some(held) = held {
+            return Ok(SessionClaim::Held(held.parse().ok()));
+        }
+        redis::cmd("ZADD")
+            .arg(ACTIVITY_KEY)
+            .arg(Utc::now().timestamp_millis())
+            .arg(username)
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(SessionClaim::Claimed)
     }
 
-    async fn set_offline(&self, username: &str) -> Result<()> {
-        let key = format!("user:session:{username}");
+    async fn take_over_session(
+        &self,
+        username: &str,
+        stale: &SessionOwner,
+        owner: &SessionOwner,
+    ) -> Result<bool> {
         let mut conn = self.conn.clone();
-        redis::cmd("DEL")
-            .arg(key)
-            .query_async::<()>(&mut conn)
+        let replaced: u64 = redis::Script::new(TAKE_OVER_SESSION)
+            .key(format!("user:session:{username}"))
+            .key(ACTIVITY_KEY)
+            .arg(stale.to_string())
+            .arg(owner.to_string())
+            .arg(SESSION_TTL_SECS)
+            .arg(Utc::now().timestamp_millis())
+            .arg(username)
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(replaced == 1)
+    }
+
+    async fn set_offline(&self, username: &str, owner: &SessionOwner) -> Result<()> {
+        let mut conn = self.conn.clone();
+        redis::Script::new(RELEASE_SESSION)
+            .key(format!("user:session:{username}"))
+            .key(ACTIVITY_KEY)
+            .arg(owner.to_string())
+            .arg(username)
+            .invoke_async::<()>(&mut conn)
             .await?;
 
         Ok(())
     }
 
-    async fn refresh_heartbeat(&self, username: &str) -> Result<()> {
+    async fn refresh_heartbeat(&self, username: &str, last_active: i64) -> Result<()> {
         let key = format!("user:session:{username}");
         let mut conn = self.conn.clone();
-        redis::cmd("EXPIRE")
+        redis::pipe()
+            .cmd("EXPIRE")
             .arg(key)
-            .arg(30)
+            .arg(SESSION_TTL_SECS)
+            .ignore()
+            .cmd("ZADD")
+            .arg(ACTIVITY_KEY)
+            .arg(last_active)
+            .arg(username)
+            .ignore()
             .query_async::<()>(&mut conn)
             .await?;
 
         Ok(())
     }
 
-    async fn register_node(&self, address: &str) -> Result<()> {
+    async fn get_online_users(&self) -> Result<Vec<(String, i64)>> {
+        let mut conn = self.conn.clone();
+        let scored: Vec<(String, f64)> = redis::cmd("ZRANGE")
+            .arg(ACTIVITY_KEY)
+            .arg(0)
+            .arg(-1)
+            .arg("WITHSCORES")
+            .query_async(&mut conn)
+            .await?;
+        if scored.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Sessions lost with their node expire without removing their activity.
+        let mut exists = redis::pipe();
+        for (username, _) in &scored {
+            exists.cmd("EXISTS").arg(format!("user:session:{username}"));
+        }
+        let live: Vec<bool> = exists.query_async(&mut conn).await?;
+
+        let (online, expired): (Vec<_>, Vec<_>) =
+            scored.into_iter().zip(live).partition(|(_, live)| *live);
+        if !expired.is_empty() {
+            let expired: Vec<String> = expired.into_iter().map(|((name, _), _)| name).collect();
+            redis::cmd("ZREM")
+                .arg(ACTIVITY_KEY)
+                .arg(expired)
+                .query_async::<()>(&mut conn)
+                .await?;
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        Ok(online
+            .into_iter()
+            .map(|((username, score), _)| (username, score as i64))
+            .collect())
+    }
+
+    async fn get_sessions(&self) -> Result<Vec<(String, SessionOwner)>> {
+        let mut conn = self.conn.clone();
+        let usernames: Vec<String> = redis::cmd("ZRANGE")
+            .arg(ACTIVITY_KEY)
+            .arg(0)
+            .arg(-1)
+            .query_async(&mut conn)
+            .await?;
+        if usernames.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let keys: Vec<String> = usernames
+            .iter()
+            .map(|username| format!("user:session:{username}"))
+            .collect();
+        let owners: Vec<Option<String>> =
+            redis::cmd("MGET").arg(keys).query_async(&mut conn).await?;
+
+        Ok(owned_sessions(usernames, owners))
+    }
+
+    async fn register_node(
+        &self,
+        address: &NodeAddr,
+        max_connections: Option<usize>,
+    ) -> Result<()> {
         let mut conn = self.conn.clone();
         let timestamp = Utc::now().timestamp();
+        let cap_key = format!("mcs:node:max_connections:{address}");
 
-        redis::cmd("ZADD")
+        // Atomic so a load balancer never sees the node without its cap.
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        match max_connections {
+            Some(max) => pipe.cmd("SET").arg(cap_key).arg(max).ignore(),
+            None => pipe.cmd("DEL").arg(cap_key).ignore(),
+        };
+        pipe.cmd("ZADD")
             .arg("mcs:node")
             .arg(timestamp)
-            .arg(address)
+            .arg(address.as_str())
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_node_draining(&self, address: &NodeAddr, ttl_secs: u64) -> Result<()> {
+        let mut conn = self.conn.clone();
+        redis::cmd("SET")
+            .arg(format!("mcs:node:drain:{address}"))
+            .arg(1)
+            .arg("EX")
+            .arg(ttl_secs)
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn deregister_node(&self, address: &NodeAddr) -> Result<()> {
+        let mut conn = self.conn.clone();
+        redis::pipe()
+            .cmd("ZREM")
+            .arg("mcs:node")
+            .arg(address.as_str())
+            .ignore()
+            .cmd("DEL")
+            .arg(format!("mcs:node:max_connections:{address}"))
+            .ignore()
             .query_async::<()>(&mut conn)
             .await?;
 
         Ok(())
     }
 
-    async fn broadcast(&self, msg: Message) -> Result<()> {
-        let payload = postcard::to_stdvec(&msg)?;
+    async fn broadcast(&self, room: &str, msg: Message) -> Result<()> {
+        let payload = encode(&self.node_id, msg.clone())?;
+        // Local sessions get it even when redis can't pass it on to other nodes.
+        if self.pubsub.lock().await.rooms.contains(room) {
+            let _ = self.local_sender.send(msg);
+        }
+
         let mut conn = self.conn.clone();
         redis::cmd("PUBLISH")
-            .arg("mcs:chat")
+            .arg(room_channel(room))
             .arg(payload)
             .query_async::<()>(&mut conn)
             .await?;
+        Ok(())
+    }
+
+    // The lock keeps the room set and the live subscriptions in step.
+    #[allow(clippy::significant_drop_tightening)]
+    async fn subscribe_room(&self, room: &str) -> Result<()> {
+        let mut subscriptions = self.pubsub.lock().await;
+        // Recorded first so a reconnect subscribes it even if this attempt fails.
+        subscriptions.rooms.insert(room.to_string());
+        if let Some(sink) = &mut subscriptions.sink {
+            sink.subscribe(room_channel(room)).await?;
+        }
+        Ok(())
+    }
 
+    #[allow(clippy::significant_drop_tightening)]
+    async fn unsubscribe_room(&self, room: &str) -> Result<()> {
+        let mut subscriptions = self.pubsub.lock().await;
+        subscriptions.rooms.remove(room);
+        if let Some(sink) = &mut subscriptions.sink {
+            sink.unsubscribe(room_channel(room)).await?;
+        }
         Ok(())
     }
+
+    async fn send_to_user(&self, username: &str, msg: Message) -> Result<()> {
+        let payload = encode(&self.node_id, msg)?;
+        let mut conn = self.conn.clone();
+        redis::cmd("PUBLISH")
+            .arg(format!("{USER_CHANNEL_PREFIX}{username}"))
+            .arg(payload)
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn record_login_failure(&self, username: &str, cooldown_secs: u64) -> Result<u64> {
+        let key = format!("auth:fail:{username}");
+        let mut conn = self.conn.clone();
+        let (count,): (u64,) = redis::pipe()
+            .atomic()
+            .cmd("INCR")
+            .arg(&key)
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(cooldown_secs)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(count)
+    }
+
+    async fn get_login_failures(&self, username: &str) -> Result<(u64, u64)> {
+        let key = format!("auth:fail:{username}");
+        let mut conn = self.conn.clone();
+        let (count, ttl): (Option<u64>, i64) = redis::pipe()
+            .cmd("GET")
+            .arg(&key)
+            .cmd("TTL")
+            .arg(&key)
+            .query_async(&mut conn)
+            .await?;
+
+        Ok((count.unwrap_or(0), ttl.try_into().unwrap_or(0)))
+    }
+
+    async fn clear_login_failures(&self, username: &str) -> Result<()> {
+        let key = format!("auth:fail:{username}");
+        let mut conn = self.conn.clone();
+        redis::cmd("DEL")
+            .arg(key)
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, owned_sessions, room_channel, route, run_subscriber};
+    use crate::error::Error;
+    use crate::repository::mock::MockRepository;
+    use crate::repository::{SessionOwner, UserMessage};
+    use crate::service::rooms::RoomMembership;
+    use futures::{StreamExt, stream};
+    use protocol::Message;
+    use redis::Value;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::sync::broadcast::{self, Sender, error::TryRecvError};
+
+    /// Stands in for redis: publishes reach the node only on channels its
+    /// room membership has subscribed it to.
+    struct FakePubSub {
+        subscriptions: Arc<MockRepository>,
+        sender: Sender<Message>,
+        user_sender: Sender<UserMessage>,
+    }
+
+    impl FakePubSub {
+        fn publish(&self, room: &str, msg: &Message) {
+            if self.subscriptions.rooms.lock().unwrap().contains(room) {
+                let payload = encode("node-b", msg.clone()).unwrap();
+                route(
+                    &room_channel(room),
+                    &payload,
+                    "node-a",
+                    &self.sender,
+                    &self.user_sender,
+                );
+            }
+        }
+    }
+
+    fn announcement(content: &str) -> Message {
+        Message::Announcement {
+            content: content.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn node_only_receives_rooms_it_is_subscribed_to() {
+        let repo = Arc::new(MockRepository::default());
+        let rooms = RoomMembership::new(repo.clone());
+        let (sender, mut rx) = broadcast::channel(8);
+        let bus = FakePubSub {
+            subscriptions: repo,
+            sender,
+            user_sender: broadcast::channel(8).0,
+        };
+
+        rooms.enter("lobby").await.unwrap();
+        bus.publish("games", &announcement("elsewhere"));
+        bus.publish("lobby", &announcement("here"));
+
+        assert!(
+            matches!(rx.try_recv(), Ok(Message::Announcement { content }) if content == "here")
+        );
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
+
+        rooms.leave("lobby").await.unwrap();
+        bus.publish("lobby", &announcement("gone"));
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    fn pubsub_message(channel: &str, msg: &Message) -> redis::Msg {
+        redis::Msg::from_owned_value(Value::Array(vec![
+            Value::BulkString(b"message".to_vec()),
+            Value::BulkString(channel.as_bytes().to_vec()),
+            Value::BulkString(encode("node-b", msg.clone()).unwrap()),
+        ]))
+        .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscriber_reconnects_when_the_stream_drops() {
+        let (sender, mut rx) = broadcast::channel(8);
+        let attempts = Arc::new(AtomicU32::new(0));
+        let reconnect = {
+            let attempts = attempts.clone();
+            move || {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    if attempt == 1 {
+                        return Err(Error::IO(std::io::ErrorKind::ConnectionRefused.into()));
+                    }
+                    let msg = pubsub_message(&room_channel("lobby"), &announcement("back"));
+                    Ok(stream::iter([msg]).chain(stream::pending()).boxed())
+                }
+            }
+        };
+
+        // The first connection drops straight away.
+        let task = tokio::spawn(run_subscriber(
+            stream::empty().boxed(),
+            reconnect,
+            "node-a".to_string(),
+            sender,
+            broadcast::channel(8).0,
+        ));
+
+        assert!(
+            matches!(rx.recv().await, Ok(Message::Announcement { content }) if content == "back")
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(!task.is_finished());
+        task.abort();
+    }
+
+    #[test]
+    fn own_broadcast_echoed_back_is_not_delivered_again() {
+        let (sender, mut rx) = broadcast::channel(8);
+        let user_sender = broadcast::channel(8).0;
+        let channel = room_channel("lobby");
+
+        // node-a delivered its own message locally when it published it.
+        sender.send(announcement("mine")).unwrap();
+        let echo = encode("node-a", announcement("mine")).unwrap();
+        route(&channel, &echo, "node-a", &sender, &user_sender);
+        let other = encode("node-b", announcement("theirs")).unwrap();
+        route(&channel, &other, "node-a", &sender, &user_sender);
+
+        assert!(
+            matches!(rx.try_recv(), Ok(Message::Announcement { content }) if content == "mine")
+        );
+        assert!(
+            matches!(rx.try_recv(), Ok(Message::Announcement { content }) if content == "theirs")
+        );
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn user_and_room_channels_route_to_their_own_senders() {
+        let (sender, mut rx) = broadcast::channel(8);
+        let (user_sender, mut user_rx) = broadcast::channel(8);
+        let payload = encode("node-b", Message::Heartbeat).unwrap();
+
+        route("mcs:user:alice", &payload, "node-a", &sender, &user_sender);
+        route("mcs:other", &payload, "node-a", &sender, &user_sender);
+
+        assert_eq!(user_rx.try_recv().unwrap().username, "alice");
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
+    }
+
+    #[test]
+    fn sessions_pair_with_their_owners_on_any_node() {
+        let usernames = ["alice", "bob", "carol", "dave"].map(String::from).to_vec();
+        let owners = vec![
+            Some("node-a:64400/1".to_string()),
+            Some("node-b:64400/7".to_string()),
+            // Expired between listing and reading its owner.
+            None,
+            // Written before owners were recorded.
+            Some("1".to_string()),
+        ];
+
+        let sessions = owned_sessions(usernames, owners);
+        assert_eq!(
+            sessions,
+            [
+                (
+                    "alice".to_string(),
+                    SessionOwner {
+                        node: "node-a:64400".parse().unwrap(),
+                        nonce: 1,
+                    }
+                ),
+                (
+                    "bob".to_string(),
+                    SessionOwner {
+                        node: "node-b:64400".parse().unwrap(),
+                        nonce: 7,
+                    }
+                ),
+            ]
+        );
+    }
 }
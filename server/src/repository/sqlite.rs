@@ -0,0 +1,687 @@
+use super::password::{build_hasher, hash_password, verify_password};
+use super::{HistoryPage, HistoryQuery, MessageRepository, UserRepository, escape_like, id_bound};
+use crate::config::Argon2Config;
+use crate::error::Result;
+use argon2::Argon2;
+use async_trait::async_trait;
+use protocol::ChatPacket;
+use sqlx::{
+    FromRow, SqlitePool,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+};
+use std::str::FromStr;
+
+/// Lightweight alternative to `PostgresRepository` for single-node deployments.
+///
+/// Queries are checked at runtime rather than with `query!`, since the offline
+/// query cache only describes the Postgres schema.
+#[derive(Clone)]
+pub struct SqliteRepository {
+    pool: SqlitePool,
+    hasher: Argon2<'static>,
+}
+
+#[derive(FromRow)]
+struct MessageRow {
+    id: i64,
+    sender: String,
+    content: String,
+    timestamp: i64,
+    deleted: bool,
+}
+
+impl From<MessageRow> for ChatPacket {
+    fn from(r: MessageRow) -> Self {
+        Self {
+            id: r.id.cast_unsigned(),
+            sender: r.sender,
+            content: r.content,
+            timestamp: r.timestamp,
+            deleted: r.deleted,
+        }
+    }
+}
+
+impl SqliteRepository {
+    pub async fn new(url: &str, argon2: &Argon2Config) -> Result<Self> {
+        let hasher = build_hasher(argon2)?;
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let pool = if url.contains(":memory:") {
+            // An in-memory database lives only as long as its connection, and
+            // every other connection would get its own copy, so keep just the one.
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new().max_connections(5)
+        }
+        .connect_with(options)
+        .await?;
+        sqlx::migrate!("./migrations_sqlite").run(&pool).await?;
+        Ok(Self { pool, hasher })
+    }
+}
+
+#[async_trait]
+impl UserRepository for SqliteRepository {
+    async fn user_exists(&self, username: &str) -> Result<bool> {
+        let exists: bool =
+            sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE username = ?1)")
+                .bind(username)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(exists)
+    }
+
+    async fn create_user(&self, username: &str, password: &str) -> Result<()> {
+        let password_hash = hash_password(&self.hasher, password)?;
+
+        sqlx::query("INSERT INTO users (username, password_hash) VALUES (?1, ?2) ON CONFLICT (username) DO NOTHING")
+            .bind(username)
+            .bind(password_hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn verify_credentials(&self, username: &str, password: &str) -> Result<bool> {
+        let hash: Option<String> =
+            sqlx::query_scalar("SELECT password_hash FROM users WHERE username = ?1")
+                .bind(username)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        if let Some(hash) = hash {
+            return verify_password(&self.hasher, password, &hash);
+        }
+
+        Ok(false)
+    }
+
+    async fn update_password(
+        &self,
+        username: &str,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<bool> {
+        let hash: Option<String> =
+            sqlx::query_scalar("SELECT password_hash FROM users WHERE username = ?1")
+                .bind(username)
+                .fetch_optional(&self.pool)
+                .await?;
+        let Some(hash) = hash else {
+            return Ok(false);
+        };
+        if !verify_password(&self.hasher, old_password, &hash)? {
+            return Ok(false);
+        }
+
+        // Matching on the old hash loses the race to a concurrent change rather than overwriting it.
+        let new_hash = hash_password(&self.hasher, new_password)?;
+        let result = sqlx::query(
+            "UPDATE users SET password_hash = ?1 WHERE username = ?2 AND password_hash = ?3",
+        )
+        .bind(new_hash)
+        .bind(username)
+        .bind(hash)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn ban_user(&self, username: &str, reason: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO banned_users (username, reason, banned_at) VALUES (?1, ?2, ?3)
+            ON CONFLICT (username) DO UPDATE SET reason = excluded.reason, banned_at = excluded.banned_at",
+        )
+        .bind(username)
+        .bind(reason)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_ban(&self, username: &str) -> Result<Option<String>> {
+        Ok(
+            sqlx::query_scalar("SELECT reason FROM banned_users WHERE username = ?1")
+                .bind(username)
+                .fetch_optional(&self.pool)
+                .await?,
+        )
+    }
+}
+
+#[async_trait]
+impl MessageRepository for SqliteRepository {
+    async fn save_message(&self, msg: &ChatPacket) -> Result<u64> {
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO messages (sender, content, timestamp) VALUES (?1, ?2, ?3) RETURNING id",
+        )
+        .bind(&msg.sender)
+        .bind(&msg.content)
+        .bind(msg.timestamp)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(id.cast_unsigned())
+    }
+
+    async fn get_recent_messages(&self, query: HistoryQuery) -> Result<HistoryPage> {
+        // Binding an i64 keeps the comparison numeric under the column's INTEGER affinity.
+        let rows: Vec<MessageRow> = sqlx::query_as(
+            "SELECT id, sender, content, timestamp, deleted FROM messages
+            WHERE (timestamp, id) < (?1, ?2)
+            ORDER BY timestamp DESC, id DESC LIMIT ?3",
+        )
+        .bind(query.before_ts)
+        .bind(id_bound(query.before_id))
+        .bind(i64::from(query.limit) + 1)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(HistoryPage::from_newest_first(
+            rows.into_iter().map(ChatPacket::from).collect(),
+            query.limit,
+        ))
+    }
+
+    async fn get_messages_by_sender(
+        &self,
+        sender: &str,
+        before_ts: i64,
+        before_id: u64,
+        limit: u32,
+    ) -> Result<HistoryPage> {
+        let rows: Vec<MessageRow> = sqlx::query_as(
+            "SELECT id, sender, content, timestamp, deleted FROM messages
+            WHERE sender = ?1 AND (timestamp, id) < (?2, ?3)
+            ORDER BY timestamp DESC, id DESC LIMIT ?4",
+        )
+        .bind(sender)
+        .bind(before_ts)
+        .bind(id_bound(before_id))
+        .bind(i64::from(limit) + 1)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(HistoryPage::from_newest_first(
+            rows.into_iter().map(ChatPacket::from).collect(),
+            limit,
+        ))
+    }
+
+    async fn get_messages_after(
+        &self,
+        after_ts: i64,
+        after_id: u64,
+        limit: u32,
+    ) -> Result<HistoryPage> {
+        let rows: Vec<MessageRow> = sqlx::query_as(
+            "SELECT id, sender, content, timestamp, deleted FROM messages
+            WHERE (timestamp, id) > (?1, ?2)
+            ORDER BY timestamp, id LIMIT ?3",
+        )
+        .bind(after_ts)
+        .bind(id_bound(after_id))
+        .bind(i64::from(limit) + 1)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(HistoryPage::from_oldest_first(
+            rows.into_iter().map(ChatPacket::from).collect(),
+            limit,
+        ))
+    }
+
+    async fn edit_message(&self, id: u64, sender: &str, content: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE messages SET content = ?1 WHERE id = ?2 AND sender = ?3 AND NOT deleted",
+        )
+        .bind(content)
+        .bind(id.cast_signed())
+        .bind(sender)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete_message(&self, id: u64, sender: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE messages SET content = '', deleted = TRUE WHERE id = ?1 AND sender = ?2 AND NOT deleted",
+        )
+        .bind(id.cast_signed())
+        .bind(sender)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn save_announcement(&self, sender: &str, content: &str, timestamp: i64) -> Result<()> {
+        sqlx::query("INSERT INTO announcements (sender, content, timestamp) VALUES (?1, ?2, ?3)")
+            .bind(sender)
+            .bind(content)
+            .bind(timestamp)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_latest_announcement(&self) -> Result<Option<String>> {
+        Ok(
+            sqlx::query_scalar("SELECT content FROM announcements ORDER BY id DESC LIMIT 1")
+                .fetch_optional(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn search_messages(&self, query: &str, limit: u32) -> Result<Vec<ChatPacket>> {
+        // SQLite's LIKE is already case-insensitive for ASCII.
+        let rows: Vec<MessageRow> = sqlx::query_as(
+            r"SELECT id, sender, content, timestamp, deleted FROM messages
+            WHERE NOT deleted AND content LIKE '%' || ?1 || '%' ESCAPE '\'
+            ORDER BY timestamp DESC LIMIT ?2",
+        )
+        .bind(escape_like(query))
+        .bind(i64::from(limit))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(ChatPacket::from).collect())
+    }
+
+    async fn prune_older_than(&self, before_ts: i64) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM messages WHERE timestamp < ?1")
+            .bind(before_ts)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn count_messages(&self) -> Result<u64> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(count.cast_unsigned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SqliteRepository;
+    use crate::config::Argon2Config;
+    use crate::repository::{HISTORY_PAGE_SIZE, HistoryQuery, MessageRepository, UserRepository};
+    use protocol::ChatPacket;
+
+    /// The default page before `(before_ts, before_id)`.
+    const fn before(before_ts: i64, before_id: u64) -> HistoryQuery {
+        HistoryQuery {
+            before_ts,
+            before_id,
+            limit: HISTORY_PAGE_SIZE,
+        }
+    }
+
+    async fn repo() -> SqliteRepository {
+        let argon2 = Argon2Config {
+            memory_kib: 8,
+            iterations: 1,
+            parallelism: 1,
+        };
+        SqliteRepository::new("sqlite::memory:", &argon2)
+            .await
+            .unwrap()
+    }
+
+    fn packet(sender: &str, content: &str, timestamp: i64) -> ChatPacket {
+        ChatPacket {
+            id: 0,
+            sender: sender.to_string(),
+            content: content.to_string(),
+            timestamp,
+            deleted: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn users_are_created_once_and_verified() {
+        let repo = repo().await;
+        assert!(!repo.user_exists("alice").await.unwrap());
+        repo.create_user("alice", "hunter22").await.unwrap();
+        repo.create_user("alice", "other").await.unwrap();
+
+        assert!(repo.user_exists("alice").await.unwrap());
+        assert!(repo.verify_credentials("alice", "hunter22").await.unwrap());
+        assert!(!repo.verify_credentials("alice", "other").await.unwrap());
+        assert!(!repo.verify_credentials("bob", "hunter22").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn bans_are_stamped_in_milliseconds() {
+        let repo = repo().await;
+        let before = chrono::Utc::now().timestamp_millis();
+        repo.ban_user("bob", "spam").await.unwrap();
+
+        let banned_at: i64 =
+            sqlx::query_scalar("SELECT banned_at FROM banned_users WHERE username = 'bob'")
+                .fetch_one(&repo.pool)
+                .await
+                .unwrap();
+        assert!(banned_at >= before);
+        assert_eq!(repo.get_ban("bob").await.unwrap().as_deref(), Some("spam"));
+    }
+
+    #[tokio::test]
+    async fn password_is_updated_after_verifying_the_old_one() {
+        let repo = repo().await;
+        repo.create_user("alice", "hunter22").await.unwrap();
+
+        assert!(
+            repo.update_password("alice", "hunter22", "correct horse")
+                .await
+                .unwrap()
+        );
+        assert!(
+            repo.verify_credentials("alice", "correct horse")
+                .await
+                .unwrap()
+        );
+        assert!(!repo.verify_credentials("alice", "hunter22").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn wrong_old_password_leaves_the_password_alone() {
+        let repo = repo().await;
+        repo.create_user("alice", "hunter22").await.unwrap();
+
+        assert!(
+            !repo
+                .update_password("alice", "guess", "correct horse")
+                .await
+                .unwrap()
+        );
+        assert!(
+            !repo
+                .update_password("bob", "hunter22", "correct horse")
+                .await
+                .unwrap()
+        );
+        assert!(repo.verify_credentials("alice", "hunter22").await.unwrap());
+        assert!(!repo.user_exists("bob").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn recent_messages_are_oldest_first_before_timestamp() {
+        let repo = repo().await;
+        for ts in 1..=60 {
+            repo.save_message(&packet("alice", "hi", ts)).await.unwrap();
+        }
+
+        let history = repo
+            .get_recent_messages(before(56, 0))
+            .await
+            .unwrap()
+            .messages;
+
+        let timestamps: Vec<i64> = history.iter().map(|m| m.timestamp).collect();
+        assert_eq!(timestamps, (6..=55).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn recent_messages_come_oldest_first() {
+        let repo = repo().await;
+        for ts in 1..=5 {
+            repo.save_message(&packet("alice", "hi", ts)).await.unwrap();
+        }
+        let query = HistoryQuery {
+            limit: 3,
+            ..before(5, 0)
+        };
+
+        let page = repo.get_recent_messages(query).await.unwrap();
+        let timestamps: Vec<i64> = page.messages.iter().map(|m| m.timestamp).collect();
+        assert_eq!(timestamps, [2, 3, 4]);
+        assert!(page.has_more);
+    }
+
+    #[tokio::test]
+    async fn has_more_is_set_only_when_older_rows_remain() {
+        let repo = repo().await;
+        for ts in 1..=51 {
+            repo.save_message(&packet("alice", "hi", ts)).await.unwrap();
+        }
+
+        let page = repo.get_recent_messages(before(52, 0)).await.unwrap();
+        assert_eq!(page.messages.len(), HISTORY_PAGE_SIZE as usize);
+        assert_eq!(page.messages[0].timestamp, 2);
+        assert!(page.has_more);
+
+        let page = repo.get_recent_messages(before(51, 0)).await.unwrap();
+        assert_eq!(page.messages.len(), HISTORY_PAGE_SIZE as usize);
+        assert!(!page.has_more);
+
+        let page = repo.get_recent_messages(before(2, 0)).await.unwrap();
+        assert_eq!(page.messages.len(), 1);
+        assert!(!page.has_more);
+    }
+
+    #[tokio::test]
+    async fn same_second_messages_page_in_id_order() {
+        let repo = repo().await;
+        let mut ids = Vec::new();
+        for ts in [1, 2, 2, 2] {
+            ids.push(repo.save_message(&packet("alice", "hi", ts)).await.unwrap());
+        }
+        for _ in 0..=HISTORY_PAGE_SIZE {
+            ids.push(repo.save_message(&packet("alice", "hi", 3)).await.unwrap());
+        }
+
+        let mut paged = Vec::new();
+        let mut cursor = (i64::MAX, u64::MAX);
+        loop {
+            let page = repo
+                .get_recent_messages(before(cursor.0, cursor.1))
+                .await
+                .unwrap();
+            let oldest = page.messages.first().map(|m| (m.timestamp, m.id));
+            paged.splice(0..0, page.messages.iter().map(|m| m.id));
+            match oldest {
+                Some(oldest) if page.has_more => cursor = oldest,
+                _ => break,
+            }
+        }
+
+        assert_eq!(paged, ids);
+        let again = repo
+            .get_recent_messages(before(i64::MAX, u64::MAX))
+            .await
+            .unwrap();
+        let again: Vec<u64> = again.messages.iter().map(|m| m.id).collect();
+        assert_eq!(again, ids[ids.len() - HISTORY_PAGE_SIZE as usize..]);
+    }
+
+    #[tokio::test]
+    async fn messages_after_page_forward_oldest_first() {
+        let repo = repo().await;
+        let mut ids = Vec::new();
+        for ts in [1, 2, 2, 3] {
+            ids.push(repo.save_message(&packet("alice", "hi", ts)).await.unwrap());
+        }
+
+        let page = repo.get_messages_after(1, ids[0], 2).await.unwrap();
+        let paged: Vec<u64> = page.messages.iter().map(|m| m.id).collect();
+        assert_eq!(paged, ids[1..3]);
+        assert!(page.has_more);
+
+        let page = repo.get_messages_after(2, ids[2], 2).await.unwrap();
+        let paged: Vec<u64> = page.messages.iter().map(|m| m.id).collect();
+        assert_eq!(paged, ids[3..]);
+        assert!(!page.has_more);
+
+        let page = repo.get_messages_after(3, ids[3], 2).await.unwrap();
+        assert!(page.messages.is_empty());
+        assert!(!page.has_more);
+    }
+
+    #[tokio::test]
+    async fn messages_by_sender_are_filtered_and_paged() {
+        let repo = repo().await;
+        for ts in 1..=10 {
+            let sender = if ts % 2 == 0 { "alice" } else { "bob" };
+            repo.save_message(&packet(sender, "hi", ts)).await.unwrap();
+        }
+
+        let page = repo
+            .get_messages_by_sender("alice", i64::MAX, u64::MAX, 3)
+            .await
+            .unwrap();
+        let timestamps: Vec<i64> = page.messages.iter().map(|m| m.timestamp).collect();
+        assert_eq!(timestamps, vec![6, 8, 10]);
+        assert!(page.messages.iter().all(|m| m.sender == "alice"));
+        assert!(page.has_more);
+
+        let oldest = &page.messages[0];
+        let page = repo
+            .get_messages_by_sender("alice", oldest.timestamp, oldest.id, 3)
+            .await
+            .unwrap();
+        let timestamps: Vec<i64> = page.messages.iter().map(|m| m.timestamp).collect();
+        assert_eq!(timestamps, vec![2, 4]);
+        assert!(!page.has_more);
+    }
+
+    #[tokio::test]
+    async fn messages_by_unknown_sender_are_empty() {
+        let repo = repo().await;
+        repo.save_message(&packet("alice", "hi", 1)).await.unwrap();
+
+        let page = repo
+            .get_messages_by_sender("carol", i64::MAX, u64::MAX, 10)
+            .await
+            .unwrap();
+        assert!(page.messages.is_empty());
+        assert!(!page.has_more);
+    }
+
+    #[tokio::test]
+    async fn timestamps_compare_numerically() {
+        let repo = repo().await;
+        repo.save_message(&packet("alice", "old", 9)).await.unwrap();
+        repo.save_message(&packet("alice", "new", 10))
+            .await
+            .unwrap();
+
+        let history = repo
+            .get_recent_messages(before(10, 0))
+            .await
+            .unwrap()
+            .messages;
+
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].content, "old");
+    }
+
+    #[tokio::test]
+    async fn edit_and_delete_require_ownership() {
+        let repo = repo().await;
+        let id = repo
+            .save_message(&packet("alice", "typo", 1))
+            .await
+            .unwrap();
+
+        assert!(!repo.edit_message(id, "bob", "hacked").await.unwrap());
+        assert!(repo.edit_message(id, "alice", "fixed").await.unwrap());
+        assert!(!repo.delete_message(id, "bob").await.unwrap());
+        assert!(repo.delete_message(id, "alice").await.unwrap());
+        assert!(!repo.edit_message(id, "alice", "again").await.unwrap());
+
+        let history = repo
+            .get_recent_messages(before(i64::MAX, u64::MAX))
+            .await
+            .unwrap()
+            .messages;
+        assert!(history[0].deleted);
+        assert!(history[0].content.is_empty());
+    }
+
+    #[tokio::test]
+    async fn latest_announcement_wins() {
+        let repo = repo().await;
+        assert_eq!(repo.get_latest_announcement().await.unwrap(), None);
+
+        repo.save_announcement("admin", "first", 1).await.unwrap();
+        repo.save_announcement("admin", "second", 2).await.unwrap();
+
+        assert_eq!(
+            repo.get_latest_announcement().await.unwrap().as_deref(),
+            Some("second")
+        );
+    }
+
+    #[tokio::test]
+    async fn search_is_literal_and_case_insensitive() {
+        let repo = repo().await;
+        repo.save_message(&packet("alice", "I love Rust", 1))
+            .await
+            .unwrap();
+        repo.save_message(&packet("bob", "100% rusty", 2))
+            .await
+            .unwrap();
+        repo.save_message(&packet("carol", "go is fine", 3))
+            .await
+            .unwrap();
+
+        let results = repo.search_messages("RUST", 10).await.unwrap();
+        let contents: Vec<&str> = results.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["100% rusty", "I love Rust"]);
+
+        let results = repo.search_messages("0%", 10).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(repo.search_messages("_", 10).await.unwrap().is_empty());
+        assert_eq!(repo.search_messages("rust", 1).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn count_includes_deleted_messages() {
+        let repo = repo().await;
+        assert_eq!(repo.count_messages().await.unwrap(), 0);
+
+        let id = repo.save_message(&packet("alice", "one", 1)).await.unwrap();
+        repo.save_message(&packet("alice", "two", 2)).await.unwrap();
+        repo.delete_message(id, "alice").await.unwrap();
+
+        assert_eq!(repo.count_messages().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn prune_removes_only_old_messages() {
+        let repo = repo().await;
+        repo.save_message(&packet("alice", "old", 100))
+            .await
+            .unwrap();
+        repo.save_message(&packet("alice", "new", 200))
+            .await
+            .unwrap();
+
+        assert_eq!(repo.prune_older_than(150).await.unwrap(), 1);
+
+        let history = repo
+            .get_recent_messages(before(i64::MAX, u64::MAX))
+            .await
+            .unwrap()
+            .messages;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].content, "new");
+    }
+}
@@ -1,45 +1,770 @@
 use crate::error::{Error, Result};
-use crate::repository::{PresenceRepository, UserRepository};
-use std::sync::Arc;
+use crate::repository::{PresenceRepository, SessionClaim, SessionOwner, UserRepository};
+use metrics::counter;
+use protocol::{NodeAddr, OnlineUser, SessionInfo};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
 
+/// Failed login attempts allowed per username before a cooldown applies.
+#[derive(Debug, Clone, Copy)]
+pub struct LoginLimit {
+    pub max_attempts: u64,
+    /// Failed attempts allowed per client IP across all usernames; `0` turns
+    /// the per-IP limit off. Behind an lb without PROXY protocol every client
+    /// shares the lb's address, so leave it off there.
+    pub max_attempts_per_ip: u64,
+    pub cooldown_secs: u64,
+}
+
+/// Logs users in against the user store, tracking sessions and failed
+/// logins in the presence store.
+///
+/// Each session is recorded there under its node and a nonce, so a login can
+/// take over a session this node no longer runs, such as one left behind by
+/// a crash, instead of waiting for it to expire.
+///
+/// Accounts live in the database, so logins keep working while the presence
+/// store is down, with these parts degraded until it's back:
+/// - failed logins aren't counted and lockouts aren't enforced;
+/// - a second session under the same name is only refused on this node;
+/// - sessions aren't marked online; each session's heartbeat retries that;
+/// - chat still reaches this node's sessions but not other nodes'.
 #[derive(Clone)]
 pub struct AuthService {
     users: Arc<dyn UserRepository>,
     presence: Arc<dyn PresenceRepository>,
+    login_limit: LoginLimit,
+    /// Whether an empty password joins as a guest instead of an account.
+    allow_guest: bool,
+    /// Names no one may register, though existing accounts under them still log in.
+    reserved: HashSet<String>,
+    node: NodeAddr,
+    /// Owner of each session live on this node, by username.
+    sessions: Arc<Mutex<HashMap<String, SessionOwner>>>,
+    /// Seeded from the clock so nonces differ across restarts of the node.
+    next_nonce: Arc<AtomicU64>,
+    /// Sessions let in while the presence store was down, still to be marked online.
+    unclaimed: Arc<Mutex<HashSet<String>>>,
 }
 
 impl AuthService {
-    pub fn new(users: Arc<dyn UserRepository>, presence: Arc<dyn PresenceRepository>) -> Self {
-        Self { users, presence }
+    pub fn new(
+        users: Arc<dyn UserRepository>,
+        presence: Arc<dyn PresenceRepository>,
+        node: NodeAddr,
+        login_limit: LoginLimit,
+        allow_guest: bool,
+    ) -> Self {
+        #[allow(clippy::cast_possible_truncation)]
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        Self {
+            users,
+            presence,
+            login_limit,
+            allow_guest,
+            reserved: HashSet::new(),
+            node,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            next_nonce: Arc::new(AtomicU64::new(seed)),
+            unclaimed: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    #[must_use]
+    pub fn with_reserved_names(mut self, reserved: HashSet<String>) -> Self {
+        self.reserved = reserved;
+        self
     }
 
-    pub async fn register_and_login(&self, username: &str, password: &str) -> Result<()> {
-        if username.trim().len() < 3 {
-            return Err(Error::UsernameTooShort(username.to_string()));
+    pub const fn guests_allowed(&self) -> bool {
+        self.allow_guest
+    }
+
+    /// Logs a user in, registering them on first use, and returns the name
+    /// their session runs under.
+    ///
+    /// With guests allowed, an empty password skips accounts entirely and
+    /// the session is named with `protocol::GUEST_PREFIX`.
+    pub async fn register_and_login(
+        &self,
+        username: &str,
+        password: &str,
+        client_ip: Option<IpAddr>,
+    ) -> Result<String> {
+        protocol::validate_username(username).map_err(Error::InvalidUsername)?;
+
+        if self.allow_guest && password.is_empty() {
+            return self.login_guest(username).await;
         }
 
-        let is_valid = self.users.verify_credentials(username, password).await?;
-        if !is_valid {
-            self.users.create_user(username, password).await?;
-            if !self.users.verify_credentials(username, password).await? {
+        // Usernames can't contain ':', so these never share a counter with one.
+        let ip_key = client_ip
+            .filter(|_| self.login_limit.max_attempts_per_ip > 0)
+            .map(|ip| format!("ip:{ip}"));
+        if let Some(key) = &ip_key
+            && let Some(remaining) = self
+                .locked_out(key, self.login_limit.max_attempts_per_ip)
+                .await
+        {
+            counter!("mcs_auth_failures_total", "reason" => "locked_out_ip").increment(1);
+            return Err(Error::TooManyAttempts(remaining));
+        }
+
+        if let Some(remaining) = self
+            .locked_out(username, self.login_limit.max_attempts)
+            .await
+        {
+            counter!("mcs_auth_failures_total", "reason" => "locked_out").increment(1);
+            return Err(Error::TooManyAttempts(remaining));
+        }
+
+        // A banned name is never registered, so it can't be taken over.
+        let ban = self.users.get_ban(username).await?;
+        if ban.is_none() && !self.users.user_exists(username).await? {
+            if self.reserved.contains(username) {
+                counter!("mcs_auth_failures_total", "reason" => "reserved").increment(1);
                 return Err(Error::InvalidCredentials);
             }
+            self.users.create_user(username, password).await?;
+            info!(user=%username, "registered new user");
+        }
+
+        // Verifying after a create also catches a concurrent registration of the same name.
+        if !self.users.verify_credentials(username, password).await? {
+            for key in std::iter::once(username).chain(ip_key.as_deref()) {
+                if let Err(e) = self
+                    .presence
+                    .record_login_failure(key, self.login_limit.cooldown_secs)
+                    .await
+                {
+                    degraded("record_login_failure", &e);
+                }
+            }
+            counter!("mcs_auth_failures_total", "reason" => "invalid_credentials").increment(1);
+            return Err(Error::InvalidCredentials);
+        }
+
+        // Only someone holding the password learns why the account is closed.
+        if let Some(reason) = ban {
+            counter!("mcs_auth_failures_total", "reason" => "banned").increment(1);
+            return Err(Error::Banned(reason));
+        }
+
+        if let Err(e) = self.presence.clear_login_failures(username).await {
+            degraded("clear_login_failures", &e);
+        }
+        self.claim_session(username).await?;
+
+        Ok(username.to_string())
+    }
+
+    async fn login_guest(&self, username: &str) -> Result<String> {
+        let name = protocol::guest_name(username);
+        if let Some(reason) = self.users.get_ban(&name).await? {
+            counter!("mcs_auth_failures_total", "reason" => "banned").increment(1);
+            return Err(Error::Banned(reason));
+        }
+
+        self.claim_session(&name).await?;
+        info!(user=%name, "guest joined");
+        Ok(name)
+    }
+
+    /// Returns the cooldown left if `key` has failed `max_attempts` times.
+    /// Without the presence store the lockout can't be checked, so it's skipped.
+    async fn locked_out(&self, key: &str, max_attempts: u64) -> Option<u64> {
+        match self.presence.get_login_failures(key).await {
+            Ok((failures, remaining)) => (failures >= max_attempts).then_some(remaining),
+            Err(e) => {
+                degraded("get_login_failures", &e);
+                None
+            }
+        }
+    }
+
+    /// Marks `username` online, refusing a second concurrent session. Without
+    /// the presence store the session is let in and marked online later.
+    async fn claim_session(&self, username: &str) -> Result<()> {
+        let owner = {
+            let mut sessions = self.sessions.lock().unwrap();
+            // Reserved before asking redis, so two logins here can't both win.
+            if sessions.contains_key(username) {
+                return Err(Error::AlreadyOnline(username.to_string()));
+            }
+            let owner = SessionOwner {
+                node: self.node.clone(),
+                nonce: self.next_nonce.fetch_add(1, Ordering::Relaxed),
+            };
+            sessions.insert(username.to_string(), owner.clone());
+            owner
+        };
+
+        match self.mark_online(username, &owner).await {
+            Ok(true) => Ok(()),
+            Ok(false) => {
+                self.sessions.lock().unwrap().remove(username);
+                Err(Error::AlreadyOnline(username.to_string()))
+            }
+            Err(e) => {
+                degraded("set_online", &e);
+                self.unclaimed.lock().unwrap().insert(username.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    /// Marks `username` online under `owner`, taking over a stale session,
+    /// and returns `false` if a live one holds the name.
+    async fn mark_online(&self, username: &str, owner: &SessionOwner) -> Result<bool> {
+        let held = match self.presence.set_online(username, owner).await? {
+            SessionClaim::Claimed => return Ok(true),
+            SessionClaim::Held(held) => held,
+        };
+        let Some(stale) = held.filter(|held| is_stale(held, owner)) else {
+            return Ok(false);
+        };
+
+        // Fails if the stale session was replaced meanwhile, like any other holder.
+        let taken = self
+            .presence
+            .take_over_session(username, &stale, owner)
+            .await?;
+        if taken {
+            info!(user=%username, stale_nonce=stale.nonce, "took over stale session");
+            counter!("mcs_session_takeovers_total").increment(1);
         }
+        Ok(taken)
+    }
+
+    /// Changes a registered user's password once `old_password` checks out.
+    ///
+    /// The session stays online; only later logins need the new password.
+    pub async fn change_password(
+        &self,
+        username: &str,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<()> {
+        protocol::validate_password(new_password).map_err(Error::InvalidPassword)?;
 
-        if !self.presence.set_online(username).await? {
-            return Err(Error::UsernameTaken(
-                "user is already logged in".to_string(),
-            ));
+        // Guests have no account row, so they fail here like a wrong password.
+        if !self
+            .users
+            .update_password(username, old_password, new_password)
+            .await?
+        {
+            counter!("mcs_auth_failures_total", "reason" => "password_change").increment(1);
+            return Err(Error::InvalidCredentials);
         }
 
+        info!(user=%username, "changed password");
         Ok(())
     }
 
+    /// Stops `username` from logging in again; existing sessions are kicked separately.
+    pub async fn ban_user(&self, username: &str, reason: &str) -> Result<()> {
+        info!(user=%username, %reason, "banning user");
+        self.users.ban_user(username, reason).await
+    }
+
     pub async fn logout(&self, username: &str) -> Result<()> {
-        self.presence.set_offline(username).await
+        self.unclaimed.lock().unwrap().remove(username);
+        let Some(owner) = self.sessions.lock().unwrap().remove(username) else {
+            return Ok(());
+        };
+        // Leaves the name alone if another session has taken it over since.
+        self.presence.set_offline(username, &owner).await
+    }
+
+    /// Keeps `username` marked online, first marking it if that couldn't be
+    /// done earlier, along with when they last chatted or typed.
+    /// Failures are retried on the next heartbeat rather than ending the session.
+    pub async fn refresh_session(&self, username: &str, last_active: i64) {
+        let unclaimed = self.unclaimed.lock().unwrap().contains(username);
+        let owner = self.sessions.lock().unwrap().get(username).cloned();
+        // Setting it again also covers a mark that lapsed while the store was down.
+        let result = if let Some(owner) = owner.filter(|_| unclaimed) {
+            self.mark_online(username, &owner).await.map(|marked| {
+                if !marked {
+                    warn!(user=%username, "another session claimed the name during the presence outage");
+                }
+            })
+        } else {
+            self.presence.refresh_heartbeat(username, last_active).await
+        };
+
+        match result {
+            Ok(()) if unclaimed => {
+                info!(user=%username, "session marked online after presence outage");
+                self.unclaimed.lock().unwrap().remove(username);
+            }
+            Ok(()) => {}
+            Err(e) => {
+                degraded("refresh_heartbeat", &e);
+                self.unclaimed.lock().unwrap().insert(username.to_string());
+            }
+        }
+    }
+
+    /// Returns everyone online on any node, by username.
+    pub async fn online_users(&self) -> Result<Vec<OnlineUser>> {
+        let mut users: Vec<OnlineUser> = self
+            .presence
+            .get_online_users()
+            .await?
+            .into_iter()
+            .map(|(username, last_active)| OnlineUser {
+                username,
+                last_active,
+            })
+            .collect();
+        users.sort_unstable_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+
+    /// Returns every session on any node, by username.
+    pub async fn sessions(&self) -> Result<Vec<SessionInfo>> {
+        let mut sessions: Vec<SessionInfo> = self
+            .presence
+            .get_sessions()
+            .await?
+            .into_iter()
+            .map(|(username, owner)| SessionInfo {
+                username,
+                node: owner.node.to_string(),
+            })
+            .collect();
+        sessions.sort_unstable_by(|a, b| a.username.cmp(&b.username));
+        Ok(sessions)
+    }
+}
+
+/// Whether `held` is a session this node no longer runs, which `owner` may
+/// take over.
+///
+/// Only asked once no live session here has the name, so any other session
+/// of this node's is dead. One on another node can't be told apart from a
+/// live one, so it's left to expire.
+fn is_stale(held: &SessionOwner, owner: &SessionOwner) -> bool {
+    held.node == owner.node && held.nonce != owner.nonce
+}
+
+/// Logs and counts a presence store failure that the node works around.
+pub fn degraded(operation: &'static str, e: &Error) {
+    warn!(operation, err=?e, "presence store unavailable, continuing degraded");
+    counter!("mcs_presence_degraded_total", "operation" => operation).increment(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthService, LoginLimit, is_stale};
+    use crate::error::Error;
+    use crate::repository::SessionOwner;
+    use crate::repository::mock::MockRepository;
+    use logging::testing::record_metrics;
+    use metrics_util::debugging::DebugValue;
+    use protocol::{ChatError, NodeAddr};
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+
+    const LIMIT: LoginLimit = LoginLimit {
+        max_attempts: 3,
+        max_attempts_per_ip: 0,
+        cooldown_secs: 60,
+    };
+
+    fn node() -> NodeAddr {
+        "node-a:64400".parse().unwrap()
+    }
+
+    fn auth_service() -> (AuthService, Arc<MockRepository>) {
+        let repo = Arc::new(MockRepository::default());
+        repo.users
+            .lock()
+            .unwrap()
+            .insert("alice".to_string(), "correct".to_string());
+        (
+            AuthService::new(repo.clone(), repo.clone(), node(), LIMIT, false),
+            repo,
+        )
+    }
+
+    fn guest_auth_service() -> (AuthService, Arc<MockRepository>) {
+        let (_, repo) = auth_service();
+        (
+            AuthService::new(repo.clone(), repo.clone(), node(), LIMIT, true),
+            repo,
+        )
+    }
+
+    #[tokio::test]
+    async fn reserved_names_are_never_registered() {
+        let (auth, repo) = auth_service();
+        let reserved = ["alice".to_string(), "admin".to_string()];
+        let auth = auth.with_reserved_names(reserved.into_iter().collect());
+
+        assert!(matches!(
+            auth.register_and_login("admin", "pw", None).await,
+            Err(Error::InvalidCredentials)
+        ));
+        assert!(!repo.users.lock().unwrap().contains_key("admin"));
+        auth.register_and_login("alice", "correct", None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_lock_out_user() {
+        let (auth, _) = auth_service();
+
+        for _ in 0..LIMIT.max_attempts {
+            let result = auth.register_and_login("alice", "wrong", None).await;
+            assert!(matches!(result, Err(Error::InvalidCredentials)));
+        }
+
+        let result = auth.register_and_login("alice", "correct", None).await;
+        assert!(matches!(result, Err(Error::TooManyAttempts(60))));
     }
 
-    pub async fn refresh_session(&self, username: &str) -> Result<()> {
-        self.presence.refresh_heartbeat(username).await
+    #[tokio::test]
+    async fn failures_across_usernames_lock_out_the_ip() {
+        let (_, repo) = auth_service();
+        repo.users
+            .lock()
+            .unwrap()
+            .insert("bob".to_string(), "correct".to_string());
+        let limit = LoginLimit {
+            max_attempts_per_ip: 2,
+            ..LIMIT
+        };
+        let auth = AuthService::new(repo.clone(), repo, node(), limit, false);
+        let ip = Some("203.0.113.7".parse().unwrap());
+
+        for user in ["alice", "bob"] {
+            let result = auth.register_and_login(user, "wrong", ip).await;
+            assert!(matches!(result, Err(Error::InvalidCredentials)));
+        }
+
+        let result = auth.register_and_login("alice", "correct", ip).await;
+        assert!(matches!(result, Err(Error::TooManyAttempts(60))));
+        let other_ip = Some("198.51.100.1".parse().unwrap());
+        auth.register_and_login("alice", "correct", other_ip)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn login_succeeds_degraded_while_presence_is_down() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (auth, repo) = auth_service();
+        repo.presence_down.store(true, Ordering::Relaxed);
+
+        let (_, recorded) = record_metrics(|| {
+            runtime
+                .block_on(auth.register_and_login("alice", "correct", None))
+                .unwrap()
+        });
+
+        assert!(recorded.get("mcs_presence_degraded_total").is_some());
+        assert!(repo.online.lock().unwrap().is_empty());
+
+        // Once the store is back, the next heartbeat marks the session online.
+        repo.presence_down.store(false, Ordering::Relaxed);
+        runtime.block_on(auth.refresh_session("alice", 0));
+        assert!(repo.online.lock().unwrap().contains_key("alice"));
+    }
+
+    #[tokio::test]
+    async fn unknown_user_is_registered() {
+        let (auth, repo) = auth_service();
+
+        auth.register_and_login("bob", "secret", None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            repo.users.lock().unwrap().get("bob").map(String::as_str),
+            Some("secret")
+        );
+    }
+
+    #[tokio::test]
+    async fn existing_user_logs_in_with_correct_password() {
+        let (auth, repo) = auth_service();
+
+        auth.register_and_login("alice", "correct", None)
+            .await
+            .unwrap();
+
+        assert_eq!(repo.users.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_rejected_without_touching_account() {
+        let (auth, repo) = auth_service();
+
+        let err = auth
+            .register_and_login("alice", "wrong", None)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::InvalidCredentials));
+        assert_eq!(err.to_chat_error(), ChatError::InvalidCredentials);
+        assert_eq!(
+            repo.users.lock().unwrap().get("alice").map(String::as_str),
+            Some("correct")
+        );
+    }
+
+    #[tokio::test]
+    async fn successful_login_resets_failures() {
+        let (auth, repo) = auth_service();
+
+        for _ in 0..LIMIT.max_attempts - 1 {
+            let _ = auth.register_and_login("alice", "wrong", None).await;
+        }
+        auth.register_and_login("alice", "correct", None)
+            .await
+            .unwrap();
+
+        assert!(repo.login_failures.lock().unwrap().get("alice").is_none());
+    }
+
+    #[tokio::test]
+    async fn invalid_username_is_rejected() {
+        let (auth, repo) = auth_service();
+
+        let result = auth.register_and_login("bad name", "pw", None).await;
+
+        assert!(matches!(result, Err(Error::InvalidUsername(_))));
+        assert!(!repo.users.lock().unwrap().contains_key("bad name"));
+    }
+
+    #[test]
+    fn invalid_credentials_increment_failure_counter() {
+        let (auth, _) = auth_service();
+
+        let (_, recorded) = record_metrics(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(auth.register_and_login("alice", "wrong", None))
+                .unwrap_err()
+        });
+
+        assert_eq!(
+            recorded.get("mcs_auth_failures_total"),
+            Some(&DebugValue::Counter(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn banned_user_cannot_log_in() {
+        let (auth, repo) = auth_service();
+        auth.register_and_login("alice", "correct", None)
+            .await
+            .unwrap();
+        auth.logout("alice").await.unwrap();
+        auth.ban_user("alice", "spam").await.unwrap();
+
+        let err = auth
+            .register_and_login("alice", "correct", None)
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.to_chat_error(), ChatError::Banned("spam".to_string()));
+        assert!(!repo.online.lock().unwrap().contains_key("alice"));
+    }
+
+    #[tokio::test]
+    async fn ban_is_only_reported_to_the_password_holder() {
+        let (auth, repo) = auth_service();
+        auth.register_and_login("alice", "correct", None)
+            .await
+            .unwrap();
+        auth.logout("alice").await.unwrap();
+        auth.ban_user("alice", "spam").await.unwrap();
+        auth.ban_user("ghost", "spam").await.unwrap();
+
+        for (user, password) in [("alice", "wrong"), ("ghost", "anything")] {
+            let err = auth
+                .register_and_login(user, password, None)
+                .await
+                .unwrap_err();
+            assert_eq!(err.to_chat_error(), ChatError::InvalidCredentials);
+        }
+        assert!(!repo.users.lock().unwrap().contains_key("ghost"));
+    }
+
+    #[tokio::test]
+    async fn guest_joins_without_an_account() {
+        let (auth, repo) = guest_auth_service();
+
+        let name = auth.register_and_login("bob", "", None).await.unwrap();
+
+        assert_eq!(name, "~bob");
+        assert!(!repo.users.lock().unwrap().contains_key("bob"));
+        assert!(repo.online.lock().unwrap().contains_key("~bob"));
+    }
+
+    #[tokio::test]
+    async fn guest_names_never_collide_with_registered_users() {
+        let (auth, _) = guest_auth_service();
+
+        assert_eq!(
+            auth.register_and_login("alice", "correct", None)
+                .await
+                .unwrap(),
+            "alice"
+        );
+        assert_eq!(
+            auth.register_and_login("alice", "", None).await.unwrap(),
+            "~alice"
+        );
+        let err = auth
+            .register_and_login("alice", "", None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::AlreadyOnline(_)));
+    }
+
+    #[test]
+    fn only_other_sessions_of_this_node_are_stale() {
+        let owner = |node: &str, nonce| SessionOwner {
+            node: node.parse().unwrap(),
+            nonce,
+        };
+        let ours = owner("node-a:64400", 7);
+
+        assert!(is_stale(&owner("node-a:64400", 3), &ours));
+        assert!(!is_stale(&owner("node-b:64400", 3), &ours));
+        assert!(!is_stale(&ours, &ours));
+    }
+
+    #[tokio::test]
+    async fn login_takes_over_a_dead_session_here_but_not_one_elsewhere() {
+        let (auth, repo) = auth_service();
+        let crashed = SessionOwner {
+            node: node(),
+            nonce: 1,
+        };
+        let elsewhere = SessionOwner {
+            node: "node-b:64400".parse().unwrap(),
+            nonce: 1,
+        };
+        repo.online
+            .lock()
+            .unwrap()
+            .insert("alice".to_string(), crashed.clone());
+
+        auth.register_and_login("alice", "correct", None)
+            .await
+            .unwrap();
+        let owner = repo.online.lock().unwrap()["alice"].clone();
+        assert_ne!(owner, crashed);
+
+        // The live session here isn't taken over by a second login.
+        let err = auth
+            .register_and_login("alice", "correct", None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_chat_error(), ChatError::AlreadyOnline);
+
+        // Once the name lapses to another node, logging out leaves it be.
+        repo.online
+            .lock()
+            .unwrap()
+            .insert("alice".to_string(), elsewhere.clone());
+        auth.logout("alice").await.unwrap();
+        assert_eq!(repo.online.lock().unwrap()["alice"], elsewhere);
+
+        let err = auth
+            .register_and_login("alice", "correct", None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_chat_error(), ChatError::AlreadyOnline);
+        assert_eq!(repo.online.lock().unwrap()["alice"], elsewhere);
+    }
+
+    #[tokio::test]
+    async fn online_users_carry_their_last_active() {
+        let (auth, repo) = guest_auth_service();
+        for name in ["carol", "bob", "alice"] {
+            auth.register_and_login(name, "", None).await.unwrap();
+        }
+        auth.refresh_session("~bob", 1_000).await;
+        auth.logout("~carol").await.unwrap();
+        // A session that expired with its node leaves its activity behind.
+        repo.last_active
+            .lock()
+            .unwrap()
+            .insert("dave".to_string(), 500);
+
+        let users = auth.online_users().await.unwrap();
+
+        let names: Vec<&str> = users.iter().map(|u| u.username.as_str()).collect();
+        assert_eq!(names, ["~alice", "~bob"]);
+        assert!(users[0].last_active > 1_000, "fresh from login");
+        assert_eq!(users[1].last_active, 1_000);
+    }
+
+    #[tokio::test]
+    async fn password_change_keeps_the_session_online() {
+        let (auth, repo) = auth_service();
+        auth.register_and_login("alice", "correct", None)
+            .await
+            .unwrap();
+
+        auth.change_password("alice", "correct", "much longer")
+            .await
+            .unwrap();
+
+        assert!(repo.online.lock().unwrap().contains_key("alice"));
+        assert_eq!(
+            repo.users.lock().unwrap().get("alice").map(String::as_str),
+            Some("much longer")
+        );
+    }
+
+    #[tokio::test]
+    async fn password_change_needs_the_old_password_and_a_strong_new_one() {
+        let (auth, repo) = auth_service();
+
+        let err = auth
+            .change_password("alice", "wrong", "much longer")
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_chat_error(), ChatError::InvalidCredentials);
+
+        let err = auth
+            .change_password("alice", "correct", "short")
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_chat_error(), ChatError::PasswordTooShort);
+
+        assert_eq!(
+            repo.users.lock().unwrap().get("alice").map(String::as_str),
+            Some("correct")
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_password_is_a_credential_without_guest_mode() {
+        let (auth, _) = auth_service();
+
+        let err = auth
+            .register_and_login("alice", "", None)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::InvalidCredentials));
     }
 }
@@ -1,44 +1,801 @@
-use crate::error::Result;
-use crate::repository::{MessageRepository, PresenceRepository};
+use crate::error::{Error, Result};
+use crate::repository::{HistoryPage, HistoryQuery, MessageRepository, PresenceRepository};
+use crate::service::auth::degraded;
+use crate::service::filter::{ContentFilter, FilterOutcome};
+use crate::service::presence::{PresenceDebouncer, PresenceEvent};
+use crate::service::rooms::DEFAULT_ROOM;
+use metrics::{counter, histogram};
 use protocol::ChatPacket;
 use protocol::Message;
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time;
+use tracing::{error, info, warn};
+
+/// Upper bound on results returned by a single search.
+pub const MAX_SEARCH_RESULTS: u32 = 50;
+
+/// How history is kept and served.
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryOptions {
+    /// Messages per page sent to clients.
+    pub page_size: u32,
+    /// Also store joins and leaves as system messages, so history shows them.
+    pub presence: bool,
+}
 
 #[derive(Clone)]
 pub struct ChatService {
     messages: Arc<dyn MessageRepository>,
     presence: Arc<dyn PresenceRepository>,
+    filter: Arc<dyn ContentFilter>,
+    admins: HashSet<String>,
+    presence_debounce: Arc<PresenceDebouncer>,
+    max_message_len: usize,
+    history: HistoryOptions,
 }
 
 impl ChatService {
     pub fn new(
         messages: Arc<dyn MessageRepository>,
         presence: Arc<dyn PresenceRepository>,
+        filter: Arc<dyn ContentFilter>,
+        admins: HashSet<String>,
+        presence_grace: Duration,
+        max_message_len: usize,
+        history: HistoryOptions,
     ) -> Self {
-        Self { messages, presence }
+        Self {
+            messages,
+            presence,
+            filter,
+            admins,
+            presence_debounce: Arc::new(PresenceDebouncer::new(presence_grace)),
+            max_message_len,
+            history,
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    /// Persists and broadcasts a user's message, returning its assigned id.
+    pub async fn broadcast_user_message(&self, sender: &str, content: String) -> Result<u64> {
+        histogram!("mcs_message_size_bytes").record(content.len() as f64);
+        self.check_length(&content)?;
+        let content = self.apply_filter(content)?;
+        let mut packet = ChatPacket::new_user_packet(sender.to_string(), content);
+
+        packet.id = self.messages.save_message(&packet).await?;
+        let id = packet.id;
+        self.fan_out(Message::Chat(packet)).await?;
+        counter!("mcs_messages_total").increment(1);
+
+        Ok(id)
+    }
+
+    /// Sends `event` to every client, first storing it as a system message when
+    /// presence history is on. Live clients only get the event, never that message.
+    pub async fn broadcast_presence(&self, event: PresenceEvent) -> Result<()> {
+        if self.history.presence {
+            let packet = ChatPacket::new_server_packet(event.to_string());
+            self.messages.save_message(&packet).await?;
+        }
+        self.fan_out(event.into()).await
+    }
+
+    /// Announces a join, unless it is a quick reconnect that cancelled a pending
+    /// leave. Returns whether it was announced.
+    pub async fn announce_join(&self, username: &str) -> Result<bool> {
+        if !self.presence_debounce.on_join(username) {
+            return Ok(false);
+        }
+        self.broadcast_presence(PresenceEvent::Joined(username.to_string()))
+            .await
+            .map(|()| true)
+    }
+
+    /// Announces a leave once the user has stayed away for the grace window.
+    pub fn announce_leave(self: &Arc<Self>, username: &str) {
+        let chat = self.clone();
+        let username = username.to_string();
+        let token = self.presence_debounce.on_leave(&username);
+
+        tokio::spawn(async move {
+            time::sleep(chat.presence_debounce.grace()).await;
+            if chat.presence_debounce.confirm_leave(&username, token)
+                && let Err(e) = chat
+                    .broadcast_presence(PresenceEvent::Left(username.clone()))
+                    .await
+            {
+                warn!(user=%username, err=?e, "failed to broadcast leave message");
+            }
+        });
+    }
+
+    pub async fn edit_message(&self, sender: &str, id: u64, content: String) -> Result<()> {
+        self.check_length(&content)?;
+        let content = self.apply_filter(content)?;
+
+        if !self.messages.edit_message(id, sender, &content).await? {
+            return Err(Error::NotMessageAuthor(id));
+        }
+        self.fan_out(Message::EditMessage { id, content }).await
     }
 
-    pub async fn broadcast_user_message(&self, sender: &str, content: String) -> Result<()> {
-        let packet = ChatPacket::new_user_packet(sender.to_string(), content);
+    pub async fn delete_message(&self, sender: &str, id: u64) -> Result<()> {
+        if !self.messages.delete_message(id, sender).await? {
+            return Err(Error::NotMessageAuthor(id));
+        }
+        self.fan_out(Message::DeleteMessage { id }).await
+    }
+
+    /// Relays a typing notice to other nodes without persisting it.
+    pub async fn broadcast_typing(&self, username: &str, is_typing: bool) -> Result<()> {
+        self.fan_out(Message::Typing {
+            username: username.to_string(),
+            is_typing,
+        })
+        .await
+    }
 
-        self.messages.save_message(&packet).await?;
-        self.presence.broadcast(Message::Chat(packet)).await?;
+    /// Longest message content accepted, in bytes.
+    pub const fn max_message_len(&self) -> usize {
+        self.max_message_len
+    }
 
+    pub fn require_admin(&self, username: &str) -> Result<()> {
+        if !self.admins.contains(username) {
+            return Err(Error::NotAdmin(username.to_string()));
+        }
         Ok(())
     }
 
-    pub async fn broadcast_system_message(&self, content: String) -> Result<ChatPacket> {
-        let packet = ChatPacket::new_server_packet(content);
+    pub async fn count_messages(&self) -> Result<u64> {
+        self.messages.count_messages().await
+    }
 
-        self.messages.save_message(&packet).await?;
-        self.presence
-            .broadcast(Message::Chat(packet.clone()))
+    /// Persists and broadcasts an announcement to every connected user.
+    pub async fn broadcast_announcement(&self, sender: &str, content: String) -> Result<()> {
+        self.require_admin(sender)?;
+        self.check_length(&content)?;
+
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        self.messages
+            .save_announcement(sender, &content, timestamp)
             .await?;
+        self.fan_out(Message::Announcement { content }).await
+    }
+
+    /// Tells whichever node holds `username`'s session to close it with `reason`.
+    pub async fn kick_user(&self, username: &str, reason: &str) -> Result<()> {
+        let reason = reason.to_string();
+        self.presence
+            .send_to_user(username, Message::Kicked { reason })
+            .await
+    }
+
+    pub async fn get_latest_announcement(&self) -> Result<Option<String>> {
+        self.messages.get_latest_announcement().await
+    }
+
+    /// Returns the page before the `(before_ts, before_id)` cursor, only from
+    /// `sender` when one is given.
+    ///
+    /// A blank sender matches nobody rather than falling back to everyone.
+    pub async fn get_history(
+        &self,
+        before_ts: i64,
+        before_id: u64,
+        sender: Option<&str>,
+    ) -> Result<HistoryPage> {
+        counter!("mcs_history_requests_total").increment(1);
+        match sender.map(str::trim) {
+            None => {
+                self.messages
+                    .get_recent_messages(HistoryQuery {
+                        before_ts,
+                        before_id,
+                        limit: self.history.page_size,
+                    })
+                    .await
+            }
+            Some("") => Ok(HistoryPage::default()),
+            Some(sender) => {
+                self.messages
+                    .get_messages_by_sender(sender, before_ts, before_id, self.history.page_size)
+                    .await
+            }
+        }
+    }
+
+    /// Returns the page after the `(after_ts, after_id)` cursor, for a client
+    /// paging back toward the newest messages.
+    pub async fn get_newer_history(&self, after_ts: i64, after_id: u64) -> Result<HistoryPage> {
+        counter!("mcs_history_requests_total").increment(1);
+        self.messages
+            .get_messages_after(after_ts, after_id, self.history.page_size)
+            .await
+    }
+
+    /// Finds messages containing `query`, capping `limit` at `MAX_SEARCH_RESULTS`.
+    pub async fn search(&self, query: &str, limit: u32) -> Result<Vec<ChatPacket>> {
+        let query = query.trim();
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.messages
+            .search_messages(query, limit.clamp(1, MAX_SEARCH_RESULTS))
+            .await
+    }
+
+    /// Publishes `msg` to every node in the room, recording how long the fan-out took.
+    ///
+    /// Local sessions get it regardless, so a failed publish only degrades
+    /// delivery to other nodes and isn't reported to the sender.
+    async fn fan_out(&self, msg: Message) -> Result<()> {
+        let start = Instant::now();
+        if let Err(e) = self.presence.broadcast(DEFAULT_ROOM, msg).await {
+            degraded("broadcast", &e);
+        }
+        histogram!("mcs_broadcast_fanout_seconds").record(start.elapsed().as_secs_f64());
+        Ok(())
+    }
+
+    const fn check_length(&self, content: &str) -> Result<()> {
+        if content.len() > self.max_message_len {
+            return Err(Error::MessageTooLong(self.max_message_len));
+        }
+        Ok(())
+    }
+
+    fn apply_filter(&self, content: String) -> Result<String> {
+        match self.filter.check(&content) {
+            FilterOutcome::Allow => Ok(content),
+            FilterOutcome::Redact(redacted) => Ok(redacted),
+            FilterOutcome::Reject(reason) => Err(Error::MessageRejected(reason)),
+        }
+    }
+
+    /// Deletes messages older than `retention`, returning how many were removed.
+    pub async fn prune_messages(&self, retention: Duration) -> Result<u64> {
+        let retention_millis = i64::try_from(retention.as_millis()).unwrap_or(i64::MAX);
+        let cutoff = chrono::Utc::now()
+            .timestamp_millis()
+            .saturating_sub(retention_millis);
+        self.messages.prune_older_than(cutoff).await
+    }
+
+    /// Periodically prunes messages older than `retention`.
+    pub fn spawn_pruner(self: &Arc<Self>, retention: Duration, interval: Duration) {
+        let chat = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(interval);
+
+            loop {
+                interval.tick().await;
+                match chat.prune_messages(retention).await {
+                    Ok(removed) => info!(removed, ?retention, "pruned old messages"),
+                    Err(e) => error!(err=?e, "message pruning failed"),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChatService, HistoryOptions, MAX_SEARCH_RESULTS};
+    use crate::config::Argon2Config;
+    use crate::error::Error;
+    use crate::repository::mock::MockRepository;
+    use crate::repository::sqlite::SqliteRepository;
+    use crate::repository::{HISTORY_PAGE_SIZE, MessageRepository};
+    use crate::service::filter::{ContentFilter, FilterAction, NoopFilter, WordListFilter};
+    use crate::service::presence::PresenceEvent;
+    use crate::service::rooms::DEFAULT_ROOM;
+    use logging::testing::record_metrics;
+    use metrics_util::debugging::DebugValue;
+    use protocol::{ChatPacket, Message};
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+
+    fn chat_service() -> (ChatService, Arc<MockRepository>) {
+        chat_service_with_filter(Arc::new(NoopFilter))
+    }
+
+    fn chat_service_with_filter(
+        filter: Arc<dyn ContentFilter>,
+    ) -> (ChatService, Arc<MockRepository>) {
+        let repo = Arc::new(MockRepository::default());
+        let admins = HashSet::from(["admin".to_string()]);
+        (
+            ChatService::new(
+                repo.clone(),
+                repo.clone(),
+                filter,
+                admins,
+                Duration::from_secs(5),
+                16,
+                HistoryOptions {
+                    page_size: HISTORY_PAGE_SIZE,
+                    presence: true,
+                },
+            ),
+            repo,
+        )
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn broadcast_user_message_records_size_histogram() {
+        let (chat, repo) = chat_service();
+
+        let (_, recorded) = record_metrics(|| {
+            block_on(chat.broadcast_user_message("alice", "hello".to_string())).unwrap()
+        });
+
+        match recorded
+            .get("mcs_message_size_bytes")
+            .expect("histogram should be registered")
+        {
+            DebugValue::Histogram(values) => {
+                assert_eq!(values.len(), 1);
+                assert!((values[0].into_inner() - 5.0).abs() < f64::EPSILON);
+            }
+            other => panic!("expected a histogram, got {other:?}"),
+        }
+        assert_eq!(repo.broadcasts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn chat_reaches_local_sessions_while_presence_is_down() {
+        let (chat, repo) = chat_service();
+        let (sender, mut session) = broadcast::channel(8);
+        *repo.local_sender.lock().unwrap() = Some(sender);
+        repo.rooms.lock().unwrap().insert(DEFAULT_ROOM.to_string());
+        repo.presence_down.store(true, Ordering::Relaxed);
+
+        let (id, recorded) =
+            record_metrics(|| block_on(chat.broadcast_user_message("alice", "hello".to_string())));
+        let id = id.expect("a stored message is acked even if other nodes can't be told");
+
+        match session.try_recv() {
+            Ok(Message::Chat(packet)) => {
+                assert_eq!((packet.id, packet.content.as_str()), (id, "hello"));
+            }
+            other => panic!("expected the chat, got {other:?}"),
+        }
+        assert!(recorded.get("mcs_presence_degraded_total").is_some());
+    }
+
+    fn word_filter(action: FilterAction) -> Arc<dyn ContentFilter> {
+        Arc::new(WordListFilter::new(&["darn".to_string()], action))
+    }
+
+    #[test]
+    fn broadcast_user_message_allows_clean_content() {
+        let (chat, repo) = chat_service_with_filter(word_filter(FilterAction::Reject));
+
+        block_on(chat.broadcast_user_message("alice", "hello".to_string())).unwrap();
+
+        assert_eq!(repo.saved.lock().unwrap()[0].content, "hello");
+        assert_eq!(repo.broadcasts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn stored_and_broadcast_content_is_the_raw_user_text() {
+        let (chat, repo) = chat_service_with_filter(Arc::new(NoopFilter));
+
+        block_on(chat.broadcast_user_message("alice", "bob: hi".to_string())).unwrap();
+
+        let saved = repo.saved.lock().unwrap()[0].clone();
+        assert_eq!(
+            (saved.sender.as_str(), saved.content.as_str()),
+            ("alice", "bob: hi")
+        );
+        match &repo.broadcasts.lock().unwrap()[0] {
+            Message::Chat(packet) => assert_eq!(packet.content, "bob: hi"),
+            other => panic!("expected a chat broadcast, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn broadcast_user_message_persists_redacted_content() {
+        let (chat, repo) = chat_service_with_filter(word_filter(FilterAction::Redact));
+
+        block_on(chat.broadcast_user_message("alice", "oh darn".to_string())).unwrap();
+
+        assert_eq!(repo.saved.lock().unwrap()[0].content, "oh ****");
+        match &repo.broadcasts.lock().unwrap()[0] {
+            Message::Chat(packet) => assert_eq!(packet.content, "oh ****"),
+            other => panic!("expected a chat broadcast, got {other:?}"),
+        }
+    }
 
-        Ok(packet)
+    #[test]
+    fn broadcast_user_message_rejects_blocked_content() {
+        let (chat, repo) = chat_service_with_filter(word_filter(FilterAction::Reject));
+
+        let result = block_on(chat.broadcast_user_message("alice", "oh darn".to_string()));
+
+        assert!(matches!(result, Err(Error::MessageRejected(_))));
+        assert!(repo.saved.lock().unwrap().is_empty());
+        assert!(repo.broadcasts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn broadcast_user_message_enforces_length_limit() {
+        let (chat, repo) = chat_service();
+
+        block_on(chat.broadcast_user_message("alice", "x".repeat(16))).unwrap();
+        let result = block_on(chat.broadcast_user_message("alice", "x".repeat(17)));
+
+        assert!(matches!(result, Err(Error::MessageTooLong(16))));
+        assert_eq!(repo.saved.lock().unwrap().len(), 1);
+        assert_eq!(repo.broadcasts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn edit_message_by_author_succeeds() {
+        let (chat, repo) = chat_service();
+        block_on(chat.broadcast_user_message("alice", "helo".to_string())).unwrap();
+
+        block_on(chat.edit_message("alice", 1, "hello".to_string())).unwrap();
+
+        assert_eq!(repo.saved.lock().unwrap()[0].content, "hello");
+        assert!(matches!(
+            &repo.broadcasts.lock().unwrap()[1],
+            Message::EditMessage { id: 1, content } if content == "hello"
+        ));
+    }
+
+    #[test]
+    fn edit_and_delete_by_other_user_fails() {
+        let (chat, repo) = chat_service();
+        block_on(chat.broadcast_user_message("alice", "hello".to_string())).unwrap();
+
+        let edit = block_on(chat.edit_message("mallory", 1, "pwned".to_string()));
+        let delete = block_on(chat.delete_message("mallory", 1));
+
+        assert!(matches!(edit, Err(Error::NotMessageAuthor(1))));
+        assert!(matches!(delete, Err(Error::NotMessageAuthor(1))));
+        assert_eq!(repo.saved.lock().unwrap()[0].content, "hello");
+        assert_eq!(repo.broadcasts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn delete_message_by_author_tombstones() {
+        let (chat, repo) = chat_service();
+        block_on(chat.broadcast_user_message("alice", "oops".to_string())).unwrap();
+
+        block_on(chat.delete_message("alice", 1)).unwrap();
+
+        assert!(repo.saved.lock().unwrap()[0].deleted);
+        assert!(matches!(
+            repo.broadcasts.lock().unwrap()[1],
+            Message::DeleteMessage { id: 1 }
+        ));
+        assert!(block_on(chat.edit_message("alice", 1, "back".to_string())).is_err());
+    }
+
+    #[test]
+    fn broadcast_typing_is_not_persisted() {
+        let (chat, repo) = chat_service();
+
+        block_on(chat.broadcast_typing("alice", true)).unwrap();
+
+        assert!(repo.saved.lock().unwrap().is_empty());
+        assert!(
+            block_on(chat.get_history(i64::MAX, u64::MAX, None))
+                .unwrap()
+                .messages
+                .is_empty()
+        );
+        assert!(matches!(
+            &repo.broadcasts.lock().unwrap()[0],
+            Message::Typing { username, is_typing: true } if username == "alice"
+        ));
+    }
+
+    #[test]
+    fn announcement_from_non_admin_is_rejected() {
+        let (chat, repo) = chat_service();
+
+        let result = block_on(chat.broadcast_announcement("alice", "hi all".to_string()));
+
+        assert!(matches!(result, Err(Error::NotAdmin(_))));
+        assert!(repo.broadcasts.lock().unwrap().is_empty());
+        assert_eq!(block_on(chat.get_latest_announcement()).unwrap(), None);
+    }
+
+    #[test]
+    fn overlong_announcement_is_rejected() {
+        let (chat, repo) = chat_service();
+
+        let result = block_on(chat.broadcast_announcement("admin", "x".repeat(17)));
+
+        assert!(matches!(result, Err(Error::MessageTooLong(16))));
+        assert!(repo.broadcasts.lock().unwrap().is_empty());
+        assert_eq!(block_on(chat.get_latest_announcement()).unwrap(), None);
+    }
+
+    #[test]
+    fn announcement_from_admin_is_broadcast_and_persisted() {
+        let (chat, repo) = chat_service();
+
+        block_on(chat.broadcast_announcement("admin", "maintenance at 5".to_string())).unwrap();
+
+        assert!(matches!(
+            &repo.broadcasts.lock().unwrap()[0],
+            Message::Announcement { content } if content == "maintenance at 5"
+        ));
+        assert_eq!(
+            block_on(chat.get_latest_announcement()).unwrap().as_deref(),
+            Some("maintenance at 5")
+        );
+    }
+
+    fn seed_messages(repo: &MockRepository, contents: &[&str]) {
+        let mut saved = repo.saved.lock().unwrap();
+        for (i, content) in (1..).zip(contents) {
+            saved.push(ChatPacket {
+                id: i,
+                sender: "alice".to_string(),
+                content: (*content).to_string(),
+                timestamp: i.cast_signed(),
+                deleted: false,
+            });
+        }
+    }
+
+    #[test]
+    fn search_returns_matching_subset_newest_first() {
+        let (chat, repo) = chat_service();
+        seed_messages(
+            &repo,
+            &[
+                "I love Rust",
+                "go is fine",
+                "rusty nails",
+                "hello",
+                "old rust",
+            ],
+        );
+        repo.saved.lock().unwrap()[4].deleted = true;
+
+        let results = block_on(chat.search("  RUST ", 10)).unwrap();
+
+        let ids: Vec<u64> = results.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![3, 1]);
     }
 
-    pub async fn get_history(&self, before_ts: i64) -> Result<Vec<ChatPacket>> {
-        self.messages.get_recent_messages(before_ts).await
+    #[test]
+    fn search_caps_limit_and_ignores_blank_queries() {
+        let (chat, repo) = chat_service();
+        let contents = vec!["match"; 60];
+        seed_messages(&repo, &contents);
+
+        let results = block_on(chat.search("match", 1000)).unwrap();
+        assert_eq!(results.len(), MAX_SEARCH_RESULTS as usize);
+        assert_eq!(block_on(chat.search("match", 0)).unwrap().len(), 1);
+        assert!(block_on(chat.search("   ", 10)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_filters_by_sender_and_blank_sender_matches_nobody() {
+        let (chat, repo) = chat_service();
+        seed_messages(&repo, &["one", "two", "three"]);
+        repo.saved.lock().unwrap()[1].sender = "bob".to_string();
+
+        let page = block_on(chat.get_history(i64::MAX, u64::MAX, Some(" bob "))).unwrap();
+        let ids: Vec<u64> = page.messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![2]);
+
+        let page = block_on(chat.get_history(i64::MAX, u64::MAX, None)).unwrap();
+        assert_eq!(page.messages.len(), 3);
+
+        let page = block_on(chat.get_history(i64::MAX, u64::MAX, Some("  "))).unwrap();
+        assert!(page.messages.is_empty());
+        assert!(!page.has_more);
+    }
+
+    #[test]
+    fn history_pages_are_oldest_first_at_the_configured_size() {
+        let repo = Arc::new(MockRepository::default());
+        let chat = ChatService::new(
+            repo.clone(),
+            repo.clone(),
+            Arc::new(NoopFilter),
+            HashSet::new(),
+            Duration::from_secs(5),
+            16,
+            HistoryOptions {
+                page_size: 2,
+                presence: true,
+            },
+        );
+        seed_messages(&repo, &["one", "two", "three"]);
+
+        let page = block_on(chat.get_history(i64::MAX, u64::MAX, None)).unwrap();
+        let ids: Vec<u64> = page.messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert!(page.has_more);
+    }
+
+    #[test]
+    fn prune_removes_only_messages_past_retention() {
+        let (chat, repo) = chat_service();
+        let mut old = ChatPacket::new_user_packet("alice".to_string(), "old".to_string());
+        old.timestamp -= 10 * 24 * 60 * 60 * 1000;
+        let recent = ChatPacket::new_user_packet("bob".to_string(), "new".to_string());
+        block_on(async {
+            repo.save_message(&old).await.unwrap();
+            repo.save_message(&recent).await.unwrap();
+        });
+
+        let removed = block_on(chat.prune_messages(Duration::from_hours(7 * 24))).unwrap();
+
+        assert_eq!(removed, 1);
+        let remaining: Vec<String> = repo
+            .saved
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| m.content.clone())
+            .collect();
+        assert_eq!(remaining, vec!["new"]);
+    }
+
+    #[tokio::test]
+    async fn prune_on_sqlite_compares_millisecond_timestamps() {
+        let argon2 = Argon2Config {
+            memory_kib: 8,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let repo = Arc::new(
+            SqliteRepository::new("sqlite::memory:", &argon2)
+                .await
+                .unwrap(),
+        );
+        let mut old = ChatPacket::new_user_packet("alice".to_string(), "old".to_string());
+        old.timestamp -= 2 * 60 * 60 * 1000;
+        let recent = ChatPacket::new_user_packet("bob".to_string(), "new".to_string());
+        repo.save_message(&old).await.unwrap();
+        repo.save_message(&recent).await.unwrap();
+        let chat = ChatService::new(
+            repo.clone(),
+            Arc::new(MockRepository::default()),
+            Arc::new(NoopFilter),
+            HashSet::new(),
+            Duration::from_secs(5),
+            16,
+            HistoryOptions {
+                page_size: HISTORY_PAGE_SIZE,
+                presence: true,
+            },
+        );
+
+        let removed = chat.prune_messages(Duration::from_hours(1)).await.unwrap();
+
+        assert_eq!(removed, 1);
+        assert_eq!(repo.count_messages().await.unwrap(), 1);
+    }
+
+    fn system_messages(repo: &MockRepository) -> Vec<String> {
+        repo.saved
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| m.content.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn presence_is_broadcast_as_an_event() {
+        let (chat, repo) = chat_service();
+
+        chat.broadcast_presence(PresenceEvent::Joined("alice".to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(system_messages(&repo), vec!["alice joined.\n"]);
+        let broadcasts = repo.broadcasts.lock().unwrap().clone();
+        assert_eq!(broadcasts.len(), 1, "the stored message isn't broadcast");
+        assert!(matches!(&broadcasts[0], Message::UserJoined(name) if name == "alice"));
+    }
+
+    #[tokio::test]
+    async fn presence_history_can_be_turned_off() {
+        let repo = Arc::new(MockRepository::default());
+        let chat = ChatService::new(
+            repo.clone(),
+            repo.clone(),
+            Arc::new(NoopFilter),
+            HashSet::new(),
+            Duration::from_secs(5),
+            16,
+            HistoryOptions {
+                page_size: HISTORY_PAGE_SIZE,
+                presence: false,
+            },
+        );
+
+        chat.broadcast_presence(PresenceEvent::Left("alice".to_string()))
+            .await
+            .unwrap();
+
+        assert!(repo.saved.lock().unwrap().is_empty());
+        assert!(matches!(
+            &repo.broadcasts.lock().unwrap()[0],
+            Message::UserLeft(name) if name == "alice"
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quick_reconnect_suppresses_leave_and_join() {
+        let (chat, repo) = chat_service();
+        let chat = Arc::new(chat);
+
+        assert!(chat.announce_join("alice").await.unwrap());
+        chat.announce_leave("alice");
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(!chat.announce_join("alice").await.unwrap());
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        assert_eq!(system_messages(&repo), vec!["alice joined.\n"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn real_departure_is_announced_after_grace() {
+        let (chat, repo) = chat_service();
+        let chat = Arc::new(chat);
+
+        chat.announce_join("alice").await.unwrap();
+        chat.announce_leave("alice");
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        assert_eq!(
+            system_messages(&repo).len(),
+            1,
+            "leave is held during grace"
+        );
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(
+            system_messages(&repo),
+            vec!["alice joined.\n", "alice left.\n"]
+        );
+
+        assert!(chat.announce_join("alice").await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flapping_connection_announces_only_final_leave() {
+        let (chat, repo) = chat_service();
+        let chat = Arc::new(chat);
+
+        chat.announce_join("alice").await.unwrap();
+        for _ in 0..3 {
+            chat.announce_leave("alice");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            chat.announce_join("alice").await.unwrap();
+        }
+        chat.announce_leave("alice");
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        assert_eq!(
+            system_messages(&repo),
+            vec!["alice joined.\n", "alice left.\n"]
+        );
     }
 }
@@ -0,0 +1,137 @@
+use std::collections::HashSet;
+
+/// Result of running chat content through a `ContentFilter`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterOutcome {
+    /// Content is delivered unchanged.
+    Allow,
+    /// Content is refused with a reason shown to the sender.
+    Reject(String),
+    /// Content is delivered with the given replacement text.
+    Redact(String),
+}
+
+/// Inspects user chat content before it is persisted and broadcast.
+pub trait ContentFilter: Send + Sync {
+    fn check(&self, content: &str) -> FilterOutcome;
+}
+
+/// Allows every message.
+pub struct NoopFilter;
+
+impl ContentFilter for NoopFilter {
+    fn check(&self, _content: &str) -> FilterOutcome {
+        FilterOutcome::Allow
+    }
+}
+
+/// What a `WordListFilter` does when it finds a blocked word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    Redact,
+    Reject,
+}
+
+/// Matches whole words, case-insensitively, against a fixed list.
+pub struct WordListFilter {
+    words: HashSet<String>,
+    action: FilterAction,
+}
+
+impl WordListFilter {
+    pub fn new(words: &[String], action: FilterAction) -> Self {
+        let words = words
+            .iter()
+            .map(|w| w.trim().to_lowercase())
+            .filter(|w| !w.is_empty())
+            .collect();
+
+        Self { words, action }
+    }
+
+    /// Returns the byte ranges of every blocked word in `content`.
+    fn find_blocked(&self, content: &str) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        let mut start = None;
+
+        for (i, c) in content.char_indices().chain([(content.len(), ' ')]) {
+            match (c.is_alphanumeric(), start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    if self.words.contains(&content[s..i].to_lowercase()) {
+                        ranges.push((s, i));
+                    }
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+
+        ranges
+    }
+}
+
+impl ContentFilter for WordListFilter {
+    fn check(&self, content: &str) -> FilterOutcome {
+        let blocked = self.find_blocked(content);
+        if blocked.is_empty() {
+            return FilterOutcome::Allow;
+        }
+
+        match self.action {
+            FilterAction::Reject => {
+                FilterOutcome::Reject("message contains blocked words".to_string())
+            }
+            FilterAction::Redact => {
+                let mut redacted = String::with_capacity(content.len());
+                let mut last = 0;
+                for (start, end) in blocked {
+                    redacted.push_str(&content[last..start]);
+                    redacted.extend(std::iter::repeat_n(
+                        '*',
+                        content[start..end].chars().count(),
+                    ));
+                    last = end;
+                }
+                redacted.push_str(&content[last..]);
+                FilterOutcome::Redact(redacted)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContentFilter, FilterAction, FilterOutcome, NoopFilter, WordListFilter};
+
+    fn filter(action: FilterAction) -> WordListFilter {
+        WordListFilter::new(&["darn".to_string(), " Heck ".to_string()], action)
+    }
+
+    #[test]
+    fn noop_filter_allows() {
+        assert_eq!(NoopFilter.check("darn it"), FilterOutcome::Allow);
+    }
+
+    #[test]
+    fn word_list_allows_clean_content() {
+        let f = filter(FilterAction::Reject);
+        assert_eq!(f.check("hello there"), FilterOutcome::Allow);
+        assert_eq!(f.check("darned partial words"), FilterOutcome::Allow);
+    }
+
+    #[test]
+    fn word_list_redacts_matches() {
+        let f = filter(FilterAction::Redact);
+        assert_eq!(
+            f.check("oh DARN, what the heck!"),
+            FilterOutcome::Redact("oh ****, what the ****!".to_string())
+        );
+    }
+
+    #[test]
+    fn word_list_rejects_matches() {
+        let f = filter(FilterAction::Reject);
+        assert!(matches!(f.check("heck"), FilterOutcome::Reject(_)));
+    }
+}
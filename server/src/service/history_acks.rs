@@ -0,0 +1,163 @@
+use metrics::counter;
+use protocol::Message;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Unacked batches kept per user; older ones are given up on first.
+const MAX_UNACKED_BATCHES: usize = 4;
+/// How many times a batch is resent before it's given up on.
+const MAX_RESENDS: u32 = 3;
+/// How long a batch waits for a user who doesn't come back.
+const UNACKED_TTL: Duration = Duration::from_mins(5);
+
+/// History batches sent to each user but not yet acked.
+///
+/// Kept for the whole node rather than a session, so a batch lost with its
+/// connection is sent again when the user reconnects here.
+#[derive(Default)]
+pub struct HistoryAcks {
+    pending: Mutex<HashMap<String, Vec<Unacked>>>,
+}
+
+struct Unacked {
+    /// `(timestamp, id)` of the batch's newest message, which its ack echoes.
+    up_to: (i64, u64),
+    batch: Message,
+    resends: u32,
+    sent_at: Instant,
+}
+
+impl HistoryAcks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remembers a `HistoryResponse` sent to `username` until it is acked.
+    pub fn sent(&self, username: &str, response: &Message) {
+        let Message::HistoryResponse { messages, .. } = response else {
+            return;
+        };
+        let Some(up_to) = messages.iter().map(|m| (m.timestamp, m.id)).max() else {
+            return;
+        };
+
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, batches| {
+            batches.retain(|b| now.duration_since(b.sent_at) < UNACKED_TTL);
+            !batches.is_empty()
+        });
+        let batches = pending.entry(username.to_string()).or_default();
+        batches.retain(|b| b.up_to != up_to);
+        if batches.len() == MAX_UNACKED_BATCHES {
+            batches.remove(0);
+        }
+        batches.push(Unacked {
+            up_to,
+            batch: response.clone(),
+            resends: 0,
+            sent_at: now,
+        });
+        drop(pending);
+    }
+
+    /// Forgets the batch whose newest message is at `up_to`.
+    pub fn acked(&self, username: &str, up_to: (i64, u64)) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(batches) = pending.get_mut(username) {
+            batches.retain(|b| b.up_to != up_to);
+            if batches.is_empty() {
+                pending.remove(username);
+            }
+        }
+    }
+
+    /// Returns the batches to send `username` again, oldest first. Each stays
+    /// pending until acked or resent `MAX_RESENDS` times.
+    pub fn resends(&self, username: &str) -> Vec<Message> {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(batches) = pending.get_mut(username) else {
+            return Vec::new();
+        };
+        batches.retain(|b| b.resends < MAX_RESENDS);
+        let resent: Vec<Message> = batches
+            .iter_mut()
+            .map(|b| {
+                b.resends += 1;
+                b.batch.clone()
+            })
+            .collect();
+        if batches.is_empty() {
+            pending.remove(username);
+        }
+        drop(pending);
+
+        counter!("mcs_history_resends_total").increment(resent.len() as u64);
+        resent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HistoryAcks, MAX_RESENDS, MAX_UNACKED_BATCHES};
+    use crate::repository::HistoryPage;
+    use protocol::{ChatPacket, HistoryDirection, Message};
+
+    fn batch(id: u64, timestamp: i64) -> Message {
+        Message::HistoryResponse {
+            messages: vec![ChatPacket {
+                id,
+                sender: "bob".to_string(),
+                content: "hi".to_string(),
+                timestamp,
+                deleted: false,
+            }],
+            has_more: false,
+            sender: None,
+            direction: HistoryDirection::Before,
+        }
+    }
+
+    #[test]
+    fn acks_match_the_batch_cursor_by_timestamp_and_id() {
+        let acks = HistoryAcks::new();
+        acks.sent("alice", &batch(7, 100));
+
+        acks.acked("alice", (100, 6));
+        acks.acked("bob", (100, 7));
+        assert_eq!(acks.resends("alice").len(), 1);
+
+        acks.acked("alice", (100, 7));
+        assert!(acks.resends("alice").is_empty());
+    }
+
+    #[test]
+    fn batches_are_given_up_on_after_the_resend_limit() {
+        let acks = HistoryAcks::new();
+        acks.sent("alice", &batch(1, 10));
+
+        for _ in 0..MAX_RESENDS {
+            assert_eq!(acks.resends("alice").len(), 1);
+        }
+        assert!(acks.resends("alice").is_empty());
+    }
+
+    #[test]
+    fn only_the_latest_batches_are_kept() {
+        let acks = HistoryAcks::new();
+        let sent = u64::try_from(MAX_UNACKED_BATCHES).unwrap() + 1;
+        for id in 1..=sent {
+            acks.sent("alice", &batch(id, 10));
+        }
+        acks.sent("alice", &batch(sent, 10));
+        acks.sent("alice", &Message::from(HistoryPage::default()));
+
+        let resent = acks.resends("alice");
+        assert_eq!(resent.len(), MAX_UNACKED_BATCHES);
+        assert!(matches!(
+            &resent[0],
+            Message::HistoryResponse { messages, .. } if messages[0].id == 2
+        ));
+    }
+}
@@ -0,0 +1,121 @@
+use metrics::gauge;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+/// Consecutive overloaded samples before the node counts as busy.
+const BUSY_AFTER_FULL_SAMPLES: u32 = 3;
+
+/// Watches how many of the node's sessions can't keep up with broadcasts.
+///
+/// A session is behind when its own backlog reaches half the channel, or it
+/// lagged and lost messages. One stalled client says nothing about the node,
+/// so it's overloaded only while at least half its sessions are behind. It
+/// turns busy after several such samples in a row, and only recovers once
+/// fewer than a quarter are, so a node hovering at the limit doesn't flap.
+pub struct LoadMonitor {
+    capacity: usize,
+    /// Bumped by every sample, so a session is counted once per interval.
+    interval: AtomicU64,
+    behind: AtomicU32,
+    full_samples: AtomicU32,
+    busy: AtomicBool,
+}
+
+impl LoadMonitor {
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            interval: AtomicU64::new(1),
+            behind: AtomicU32::new(0),
+            full_samples: AtomicU32::new(0),
+            busy: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether a session with `backlog` broadcasts still to send is behind.
+    pub const fn is_backlogged(&self, backlog: usize) -> bool {
+        backlog >= self.capacity.div_ceil(2)
+    }
+
+    /// Counts a session as behind in this interval, once however often it
+    /// reports; `counted` is the session's record of when it last did.
+    pub fn note_behind(&self, counted: &mut u64) {
+        let interval = self.interval.load(Ordering::Relaxed);
+        if *counted != interval {
+            *counted = interval;
+            self.behind.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Closes the interval over `sessions` sessions, returning whether the node is busy.
+    pub fn sample(&self, sessions: u32) -> bool {
+        self.interval.fetch_add(1, Ordering::Relaxed);
+        let behind = self.behind.swap(0, Ordering::Relaxed);
+        gauge!("mcs_sessions_behind").set(f64::from(behind));
+
+        let busy = if sessions > 0 && behind * 2 >= sessions {
+            let full = self.full_samples.fetch_add(1, Ordering::Relaxed) + 1;
+            full >= BUSY_AFTER_FULL_SAMPLES || self.is_busy()
+        } else {
+            self.full_samples.store(0, Ordering::Relaxed);
+            self.is_busy() && behind * 4 >= sessions.max(1)
+        };
+
+        gauge!("mcs_node_busy").set(if busy { 1.0 } else { 0.0 });
+        self.busy.store(busy, Ordering::Relaxed);
+        busy
+    }
+
+    /// Whether the node is too backed up to take new sessions.
+    pub fn is_busy(&self) -> bool {
+        self.busy.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BUSY_AFTER_FULL_SAMPLES, LoadMonitor};
+
+    /// Reports `behind` distinct sessions as behind, then samples `sessions`.
+    fn sample(load: &LoadMonitor, behind: u32, sessions: u32) -> bool {
+        for _ in 0..behind {
+            load.note_behind(&mut 0);
+        }
+        load.sample(sessions)
+    }
+
+    #[test]
+    fn brief_spike_is_not_busy() {
+        let load = LoadMonitor::new(10);
+        for _ in 1..BUSY_AFTER_FULL_SAMPLES {
+            assert!(!sample(&load, 5, 10));
+        }
+        assert!(!sample(&load, 1, 10));
+        assert!(!sample(&load, 5, 10));
+    }
+
+    #[test]
+    fn busy_clears_only_once_under_a_quarter_are_behind() {
+        let load = LoadMonitor::new(10);
+        for _ in 0..BUSY_AFTER_FULL_SAMPLES {
+            sample(&load, 5, 10);
+        }
+        assert!(load.is_busy());
+
+        assert!(sample(&load, 3, 10), "still a quarter behind");
+        assert!(!sample(&load, 2, 10));
+    }
+
+    #[test]
+    fn one_stalled_session_never_makes_the_node_busy() {
+        let load = LoadMonitor::new(10);
+        let mut counted = 0;
+        for _ in 0..BUSY_AFTER_FULL_SAMPLES * 2 {
+            for _ in 0..100 {
+                load.note_behind(&mut counted);
+            }
+            assert!(!load.sample(3));
+        }
+        assert!(load.is_backlogged(5));
+        assert!(!load.is_backlogged(4));
+    }
+}
@@ -1,6 +1,12 @@
 pub mod auth;
 pub mod chat;
+pub mod filter;
+pub mod history_acks;
+pub mod load;
 pub mod node;
+pub mod presence;
+pub mod rate_limit;
+pub mod rooms;
 pub mod state;
 
 pub use auth::AuthService;
@@ -1,39 +1,222 @@
 use crate::error::Result;
 use crate::repository::PresenceRepository;
-use std::sync::Arc;
+use protocol::NodeAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::time;
+use tokio::{task::AbortHandle, time};
 use tracing::{error, info};
 
 #[derive(Clone)]
 pub struct NodeService {
     presence: Arc<dyn PresenceRepository>,
-    node_id: String,
+    node_id: NodeAddr,
+    drain_grace: Duration,
+    heartbeat_interval: Duration,
+    /// Most connections load balancers should send this node, if capped.
+    max_connections: Option<usize>,
+    heartbeat: Arc<Mutex<Option<AbortHandle>>>,
+    draining: Arc<AtomicBool>,
 }
 
 impl NodeService {
-    pub fn new(presence: Arc<dyn PresenceRepository>, node_id: String) -> Self {
-        Self { presence, node_id }
+    pub fn new(
+        presence: Arc<dyn PresenceRepository>,
+        node_id: NodeAddr,
+        drain_grace: Duration,
+        heartbeat_interval: Duration,
+    ) -> Self {
+        Self {
+            presence,
+            node_id,
+            drain_grace,
+            heartbeat_interval,
+            max_connections: None,
+            heartbeat: Arc::new(Mutex::new(None)),
+            draining: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[must_use]
+    pub const fn with_max_connections(mut self, max_connections: Option<usize>) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub const fn id(&self) -> &NodeAddr {
+        &self.node_id
     }
 
     pub async fn register(&self) -> Result<()> {
         info!(node_id=%self.node_id, "registering node");
-        self.presence.register_node(&self.node_id).await
+        self.presence
+            .register_node(&self.node_id, self.max_connections)
+            .await
     }
 
     pub fn start_heartbeat(&self) {
         let presence = self.presence.clone();
         let node_id = self.node_id.clone();
+        let period = self.heartbeat_interval;
+        let max_connections = self.max_connections;
 
-        tokio::spawn(async move {
-            let mut interval = time::interval(Duration::from_secs(3));
+        let handle = tokio::spawn(async move {
+            let mut interval = time::interval(period);
 
             loop {
                 interval.tick().await;
-                if let Err(e) = presence.register_node(&node_id).await {
+                if let Err(e) = presence.register_node(&node_id, max_connections).await {
                     error!(node_id=%node_id, err=?e, "heatbeat failed");
                 }
             }
         });
+        *self.heartbeat.lock().unwrap() = Some(handle.abort_handle());
+    }
+
+    /// Stops the heartbeat and removes the node so load balancers drop it immediately.
+    pub async fn deregister(&self) -> Result<()> {
+        let heartbeat = self.heartbeat.lock().unwrap().take();
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.abort();
+        }
+        info!(node_id=%self.node_id, "deregistering node");
+        self.presence.deregister_node(&self.node_id).await
+    }
+
+    /// Marks the node as draining, waits out the grace period, then deregisters it.
+    ///
+    /// The heartbeat keeps the node listed while draining so load balancers
+    /// leave existing connections in place but stop sending new ones.
+    pub async fn drain(&self) -> Result<()> {
+        info!(node_id=%self.node_id, grace=?self.drain_grace, "draining node");
+        self.draining.store(true, Ordering::Relaxed);
+        // Outlive the grace period so the flag can't lapse before deregistration.
+        let ttl = self.drain_grace.as_secs() + 30;
+        self.presence.set_node_draining(&self.node_id, ttl).await?;
+
+        time::sleep(self.drain_grace).await;
+        self.deregister().await
+    }
+
+    /// Keeps load balancers from routing new clients here for `ttl`, reusing
+    /// the drain flag. Renewed while the node stays busy, it lapses once load drops.
+    pub async fn flag_busy(&self, ttl: Duration) -> Result<()> {
+        // A real drain's longer-lived flag must not be cut short.
+        if self.draining.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.presence
+            .set_node_draining(&self.node_id, ttl.as_secs().max(1))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NodeService;
+    use crate::repository::mock::MockRepository;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_flags_node_before_deregistering() {
+        let repo = Arc::new(MockRepository::default());
+        let node = NodeService::new(
+            repo.clone(),
+            "node-a:64400".parse().unwrap(),
+            Duration::from_secs(10),
+            Duration::from_secs(3),
+        );
+        node.register().await.unwrap();
+
+        let drain = tokio::spawn({
+            let node = node.clone();
+            async move { node.drain().await }
+        });
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(repo.draining_nodes.lock().unwrap().contains("node-a:64400"));
+        assert!(repo.nodes.lock().unwrap().contains("node-a:64400"));
+
+        drain.await.unwrap().unwrap();
+        assert!(!repo.nodes.lock().unwrap().contains("node-a:64400"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deregister_stops_heartbeat_from_re_adding_node() {
+        let repo = Arc::new(MockRepository::default());
+        let node = NodeService::new(
+            repo.clone(),
+            "node-a:64400".parse().unwrap(),
+            Duration::from_secs(10),
+            Duration::from_secs(3),
+        );
+        node.register().await.unwrap();
+        node.start_heartbeat();
+
+        node.deregister().await.unwrap();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        assert!(!repo.nodes.lock().unwrap().contains("node-a:64400"));
+    }
+
+    #[tokio::test]
+    async fn node_registers_under_its_canonical_address() {
+        let repo = Arc::new(MockRepository::default());
+        let node = NodeService::new(
+            repo.clone(),
+            "[FD00:0::05]:64400".parse().unwrap(),
+            Duration::from_secs(10),
+            Duration::from_secs(3),
+        );
+
+        node.register().await.unwrap();
+        node.flag_busy(Duration::from_secs(5)).await.unwrap();
+
+        assert!(repo.nodes.lock().unwrap().contains("[fd00::5]:64400"));
+        assert!(
+            repo.draining_nodes
+                .lock()
+                .unwrap()
+                .contains("[fd00::5]:64400")
+        );
+    }
+
+    #[tokio::test]
+    async fn node_registers_its_connection_cap() {
+        let repo = Arc::new(MockRepository::default());
+        let node = NodeService::new(
+            repo.clone(),
+            "node-a:64400".parse().unwrap(),
+            Duration::from_secs(10),
+            Duration::from_secs(3),
+        )
+        .with_max_connections(Some(500));
+
+        node.register().await.unwrap();
+        assert_eq!(
+            repo.node_max_connections
+                .lock()
+                .unwrap()
+                .get("node-a:64400"),
+            Some(&500)
+        );
+
+        node.deregister().await.unwrap();
+        assert!(repo.node_max_connections.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn busy_node_is_flagged_for_load_balancers() {
+        let repo = Arc::new(MockRepository::default());
+        let node = NodeService::new(
+            repo.clone(),
+            "node-a:64400".parse().unwrap(),
+            Duration::from_secs(10),
+            Duration::from_secs(3),
+        );
+
+        node.flag_busy(Duration::from_secs(5)).await.unwrap();
+
+        assert!(repo.draining_nodes.lock().unwrap().contains("node-a:64400"));
     }
 }
@@ -0,0 +1,115 @@
+use protocol::Message;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// A user arriving or departing, sent to clients as its own message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PresenceEvent {
+    Joined(String),
+    Left(String),
+}
+
+impl fmt::Display for PresenceEvent {
+    /// The system message text kept in history for this event.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Joined(username) => writeln!(f, "{username} joined."),
+            Self::Left(username) => writeln!(f, "{username} left."),
+        }
+    }
+}
+
+impl From<PresenceEvent> for Message {
+    fn from(event: PresenceEvent) -> Self {
+        match event {
+            PresenceEvent::Joined(username) => Self::UserJoined(username),
+            PresenceEvent::Left(username) => Self::UserLeft(username),
+        }
+    }
+}
+
+/// Tracks pending "left" notices so a quick reconnect can cancel them.
+///
+/// A leave is only announced once the user has stayed away for the whole
+/// grace window; a rejoin inside the window suppresses both notices.
+pub struct PresenceDebouncer {
+    grace: Duration,
+    /// Pending leaves by username, tagged so a stale timer can't fire for a newer leave.
+    pending_leaves: Mutex<HashMap<String, u64>>,
+    next_token: AtomicU64,
+}
+
+impl PresenceDebouncer {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            pending_leaves: Mutex::new(HashMap::new()),
+            next_token: AtomicU64::new(0),
+        }
+    }
+
+    pub const fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    /// Records a join, returning `false` if it cancelled a pending leave.
+    pub fn on_join(&self, username: &str) -> bool {
+        self.pending_leaves
+            .lock()
+            .unwrap()
+            .remove(username)
+            .is_none()
+    }
+
+    /// Records a leave and returns the token to confirm it with after the grace window.
+    pub fn on_leave(&self, username: &str) -> u64 {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        self.pending_leaves
+            .lock()
+            .unwrap()
+            .insert(username.to_string(), token);
+        token
+    }
+
+    /// Returns `true` if the leave for `token` is still pending, clearing it.
+    pub fn confirm_leave(&self, username: &str, token: u64) -> bool {
+        let mut pending = self.pending_leaves.lock().unwrap();
+        let confirmed = pending.get(username) == Some(&token);
+        if confirmed {
+            pending.remove(username);
+        }
+        drop(pending);
+        confirmed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PresenceDebouncer;
+    use std::time::Duration;
+
+    #[test]
+    fn rejoin_cancels_pending_leave() {
+        let debouncer = PresenceDebouncer::new(Duration::from_secs(5));
+        assert!(debouncer.on_join("alice"));
+
+        let token = debouncer.on_leave("alice");
+        assert!(!debouncer.on_join("alice"));
+        assert!(!debouncer.confirm_leave("alice", token));
+    }
+
+    #[test]
+    fn stale_token_does_not_confirm_newer_leave() {
+        let debouncer = PresenceDebouncer::new(Duration::from_secs(5));
+        let first = debouncer.on_leave("alice");
+        debouncer.on_join("alice");
+        let second = debouncer.on_leave("alice");
+
+        assert!(!debouncer.confirm_leave("alice", first));
+        assert!(debouncer.confirm_leave("alice", second));
+        assert!(debouncer.on_join("alice"));
+    }
+}
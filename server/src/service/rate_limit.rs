@@ -0,0 +1,142 @@
+use protocol::{Message, RateUsage};
+use std::time::Duration;
+use tokio::time::Instant;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Per-user send limits applied to chat traffic.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub messages_per_sec: u32,
+    pub bytes_per_sec: u32,
+    /// Whether messages over the limit are refused; otherwise usage is only
+    /// tracked and reported.
+    pub enforce: bool,
+}
+
+/// Tracks a single user's usage within a fixed one-second window.
+pub struct UserRateLimiter {
+    limit: RateLimit,
+    window_start: Instant,
+    usage: RateUsage,
+}
+
+impl UserRateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            window_start: Instant::now(),
+            usage: RateUsage::default(),
+        }
+    }
+
+    /// Records a message of `bytes` length, returning `false` if it exceeds an
+    /// enforced limit.
+    pub fn try_consume(&mut self, bytes: usize) -> bool {
+        self.roll_window();
+        let bytes = u32::try_from(bytes).unwrap_or(u32::MAX);
+        let messages = self.usage.messages.saturating_add(1);
+        let total_bytes = self.usage.bytes.saturating_add(bytes);
+
+        if self.limit.enforce
+            && (messages > self.limit.messages_per_sec || total_bytes > self.limit.bytes_per_sec)
+        {
+            return false;
+        }
+
+        self.usage = RateUsage {
+            messages,
+            bytes: total_bytes,
+        };
+        true
+    }
+
+    /// Builds a status report of the configured limits and current usage.
+    pub fn status(&mut self) -> Message {
+        self.roll_window();
+        Message::RateStatusResponse {
+            messages_per_sec: self.limit.messages_per_sec,
+            bytes_per_sec: self.limit.bytes_per_sec,
+            current_usage: self.usage,
+        }
+    }
+
+    fn roll_window(&mut self) {
+        if self.window_start.elapsed() >= WINDOW {
+            self.window_start = Instant::now();
+            self.usage = RateUsage::default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateLimit, UserRateLimiter};
+    use protocol::{Message, RateUsage};
+    use std::time::Duration;
+
+    const LIMIT: RateLimit = RateLimit {
+        messages_per_sec: 2,
+        bytes_per_sec: 10,
+        enforce: true,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn status_reflects_configured_limits() {
+        let mut limiter = UserRateLimiter::new(LIMIT);
+        assert!(limiter.try_consume(4));
+
+        match limiter.status() {
+            Message::RateStatusResponse {
+                messages_per_sec,
+                bytes_per_sec,
+                current_usage,
+            } => {
+                assert_eq!(messages_per_sec, 2);
+                assert_eq!(bytes_per_sec, 10);
+                assert_eq!(
+                    current_usage,
+                    RateUsage {
+                        messages: 1,
+                        bytes: 4
+                    }
+                );
+            }
+            other => panic!("expected a rate status, got {other:?}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn try_consume_enforces_limits_and_resets() {
+        let mut limiter = UserRateLimiter::new(LIMIT);
+        assert!(limiter.try_consume(1));
+        assert!(limiter.try_consume(1));
+        assert!(!limiter.try_consume(1), "message limit should be hit");
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!limiter.try_consume(11), "byte limit should be hit");
+        assert!(limiter.try_consume(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unenforced_limits_only_track_usage() {
+        let mut limiter = UserRateLimiter::new(RateLimit {
+            enforce: false,
+            ..LIMIT
+        });
+        for _ in 0..3 {
+            assert!(limiter.try_consume(5));
+        }
+
+        match limiter.status() {
+            Message::RateStatusResponse { current_usage, .. } => assert_eq!(
+                current_usage,
+                RateUsage {
+                    messages: 3,
+                    bytes: 15
+                }
+            ),
+            other => panic!("expected a rate status, got {other:?}"),
+        }
+    }
+}
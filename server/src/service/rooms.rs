@@ -0,0 +1,92 @@
+use crate::error::Result;
+use crate::repository::PresenceRepository;
+use metrics::gauge;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// The room every session is in until users can choose one.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// Counts this node's sessions per room, so the node only listens to the
+/// rooms someone here is in.
+pub struct RoomMembership {
+    presence: Arc<dyn PresenceRepository>,
+    /// Held across (un)subscribing so transitions reach redis in order.
+    members: Mutex<HashMap<String, usize>>,
+}
+
+// The lock is deliberately held until (un)subscribing finishes.
+#[allow(clippy::significant_drop_tightening)]
+impl RoomMembership {
+    pub fn new(presence: Arc<dyn PresenceRepository>) -> Self {
+        Self {
+            presence,
+            members: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a local session to `room`, subscribing the node if it's the first.
+    pub async fn enter(&self, room: &str) -> Result<()> {
+        let mut members = self.members.lock().await;
+        let count = members.get(room).copied().unwrap_or(0);
+        if count == 0 {
+            self.presence.subscribe_room(room).await?;
+        }
+        members.insert(room.to_string(), count + 1);
+        record_rooms(&members);
+        Ok(())
+    }
+
+    /// Removes a local session from `room`, unsubscribing once nobody is left.
+    pub async fn leave(&self, room: &str) -> Result<()> {
+        let mut members = self.members.lock().await;
+        let Some(count) = members.get_mut(room) else {
+            return Ok(());
+        };
+        *count -= 1;
+        if *count > 0 {
+            return Ok(());
+        }
+
+        members.remove(room);
+        record_rooms(&members);
+        self.presence.unsubscribe_room(room).await
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn record_rooms(members: &HashMap<String, usize>) {
+    gauge!("mcs_subscribed_rooms").set(members.len() as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RoomMembership;
+    use crate::repository::mock::MockRepository;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn node_listens_while_any_local_session_is_in_the_room() {
+        let repo = Arc::new(MockRepository::default());
+        let rooms = RoomMembership::new(repo.clone());
+        let subscribed = || repo.rooms.lock().unwrap().clone();
+
+        rooms.enter("lobby").await.unwrap();
+        rooms.enter("lobby").await.unwrap();
+        rooms.enter("games").await.unwrap();
+        assert_eq!(
+            subscribed(),
+            HashSet::from(["lobby".into(), "games".into()])
+        );
+
+        rooms.leave("lobby").await.unwrap();
+        rooms.leave("games").await.unwrap();
+        assert_eq!(subscribed(), HashSet::from(["lobby".into()]));
+
+        rooms.leave("lobby").await.unwrap();
+        rooms.leave("lobby").await.unwrap();
+        assert!(subscribed().is_empty());
+    }
+}
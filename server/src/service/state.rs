@@ -1,9 +1,30 @@
+use crate::config::Config;
 use crate::error::Result;
-use crate::repository::{postgres::PostgresRepository, redis::RedisRepository};
+use crate::repository::{
+    MessageRepository, PresenceRepository, UserMessage, UserRepository,
+    ephemeral::EphemeralRepository, postgres::PostgresRepository, redis::RedisRepository,
+    sqlite::SqliteRepository,
+};
+use crate::service::chat::HistoryOptions;
+use crate::service::filter::{ContentFilter, NoopFilter, WordListFilter};
+use crate::service::history_acks::HistoryAcks;
+use crate::service::load::LoadMonitor;
+use crate::service::rate_limit::RateLimit;
+use crate::service::rooms::RoomMembership;
 use crate::service::{AuthService, ChatService, NodeService};
-use protocol::Message;
+use metrics::gauge;
+use protocol::{AdminCmd, Message, NodeAddr};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast::{self, Sender};
+use tokio::time;
+use tracing::{info, warn};
+
+/// How often session backlogs are sampled.
+const LOAD_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a busy node stays out of lb rotation unless the flag is renewed.
+const BUSY_FLAG_TTL: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 pub struct AppState {
@@ -11,27 +32,435 @@ pub struct AppState {
     pub chat: Arc<ChatService>,
     pub node: Arc<NodeService>,
     pub internal_broadcast_tx: Sender<Message>,
+    /// Messages for single users, e.g. kicks, arriving from any node.
+    pub user_tx: Sender<UserMessage>,
+    pub rate_limit: RateLimit,
+    pub send_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub keepalive_interval: Duration,
+    /// Sessions currently running on this node.
+    pub active_sessions: Arc<AtomicU32>,
+    pub load: Arc<LoadMonitor>,
+    /// Rooms this node's sessions are in, and so the ones it listens to.
+    pub rooms: Arc<RoomMembership>,
+    /// History batches sent to users on this node and not yet acked.
+    pub history_acks: Arc<HistoryAcks>,
+    pub started_at: Instant,
 }
 
 impl AppState {
-    pub async fn new(db_url: &str, redis_url: &str, node_id: String) -> Result<Self> {
-        let (tx, _) = broadcast::channel(100);
-        let pg_repo = Arc::new(PostgresRepository::new(db_url).await?);
-        let redis_repo = Arc::new(RedisRepository::new(redis_url, tx.clone()).await?);
+    pub async fn new(config: &Config, node_id: NodeAddr) -> Result<Self> {
+        let (tx, _) = broadcast::channel(config.broadcast_capacity);
+        let (user_tx, _) = broadcast::channel(config.broadcast_capacity);
+        let (users, messages) = open_database(config).await?;
+        let messages = match messages {
+            Some(messages) => messages,
+            None => Arc::new(EphemeralRepository::new(&config.redis_url).await?),
+        };
+        let redis_repo = Arc::new(
+            RedisRepository::new(
+                &config.redis_url,
+                node_id.to_string(),
+                tx.clone(),
+                user_tx.clone(),
+            )
+            .await?,
+        );
+
+        Ok(Self::from_repositories(
+            config, users, messages, redis_repo, node_id, tx, user_tx,
+        ))
+    }
+
+    pub fn from_repositories(
+        config: &Config,
+        users: Arc<dyn UserRepository>,
+        messages: Arc<dyn MessageRepository>,
+        presence: Arc<dyn PresenceRepository>,
+        node_id: NodeAddr,
+        tx: Sender<Message>,
+        user_tx: Sender<UserMessage>,
+    ) -> Self {
+        let filter: Arc<dyn ContentFilter> = if config.filter_words.is_empty() {
+            Arc::new(NoopFilter)
+        } else {
+            Arc::new(WordListFilter::new(
+                &config.filter_words,
+                config.filter_action,
+            ))
+        };
 
-        let auth_service = Arc::new(AuthService::new(pg_repo.clone(), redis_repo.clone()));
-        let chat_service = Arc::new(ChatService::new(pg_repo.clone(), redis_repo.clone()));
-        let node_service = Arc::new(NodeService::new(redis_repo, node_id));
+        let auth_service = AuthService::new(
+            users,
+            presence.clone(),
+            node_id.clone(),
+            config.login_limit,
+            config.allow_guest,
+        );
+        // Accounts held in memory are gone after a restart, when anyone could
+        // otherwise be first to register an admin's name.
+        let auth_service = Arc::new(if config.persistence {
+            auth_service
+        } else {
+            auth_service.with_reserved_names(config.admins.iter().cloned().collect())
+        });
+        let chat_service = Arc::new(ChatService::new(
+            messages,
+            presence.clone(),
+            filter,
+            config.admins.iter().cloned().collect(),
+            config.presence_grace,
+            config.max_message_len,
+            HistoryOptions {
+                page_size: config.history_page_size,
+                presence: config.presence_history,
+            },
+        ));
+        let rooms = Arc::new(RoomMembership::new(presence.clone()));
+        let node_service = Arc::new(
+            NodeService::new(presence, node_id, config.drain_grace, config.node_heartbeat)
+                .with_max_connections(config.max_connections),
+        );
 
-        Ok(Self {
+        Self {
             auth: auth_service,
             chat: chat_service,
             node: node_service,
             internal_broadcast_tx: tx,
-        })
+            user_tx,
+            rate_limit: config.rate_limit,
+            send_timeout: config.send_timeout,
+            idle_timeout: config.idle_timeout,
+            keepalive_interval: config.keepalive_interval,
+            active_sessions: Arc::new(AtomicU32::new(0)),
+            load: Arc::new(LoadMonitor::new(config.broadcast_capacity)),
+            rooms,
+            history_acks: Arc::new(HistoryAcks::new()),
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Samples how many sessions fell behind, returning whether the node is busy.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn check_load(&self) -> bool {
+        gauge!("mcs_broadcast_channel_depth").set(self.internal_broadcast_tx.len() as f64);
+        self.load
+            .sample(self.active_sessions.load(Ordering::Relaxed))
+    }
+
+    /// Whether the node is too backed up to feed new sessions.
+    pub fn is_busy(&self) -> bool {
+        self.load.is_busy()
+    }
+
+    /// Samples load in the background, keeping load balancers away while busy.
+    pub fn spawn_load_monitor(&self) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(LOAD_SAMPLE_INTERVAL);
+            let mut was_busy = false;
+            loop {
+                interval.tick().await;
+                let busy = state.check_load();
+                if busy != was_busy {
+                    info!(busy, "node load state changed");
+                    was_busy = busy;
+                }
+                if busy && let Err(e) = state.node.flag_busy(BUSY_FLAG_TTL).await {
+                    warn!(err=?e, "failed to flag node as busy");
+                }
+            }
+        });
     }
 
     pub fn subscribe(&self) -> broadcast::Receiver<Message> {
         self.internal_broadcast_tx.subscribe()
     }
+
+    pub fn subscribe_user(&self) -> broadcast::Receiver<UserMessage> {
+        self.user_tx.subscribe()
+    }
+
+    /// Disconnects `username` wherever they are connected, banning them first if asked.
+    pub async fn kick(
+        &self,
+        requester: &str,
+        username: &str,
+        reason: &str,
+        ban: bool,
+    ) -> Result<()> {
+        self.chat.require_admin(requester)?;
+
+        if ban {
+            self.auth.ban_user(username, reason).await?;
+        }
+        self.chat.kick_user(username, reason).await
+    }
+
+    /// Runs an operator command for `requester`, returning the reply, if any.
+    pub async fn admin_command(&self, requester: &str, cmd: AdminCmd) -> Result<Option<Message>> {
+        self.chat.require_admin(requester)?;
+
+        match cmd {
+            AdminCmd::ListSessions => Ok(Some(Message::Sessions(self.auth.sessions().await?))),
+            AdminCmd::Disconnect(username) => {
+                self.chat
+                    .kick_user(&username, "disconnected by an admin")
+                    .await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reports this node's statistics to an admin.
+    pub async fn stats(&self, requester: &str) -> Result<Message> {
+        self.chat.require_admin(requester)?;
+
+        Ok(Message::StatsResponse {
+            active_users: self.active_sessions.load(Ordering::Relaxed),
+            messages_stored: self.chat.count_messages().await?,
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            node_id: self.node.id().to_string(),
+        })
+    }
+}
+
+/// Opens the database holding accounts and messages. With persistence off no
+/// database is opened: accounts only need to last as long as the node, so an
+/// in-memory one holds them, and no message store is returned, leaving
+/// messages to be cached in redis.
+async fn open_database(
+    config: &Config,
+) -> Result<(Arc<dyn UserRepository>, Option<Arc<dyn MessageRepository>>)> {
+    if !config.persistence {
+        let users = SqliteRepository::new("sqlite::memory:", &config.argon2).await?;
+        return Ok((Arc::new(users), None));
+    }
+    if config.db_url.starts_with("sqlite:") {
+        let repo = Arc::new(SqliteRepository::new(&config.db_url, &config.argon2).await?);
+        Ok((repo.clone(), Some(repo)))
+    } else {
+        let repo = Arc::new(PostgresRepository::new(&config.db_url, &config.argon2).await?);
+        Ok((repo.clone(), Some(repo)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AppState, open_database};
+    use crate::config::Config;
+    use crate::error::Error;
+    use crate::repository::{
+        MessageRepository, PresenceRepository, SessionOwner, mock::MockRepository,
+    };
+    use protocol::{AdminCmd, ChatError, ChatPacket, Message};
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use tokio::sync::broadcast;
+
+    fn state(repo: &Arc<MockRepository>) -> AppState {
+        let mut config = Config::for_tests();
+        config.admins = vec!["admin".to_string()];
+        let (tx, _) = broadcast::channel(100);
+        AppState::from_repositories(
+            &config,
+            repo.clone(),
+            repo.clone(),
+            repo.clone(),
+            "node-a:64400".parse().unwrap(),
+            tx,
+            broadcast::channel(100).0,
+        )
+    }
+
+    #[tokio::test]
+    async fn without_persistence_no_database_is_opened_and_admin_names_stay_free() {
+        let mut config = Config::for_tests();
+        config.persistence = false;
+        config.admins = vec!["admin".to_string()];
+        // Nothing listens here, so opening it would fail.
+        config.db_url = "postgres://mcs@127.0.0.1:1/mcs".to_string();
+        let (users, messages) = open_database(&config).await.unwrap();
+        assert!(messages.is_none());
+
+        // Stands in for redis, which caches the messages as well as presence.
+        let redis = Arc::new(MockRepository::default());
+        let state = AppState::from_repositories(
+            &config,
+            users,
+            redis.clone(),
+            redis.clone(),
+            "node-a:64400".parse().unwrap(),
+            broadcast::channel(100).0,
+            broadcast::channel(100).0,
+        );
+
+        assert!(matches!(
+            state.auth.register_and_login("admin", "pw", None).await,
+            Err(Error::InvalidCredentials)
+        ));
+        let alice = state
+            .auth
+            .register_and_login("alice", "pw", None)
+            .await
+            .unwrap();
+        let history = state.chat.get_history(i64::MAX, u64::MAX, None).await;
+        assert!(history.unwrap().messages.is_empty());
+
+        state
+            .chat
+            .broadcast_user_message(&alice, "hi".to_string())
+            .await
+            .unwrap();
+        let history = state.chat.get_history(i64::MAX, u64::MAX, None).await;
+        assert_eq!(history.unwrap().messages[0].content, "hi");
+        assert_eq!(redis.saved.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stats_require_admin() {
+        let repo = Arc::new(MockRepository::default());
+        let state = state(&repo);
+
+        let err = state.stats("alice").await.unwrap_err();
+        assert!(matches!(&err, Error::NotAdmin(user) if user == "alice"));
+        assert_eq!(err.to_chat_error(), ChatError::Unauthorized);
+    }
+
+    #[tokio::test]
+    async fn kick_requires_admin() {
+        let repo = Arc::new(MockRepository::default());
+        let state = state(&repo);
+
+        let err = state.kick("alice", "bob", "spam", true).await.unwrap_err();
+
+        assert_eq!(err.to_chat_error(), ChatError::Unauthorized);
+        assert!(repo.user_messages.lock().unwrap().is_empty());
+        assert!(repo.bans.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ban_records_user_and_kicks_them() {
+        let repo = Arc::new(MockRepository::default());
+        let state = state(&repo);
+
+        state.kick("admin", "bob", "spam", true).await.unwrap();
+
+        assert_eq!(
+            repo.bans.lock().unwrap().get("bob").map(String::as_str),
+            Some("spam")
+        );
+        assert!(matches!(
+            repo.user_messages.lock().unwrap().as_slice(),
+            [(user, Message::Kicked { reason })] if user == "bob" && reason == "spam"
+        ));
+    }
+
+    #[tokio::test]
+    async fn admin_commands_require_admin() {
+        let repo = Arc::new(MockRepository::default());
+        let state = state(&repo);
+
+        for cmd in [
+            AdminCmd::ListSessions,
+            AdminCmd::Disconnect("bob".to_string()),
+        ] {
+            let err = state.admin_command("alice", cmd).await.unwrap_err();
+            assert_eq!(err.to_chat_error(), ChatError::Unauthorized);
+        }
+        assert!(repo.user_messages.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn list_sessions_covers_every_node() {
+        let repo = Arc::new(MockRepository::default());
+        let state = state(&repo);
+        for (username, node) in [("bob", "node-b:64400"), ("alice", "node-a:64400")] {
+            let owner = SessionOwner {
+                node: node.parse().unwrap(),
+                nonce: 1,
+            };
+            repo.set_online(username, &owner).await.unwrap();
+        }
+
+        let reply = state
+            .admin_command("admin", AdminCmd::ListSessions)
+            .await
+            .unwrap();
+        let Some(Message::Sessions(sessions)) = reply else {
+            panic!("expected sessions, got {reply:?}");
+        };
+        let listed: Vec<_> = sessions
+            .iter()
+            .map(|s| (s.username.as_str(), s.node.as_str()))
+            .collect();
+        assert_eq!(listed, [("alice", "node-a:64400"), ("bob", "node-b:64400")]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_goes_to_the_users_own_channel() {
+        let repo = Arc::new(MockRepository::default());
+        let state = state(&repo);
+
+        let reply = state
+            .admin_command("admin", AdminCmd::Disconnect("bob".to_string()))
+            .await
+            .unwrap();
+
+        assert!(reply.is_none());
+        assert!(matches!(
+            repo.user_messages.lock().unwrap().as_slice(),
+            [(user, Message::Kicked { .. })] if user == "bob"
+        ));
+    }
+
+    #[tokio::test]
+    async fn sessions_persistently_behind_make_node_busy() {
+        let repo = Arc::new(MockRepository::default());
+        let state = state(&repo);
+        state.active_sessions.store(2, Ordering::Relaxed);
+        let _slow_session = state.subscribe();
+        for _ in 0..100 {
+            state
+                .internal_broadcast_tx
+                .send(Message::Heartbeat)
+                .unwrap();
+        }
+
+        for _ in 0..3 {
+            state.check_load();
+        }
+        assert!(!state.is_busy(), "a full channel alone isn't pressure");
+
+        for _ in 0..3 {
+            state.load.note_behind(&mut 0);
+            state.check_load();
+        }
+        assert!(state.is_busy());
+    }
+
+    #[tokio::test]
+    async fn stats_report_node_counts() {
+        let repo = Arc::new(MockRepository::default());
+        let state = state(&repo);
+        for content in ["one", "two", "three"] {
+            let packet = ChatPacket::new_user_packet("alice".to_string(), content.to_string());
+            repo.save_message(&packet).await.unwrap();
+        }
+        state.active_sessions.fetch_add(2, Ordering::Relaxed);
+
+        match state.stats("admin").await.unwrap() {
+            Message::StatsResponse {
+                active_users,
+                messages_stored,
+                node_id,
+                ..
+            } => {
+                assert_eq!(active_users, 2);
+                assert_eq!(messages_stored, 3);
+                assert_eq!(node_id, "node-a:64400");
+            }
+            other => panic!("expected stats, got {other:?}"),
+        }
+    }
 }
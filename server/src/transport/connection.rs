@@ -0,0 +1,446 @@
+use std::net::SocketAddr;
+
+use chrono::Utc;
+use futures::{SinkExt, StreamExt};
+use metrics::counter;
+use protocol::{ChatError, JoinPacket, McsCodec, Message};
+use tokio::io::{AsyncRead, AsyncWrite, WriteHalf, split};
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{Instrument, debug, error, info, info_span, warn};
+use uuid::Uuid;
+
+use crate::service::AppState;
+use crate::service::rooms::DEFAULT_ROOM;
+use crate::transport::proxy;
+use crate::transport::session::ClientSession;
+
+/// Takes a freshly accepted socket through the PROXY header, if one is
+/// expected, and the TLS handshake, if configured, then handles the connection.
+///
+/// Without a PROXY header, `addr` is the peer as seen here, which is the lb's
+/// address when clients come through one. The connection keeps the id the lb
+/// logged it under, or gets a fresh one when there's none.
+pub async fn accept<S>(
+    state: AppState,
+    mut socket: S,
+    addr: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    proxy_protocol: bool,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Sync + Send + 'static,
+{
+    let header = if proxy_protocol {
+        match time::timeout(proxy::HEADER_TIMEOUT, proxy::read_header(&mut socket)).await {
+            Ok(Ok(header)) => header,
+            // The lb's health checks hang up without sending anything.
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                debug!(%addr, "health check probe (connection closed)");
+                return;
+            }
+            Ok(Err(e)) => {
+                warn!(%addr, err=?e, "rejecting connection with a bad PROXY header");
+                return;
+            }
+            Err(_) => {
+                warn!(%addr, "timed out waiting for a PROXY header");
+                return;
+            }
+        }
+    } else {
+        proxy::ProxyHeader::default()
+    };
+    let client_addr = header.client_addr.unwrap_or(addr);
+    let conn_id = header
+        .connection_id
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    match acceptor {
+        Some(acceptor) => match acceptor.accept(socket).await {
+            Ok(stream) => handle_connection(state, stream, client_addr, conn_id).await,
+            Err(e) => warn!(%client_addr, %conn_id, err=?e, "TLS handshake failed"),
+        },
+        None => handle_connection(state, socket, client_addr, conn_id).await,
+    }
+}
+
+/// Runs the join handshake on a freshly accepted stream, then the client's
+/// session, inside a `connection` span carrying `client_ip` and `conn_id`.
+pub async fn handle_connection<S>(state: AppState, stream: S, addr: SocketAddr, conn_id: String)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Sync + Send + 'static,
+{
+    let span = info_span!("connection", client_ip = %addr.ip(), %conn_id);
+    serve_connection(state, stream, addr).instrument(span).await;
+}
+
+async fn serve_connection<S>(state: AppState, stream: S, addr: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Sync + Send + 'static,
+{
+    let (reader, writer) = split(stream);
+    let mut framed_reader = FramedRead::new(reader, McsCodec::new());
+    let mut framed_writer = FramedWrite::new(writer, McsCodec::new());
+
+    // Health check probes hang up straight away, so a failed send is expected.
+    let _ = framed_writer
+        .send(Message::Hello {
+            guest_allowed: state.auth.guests_allowed(),
+            max_message_len: state.chat.max_message_len(),
+        })
+        .await;
+
+    match framed_reader.next().await {
+        // Shed load rather than start a session this node can't keep fed.
+        Some(Ok(Message::Join(JoinPacket { username, .. }))) if state.is_busy() => {
+            warn!(user=%username, "node busy, refusing join");
+            counter!("mcs_joins_shed_total").increment(1);
+            let _ = framed_writer
+                .send(Message::Error(ChatError::ServerBusy))
+                .await;
+        }
+        // 1. Success: User sent a Join Packet
+        Some(Ok(Message::Join(JoinPacket { username, password }))) => {
+            match state
+                .auth
+                .register_and_login(&username, &password, Some(addr.ip()))
+                .await
+            {
+                Ok(username) => {
+                    info!(user=%username, "user authenticated");
+                    // Subscribed before the join goes out, so the user sees it too.
+                    if let Err(e) = state.rooms.enter(DEFAULT_ROOM).await {
+                        error!(err=?e, room=DEFAULT_ROOM, "failed to subscribe to room");
+                    }
+                    send_join_backlog(&state, &username, &mut framed_writer).await;
+
+                    let mut session =
+                        ClientSession::new(username, state, framed_reader, framed_writer);
+                    session.run().await;
+                }
+                Err(e) => {
+                    warn!(user=%username, err=?e, "failed to authenticate user");
+                    let _ = framed_writer.send(Message::Error(e.to_chat_error())).await;
+                }
+            }
+        }
+        // 2. Health Check: Connection closed immediately (0 bytes)
+        None => {
+            // This is normal behavior for the Load Balancer's health check.
+            // We use 'debug!' so it doesn't spam your console logs.
+            tracing::debug!("health check probe (connection closed)");
+        }
+        // 3. Actual Protocol Violation: User sent Chat/Heartbeat BEFORE Joining
+        Some(Ok(msg)) => {
+            warn!(
+                ?msg,
+                "protocol violation: expected JoinPacket, got {:?}", msg
+            );
+        }
+        // 4. Decode Error
+        Some(Err(e)) => {
+            warn!(err = ?e, "failed to decode packet");
+        }
+    }
+}
+
+/// Announces the join and sends the newly joined user history and the latest announcement.
+async fn send_join_backlog<S>(
+    state: &AppState,
+    username: &str,
+    framed_writer: &mut FramedWrite<WriteHalf<S>, McsCodec>,
+) where
+    S: AsyncWrite,
+{
+    if let Err(e) = state.chat.announce_join(username).await {
+        warn!(err=?e, "failed to broadcast join");
+    }
+
+    // Batches a dropped connection lost go first, so the latest page ends up on top.
+    for batch in state.history_acks.resends(username) {
+        let _ = framed_writer.send(batch).await;
+    }
+
+    // History up to now, including the join if it was stored.
+    let now = Utc::now().timestamp_millis();
+    match state.chat.get_history(now + 1, 0, None).await {
+        Ok(history) => {
+            let response = Message::from(history);
+            state.history_acks.sent(username, &response);
+            let _ = framed_writer.send(response).await;
+        }
+        Err(e) => {
+            error!(err=?e, "failed to fetch history during join");
+        }
+    }
+
+    match state.chat.get_latest_announcement().await {
+        Ok(Some(content)) => {
+            let _ = framed_writer.send(Message::Announcement { content }).await;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            warn!(err=?e, "failed to fetch announcement during join");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept, handle_connection};
+    use crate::config::Config;
+    use crate::repository::{MessageRepository, UserRepository, mock::MockRepository};
+    use crate::service::AppState;
+    use crate::transport::tls::build_acceptor;
+    use futures::{SinkExt, StreamExt};
+    use logging::testing::LogBuffer;
+    use protocol::{ChatPacket, JoinPacket, McsCodec, Message};
+    use rcgen::{CertificateParams, KeyPair};
+    use rustls::{ClientConfig, RootCertStore};
+    use rustls_pki_types::ServerName;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::broadcast;
+    use tokio_rustls::TlsConnector;
+    use tokio_util::codec::Framed;
+
+    fn state(repo: &Arc<MockRepository>) -> AppState {
+        state_with(repo, &Config::for_tests())
+    }
+
+    fn state_with(repo: &Arc<MockRepository>, config: &Config) -> AppState {
+        let (tx, _) = broadcast::channel(100);
+        AppState::from_repositories(
+            config,
+            repo.clone(),
+            repo.clone(),
+            repo.clone(),
+            "node-a:64400".parse().unwrap(),
+            tx,
+            broadcast::channel(100).0,
+        )
+    }
+
+    fn join(username: &str) -> Message {
+        Message::Join(JoinPacket {
+            username: username.to_string(),
+            password: "password".to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn joined_user_receives_structured_history() {
+        let repo = Arc::new(MockRepository::default());
+        for (timestamp, sender, content) in [(1, "alice", "hi"), (2, "bob", "hello")] {
+            let mut packet = ChatPacket::new_user_packet(sender.to_string(), content.to_string());
+            packet.timestamp = timestamp;
+            repo.save_message(&packet).await.unwrap();
+        }
+        let state = state(&repo);
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        tokio::spawn(handle_connection(
+            state,
+            server_io,
+            "127.0.0.1:50000".parse().unwrap(),
+            "conn-1".to_string(),
+        ));
+        let mut framed = Framed::new(client_io, McsCodec::new());
+        assert!(matches!(
+            framed.next().await,
+            Some(Ok(Message::Hello { .. }))
+        ));
+        framed.send(join("carol")).await.unwrap();
+
+        match framed.next().await {
+            Some(Ok(Message::HistoryResponse { messages, .. })) => {
+                let senders: Vec<&str> = messages.iter().map(|m| m.sender.as_str()).collect();
+                let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+                assert_eq!(senders, vec!["alice", "bob", "server"]);
+                assert_eq!(contents[..2], ["hi", "hello"]);
+            }
+            other => panic!("expected join history, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_login_is_charged_to_the_proxied_client_ip() {
+        let repo = Arc::new(MockRepository::default());
+        repo.create_user("carol", "password").await.unwrap();
+        let mut config = Config::for_tests();
+        config.login_limit.max_attempts_per_ip = 5;
+        let state = state_with(&repo, &config);
+
+        let (mut client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(accept(
+            state,
+            server_io,
+            "10.0.0.2:40000".parse().unwrap(),
+            None,
+            true,
+        ));
+        client_io
+            .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 5555 64400\r\n")
+            .await
+            .unwrap();
+        let mut framed = Framed::new(client_io, McsCodec::new());
+        assert!(matches!(
+            framed.next().await,
+            Some(Ok(Message::Hello { .. }))
+        ));
+        framed
+            .send(Message::Join(JoinPacket {
+                username: "carol".to_string(),
+                password: "wrong-password".to_string(),
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(framed.next().await, Some(Ok(Message::Error(_)))));
+        drop(framed);
+        server.await.unwrap();
+
+        let failures = repo.login_failures.lock().unwrap().clone();
+        assert_eq!(failures.get("ip:203.0.113.7").map(|f| f.0), Some(1));
+        assert!(!failures.contains_key("ip:10.0.0.2"));
+    }
+
+    #[tokio::test]
+    async fn connection_logs_carry_the_client_ip_and_connection_id_from_the_lb() {
+        let logs = LogBuffer::default();
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_writer(logs.clone())
+                .with_ansi(false)
+                .finish(),
+        );
+        let mut config = Config::for_tests();
+        config.idle_timeout = Duration::from_millis(100);
+        let state = state_with(&Arc::new(MockRepository::default()), &config);
+
+        let (mut client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(accept(
+            state,
+            server_io,
+            "10.0.0.2:40000".parse().unwrap(),
+            None,
+            true,
+        ));
+        // A PROXY v2 header from 203.0.113.7:5555 with the lb's id in a unique id TLV.
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x1b".to_vec();
+        header.extend([203, 0, 113, 7, 10, 0, 0, 1, 0x15, 0xb3, 0xfb, 0x90]);
+        header.extend(b"\x05\x00\x0clb-conn-0007");
+        client_io.write_all(&header).await.unwrap();
+        let mut framed = Framed::new(client_io, McsCodec::new());
+        framed.send(join("carol")).await.unwrap();
+        server.await.unwrap();
+
+        let logs = logs.contents();
+        for event in ["user authenticated", "client idle too long"] {
+            let line = logs
+                .lines()
+                .find(|line| line.contains(event))
+                .unwrap_or_else(|| panic!("nothing logged {event:?}:\n{logs}"));
+            assert!(line.contains("conn_id=lb-conn-0007"), "{line}");
+            assert!(line.contains("client_ip=203.0.113.7"), "{line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn tls_client_can_join() {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let dir = std::env::temp_dir().join(format!("mcs-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("server.cert");
+        let key_path = dir.join("server.key");
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+        let acceptor = build_acceptor(
+            cert_path.to_str().unwrap(),
+            key_path.to_str().unwrap(),
+            "1.2",
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let state = state(&Arc::new(MockRepository::default()));
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        tokio::spawn(async move {
+            let stream = acceptor.accept(server_io).await.unwrap();
+            handle_connection(
+                state,
+                stream,
+                "127.0.0.1:50000".parse().unwrap(),
+                "conn-1".to_string(),
+            )
+            .await;
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let domain = ServerName::try_from("localhost").unwrap();
+        let stream = connector.connect(domain, client_io).await.unwrap();
+        let mut framed = Framed::new(stream, McsCodec::new());
+        assert!(matches!(
+            framed.next().await,
+            Some(Ok(Message::Hello { .. }))
+        ));
+
+        framed.send(join("alice")).await.unwrap();
+
+        match framed.next().await {
+            Some(Ok(Message::HistoryResponse { .. })) => {}
+            other => panic!("expected join history, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn guest_mode_is_advertised_and_names_the_session() {
+        let repo = Arc::new(MockRepository::default());
+        let mut config = Config::for_tests();
+        config.allow_guest = true;
+        let state = state_with(&repo, &config);
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        tokio::spawn(handle_connection(
+            state,
+            server_io,
+            "127.0.0.1:50000".parse().unwrap(),
+            "conn-1".to_string(),
+        ));
+        let mut framed = Framed::new(client_io, McsCodec::new());
+        assert!(matches!(
+            framed.next().await,
+            Some(Ok(Message::Hello {
+                guest_allowed: true,
+                ..
+            }))
+        ));
+        framed
+            .send(Message::Join(JoinPacket {
+                username: "dave".to_string(),
+                password: String::new(),
+            }))
+            .await
+            .unwrap();
+
+        match framed.next().await {
+            Some(Ok(Message::HistoryResponse { messages, .. })) => {
+                assert_eq!(messages.last().unwrap().content, "~dave joined.\n");
+            }
+            other => panic!("expected join history, got {other:?}"),
+        }
+        assert!(repo.users.lock().unwrap().is_empty());
+    }
+}
@@ -1 +1,4 @@
+pub mod connection;
+pub mod proxy;
 pub mod session;
+pub mod tls;
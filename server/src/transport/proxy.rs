@@ -0,0 +1,293 @@
+//! PROXY protocol, which the lb uses to pass on each client's address and
+//! the id it gave the connection.
+//!
+//! The lb sends v1 or v2 as configured, and only v2's TLVs carry the id;
+//! either is accepted, from the lb or other proxies.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long a new connection may take to send its PROXY header.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest v1 header the spec allows, `\r\n` included.
+const MAX_HEADER_LEN: usize = 107;
+
+/// Opens every v2 header. No v1 header is shorter, so reading this many bytes
+/// first is safe either way.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The v2 TLV carrying the id the proxy gave the connection.
+const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+/// Longest unique id the spec allows.
+const MAX_UNIQUE_ID_LEN: usize = 128;
+
+/// What a PROXY header says about the connection behind it.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The client's address, or `None` when the proxy doesn't know it.
+    pub client_addr: Option<SocketAddr>,
+    /// The id the proxy logged the connection under, if it sent one.
+    pub connection_id: Option<String>,
+}
+
+/// Reads a PROXY v1 or v2 header off the front of `stream`.
+///
+/// Reads no further than the header, so the client's own bytes are left for
+/// whoever reads next.
+pub async fn read_header<S>(stream: &mut S) -> io::Result<ProxyHeader>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut start = [0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+
+    let mut line = Vec::with_capacity(MAX_HEADER_LEN);
+    line.extend_from_slice(&start);
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_HEADER_LEN {
+            return Err(invalid("PROXY header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY header isn't ASCII"))?;
+    Ok(ProxyHeader {
+        client_addr: parse_header(line)?,
+        connection_id: None,
+    })
+}
+
+/// Reads the rest of a v2 header, after its signature.
+async fn read_v2<S>(stream: &mut S) -> io::Result<ProxyHeader>
+where
+    S: AsyncRead + Unpin,
+{
+    let [version_command, family, len @ ..] = {
+        let mut fixed = [0; 4];
+        stream.read_exact(&mut fixed).await?;
+        fixed
+    };
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let mut body = vec![0; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut body).await?;
+    parse_v2(version_command & 0x0f, family, &body)
+}
+
+fn parse_v2(command: u8, family: u8, body: &[u8]) -> io::Result<ProxyHeader> {
+    // The address family in the high nibble fixes the addresses' length,
+    // whichever transport the low nibble names.
+    let addr_len = match family >> 4 {
+        0x0 => 0,
+        0x1 => 12,
+        0x2 => 36,
+        0x3 => 216,
+        _ => return Err(invalid("unsupported PROXY v2 address family")),
+    };
+    if body.len() < addr_len {
+        return Err(invalid("truncated PROXY v2 addresses"));
+    }
+    let (addrs, tlvs) = body.split_at(addr_len);
+
+    let client_addr = match (command, family) {
+        (0x1, 0x11) => {
+            let ip: [u8; 4] = addrs[..4].try_into().unwrap_or_default();
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        (0x1, 0x21) => {
+            let ip: [u8; 16] = addrs[..16].try_into().unwrap_or_default();
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Some(SocketAddr::new(Ipv6Addr::from(ip).to_canonical(), port))
+        }
+        // LOCAL connections come from the proxy itself rather than a client,
+        // and other families or transports, such as UDP or unix sockets,
+        // carry nothing a TCP address can be built from.
+        (0x0 | 0x1, _) => None,
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    };
+
+    Ok(ProxyHeader {
+        client_addr,
+        connection_id: unique_id(tlvs)?,
+    })
+}
+
+/// Finds the unique id among `tlvs`, ignoring one that isn't printable ASCII
+/// so it can't garble the logs it ends up in.
+fn unique_id(mut tlvs: &[u8]) -> io::Result<Option<String>> {
+    while let [kind, len_hi, len_lo, rest @ ..] = tlvs {
+        let len = usize::from(u16::from_be_bytes([*len_hi, *len_lo]));
+        if rest.len() < len {
+            return Err(invalid("truncated PROXY v2 TLV"));
+        }
+        let (value, next) = rest.split_at(len);
+        if *kind == PP2_TYPE_UNIQUE_ID {
+            return Ok(std::str::from_utf8(value)
+                .ok()
+                .filter(|id| {
+                    (1..=MAX_UNIQUE_ID_LEN).contains(&id.len())
+                        && id.bytes().all(|b| b.is_ascii_graphic())
+                })
+                .map(str::to_string));
+        }
+        tlvs = next;
+    }
+    Ok(None)
+}
+
+fn parse_header(line: &str) -> io::Result<Option<SocketAddr>> {
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(invalid("missing PROXY signature"));
+    }
+    match fields.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4" | "TCP6") => {}
+        _ => return Err(invalid("unsupported PROXY protocol family")),
+    }
+
+    let src: IpAddr = parse_field(fields.next(), "source address")?;
+    let _dst: IpAddr = parse_field(fields.next(), "destination address")?;
+    let src_port: u16 = parse_field(fields.next(), "source port")?;
+    let _dst_port: u16 = parse_field(fields.next(), "destination port")?;
+    if fields.next().is_some() {
+        return Err(invalid("trailing fields in PROXY header"));
+    }
+
+    // The lb sends IPv4 clients as IPv4-mapped IPv6 when the backend is IPv6.
+    Ok(Some(SocketAddr::new(src.to_canonical(), src_port)))
+}
+
+fn parse_field<T: std::str::FromStr>(field: Option<&str>, name: &str) -> io::Result<T> {
+    field
+        .and_then(|f| f.parse().ok())
+        .ok_or_else(|| invalid(&format!("bad {name} in PROXY header")))
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProxyHeader, V2_SIGNATURE, read_header};
+    use tokio::io::AsyncReadExt;
+
+    /// A v2 PROXY header from 203.0.113.7:5555 to 10.0.0.1:64400, with `tlvs` after.
+    fn v2_header(tlvs: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11]);
+        header.extend(u16::try_from(12 + tlvs.len()).unwrap().to_be_bytes());
+        header.extend([203, 0, 113, 7, 10, 0, 0, 1]);
+        header.extend(5555_u16.to_be_bytes());
+        header.extend(64400_u16.to_be_bytes());
+        header.extend(tlvs);
+        header
+    }
+
+    fn unique_id_tlv(id: &[u8]) -> Vec<u8> {
+        let mut tlv = vec![0x05];
+        tlv.extend(u16::try_from(id.len()).unwrap().to_be_bytes());
+        tlv.extend(id);
+        tlv
+    }
+
+    #[tokio::test]
+    async fn header_is_consumed_and_nothing_more() {
+        let mut v2 = v2_header(&[]);
+        v2.extend(b"hello");
+        for input in [
+            &b"PROXY TCP4 203.0.113.7 10.0.0.1 5555 64400\r\nhello"[..],
+            &v2,
+        ] {
+            let mut input = input;
+
+            let header = read_header(&mut input).await.unwrap();
+
+            assert_eq!(
+                header.client_addr,
+                Some("203.0.113.7:5555".parse().unwrap())
+            );
+            let mut rest = String::new();
+            input.read_to_string(&mut rest).await.unwrap();
+            assert_eq!(rest, "hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn mapped_and_unknown_sources() {
+        let mut mapped: &[u8] = b"PROXY TCP6 ::ffff:203.0.113.7 ::1 5555 64400\r\n";
+        assert_eq!(
+            read_header(&mut mapped).await.unwrap().client_addr,
+            Some("203.0.113.7:5555".parse().unwrap())
+        );
+
+        let mut unknown: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(
+            read_header(&mut unknown).await.unwrap(),
+            ProxyHeader::default()
+        );
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            read_header(&mut local.as_slice()).await.unwrap(),
+            ProxyHeader::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn v2_unique_id_is_the_connection_id() {
+        let mut tlvs = vec![0x04, 0x00, 0x01, 0xff];
+        tlvs.extend(unique_id_tlv(b"3f2c9a1e-lb"));
+        let header = read_header(&mut v2_header(&tlvs).as_slice()).await.unwrap();
+        assert_eq!(header.connection_id.as_deref(), Some("3f2c9a1e-lb"));
+
+        for id in [&b""[..], b"two words", b"\x1b[31m"] {
+            let header = read_header(&mut v2_header(&unique_id_tlv(id)).as_slice())
+                .await
+                .unwrap();
+            assert_eq!(header.connection_id, None, "{id:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_addresses_are_skipped_by_their_family_length() {
+        let id = unique_id_tlv(b"lb-conn-0007");
+        // UDP over IPv4, then a unix socket pair.
+        for (family, addr_len) in [(0x12, 12), (0x31, 216)] {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.extend([0x21, family]);
+            header.extend(u16::try_from(addr_len + id.len()).unwrap().to_be_bytes());
+            header.extend(vec![0; addr_len]);
+            header.extend(&id);
+
+            let header = read_header(&mut header.as_slice()).await.unwrap();
+            assert_eq!(header.client_addr, None);
+            assert_eq!(header.connection_id.as_deref(), Some("lb-conn-0007"));
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_headers_are_rejected() {
+        for input in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 nonsense 10.0.0.1 5555 64400\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 5555\r\n",
+            &[b'P'; 200],
+            &v2_header(&[0x05, 0x00, 0x10, b'x'])[..],
+        ] {
+            let mut input = input;
+            assert!(read_header(&mut input).await.is_err(), "{input:?}");
+        }
+    }
+}
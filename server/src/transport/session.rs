@@ -1,14 +1,19 @@
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
-use crate::service::AppState;
+use crate::repository::{HistoryPage, UserMessage};
+use crate::service::{AppState, rate_limit::UserRateLimiter, rooms::DEFAULT_ROOM};
+use chrono::Utc;
 use futures::{SinkExt, StreamExt};
-use protocol::{McsCodec, Message};
+use metrics::{counter, gauge};
+use protocol::{AdminCmd, ChatError, ChatPacket, HistoryDirection, McsCodec, Message};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
-    sync::broadcast::Receiver,
+    sync::broadcast::{Receiver, error::RecvError},
+    time::{self, Instant},
 };
 use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{error, warn};
+use tracing::{Instrument, error, info_span, warn};
 
 pub struct ClientSession<S> {
     username: String,
@@ -16,6 +21,13 @@ pub struct ClientSession<S> {
     reader: FramedRead<ReadHalf<S>, McsCodec>,
     writer: FramedWrite<WriteHalf<S>, McsCodec>,
     rx: Receiver<Message>,
+    user_rx: Receiver<UserMessage>,
+    limiter: UserRateLimiter,
+    last_seen: Instant,
+    /// Unix time in milliseconds of the user's last chat message or typing, or of the login.
+    last_active: i64,
+    /// The load interval this session last counted itself behind in.
+    load_counted: u64,
 }
 
 impl<S> ClientSession<S>
@@ -29,23 +41,43 @@ where
         writer: FramedWrite<WriteHalf<S>, McsCodec>,
     ) -> Self {
         let rx = state.subscribe();
+        let user_rx = state.subscribe_user();
+        let limiter = UserRateLimiter::new(state.rate_limit);
         Self {
             username,
             state,
             reader,
             writer,
             rx,
+            user_rx,
+            limiter,
+            last_seen: Instant::now(),
+            last_active: Utc::now().timestamp_millis(),
+            load_counted: 0,
         }
     }
 
+    /// Serves the session until it ends, inside a `session` span carrying the user.
     pub async fn run(&mut self) {
+        let span = info_span!("session", user = %self.username);
+        self.serve().instrument(span).await;
+    }
+
+    async fn serve(&mut self) {
         let mut interval = tokio::time::interval(Duration::from_secs(10));
+        let keepalive_period = self.state.keepalive_interval;
+        let mut keepalive = time::interval_at(Instant::now() + keepalive_period, keepalive_period);
+        gauge!("mcs_active_sessions").increment(1);
+        self.state.active_sessions.fetch_add(1, Ordering::Relaxed);
 
         loop {
             tokio::select! {
                 result = self.reader.next() => {
                     match result {
-                        Some(Ok(msg)) => self.handle_client_message(msg).await,
+                        Some(Ok(msg)) => {
+                            self.last_seen = Instant::now();
+                            self.handle_client_message(msg).await;
+                        }
                         Some(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
                         Some(Err(e)) => {
                             error!(user=%self.username, err=?e, "failed to decode message");
@@ -55,16 +87,44 @@ where
                     }
                 }
 
-                Ok(msg) = self.rx.recv() => {
-                    if let Err(e) = self.writer.send(msg).await {
-                        error!(user=%self.username, err=?e, "failed to send broadcast to client");
+                result = self.rx.recv() => {
+                    let delivered = match result {
+                        Ok(msg) => {
+                            if self.state.load.is_backlogged(self.rx.len()) {
+                                self.state.load.note_behind(&mut self.load_counted);
+                            }
+                            self.send_with_timeout(msg).await
+                        }
+                        Err(RecvError::Lagged(count)) => self.recover_from_lag(count).await,
+                        Err(RecvError::Closed) => false,
+                    };
+                    if !delivered {
                         break;
                     }
                 }
 
-                _ = interval.tick() => {
-                    if let Err(e) = self.state.auth.refresh_session(&self.username).await {
-                        error!(user=%self.username, err=?e, "failed to refresh session");
+                result = self.user_rx.recv() => {
+                    if let Ok(UserMessage { username, message }) = result
+                        && username == self.username
+                        && !self.handle_user_message(message).await
+                    {
+                        break;
+                    }
+                }
+
+                () = time::sleep_until(self.last_seen + self.state.idle_timeout) => {
+                    warn!(user=%self.username, "client idle too long, disconnecting");
+                    let _ = self.writer.send(Message::Error(ChatError::IdleTimeout)).await;
+                    break;
+                }
+
+                _ = interval.tick() => self.refresh_session().await,
+
+                // Notices a client that reset the connection. One that vanished
+                // without a word leaves it half-open, where writes still succeed
+                // into the kernel's buffer, so it's the idle timeout that ends it.
+                _ = keepalive.tick() => {
+                    if !self.send_with_timeout(Message::Heartbeat).await {
                         break;
                     }
                 }
@@ -74,44 +134,786 @@ where
         self.disconnect().await;
     }
 
+    /// Sends a message, returning `false` if the client should be dropped.
+    async fn send_with_timeout(&mut self, msg: Message) -> bool {
+        match time::timeout(self.state.send_timeout, self.writer.send(msg)).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                error!(user=%self.username, err=?e, "failed to send broadcast to client");
+                false
+            }
+            Err(_) => {
+                warn!(user=%self.username, "send timed out, dropping stalled client");
+                false
+            }
+        }
+    }
+
+    /// Delivers a message addressed to this user, returning `false` if the session should end.
+    async fn handle_user_message(&mut self, msg: Message) -> bool {
+        if let Message::Kicked { reason } = &msg {
+            warn!(user=%self.username, %reason, "kicked by an admin");
+            let _ = self.send_with_timeout(msg).await;
+            return false;
+        }
+        self.send_with_timeout(msg).await
+    }
+
+    /// Tells a lagging client what it missed and resends the latest history page.
+    async fn recover_from_lag(&mut self, count: u64) -> bool {
+        warn!(user=%self.username, count, "client lagged behind broadcasts");
+        self.state.load.note_behind(&mut self.load_counted);
+        counter!("mcs_broadcast_lagged_messages_total").increment(count);
+        if !self
+            .send_with_timeout(Message::Error(ChatError::MessagesDropped { count }))
+            .await
+        {
+            return false;
+        }
+
+        match self.state.chat.get_history(i64::MAX, u64::MAX, None).await {
+            Ok(history) => {
+                let response = Message::from(history);
+                self.state.history_acks.sent(&self.username, &response);
+                self.send_with_timeout(response).await
+            }
+            Err(e) => {
+                warn!(user=%self.username, err=?e, "failed to refetch history after lag");
+                true
+            }
+        }
+    }
+
+    /// Broadcasts a user's message and acks it to the sender.
+    async fn handle_chat(&mut self, packet: ChatPacket) {
+        // Clients put a local id in the packet so they can match the ack. Only the
+        // content is trusted; sender and timestamp are assigned by the server.
+        let local_id = packet.id;
+        let result = if self.limiter.try_consume(packet.content.len()) {
+            self.state
+                .chat
+                .broadcast_user_message(&self.username, packet.content)
+                .await
+                .map_err(|e| {
+                    error!(user=%self.username, err=?e, "failed to broadcast message");
+                    e.to_chat_error()
+                })
+        } else {
+            warn!(user=%self.username, "message rate limit exceeded");
+            Err(ChatError::RateLimited)
+        };
+        // The echo waits in the broadcast channel until this returns, so the
+        // sender always gets the id from the ack before its own copy arrives.
+        let _ = self
+            .writer
+            .send(Message::ChatAck { local_id, result })
+            .await;
+    }
+
+    /// Answers a history page request, echoing its sender filter and direction.
+    ///
+    /// Batches the client never acked are sent again first; clients drop
+    /// messages they already have, so a resend is harmless.
+    async fn handle_history_request(
+        &mut self,
+        (cursor_ts, cursor_id): (i64, u64),
+        sender: Option<String>,
+        direction: HistoryDirection,
+    ) {
+        for batch in self.state.history_acks.resends(&self.username) {
+            let _ = self.writer.send(batch).await;
+        }
+
+        let chat = &self.state.chat;
+        let page = match (direction, sender.as_deref()) {
+            (HistoryDirection::Before, sender) => {
+                chat.get_history(cursor_ts, cursor_id, sender).await
+            }
+            (HistoryDirection::After, None) => chat.get_newer_history(cursor_ts, cursor_id).await,
+            // Filtered views are shown apart from the timeline and only page back.
+            (HistoryDirection::After, Some(_)) => Ok(HistoryPage::default()),
+        };
+        match page {
+            Ok(history) => {
+                let response = Message::HistoryResponse {
+                    messages: history.messages,
+                    has_more: history.has_more,
+                    sender,
+                    direction,
+                };
+                self.state.history_acks.sent(&self.username, &response);
+                let _ = self.writer.send(response).await;
+            }
+            Err(e) => {
+                warn!(user=%self.username, err=?e, timestamp=%cursor_ts, "failed to provide history");
+                let _ = self.writer.send(Message::Error(e.to_chat_error())).await;
+            }
+        }
+    }
+
+    /// Forgets the batch the client confirmed.
+    fn handle_history_ack(&self, up_to_ts: i64, up_to_id: u64) {
+        self.state
+            .history_acks
+            .acked(&self.username, (up_to_ts, up_to_id));
+    }
+
+    async fn handle_change_password(&mut self, old: &str, new: &str) {
+        let reply = match self
+            .state
+            .auth
+            .change_password(&self.username, old, new)
+            .await
+        {
+            Ok(()) => Message::PasswordChanged,
+            Err(e) => {
+                warn!(user=%self.username, err=?e, "failed to change password");
+                Message::Error(e.to_chat_error())
+            }
+        };
+        let _ = self.writer.send(reply).await;
+    }
+
+    async fn refresh_session(&self) {
+        self.state
+            .auth
+            .refresh_session(&self.username, self.last_active)
+            .await;
+    }
+
+    async fn handle_online_users_request(&mut self) {
+        match self.state.auth.online_users().await {
+            Ok(users) => {
+                let _ = self.writer.send(Message::OnlineUsersResponse(users)).await;
+            }
+            Err(e) => {
+                warn!(user=%self.username, err=?e, "failed to list online users");
+                let _ = self.writer.send(Message::Error(e.to_chat_error())).await;
+            }
+        }
+    }
+
+    async fn handle_stats_request(&mut self) {
+        match self.state.stats(&self.username).await {
+            Ok(stats) => {
+                let _ = self.writer.send(stats).await;
+            }
+            Err(e) => {
+                warn!(user=%self.username, err=?e, "failed to provide stats");
+                let _ = self.writer.send(Message::Error(e.to_chat_error())).await;
+            }
+        }
+    }
+
+    async fn handle_admin_command(&mut self, cmd: AdminCmd) {
+        match self.state.admin_command(&self.username, cmd).await {
+            Ok(Some(reply)) => {
+                let _ = self.writer.send(reply).await;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!(user=%self.username, err=?e, "failed to run admin command");
+                let _ = self.writer.send(Message::Error(e.to_chat_error())).await;
+            }
+        }
+    }
+
     async fn handle_client_message(&mut self, msg: Message) {
         match msg {
             Message::Chat(packet) => {
+                self.last_active = Utc::now().timestamp_millis();
+                self.handle_chat(packet).await;
+            }
+            Message::EditMessage { id, content } => {
                 if let Err(e) = self
                     .state
                     .chat
-                    .broadcast_user_message(&self.username, packet.content)
+                    .edit_message(&self.username, id, content)
                     .await
                 {
-                    error!(user=%self.username, err=?e, "failed to broadcast message");
+                    warn!(user=%self.username, err=?e, id, "failed to edit message");
+                    let _ = self.writer.send(Message::Error(e.to_chat_error())).await;
+                }
+            }
+            Message::DeleteMessage { id } => {
+                if let Err(e) = self.state.chat.delete_message(&self.username, id).await {
+                    warn!(user=%self.username, err=?e, id, "failed to delete message");
                     let _ = self.writer.send(Message::Error(e.to_chat_error())).await;
                 }
             }
-            Message::HistoryRequest(ts) => match self.state.chat.get_history(ts).await {
-                Ok(history) => {
-                    let _ = self.writer.send(Message::HistoryResponse(history)).await;
+            Message::HistoryRequest {
+                cursor_ts,
+                cursor_id,
+                sender,
+                direction,
+            } => {
+                self.handle_history_request((cursor_ts, cursor_id), sender, direction)
+                    .await;
+            }
+            Message::HistoryAck { up_to_ts, up_to_id } => {
+                self.handle_history_ack(up_to_ts, up_to_id);
+            }
+            Message::SearchRequest { query, limit } => {
+                match self.state.chat.search(&query, limit).await {
+                    Ok(results) => {
+                        let _ = self.writer.send(Message::SearchResponse(results)).await;
+                    }
+                    Err(e) => {
+                        warn!(user=%self.username, err=?e, "failed to search messages");
+                        let _ = self.writer.send(Message::Error(e.to_chat_error())).await;
+                    }
+                }
+            }
+            Message::Typing { is_typing, .. } => {
+                self.last_active = Utc::now().timestamp_millis();
+                if let Err(e) = self
+                    .state
+                    .chat
+                    .broadcast_typing(&self.username, is_typing)
+                    .await
+                {
+                    warn!(user=%self.username, err=?e, "failed to broadcast typing state");
+                }
+            }
+            Message::Announcement { content } => {
+                if let Err(e) = self
+                    .state
+                    .chat
+                    .broadcast_announcement(&self.username, content)
+                    .await
+                {
+                    warn!(user=%self.username, err=?e, "failed to broadcast announcement");
+                    let _ = self.writer.send(Message::Error(e.to_chat_error())).await;
                 }
-                Err(e) => {
-                    warn!(user=%self.username, err=?e, timestamp=%ts, "failed to provide history");
+            }
+            Message::RateStatusRequest => {
+                let _ = self.writer.send(self.limiter.status()).await;
+            }
+            Message::Heartbeat => self.refresh_session().await,
+            Message::OnlineUsersRequest => self.handle_online_users_request().await,
+            Message::Ping { nonce, sent_ms } => {
+                let _ = self.writer.send(Message::Pong { nonce, sent_ms }).await;
+            }
+            Message::KickRequest {
+                username,
+                reason,
+                ban,
+            } => {
+                if let Err(e) = self
+                    .state
+                    .kick(&self.username, &username, &reason, ban)
+                    .await
+                {
+                    warn!(user=%self.username, err=?e, target=%username, "failed to kick user");
                     let _ = self.writer.send(Message::Error(e.to_chat_error())).await;
                 }
-            },
-            Message::Heartbeat => {
-                let _ = self.state.auth.refresh_session(&self.username).await;
             }
+            Message::AdminCommand(cmd) => self.handle_admin_command(cmd).await,
+            Message::ChangePassword { old, new } => self.handle_change_password(&old, &new).await,
+            Message::StatsRequest => self.handle_stats_request().await,
             _ => {}
         }
     }
 
     async fn disconnect(&self) {
+        gauge!("mcs_active_sessions").decrement(1);
+        self.state.active_sessions.fetch_sub(1, Ordering::Relaxed);
+
         if let Err(e) = self.state.auth.logout(&self.username).await {
             error!(user=%self.username, err=?e, "failed to clear session");
         }
+        if let Err(e) = self.state.rooms.leave(DEFAULT_ROOM).await {
+            warn!(err=?e, room=DEFAULT_ROOM, "failed to unsubscribe from room");
+        }
 
-        let _ = self
-            .state
-            .chat
-            .broadcast_system_message(format!("{} left.\n", self.username))
+        self.state.chat.announce_leave(&self.username);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientSession;
+    use crate::config::Config;
+    use crate::repository::{MessageRepository, UserMessage, mock::MockRepository};
+    use crate::service::AppState;
+    use futures::{SinkExt, StreamExt};
+    use logging::testing::record_metrics;
+    use metrics_util::debugging::DebugValue;
+    use protocol::{ChatError, ChatPacket, HistoryDirection, McsCodec, Message};
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::sync::broadcast;
+    use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+
+    fn state_with(repo: &Arc<MockRepository>, config: &Config) -> AppState {
+        AppState::from_repositories(
+            config,
+            repo.clone(),
+            repo.clone(),
+            repo.clone(),
+            "node:64400".parse().unwrap(),
+            broadcast::channel(config.broadcast_capacity).0,
+            broadcast::channel(config.broadcast_capacity).0,
+        )
+    }
+
+    /// Logs `username` in so their session is marked online.
+    async fn log_in(state: &AppState, repo: &MockRepository, username: &str) {
+        repo.users
+            .lock()
+            .unwrap()
+            .insert(username.to_string(), "correct".to_string());
+        state
+            .auth
+            .register_and_login(username, "correct", None)
+            .await
+            .unwrap();
+    }
+
+    /// Runs a session whose client never reads, returning the repository once it disconnects.
+    async fn run_stalled_session() -> Arc<MockRepository> {
+        let repo = Arc::new(MockRepository::default());
+        let mut config = Config::for_tests();
+        config.send_timeout = Duration::from_millis(100);
+        let state = state_with(&repo, &config);
+        let tx = state.internal_broadcast_tx.clone();
+        log_in(&state, &repo, "alice").await;
+
+        // The client end is kept alive but never read, so writes stall once the pipe fills.
+        let (server_io, _client_io) = tokio::io::duplex(64);
+        let (reader, writer) = tokio::io::split(server_io);
+        let mut session = ClientSession::new(
+            "alice".to_string(),
+            state,
+            FramedRead::new(reader, McsCodec::new()),
+            FramedWrite::new(writer, McsCodec::new()),
+        );
+        for _ in 0..10 {
+            let packet = ChatPacket::new_server_packet("x".repeat(32));
+            tx.send(Message::Chat(packet)).unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), session.run())
+            .await
+            .expect("session should disconnect instead of hanging");
+        repo
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_writer_disconnects_after_send_timeout() {
+        let repo = run_stalled_session().await;
+        assert!(!repo.online.lock().unwrap().contains_key("alice"));
+    }
+
+    #[test]
+    fn active_sessions_gauge_drops_after_timeout_disconnect() {
+        let ((), recorded) = record_metrics(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .start_paused(true)
+                .build()
+                .unwrap()
+                .block_on(run_stalled_session());
+        });
+
+        assert_eq!(
+            recorded.get("mcs_active_sessions"),
+            Some(&DebugValue::Gauge(0.0.into()))
+        );
+    }
+
+    /// A connection whose peer is gone: reads never complete, and writes fail
+    /// if the peer reset it, or else vanish into the kernel's buffer as they
+    /// do on a half-open connection.
+    struct DeadPeer {
+        reset: bool,
+    }
+
+    impl DeadPeer {
+        fn result(&self, written: usize) -> Poll<io::Result<usize>> {
+            Poll::Ready(if self.reset {
+                Err(io::ErrorKind::BrokenPipe.into())
+            } else {
+                Ok(written)
+            })
+        }
+    }
+
+    impl AsyncRead for DeadPeer {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for DeadPeer {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.result(buf.len())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.result(0).map_ok(|_| ())
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Runs a session over a dead peer, returning how long it took to end.
+    async fn session_with_dead_peer(reset: bool) -> Duration {
+        let repo = Arc::new(MockRepository::default());
+        let mut config = Config::for_tests();
+        config.keepalive_interval = Duration::from_secs(5);
+        config.idle_timeout = Duration::from_mins(1);
+        let state = state_with(&repo, &config);
+        log_in(&state, &repo, "alice").await;
+
+        let (reader, writer) = tokio::io::split(DeadPeer { reset });
+        let mut session = ClientSession::new(
+            "alice".to_string(),
+            state,
+            FramedRead::new(reader, McsCodec::new()),
+            FramedWrite::new(writer, McsCodec::new()),
+        );
+
+        let start = tokio::time::Instant::now();
+        tokio::time::timeout(Duration::from_mins(2), session.run())
+            .await
+            .expect("session should end with its peer gone");
+        assert!(!repo.online.lock().unwrap().contains_key("alice"));
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reset_peer_is_dropped_at_the_next_keepalive() {
+        assert_eq!(session_with_dead_peer(true).await, Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn half_open_peer_is_dropped_at_the_idle_timeout() {
+        // Keepalives keep succeeding, so only the client's silence gives it away.
+        assert_eq!(session_with_dead_peer(false).await, Duration::from_mins(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_client_disconnects_after_idle_timeout() {
+        let repo = Arc::new(MockRepository::default());
+        let mut config = Config::for_tests();
+        config.idle_timeout = Duration::from_mins(1);
+        let state = state_with(&repo, &config);
+        log_in(&state, &repo, "alice").await;
+
+        let (server_io, client_io) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server_io);
+        let mut session = ClientSession::new(
+            "alice".to_string(),
+            state,
+            FramedRead::new(reader, McsCodec::new()),
+            FramedWrite::new(writer, McsCodec::new()),
+        );
+
+        let started = tokio::time::Instant::now();
+        session.run().await;
+        assert!(started.elapsed() >= Duration::from_mins(1));
+        assert!(!repo.online.lock().unwrap().contains_key("alice"));
+
+        // Keepalives went out while the client sat silent.
+        let mut client = FramedRead::new(client_io, McsCodec::new())
+            .filter(|msg| std::future::ready(!matches!(msg, Ok(Message::Heartbeat))));
+        match client.next().await {
+            Some(Ok(Message::Error(ChatError::IdleTimeout))) => {}
+            other => panic!("expected an idle timeout error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeats_report_the_last_chat_or_typing_not_themselves() {
+        let repo = Arc::new(MockRepository::default());
+        let state = state_with(&repo, &Config::for_tests());
+        log_in(&state, &repo, "alice").await;
+
+        let (server_io, _client_io) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server_io);
+        let mut session = ClientSession::new(
+            "alice".to_string(),
+            state,
+            FramedRead::new(reader, McsCodec::new()),
+            FramedWrite::new(writer, McsCodec::new()),
+        );
+        session.last_active = 1_000;
+
+        session.handle_client_message(Message::Heartbeat).await;
+        assert_eq!(repo.last_active.lock().unwrap()["alice"], 1_000);
+
+        session
+            .handle_client_message(Message::Typing {
+                username: String::new(),
+                is_typing: true,
+            })
             .await;
+        session.handle_client_message(Message::Heartbeat).await;
+        assert!(repo.last_active.lock().unwrap()["alice"] > 1_000);
+    }
+
+    #[tokio::test]
+    async fn kick_reaches_only_the_target_session() {
+        let repo = Arc::new(MockRepository::default());
+        let mut config = Config::for_tests();
+        config.admins = vec!["admin".to_string()];
+        let state = state_with(&repo, &config);
+        let user_tx = state.user_tx.clone();
+        log_in(&state, &repo, "alice").await;
+
+        let (server_io, client_io) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server_io);
+        let mut session = ClientSession::new(
+            "alice".to_string(),
+            state.clone(),
+            FramedRead::new(reader, McsCodec::new()),
+            FramedWrite::new(writer, McsCodec::new()),
+        );
+        let session = tokio::spawn(async move { session.run().await });
+
+        state.kick("admin", "bob", "spam", false).await.unwrap();
+        state
+            .kick("admin", "alice", "flooding", false)
+            .await
+            .unwrap();
+        // Stand in for redis relaying each published kick back to this node.
+        for (username, message) in repo.user_messages.lock().unwrap().drain(..) {
+            user_tx.send(UserMessage { username, message }).unwrap();
+        }
+
+        let mut client = FramedRead::new(client_io, McsCodec::new());
+        match client.next().await {
+            Some(Ok(Message::Kicked { reason })) => assert_eq!(reason, "flooding"),
+            other => panic!("expected a kick, got {other:?}"),
+        }
+        tokio::time::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("kicked session should end")
+            .unwrap();
+        assert!(!repo.online.lock().unwrap().contains_key("alice"));
+    }
+
+    #[tokio::test]
+    async fn lagging_client_is_told_and_sent_history() {
+        let repo = Arc::new(MockRepository::default());
+        let mut config = Config::for_tests();
+        config.broadcast_capacity = 4;
+        let state = state_with(&repo, &config);
+        let tx = state.internal_broadcast_tx.clone();
+        let stored = ChatPacket::new_user_packet("bob".to_string(), "stored".to_string());
+        repo.save_message(&stored).await.unwrap();
+
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        let (reader, writer) = tokio::io::split(server_io);
+        let mut session = ClientSession::new(
+            "alice".to_string(),
+            state,
+            FramedRead::new(reader, McsCodec::new()),
+            FramedWrite::new(writer, McsCodec::new()),
+        );
+        for i in 0..10 {
+            let packet = ChatPacket::new_server_packet(format!("broadcast {i}"));
+            tx.send(Message::Chat(packet)).unwrap();
+        }
+        let session = tokio::spawn(async move { session.run().await });
+
+        let mut client = FramedRead::new(client_io, McsCodec::new());
+        match client.next().await {
+            Some(Ok(Message::Error(ChatError::MessagesDropped { count: 6 }))) => {}
+            other => panic!("expected a dropped messages error, got {other:?}"),
+        }
+        match client.next().await {
+            Some(Ok(Message::HistoryResponse { messages, .. })) => {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].content, "stored");
+            }
+            other => panic!("expected a history refetch, got {other:?}"),
+        }
+        for i in 6..10 {
+            match client.next().await {
+                Some(Ok(Message::Chat(packet))) => {
+                    assert_eq!(packet.content, format!("broadcast {i}"));
+                }
+                other => panic!("expected broadcast {i}, got {other:?}"),
+            }
+        }
+
+        drop(client);
+        session.abort();
+    }
+
+    #[tokio::test]
+    async fn server_assigns_sender_and_timestamp() {
+        let repo = Arc::new(MockRepository::default());
+        let state = state_with(&repo, &Config::for_tests());
+
+        let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+        let (reader, writer) = tokio::io::split(server_io);
+        let mut session = ClientSession::new(
+            "alice".to_string(),
+            state,
+            FramedRead::new(reader, McsCodec::new()),
+            FramedWrite::new(writer, McsCodec::new()),
+        );
+        let session = tokio::spawn(async move { session.run().await });
+
+        let (client_reader, client_writer) = tokio::io::split(client_io);
+        let mut client_writer = FramedWrite::new(client_writer, McsCodec::new());
+        let mut client_reader = FramedRead::new(client_reader, McsCodec::new());
+        let forged = ChatPacket {
+            id: 7,
+            sender: "mallory".to_string(),
+            content: "hello".to_string(),
+            timestamp: 1,
+            deleted: false,
+        };
+        client_writer.send(Message::Chat(forged)).await.unwrap();
+
+        loop {
+            match client_reader.next().await {
+                Some(Ok(Message::ChatAck {
+                    local_id: 7,
+                    result,
+                })) => {
+                    assert!(result.is_ok());
+                    break;
+                }
+                Some(Ok(_)) => {}
+                other => panic!("expected a chat ack, got {other:?}"),
+            }
+        }
+        let saved = repo.saved.lock().unwrap()[0].clone();
+        assert_eq!(saved.sender, "alice");
+        assert!(saved.timestamp > 1, "client timestamp should be replaced");
+
+        session.abort();
+    }
+
+    #[tokio::test]
+    async fn stalled_client_does_not_delay_others() {
+        let repo = Arc::new(MockRepository::default());
+        let mut config = Config::for_tests();
+        config.send_timeout = Duration::from_secs(30);
+        let state = state_with(&repo, &config);
+        let tx = state.internal_broadcast_tx.clone();
+
+        let mut sessions = Vec::new();
+        let mut clients = Vec::new();
+        for (name, capacity) in [("stalled", 64), ("reader", 64 * 1024)] {
+            let (server_io, client_io) = tokio::io::duplex(capacity);
+            let (reader, writer) = tokio::io::split(server_io);
+            let mut session = ClientSession::new(
+                name.to_string(),
+                state.clone(),
+                FramedRead::new(reader, McsCodec::new()),
+                FramedWrite::new(writer, McsCodec::new()),
+            );
+            sessions.push(tokio::spawn(async move { session.run().await }));
+            clients.push(client_io);
+        }
+        for i in 0..10 {
+            let packet = ChatPacket::new_server_packet(format!("{i} {}", "x".repeat(32)));
+            tx.send(Message::Chat(packet)).unwrap();
+        }
+
+        let mut reader = FramedRead::new(clients.pop().unwrap(), McsCodec::new());
+        let received = tokio::time::timeout(Duration::from_secs(1), async {
+            for _ in 0..10 {
+                assert!(matches!(reader.next().await, Some(Ok(Message::Chat(_)))));
+            }
+        })
+        .await;
+        assert!(received.is_ok(), "a stalled client held up delivery");
+
+        for session in sessions {
+            session.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn unacked_history_is_resent_after_a_reconnect_until_acked() {
+        let repo = Arc::new(MockRepository::default());
+        for timestamp in [1, 2] {
+            let mut packet = ChatPacket::new_user_packet("bob".to_string(), "hi".to_string());
+            packet.timestamp = timestamp;
+            repo.save_message(&packet).await.unwrap();
+        }
+        let state = state_with(&repo, &Config::for_tests());
+        let connect = || {
+            let (server_io, client_io) = tokio::io::duplex(64 * 1024);
+            let (reader, writer) = tokio::io::split(server_io);
+            let mut session = ClientSession::new(
+                "alice".to_string(),
+                state.clone(),
+                FramedRead::new(reader, McsCodec::new()),
+                FramedWrite::new(writer, McsCodec::new()),
+            );
+            let session = tokio::spawn(async move { session.run().await });
+            (session, Framed::new(client_io, McsCodec::new()))
+        };
+        let request = || Message::HistoryRequest {
+            cursor_ts: i64::MAX,
+            cursor_id: u64::MAX,
+            sender: None,
+            direction: HistoryDirection::Before,
+        };
+
+        let (session, mut client) = connect();
+        client.send(request()).await.unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(Message::HistoryResponse { .. }))
+        ));
+        session.abort();
+
+        let (session, mut client) = connect();
+        client.send(request()).await.unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                client.next().await,
+                Some(Ok(Message::HistoryResponse { .. }))
+            ));
+        }
+
+        client
+            .send(Message::HistoryAck {
+                up_to_ts: 2,
+                up_to_id: 2,
+            })
+            .await
+            .unwrap();
+        client.send(request()).await.unwrap();
+        client
+            .send(Message::Ping {
+                nonce: 1,
+                sent_ms: 0,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(Message::HistoryResponse { .. }))
+        ));
+        assert!(matches!(
+            client.next().await,
+            Some(Ok(Message::Pong { .. }))
+        ));
+
+        session.abort();
     }
 }
@@ -0,0 +1,25 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use crate::error::Result;
+
+/// Builds a TLS acceptor from the PEM certificate chain and private key at the
+/// given paths, decrypting the key with `TLS_KEY_PASSPHRASE` when it is encrypted.
+/// Clients older than `min_version` (`1.2` or `1.3`) fail the handshake.
+pub fn build_acceptor(cert_path: &str, key_path: &str, min_version: &str) -> Result<TlsAcceptor> {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let identity = ::tls::load_identity(
+        Path::new(cert_path),
+        Path::new(key_path),
+        ::tls::key_passphrase().as_deref(),
+    )?;
+    let versions = ::tls::protocol_versions(min_version)?;
+    let config = ServerConfig::builder_with_protocol_versions(versions)
+        .with_no_client_auth()
+        .with_single_cert(identity.chain, identity.key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}